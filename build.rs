@@ -0,0 +1,21 @@
+use tonic_build::manual::{Builder, Method, Service};
+
+// Generates the server of proto/resources.proto. Its messages are derived in src/grpc.rs, so that protoc
+// is not needed. The generated client needs the prelude of edition 2021, tests call the RPC by its path.
+fn main() {
+    let list_resources = Method::builder()
+        .name("list_resources")
+        .route_name("ListResources")
+        .input_type("crate::grpc::ListResourcesRequest")
+        .output_type("crate::grpc::ResourceMessage")
+        .codec_path("tonic_prost::ProstCodec")
+        .server_streaming()
+        .build();
+    let service = Service::builder()
+        .name("Resources")
+        .package("rm.v1")
+        .method(list_resources)
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -16,7 +16,7 @@ CREATE TABLE resource_pools
 
     CONSTRAINT resource_pools_name_key UNIQUE (name),
     CONSTRAINT resource_pools_allocation_strategies_allocation_strategy FOREIGN KEY (resource_pool_allocation_strategy)
-        REFERENCES public.allocation_strategies (id) MATCH SIMPLE
+        REFERENCES allocation_strategies (id) MATCH SIMPLE
         ON UPDATE NO ACTION
         ON DELETE SET NULL
 );
@@ -29,7 +29,7 @@ CREATE TABLE resources
 
     UNIQUE (value, resource_pool),
     CONSTRAINT resources_resource_pools FOREIGN KEY (resource_pool)
-        REFERENCES public.resource_pools (id) MATCH SIMPLE
+        REFERENCES resource_pools (id) MATCH SIMPLE
         ON UPDATE NO ACTION
         ON DELETE SET NULL
 );
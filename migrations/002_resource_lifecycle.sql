@@ -0,0 +1,19 @@
+-- Resources allocated with a lease are reclaimed by `pool gc` once the lease expires.
+ALTER TABLE resources ADD COLUMN lease_expires_at TIMESTAMPTZ;
+
+-- Deallocated resources of pools with a safety period stay in quarantine,
+-- so that they are not handed out again until `pool gc` removes them.
+ALTER TABLE resources ADD COLUMN quarantined_until TIMESTAMPTZ;
+
+-- Number of seconds a deallocated resource spends in quarantine, 0 deletes it immediately.
+ALTER TABLE resource_pools ADD COLUMN deallocation_safety_period INT NOT NULL DEFAULT 0;
+
+CREATE INDEX resources_lease_expires_at
+    ON resources USING btree
+    (lease_expires_at)
+    WHERE lease_expires_at IS NOT NULL;
+
+CREATE INDEX resources_quarantined_until
+    ON resources USING btree
+    (quarantined_until)
+    WHERE quarantined_until IS NOT NULL;
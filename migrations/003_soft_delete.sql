@@ -0,0 +1,16 @@
+-- Deallocated resources are only marked as deleted, `pool gc` removes them
+-- once they are older than the retention window.
+ALTER TABLE resources ADD COLUMN deleted_at TIMESTAMPTZ;
+
+-- Values must be unique only among resources that were not deleted,
+-- otherwise a deallocated value could never be allocated again.
+ALTER TABLE resources DROP CONSTRAINT resources_value_resource_pool_key;
+CREATE UNIQUE INDEX resources_value_resource_pool_key
+    ON resources USING btree
+    (value, resource_pool)
+    WHERE deleted_at IS NULL;
+
+CREATE INDEX resources_deleted_at
+    ON resources USING btree
+    (deleted_at)
+    WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,25 @@
+-- Explicit resource lifecycle, see ResourceState
+ALTER TABLE resources ADD COLUMN status VARCHAR NOT NULL DEFAULT 'allocated';
+ALTER TABLE resources ADD CONSTRAINT resources_status_check
+    CHECK (status IN ('reserved', 'allocated', 'claimed', 'bench', 'retired'));
+
+UPDATE resources SET status = 'bench' WHERE quarantined_until IS NOT NULL AND deleted_at IS NULL;
+UPDATE resources SET status = 'retired' WHERE deleted_at IS NOT NULL;
+
+DROP INDEX resources_value_resource_pool_key;
+CREATE UNIQUE INDEX resources_value_resource_pool_key
+    ON resources USING btree
+    (value, resource_pool)
+    WHERE status <> 'retired';
+
+DROP INDEX resources_quarantined_until;
+CREATE INDEX resources_quarantined_until
+    ON resources USING btree
+    (quarantined_until)
+    WHERE status = 'bench';
+
+DROP INDEX resources_deleted_at;
+CREATE INDEX resources_deleted_at
+    ON resources USING btree
+    (deleted_at)
+    WHERE status = 'retired';
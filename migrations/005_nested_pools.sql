@@ -0,0 +1,10 @@
+-- Pools can be nested, e.g. /24 pools allocated from a /16 pool
+ALTER TABLE resource_pools ADD COLUMN parent_pool INT;
+ALTER TABLE resource_pools ADD CONSTRAINT resource_pools_parent_pool FOREIGN KEY (parent_pool)
+    REFERENCES resource_pools (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL;
+
+CREATE INDEX resource_pools_parent_pool
+    ON resource_pools USING btree
+    (parent_pool ASC NULLS LAST);
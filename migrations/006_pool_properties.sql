@@ -0,0 +1,18 @@
+-- Pool properties passed to strategies as `resourcePoolProperties`, previously hardcoded.
+-- Existing pools keep the range they were using.
+ALTER TABLE resource_pools ADD COLUMN properties JSONB NOT NULL DEFAULT '{"address": "10.0.0.0", "prefix": 8}';
+
+-- Used by DB::update_pool_properties to refuse changes that would orphan allocated values
+UPDATE allocation_strategies SET script = script || '
+// resources that do not fit into the range given by resourcePoolProperties
+function outOfRange() {
+    let first = inet_aton(resourcePoolProperties.address) >>> 0;
+    let last = first + subnetAddresses(resourcePoolProperties.prefix);
+    return currentResources
+        .map(cR => cR.Properties)
+        .filter(r => {
+            let address = inet_aton(r.address) >>> 0;
+            return address < first || address >= last;
+        });
+}
+' WHERE name = 'ipv4';
@@ -0,0 +1,26 @@
+-- Allocations requested with `allocate --async`, processed by `jobs run`
+CREATE TABLE allocation_jobs
+(
+    id SERIAL PRIMARY KEY,
+    resource_pool INT NOT NULL,
+    user_input JSONB NOT NULL,
+    lease_seconds BIGINT,
+    reserve BOOLEAN NOT NULL DEFAULT false,
+    status VARCHAR NOT NULL DEFAULT 'pending',
+    result JSONB,
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    started_at TIMESTAMPTZ,
+    finished_at TIMESTAMPTZ,
+
+    CONSTRAINT allocation_jobs_status_check CHECK (status IN ('pending', 'running', 'done', 'failed')),
+    CONSTRAINT allocation_jobs_resource_pools FOREIGN KEY (resource_pool)
+        REFERENCES resource_pools (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX allocation_jobs_pending
+    ON allocation_jobs USING btree
+    (id)
+    WHERE status = 'pending';
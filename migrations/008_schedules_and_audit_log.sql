@@ -0,0 +1,41 @@
+-- History of operations on pools, append only
+CREATE TABLE audit_log
+(
+    id BIGSERIAL PRIMARY KEY,
+    resource_pool INT,
+    action VARCHAR NOT NULL,
+    details JSONB NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT audit_log_resource_pools FOREIGN KEY (resource_pool)
+        REFERENCES resource_pools (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+CREATE INDEX audit_log_resource_pool
+    ON audit_log USING btree
+    (resource_pool, id);
+
+-- Recurring allocations enqueued by the worker
+CREATE TABLE allocation_schedules
+(
+    id SERIAL PRIMARY KEY,
+    resource_pool INT NOT NULL,
+    name VARCHAR NOT NULL,
+    cron_expression VARCHAR NOT NULL,
+    user_input JSONB NOT NULL,
+    lease_seconds BIGINT,
+    next_run_at TIMESTAMPTZ NOT NULL,
+    last_run_at TIMESTAMPTZ,
+
+    CONSTRAINT allocation_schedules_name_key UNIQUE (resource_pool, name),
+    CONSTRAINT allocation_schedules_resource_pools FOREIGN KEY (resource_pool)
+        REFERENCES resource_pools (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX allocation_schedules_next_run_at
+    ON allocation_schedules USING btree
+    (next_run_at);
@@ -0,0 +1,22 @@
+-- Typed address of IP resources, NULL for resources without a valid `address`
+CREATE FUNCTION try_inet(address text) RETURNS inet
+    LANGUAGE plpgsql IMMUTABLE AS $$
+BEGIN
+    RETURN address::inet;
+EXCEPTION WHEN others THEN
+    RETURN NULL;
+END
+$$;
+
+ALTER TABLE resources ADD COLUMN ip inet GENERATED ALWAYS AS (try_inet(value->>'address')) STORED;
+
+-- Catches duplicates that differ only in JSON, e.g. additional keys next to the address
+CREATE UNIQUE INDEX resources_ip_resource_pool_key
+    ON resources USING btree
+    (resource_pool, ip)
+    WHERE status <> 'retired' AND ip IS NOT NULL;
+
+CREATE INDEX resources_ip
+    ON resources USING gist
+    (ip inet_ops)
+    WHERE ip IS NOT NULL;
@@ -0,0 +1,12 @@
+-- Retired resources moved out of the hot table by `pool archive`, in the format of `resources export`
+CREATE TABLE resources_archive
+(
+    id BIGSERIAL PRIMARY KEY,
+    resource_pool INT NOT NULL,
+    resource JSONB NOT NULL,
+    archived_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX resources_archive_resource_pool
+    ON resources_archive USING btree
+    (resource_pool, id);
@@ -0,0 +1,36 @@
+-- Point-in-time copies of pools, see `snapshot create`
+CREATE TABLE pool_snapshots
+(
+    id SERIAL PRIMARY KEY,
+    resource_pool INT,
+    label VARCHAR NOT NULL,
+    -- pool version at the time of the snapshot
+    version INT NOT NULL,
+    pool JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+
+    CONSTRAINT pool_snapshots_resource_pools FOREIGN KEY (resource_pool)
+        REFERENCES resource_pools (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE SET NULL
+);
+
+CREATE INDEX pool_snapshots_resource_pool
+    ON pool_snapshots USING btree
+    (resource_pool, id);
+
+-- Resources in the format of `resources export`
+CREATE TABLE pool_snapshot_resources
+(
+    snapshot INT NOT NULL,
+    resource JSONB NOT NULL,
+
+    CONSTRAINT pool_snapshot_resources_pool_snapshots FOREIGN KEY (snapshot)
+        REFERENCES pool_snapshots (id) MATCH SIMPLE
+        ON UPDATE NO ACTION
+        ON DELETE CASCADE
+);
+
+CREATE INDEX pool_snapshot_resources_snapshot
+    ON pool_snapshot_resources USING btree
+    (snapshot);
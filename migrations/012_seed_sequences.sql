@@ -0,0 +1,3 @@
+-- Rows seeded by 001_init have explicit ids, move the sequences past them
+SELECT setval('allocation_strategies_id_seq', coalesce(max(id), 0) + 1, false) FROM allocation_strategies;
+SELECT setval('resource_pools_id_seq', coalesce(max(id), 0) + 1, false) FROM resource_pools;
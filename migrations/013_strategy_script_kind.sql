@@ -0,0 +1,4 @@
+-- 'script' or 'module', detected from the script if NULL
+ALTER TABLE allocation_strategies ADD COLUMN script_kind VARCHAR;
+ALTER TABLE allocation_strategies ADD CONSTRAINT allocation_strategies_script_kind_check
+    CHECK (script_kind IN ('script', 'module'));
@@ -0,0 +1,8 @@
+-- helper files of a strategy bundle, imported by modules as './path' and prepended to scripts
+CREATE TABLE allocation_strategy_files
+(
+    allocation_strategy_id INTEGER NOT NULL REFERENCES allocation_strategies (id) ON DELETE CASCADE,
+    path                   VARCHAR NOT NULL,
+    content                TEXT    NOT NULL,
+    PRIMARY KEY (allocation_strategy_id, path)
+);
@@ -0,0 +1,2 @@
+-- TypeScript source of the strategy, `script` then holds the JavaScript compiled from it when it was saved
+ALTER TABLE allocation_strategies ADD COLUMN typescript TEXT;
@@ -0,0 +1,4 @@
+-- engine executing the strategy, see `Engine`
+ALTER TABLE allocation_strategies ADD COLUMN engine VARCHAR NOT NULL DEFAULT 'quickjs-subprocess';
+ALTER TABLE allocation_strategies ADD CONSTRAINT allocation_strategies_engine_check
+    CHECK (engine IN ('quickjs-subprocess', 'quickjs-embedded', 'wasm-module', 'native'));
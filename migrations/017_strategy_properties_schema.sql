@@ -0,0 +1,5 @@
+-- keys and types of `resourcePoolProperties` expected by the strategy, see `PropertiesSchema`.
+-- NULL accepts any properties.
+ALTER TABLE allocation_strategies ADD COLUMN properties_schema JSONB;
+
+UPDATE allocation_strategies SET properties_schema = '{"address": "string", "prefix": "integer"}' WHERE name = 'ipv4';
@@ -0,0 +1,7 @@
+-- JSON Schema of `userInput` declared by the strategy, see `InputSchema`. NULL accepts any input.
+ALTER TABLE allocation_strategies ADD COLUMN input_schema JSONB;
+
+UPDATE allocation_strategies SET input_schema = '{"type": "object", "properties": {
+    "resourceCount": {"type": "integer", "minimum": 1},
+    "subnet": {"type": "boolean"}
+}}' WHERE name = 'ipv4';
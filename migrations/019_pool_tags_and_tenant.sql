@@ -0,0 +1,7 @@
+-- Used to group and find pools, see `DB::list_pools`
+ALTER TABLE resource_pools ADD COLUMN tenant VARCHAR;
+ALTER TABLE resource_pools ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+CREATE INDEX resource_pools_tenant_idx ON resource_pools (tenant);
+CREATE INDEX resource_pools_tags_idx ON resource_pools USING GIN (tags);
+-- prefix search of names
+CREATE INDEX resource_pools_name_pattern_idx ON resource_pools (name text_pattern_ops);
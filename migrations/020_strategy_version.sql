@@ -0,0 +1,2 @@
+-- incremented whenever the script of the strategy changes
+ALTER TABLE allocation_strategies ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
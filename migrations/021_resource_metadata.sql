@@ -0,0 +1,2 @@
+-- Owner, labels and the like, can be changed after allocation unlike the value
+ALTER TABLE resources ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,5 @@
+-- Index-only scans of `DB::count_resources`, resources in use are the ones that are not retired
+CREATE INDEX resources_in_use
+    ON resources USING btree
+    (resource_pool)
+    WHERE status <> 'retired';
@@ -0,0 +1,15 @@
+-- Utilization of pools sampled by the leading worker, see `DB::record_pool_stats`
+CREATE TABLE pool_stats_history
+(
+    id BIGSERIAL PRIMARY KEY,
+    resource_pool INT NOT NULL REFERENCES resource_pools (id) ON DELETE CASCADE,
+    sampled_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- resources that are not retired
+    in_use BIGINT NOT NULL,
+    -- number of resources by state, e.g. {"allocated": 10, "bench": 2}
+    by_state JSONB NOT NULL
+);
+
+CREATE INDEX pool_stats_history_resource_pool_sampled_at
+    ON pool_stats_history USING btree
+    (resource_pool, sampled_at);
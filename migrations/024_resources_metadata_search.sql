@@ -0,0 +1,5 @@
+-- Containment (@>) and jsonpath (@?, @@) searches of `DB::search_resources`
+CREATE INDEX resources_metadata
+    ON resources USING gin
+    (metadata jsonb_path_ops)
+    WHERE status <> 'retired';
@@ -0,0 +1,110 @@
+-- Strategies allocating the lowest free addresses of the range given by `address` and `prefix` properties.
+-- Their pools keep gaps between addresses in use in `free_ranges`, see `DB::allocate_resources`.
+ALTER TABLE allocation_strategies ADD COLUMN sequential BOOLEAN NOT NULL DEFAULT false;
+UPDATE allocation_strategies SET sequential = true WHERE id = 1;
+
+-- Inclusive ranges of free IPv4 addresses as numbers, maintained by the `resources_free_ranges` trigger
+CREATE TABLE free_ranges
+(
+    resource_pool INT NOT NULL REFERENCES resource_pools (id) ON DELETE CASCADE,
+    first BIGINT NOT NULL,
+    last BIGINT NOT NULL,
+
+    PRIMARY KEY (resource_pool, first),
+    CONSTRAINT free_ranges_last_key UNIQUE (resource_pool, last),
+    CONSTRAINT free_ranges_check CHECK (first <= last)
+);
+
+-- Range of a pool with a sequential strategy, no rows for other pools
+CREATE FUNCTION free_range_bounds(pool_id INT, OUT first BIGINT, OUT last BIGINT) RETURNS SETOF record
+    LANGUAGE sql STABLE AS $$
+    SELECT try_inet(p.properties->>'address') - '0.0.0.0'::inet,
+        try_inet(p.properties->>'address') - '0.0.0.0'::inet + (1::bigint << (32 - (p.properties->>'prefix')::int)) - 1
+    FROM resource_pools p JOIN allocation_strategies s ON s.id = p.resource_pool_allocation_strategy
+    WHERE p.id = pool_id AND s.sequential AND family(try_inet(p.properties->>'address')) = 4
+        AND (p.properties->>'prefix')::int BETWEEN 0 AND 32
+$$;
+
+-- Recomputes gaps from resources in use, e.g. after properties of the pool changed
+CREATE FUNCTION rebuild_free_ranges(pool_id INT) RETURNS void
+    LANGUAGE sql AS $$
+    DELETE FROM free_ranges WHERE resource_pool = pool_id;
+    INSERT INTO free_ranges (resource_pool, first, last)
+    SELECT pool_id, gap_first, gap_last FROM (
+        SELECT coalesce(lag(used.n) OVER (ORDER BY used.n), bounds.first - 1) + 1 AS gap_first, used.n - 1 AS gap_last
+        FROM free_range_bounds(pool_id) bounds, LATERAL (
+            SELECT r.ip - '0.0.0.0'::inet AS n FROM resources r
+            WHERE r.resource_pool = pool_id AND r.status <> 'retired' AND family(r.ip) = 4
+                AND r.ip - '0.0.0.0'::inet BETWEEN bounds.first AND bounds.last
+            UNION ALL
+            SELECT bounds.last + 1
+        ) used
+    ) gaps
+    WHERE gap_first <= gap_last;
+$$;
+
+CREATE FUNCTION take_free_address(pool_id INT, address BIGINT) RETURNS void
+    LANGUAGE plpgsql AS $$
+DECLARE
+    range free_ranges%ROWTYPE;
+BEGIN
+    SELECT * INTO range FROM free_ranges
+        WHERE resource_pool = pool_id AND first <= address ORDER BY first DESC LIMIT 1 FOR UPDATE;
+    IF NOT FOUND OR range.last < address THEN
+        RETURN;
+    END IF;
+    DELETE FROM free_ranges WHERE resource_pool = pool_id AND first = range.first;
+    IF range.first < address THEN
+        INSERT INTO free_ranges VALUES (pool_id, range.first, address - 1);
+    END IF;
+    IF address < range.last THEN
+        INSERT INTO free_ranges VALUES (pool_id, address + 1, range.last);
+    END IF;
+END
+$$;
+
+CREATE FUNCTION give_free_address(pool_id INT, address BIGINT) RETURNS void
+    LANGUAGE plpgsql AS $$
+DECLARE
+    merged_first BIGINT := address;
+    merged_last BIGINT := address;
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM free_range_bounds(pool_id) WHERE address BETWEEN first AND last)
+        OR EXISTS (SELECT 1 FROM free_ranges WHERE resource_pool = pool_id AND address BETWEEN first AND last) THEN
+        RETURN;
+    END IF;
+    DELETE FROM free_ranges WHERE resource_pool = pool_id AND last = address - 1 RETURNING first INTO merged_first;
+    merged_first := coalesce(merged_first, address);
+    DELETE FROM free_ranges WHERE resource_pool = pool_id AND first = address + 1 RETURNING last INTO merged_last;
+    merged_last := coalesce(merged_last, address);
+    INSERT INTO free_ranges VALUES (pool_id, merged_first, merged_last);
+END
+$$;
+
+-- Resources are in use unless retired, addresses leaving or entering use are given to or taken from the free list
+CREATE FUNCTION resources_free_ranges() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+DECLARE
+    was_used BOOLEAN := false;
+    is_used BOOLEAN := false;
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        was_used := OLD.status <> 'retired' AND coalesce(family(OLD.ip) = 4, false);
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        is_used := NEW.status <> 'retired' AND coalesce(family(NEW.ip) = 4, false);
+    END IF;
+    IF was_used AND NOT is_used THEN
+        PERFORM give_free_address(OLD.resource_pool, OLD.ip - '0.0.0.0'::inet);
+    ELSIF is_used AND NOT was_used THEN
+        PERFORM take_free_address(NEW.resource_pool, NEW.ip - '0.0.0.0'::inet);
+    END IF;
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER resources_free_ranges
+    AFTER INSERT OR UPDATE OF status OR DELETE ON resources
+    FOR EACH ROW EXECUTE FUNCTION resources_free_ranges();
+
+SELECT rebuild_free_ranges(id) FROM resource_pools;
@@ -0,0 +1,3 @@
+-- SQL select lists evaluated over resources in use of a pool, embedded into the script as `resourceSummary`,
+-- see `ContextQueries`. NULL computes no summary.
+ALTER TABLE allocation_strategies ADD COLUMN context_queries JSONB;
@@ -0,0 +1,9 @@
+-- Principal or foreign reference owning the resource, e.g. `service:voip`, see `DB::transfer_resource`
+ALTER TABLE resources ADD COLUMN owner VARCHAR;
+ALTER TABLE allocation_jobs ADD COLUMN owner VARCHAR;
+
+-- Resources in use by owner across pools, see `DB::find_owned_resources`
+CREATE INDEX resources_owner
+    ON resources USING btree
+    (owner, id)
+    WHERE status <> 'retired' AND owner IS NOT NULL;
@@ -0,0 +1,3 @@
+-- Human-readable note given at allocation, e.g. `loopback for PE-router-3`
+ALTER TABLE resources ADD COLUMN description TEXT;
+ALTER TABLE allocation_jobs ADD COLUMN description TEXT;
@@ -0,0 +1,13 @@
+-- Utilization thresholds of pools evaluated by the leading worker, see `DB::evaluate_alert_rules`
+CREATE TABLE pool_alert_rules
+(
+    id SERIAL PRIMARY KEY,
+    resource_pool INT NOT NULL REFERENCES resource_pools (id) ON DELETE CASCADE,
+    -- fraction of the capacity of the pool in use, e.g. 0.9
+    threshold DOUBLE PRECISION NOT NULL CHECK (threshold > 0 AND threshold <= 1),
+    -- utilization stayed at or above the threshold since `fired_at`
+    firing BOOLEAN NOT NULL DEFAULT false,
+    fired_at TIMESTAMPTZ,
+
+    UNIQUE (resource_pool, threshold)
+);
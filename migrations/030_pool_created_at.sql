@@ -0,0 +1,2 @@
+-- Pools created before this migration count as created when it was applied, see `DB::find_idle_pools`
+ALTER TABLE resource_pools ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,2 @@
+-- Archived pools are read-only and hidden from `pool list` unless requested, see `DB::archive_pool`
+ALTER TABLE resource_pools ADD COLUMN archived_at TIMESTAMPTZ;
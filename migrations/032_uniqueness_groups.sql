@@ -0,0 +1,79 @@
+-- Pools of a group never have the same value in use, e.g. IPv4 pools sharing one address space
+CREATE TABLE uniqueness_groups
+(
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE uniqueness_group_pools
+(
+    uniqueness_group INT NOT NULL REFERENCES uniqueness_groups (id) ON DELETE CASCADE,
+    resource_pool INT NOT NULL REFERENCES resource_pools (id) ON DELETE CASCADE,
+
+    PRIMARY KEY (uniqueness_group, resource_pool)
+);
+
+CREATE INDEX uniqueness_group_pools_resource_pool
+    ON uniqueness_group_pools USING btree
+    (resource_pool ASC NULLS LAST);
+
+-- Values in use by pools of each group, maintained by the `resources_uniqueness_groups` trigger
+CREATE TABLE uniqueness_group_values
+(
+    uniqueness_group INT NOT NULL,
+    value JSONB NOT NULL,
+    resource_pool INT NOT NULL,
+
+    PRIMARY KEY (uniqueness_group, value),
+    FOREIGN KEY (uniqueness_group, resource_pool) REFERENCES uniqueness_group_pools ON DELETE CASCADE
+);
+
+CREATE INDEX uniqueness_group_values_resource_pool
+    ON uniqueness_group_values USING btree
+    (resource_pool ASC NULLS LAST, value ASC NULLS LAST);
+
+-- Claims values entering use in every group of the pool. A value in use by another pool of a group fails with
+-- `unique_violation` of `uniqueness_group_values_pkey`, detail is a JSON naming the group, the pool and the value.
+CREATE FUNCTION resources_uniqueness_groups() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+DECLARE
+    was_used BOOLEAN := false;
+    is_used BOOLEAN := false;
+    conflict RECORD;
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        was_used := OLD.status <> 'retired';
+    END IF;
+    IF TG_OP <> 'DELETE' THEN
+        is_used := NEW.status <> 'retired';
+    END IF;
+    IF was_used AND NOT is_used THEN
+        DELETE FROM uniqueness_group_values WHERE resource_pool = OLD.resource_pool AND value = OLD.value;
+    ELSIF is_used AND NOT was_used THEN
+        INSERT INTO uniqueness_group_values (uniqueness_group, value, resource_pool)
+            SELECT uniqueness_group, NEW.value, NEW.resource_pool FROM uniqueness_group_pools
+            WHERE resource_pool = NEW.resource_pool
+            ON CONFLICT DO NOTHING;
+        SELECT g.name AS uniqueness_group, p.name AS resource_pool INTO conflict
+            FROM uniqueness_group_values v
+            JOIN uniqueness_group_pools gp ON gp.uniqueness_group = v.uniqueness_group
+            JOIN uniqueness_groups g ON g.id = v.uniqueness_group
+            JOIN resource_pools p ON p.id = v.resource_pool
+            WHERE gp.resource_pool = NEW.resource_pool AND v.value = NEW.value AND v.resource_pool <> NEW.resource_pool
+            LIMIT 1;
+        IF FOUND THEN
+            RAISE unique_violation USING
+                MESSAGE = format('Value %s is in use by pool %L of uniqueness group %L',
+                                 NEW.value, conflict.resource_pool, conflict.uniqueness_group),
+                CONSTRAINT = 'uniqueness_group_values_pkey',
+                DETAIL = json_build_object('uniqueness_group', conflict.uniqueness_group,
+                                           'resource_pool', conflict.resource_pool, 'value', NEW.value);
+        END IF;
+    END IF;
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER resources_uniqueness_groups
+    AFTER INSERT OR UPDATE OF status OR DELETE ON resources
+    FOR EACH ROW EXECUTE FUNCTION resources_uniqueness_groups();
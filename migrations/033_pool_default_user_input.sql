@@ -0,0 +1,2 @@
+-- Merged under `userInput` of every allocation from the pool, see `DB::set_default_user_input`
+ALTER TABLE resource_pools ADD COLUMN default_user_input JSONB NOT NULL DEFAULT '{}';
@@ -0,0 +1,11 @@
+-- Human-readable documentation of a strategy, shown by `strategy show`. NULL is undocumented.
+ALTER TABLE allocation_strategies ADD COLUMN description TEXT;
+ALTER TABLE allocation_strategies ADD COLUMN expected_pool_properties_doc TEXT;
+ALTER TABLE allocation_strategies ADD COLUMN expected_user_input_doc TEXT;
+
+UPDATE allocation_strategies SET
+    description = 'Allocates free IPv4 addresses of the root prefix, reusing previously freed ones',
+    expected_pool_properties_doc = '`address` of the root prefix, e.g. `10.0.0.0`, and its `prefix` length, e.g. `24`',
+    expected_user_input_doc = 'Optional `resourceCount` of addresses to allocate, 1 by default. Optional `subnet`, '
+        'whether the root prefix is a real subnet, so that its network and broadcast addresses are not allocated'
+WHERE name = 'ipv4';
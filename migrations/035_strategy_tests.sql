@@ -0,0 +1,13 @@
+-- Named test cases of a strategy, run by `DB::run_strategy_tests` against the stored script
+CREATE TABLE allocation_strategy_tests
+(
+    allocation_strategy_id INTEGER NOT NULL REFERENCES allocation_strategies (id) ON DELETE CASCADE,
+    name                   VARCHAR NOT NULL,
+    user_input             JSONB   NOT NULL DEFAULT '{}',
+    pool_properties        JSONB   NOT NULL DEFAULT '{}',
+    -- values in use by the pool, passed to the script as current resources
+    current_resources      JSONB   NOT NULL DEFAULT '[]',
+    -- values the script returns
+    expected               JSONB   NOT NULL,
+    PRIMARY KEY (allocation_strategy_id, name)
+);
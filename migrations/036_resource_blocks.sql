@@ -0,0 +1,23 @@
+-- Members of a contiguous block reference the block resource they were allocated with, see `DB::insert_block`
+ALTER TABLE resources ADD COLUMN block INT;
+
+CREATE INDEX resources_block
+    ON resources USING btree
+    (block)
+    WHERE block IS NOT NULL;
+
+-- Members follow state changes of their block, e.g. deallocating the block deallocates all of them
+CREATE FUNCTION resources_block_members() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+BEGIN
+    UPDATE resources SET status = NEW.status, lease_expires_at = NEW.lease_expires_at,
+        quarantined_until = NEW.quarantined_until, deleted_at = NEW.deleted_at
+        WHERE resource_pool = NEW.resource_pool AND block = NEW.id;
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER resources_block_members
+    AFTER UPDATE OF status ON resources
+    FOR EACH ROW WHEN (OLD.status IS DISTINCT FROM NEW.status AND NEW.block IS NULL)
+    EXECUTE FUNCTION resources_block_members();
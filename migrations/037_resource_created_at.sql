@@ -0,0 +1,3 @@
+-- Start of the lifetime of a resource, capping lease renewals, see `DB::renew_lease`.
+-- Resources allocated before this migration count from the time it was applied.
+ALTER TABLE resources ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,11 @@
+-- Ids of resources are 64-bit, so that high-churn pools do not exhaust the sequence, pools and strategies
+-- follow in 047_pool_bigint_ids.sql. Rewrites the table, plan downtime for large deployments.
+ALTER SEQUENCE resources_id_seq AS BIGINT;
+
+-- columns of a trigger definition cannot change their type
+DROP TRIGGER resources_block_members ON resources;
+ALTER TABLE resources ALTER COLUMN id TYPE BIGINT, ALTER COLUMN block TYPE BIGINT;
+CREATE TRIGGER resources_block_members
+    AFTER UPDATE OF status ON resources
+    FOR EACH ROW WHEN (OLD.status IS DISTINCT FROM NEW.status AND NEW.block IS NULL)
+    EXECUTE FUNCTION resources_block_members();
@@ -0,0 +1,31 @@
+-- Time of the last change of pools, strategies and resources, maintained by the database so that
+-- no statement can forget it. Rows created before this migration count as changed when it was applied.
+ALTER TABLE allocation_strategies ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE resource_pools ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+ALTER TABLE resources ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- resources allocated within a period, see `ResourceFilter`
+CREATE INDEX resources_created_at
+    ON resources USING btree
+    (resource_pool, created_at);
+
+CREATE FUNCTION touch_updated_at() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+BEGIN
+    NEW.updated_at = now();
+    RETURN NEW;
+END
+$$;
+
+CREATE TRIGGER allocation_strategies_updated_at
+    BEFORE UPDATE ON allocation_strategies
+    FOR EACH ROW EXECUTE FUNCTION touch_updated_at();
+
+CREATE TRIGGER resource_pools_updated_at
+    BEFORE UPDATE ON resource_pools
+    FOR EACH ROW EXECUTE FUNCTION touch_updated_at();
+
+CREATE TRIGGER resources_updated_at
+    BEFORE UPDATE ON resources
+    FOR EACH ROW EXECUTE FUNCTION touch_updated_at();
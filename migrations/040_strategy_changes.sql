@@ -0,0 +1,26 @@
+-- Servers cache linked scripts of strategies and drop them when a strategy or its files change,
+-- see `ScriptCache`. The channel is suffixed with the schema as is the one of pool events.
+CREATE FUNCTION notify_strategy_change() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+DECLARE
+    changed_id INTEGER;
+BEGIN
+    IF TG_TABLE_NAME = 'allocation_strategies' THEN
+        changed_id = NEW.id;
+    ELSIF TG_OP = 'DELETE' THEN
+        changed_id = OLD.allocation_strategy_id;
+    ELSE
+        changed_id = NEW.allocation_strategy_id;
+    END IF;
+    PERFORM pg_notify('strategy_changes.' || current_schema(), json_build_object('id', changed_id)::text);
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER allocation_strategies_changed
+    AFTER UPDATE ON allocation_strategies
+    FOR EACH ROW EXECUTE FUNCTION notify_strategy_change();
+
+CREATE TRIGGER allocation_strategy_files_changed
+    AFTER INSERT OR UPDATE OR DELETE ON allocation_strategy_files
+    FOR EACH ROW EXECUTE FUNCTION notify_strategy_change();
@@ -0,0 +1,2 @@
+-- Allocates from the pool instead of the script of its strategy, see `DB::set_script_override`
+ALTER TABLE resource_pools ADD COLUMN script_override TEXT;
@@ -0,0 +1,13 @@
+-- Scripts run before and after the strategy of allocations from the pool, see `PoolHook`
+CREATE TABLE pool_hooks
+(
+    resource_pool INT NOT NULL REFERENCES resource_pools (id) ON DELETE CASCADE,
+    stage VARCHAR NOT NULL CHECK (stage IN ('pre_allocation', 'post_allocation')),
+    script TEXT NOT NULL,
+    -- the script is killed after it
+    timeout_ms INT NOT NULL CHECK (timeout_ms > 0),
+    -- `fail` fails the allocation, `skip` allocates as if the hook did not exist
+    on_error VARCHAR NOT NULL CHECK (on_error IN ('fail', 'skip')),
+
+    PRIMARY KEY (resource_pool, stage)
+);
@@ -0,0 +1,13 @@
+-- Pool events of committed transactions waiting to be delivered by the leading worker, see `DB::drain_outbox`.
+-- Rows are written together with the audit entry of the event and deleted once delivered.
+CREATE TABLE outbox
+(
+    -- id of the audit entry, the order of delivery
+    id BIGINT PRIMARY KEY,
+    resource_pool INT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- failed deliveries of the batch holding the event
+    attempts INT NOT NULL DEFAULT 0,
+    last_error TEXT
+);
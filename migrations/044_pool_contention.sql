@@ -0,0 +1,12 @@
+-- Outcomes of allocations of pools within the current window, written by every writer when backpressure
+-- is configured, see `Backpressure`
+CREATE TABLE pool_contention
+(
+    resource_pool INT PRIMARY KEY REFERENCES resource_pools (id) ON DELETE CASCADE,
+    window_started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- committed allocations and those failing with a version conflict
+    allocations INT NOT NULL,
+    conflicts INT NOT NULL,
+    -- allocations of callers are enqueued as jobs until then
+    backpressure_until TIMESTAMPTZ
+);
@@ -0,0 +1,8 @@
+-- Form of the value returned by `canonicalize(value)` of the strategy, compared for duplicates instead of
+-- the value, e.g. `{"address": "10.0.0.1"}` for `{"address": "10.0.0.1/32"}`. NULL for other strategies.
+ALTER TABLE resources ADD COLUMN canonical_value JSONB;
+
+CREATE UNIQUE INDEX resources_canonical_value_resource_pool_key
+    ON resources USING btree
+    (resource_pool, canonical_value)
+    WHERE status <> 'retired' AND canonical_value IS NOT NULL;
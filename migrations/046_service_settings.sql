@@ -0,0 +1,9 @@
+-- Settings shared by all processes of the instance, in a single row
+CREATE TABLE service_settings
+(
+    id BOOLEAN PRIMARY KEY DEFAULT true CHECK (id),
+    -- writes are refused while set, see `DB::set_maintenance`
+    maintenance BOOLEAN NOT NULL DEFAULT false
+);
+
+INSERT INTO service_settings DEFAULT VALUES;
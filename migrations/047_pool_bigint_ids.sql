@@ -0,0 +1,120 @@
+-- Ids of pools and strategies are 64-bit like those of resources, together with every column referring to them.
+-- Rewrites the tables, plan downtime for large deployments. A partition key cannot change its type, so partitioned
+-- `resources` are copied into a new table by reapplying optional/partition_resources.sql, see `DB::init_schema`.
+ALTER SEQUENCE resource_pools_id_seq AS BIGINT;
+ALTER SEQUENCE allocation_strategies_id_seq AS BIGINT;
+
+ALTER TABLE allocation_strategies ALTER COLUMN id TYPE BIGINT;
+ALTER TABLE allocation_strategy_files ALTER COLUMN allocation_strategy_id TYPE BIGINT;
+ALTER TABLE allocation_strategy_tests ALTER COLUMN allocation_strategy_id TYPE BIGINT;
+ALTER TABLE resource_pools ALTER COLUMN id TYPE BIGINT, ALTER COLUMN parent_pool TYPE BIGINT,
+    ALTER COLUMN resource_pool_allocation_strategy TYPE BIGINT;
+
+ALTER TABLE allocation_jobs ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE allocation_schedules ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE audit_log ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE free_ranges ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE outbox ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE pool_alert_rules ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE pool_contention ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE pool_hooks ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE pool_snapshots ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE pool_stats_history ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE resources_archive ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE uniqueness_group_pools ALTER COLUMN resource_pool TYPE BIGINT;
+ALTER TABLE uniqueness_group_values ALTER COLUMN resource_pool TYPE BIGINT;
+
+DO $$
+BEGIN
+    IF (SELECT relkind FROM pg_class WHERE oid = 'resources'::regclass) <> 'p' THEN
+        ALTER TABLE resources ALTER COLUMN resource_pool TYPE BIGINT;
+    END IF;
+END
+$$;
+
+-- functions of the free list take the pool id
+DROP FUNCTION rebuild_free_ranges(INT);
+DROP FUNCTION free_range_bounds(INT);
+DROP FUNCTION take_free_address(INT, BIGINT);
+DROP FUNCTION give_free_address(INT, BIGINT);
+
+CREATE FUNCTION free_range_bounds(pool_id BIGINT, OUT first BIGINT, OUT last BIGINT) RETURNS SETOF record
+    LANGUAGE sql STABLE AS $$
+    SELECT try_inet(p.properties->>'address') - '0.0.0.0'::inet,
+        try_inet(p.properties->>'address') - '0.0.0.0'::inet + (1::bigint << (32 - (p.properties->>'prefix')::int)) - 1
+    FROM resource_pools p JOIN allocation_strategies s ON s.id = p.resource_pool_allocation_strategy
+    WHERE p.id = pool_id AND s.sequential AND family(try_inet(p.properties->>'address')) = 4
+        AND (p.properties->>'prefix')::int BETWEEN 0 AND 32
+$$;
+
+CREATE FUNCTION rebuild_free_ranges(pool_id BIGINT) RETURNS void
+    LANGUAGE sql AS $$
+    DELETE FROM free_ranges WHERE resource_pool = pool_id;
+    INSERT INTO free_ranges (resource_pool, first, last)
+    SELECT pool_id, gap_first, gap_last FROM (
+        SELECT coalesce(lag(used.n) OVER (ORDER BY used.n), bounds.first - 1) + 1 AS gap_first, used.n - 1 AS gap_last
+        FROM free_range_bounds(pool_id) bounds, LATERAL (
+            SELECT r.ip - '0.0.0.0'::inet AS n FROM resources r
+            WHERE r.resource_pool = pool_id AND r.status <> 'retired' AND family(r.ip) = 4
+                AND r.ip - '0.0.0.0'::inet BETWEEN bounds.first AND bounds.last
+            UNION ALL
+            SELECT bounds.last + 1
+        ) used
+    ) gaps
+    WHERE gap_first <= gap_last;
+$$;
+
+CREATE FUNCTION take_free_address(pool_id BIGINT, address BIGINT) RETURNS void
+    LANGUAGE plpgsql AS $$
+DECLARE
+    range free_ranges%ROWTYPE;
+BEGIN
+    SELECT * INTO range FROM free_ranges
+        WHERE resource_pool = pool_id AND first <= address ORDER BY first DESC LIMIT 1 FOR UPDATE;
+    IF NOT FOUND OR range.last < address THEN
+        RETURN;
+    END IF;
+    DELETE FROM free_ranges WHERE resource_pool = pool_id AND first = range.first;
+    IF range.first < address THEN
+        INSERT INTO free_ranges VALUES (pool_id, range.first, address - 1);
+    END IF;
+    IF address < range.last THEN
+        INSERT INTO free_ranges VALUES (pool_id, address + 1, range.last);
+    END IF;
+END
+$$;
+
+CREATE FUNCTION give_free_address(pool_id BIGINT, address BIGINT) RETURNS void
+    LANGUAGE plpgsql AS $$
+DECLARE
+    merged_first BIGINT := address;
+    merged_last BIGINT := address;
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM free_range_bounds(pool_id) WHERE address BETWEEN first AND last)
+        OR EXISTS (SELECT 1 FROM free_ranges WHERE resource_pool = pool_id AND address BETWEEN first AND last) THEN
+        RETURN;
+    END IF;
+    DELETE FROM free_ranges WHERE resource_pool = pool_id AND last = address - 1 RETURNING first INTO merged_first;
+    merged_first := coalesce(merged_first, address);
+    DELETE FROM free_ranges WHERE resource_pool = pool_id AND first = address + 1 RETURNING last INTO merged_last;
+    merged_last := coalesce(merged_last, address);
+    INSERT INTO free_ranges VALUES (pool_id, merged_first, merged_last);
+END
+$$;
+
+CREATE OR REPLACE FUNCTION notify_strategy_change() RETURNS trigger
+    LANGUAGE plpgsql AS $$
+DECLARE
+    changed_id BIGINT;
+BEGIN
+    IF TG_TABLE_NAME = 'allocation_strategies' THEN
+        changed_id = NEW.id;
+    ELSIF TG_OP = 'DELETE' THEN
+        changed_id = OLD.allocation_strategy_id;
+    ELSE
+        changed_id = NEW.allocation_strategy_id;
+    END IF;
+    PERFORM pg_notify('strategy_changes.' || current_schema(), json_build_object('id', changed_id)::text);
+    RETURN NULL;
+END
+$$;
@@ -0,0 +1,149 @@
+-- Opt-in: partition resources by pool, so that per-pool scans stay fast with hundreds of millions
+-- of rows. Apply after all numbered migrations. Once `resources` is partitioned, partitions of new
+-- pools are created by DB::insert_resource_pool and dropped by DB::delete_resource_pool.
+-- Apply in a single transaction, e.g. `psql --single-transaction -f partition_resources.sql`.
+-- Reapplying it copies partitioned resources into a new table, e.g. to change the type of the partition key.
+
+-- partitions of a previous run are dropped together with the old table
+DO $$
+DECLARE
+    partition_name NAME;
+BEGIN
+    FOR partition_name IN SELECT c.relname FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+        WHERE i.inhparent = 'resources'::regclass LOOP
+        EXECUTE format('ALTER TABLE %I RENAME TO %I', partition_name, partition_name || '_old');
+    END LOOP;
+END
+$$;
+
+-- the sequence would be dropped together with the old table
+ALTER SEQUENCE resources_id_seq OWNED BY NONE;
+
+CREATE TABLE resources_partitioned
+(
+    id BIGINT NOT NULL DEFAULT nextval('resources_id_seq'),
+    resource_pool BIGINT NOT NULL,
+    value JSONB NOT NULL,
+    lease_expires_at TIMESTAMPTZ,
+    quarantined_until TIMESTAMPTZ,
+    deleted_at TIMESTAMPTZ,
+    status VARCHAR NOT NULL DEFAULT 'allocated',
+    ip inet GENERATED ALWAYS AS (try_inet(value->>'address')) STORED,
+    metadata JSONB NOT NULL DEFAULT '{}',
+    owner VARCHAR,
+    description TEXT,
+    block BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    canonical_value JSONB,
+
+    CONSTRAINT resources_status_check
+        CHECK (status IN ('reserved', 'allocated', 'claimed', 'bench', 'retired'))
+) PARTITION BY LIST (resource_pool);
+
+-- rows of pools created before partitioning was enabled, if a partition could not be created
+CREATE TABLE resources_default PARTITION OF resources_partitioned DEFAULT;
+
+DO $$
+DECLARE
+    pool_id BIGINT;
+BEGIN
+    FOR pool_id IN SELECT id FROM resource_pools LOOP
+        EXECUTE format('CREATE TABLE resources_p%s PARTITION OF resources_partitioned FOR VALUES IN (%s)',
+            pool_id, pool_id);
+    END LOOP;
+END
+$$;
+
+INSERT INTO resources_partitioned
+    (id, resource_pool, value, lease_expires_at, quarantined_until, deleted_at, status, metadata, owner, description,
+    block, created_at, updated_at, canonical_value)
+    SELECT id, resource_pool, value, lease_expires_at, quarantined_until, deleted_at, status, metadata, owner,
+    description, block, created_at, updated_at, canonical_value FROM resources;
+
+DROP TABLE resources;
+ALTER TABLE resources_partitioned RENAME TO resources;
+ALTER SEQUENCE resources_id_seq OWNED BY resources.id;
+
+-- unique indexes of partitioned tables must contain the partition key
+ALTER TABLE resources ADD CONSTRAINT resources_pkey PRIMARY KEY (id, resource_pool);
+ALTER TABLE resources ADD CONSTRAINT resources_resource_pools FOREIGN KEY (resource_pool)
+    REFERENCES resource_pools (id) MATCH SIMPLE
+    ON UPDATE NO ACTION
+    ON DELETE SET NULL;
+
+CREATE UNIQUE INDEX resources_value_resource_pool_key
+    ON resources USING btree
+    (value, resource_pool)
+    WHERE status <> 'retired';
+
+CREATE UNIQUE INDEX resources_ip_resource_pool_key
+    ON resources USING btree
+    (resource_pool, ip)
+    WHERE status <> 'retired' AND ip IS NOT NULL;
+
+CREATE UNIQUE INDEX resources_canonical_value_resource_pool_key
+    ON resources USING btree
+    (resource_pool, canonical_value)
+    WHERE status <> 'retired' AND canonical_value IS NOT NULL;
+
+CREATE INDEX resources_ip
+    ON resources USING gist
+    (ip inet_ops)
+    WHERE ip IS NOT NULL;
+
+CREATE INDEX resources_in_use
+    ON resources USING btree
+    (resource_pool)
+    WHERE status <> 'retired';
+
+CREATE INDEX resources_metadata
+    ON resources USING gin
+    (metadata jsonb_path_ops)
+    WHERE status <> 'retired';
+
+CREATE INDEX resources_owner
+    ON resources USING btree
+    (owner, id)
+    WHERE status <> 'retired' AND owner IS NOT NULL;
+
+CREATE INDEX resources_lease_expires_at
+    ON resources USING btree
+    (lease_expires_at)
+    WHERE lease_expires_at IS NOT NULL;
+
+CREATE INDEX resources_quarantined_until
+    ON resources USING btree
+    (quarantined_until)
+    WHERE status = 'bench';
+
+CREATE INDEX resources_deleted_at
+    ON resources USING btree
+    (deleted_at)
+    WHERE status = 'retired';
+
+CREATE INDEX resources_block
+    ON resources USING btree
+    (block)
+    WHERE block IS NOT NULL;
+
+CREATE INDEX resources_created_at
+    ON resources USING btree
+    (resource_pool, created_at);
+
+CREATE TRIGGER resources_free_ranges
+    AFTER INSERT OR UPDATE OF status OR DELETE ON resources
+    FOR EACH ROW EXECUTE FUNCTION resources_free_ranges();
+
+CREATE TRIGGER resources_uniqueness_groups
+    AFTER INSERT OR UPDATE OF status OR DELETE ON resources
+    FOR EACH ROW EXECUTE FUNCTION resources_uniqueness_groups();
+
+CREATE TRIGGER resources_block_members
+    AFTER UPDATE OF status ON resources
+    FOR EACH ROW WHEN (OLD.status IS DISTINCT FROM NEW.status AND NEW.block IS NULL)
+    EXECUTE FUNCTION resources_block_members();
+
+CREATE TRIGGER resources_updated_at
+    BEFORE UPDATE ON resources
+    FOR EACH ROW EXECUTE FUNCTION touch_updated_at();
@@ -0,0 +1,206 @@
+use std::time::SystemTime;
+
+use anyhow::{Result, anyhow, bail, ensure};
+use chrono::{DateTime, Utc};
+use postgres::Row;
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{DB, ResourcePool, WasmerEnv, metrics};
+use crate::host::PoolResources;
+
+/// Utilization threshold of a pool, fires once when crossed upwards and resolves when utilization drops below it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    pub id: i32,
+    pub resource_pool_id: i64,
+    // fraction of the capacity in use, in (0, 1]
+    pub threshold: f64,
+    pub firing: bool,
+    pub fired_at: Option<SystemTime>,
+}
+
+impl AlertRule {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "resourcePool": self.resource_pool_id,
+            "threshold": self.threshold,
+            "firing": self.firing,
+            "firedAt": self.fired_at.map(|fired_at| DateTime::<Utc>::from(fired_at).to_rfc3339()),
+        })
+    }
+
+    fn from_row(row: Row) -> AlertRule {
+        AlertRule { id: row.get(0), resource_pool_id: row.get(1), threshold: row.get(2), firing: row.get(3),
+            fired_at: row.get(4) }
+    }
+}
+
+/// Rules that fired or resolved in one evaluation, see `DB::evaluate_alert_rules`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AlertReport {
+    pub fired: Vec<AlertRule>,
+    pub resolved: Vec<AlertRule>,
+}
+
+const ALERT_RULE_COLUMNS: &str = "id, resource_pool, threshold, firing, fired_at";
+
+impl DB {
+    pub fn add_alert_rule(&mut self, resource_pool_id: i64, threshold: f64) -> Result<AlertRule> {
+        ensure!(threshold > 0.0 && threshold <= 1.0, "Threshold must be in (0, 1], got {}", threshold);
+        let row = self.client.query_one(
+            format!("INSERT INTO pool_alert_rules (resource_pool, threshold) VALUES ($1, $2) \
+                ON CONFLICT (resource_pool, threshold) DO UPDATE SET threshold = EXCLUDED.threshold \
+                RETURNING {}", ALERT_RULE_COLUMNS).as_str(), &[&resource_pool_id, &threshold])?;
+        Ok(AlertRule::from_row(row))
+    }
+
+    pub fn remove_alert_rule(&mut self, id: i32) -> Result<()> {
+        if self.client.execute("DELETE FROM pool_alert_rules WHERE id=$1", &[&id])? == 0 {
+            bail!("Alert rule {} not found", id);
+        }
+        Ok(())
+    }
+
+    // Rules of the pool by threshold, all rules if None.
+    pub fn get_alert_rules(&mut self, resource_pool_id: Option<i64>) -> Result<Vec<AlertRule>> {
+        let rows = self.client.query(
+            format!("SELECT {} FROM pool_alert_rules WHERE $1::bigint IS NULL OR resource_pool=$1 \
+                ORDER BY resource_pool, threshold", ALERT_RULE_COLUMNS).as_str(), &[&resource_pool_id])?;
+        Ok(rows.into_iter().map(AlertRule::from_row).collect())
+    }
+
+    // Fraction of the capacity in use according to `capacity()` of the strategy, None if it does not define it.
+    pub fn pool_utilization(&mut self, pool: &ResourcePool, wasmer_env: &mut WasmerEnv) -> Result<Option<f64>> {
+        Ok(self.pool_capacity(pool, wasmer_env)?.map(|(utilized, free)| {
+            let total = utilized + free;
+            if total > 0.0 { utilized / total } else { 1.0 }
+        }))
+    }
+
+    // Utilized and free capacity returned by `capacity()` of the strategy, None if it does not define it.
+    pub(crate) fn pool_capacity(&mut self, pool: &ResourcePool, wasmer_env: &mut WasmerEnv)
+                                -> Result<Option<(f64, f64)>> {
+        let context = self.get_allocation_context(pool.id)?;
+        let engine = wasmer_env.engine(context.engine)?;
+        let mut current_resources = PoolResources::new(&mut self.client, pool.id);
+        let capacity = engine.invoke(&context.script, json!({}), pool.properties.clone(), pool.as_json(),
+                                     &mut current_resources, "typeof capacity === 'function' ? capacity() : null")?;
+        if capacity.is_null() {
+            return Ok(None);
+        }
+        match (capacity["utilizedCapacity"].as_f64(), capacity["freeCapacity"].as_f64()) {
+            (Some(utilized), Some(free)) => Ok(Some((utilized, free))),
+            _ => bail!("Script returned invalid capacity() result '{}'", capacity),
+        }
+    }
+
+    // Evaluates rules of every pool that has some. Rules crossing their threshold upwards are fired: logged
+    // as a warning, recorded in the audit log as `alert_fired`, published as a pool event delivered to webhooks
+    // of the outbox and counted by `alerts_fired_total`. Pools whose strategy has no capacity are skipped.
+    pub fn evaluate_alert_rules(&mut self, wasmer_env: &mut WasmerEnv) -> Result<AlertReport> {
+        let rules = self.get_alert_rules(None)?;
+        let mut report = AlertReport::default();
+        let mut pool_ids = rules.iter().map(|rule| rule.resource_pool_id).collect::<Vec<_>>();
+        pool_ids.dedup();
+        for pool_id in pool_ids {
+            let pool = self.find_resource_pool_by_id(pool_id)?
+                .ok_or_else(|| anyhow!("Resource pool {} not found", pool_id))?;
+            let utilization = match self.pool_utilization(&pool, wasmer_env) {
+                Ok(Some(utilization)) => utilization,
+                Ok(None) => continue,
+                Err(err) => {
+                    warn!("Cannot compute utilization of pool '{}': {:#}", pool.name, err);
+                    continue;
+                }
+            };
+            for rule in rules.iter().filter(|rule| rule.resource_pool_id == pool_id) {
+                let crossed = utilization >= rule.threshold;
+                if crossed == rule.firing {
+                    continue;
+                }
+                let mut transaction = self.client.transaction()?;
+                let row = transaction.query_one(
+                    format!("UPDATE pool_alert_rules SET firing=$2, \
+                        fired_at=CASE WHEN $2 THEN now() ELSE fired_at END WHERE id=$1 RETURNING {}",
+                            ALERT_RULE_COLUMNS).as_str(), &[&rule.id, &crossed])?;
+                let action = if crossed { "alert_fired" } else { "alert_resolved" };
+                let id = Self::record_audit(&mut transaction, Some(pool_id), action,
+                                            json!({"rule": rule.id, "threshold": rule.threshold,
+                                                "utilization": utilization}))?;
+                Self::publish_pool_event(&mut transaction, self.outbox, id, pool_id, json!({
+                    "id": id,
+                    "pool": pool_id,
+                    "version": pool.version,
+                    "event": action,
+                    "rule": rule.id,
+                    "threshold": rule.threshold,
+                    "utilization": utilization,
+                }))?;
+                transaction.commit()?;
+                if crossed {
+                    warn!("Utilization {:.1}% of pool '{}' crossed the threshold of {:.1}%",
+                          utilization * 100.0, pool.name, rule.threshold * 100.0);
+                    metrics::record_alert(pool_id, rule.threshold);
+                    report.fired.push(AlertRule::from_row(row));
+                } else {
+                    info!("Utilization {:.1}% of pool '{}' dropped below the threshold of {:.1}%",
+                          utilization * 100.0, pool.name, rule.threshold * 100.0);
+                    report.resolved.push(AlertRule::from_row(row));
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Resource, ResourceSelector};
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_alert_rules() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let pool = db.update_pool_properties(pool, &mut wasmer_env, json!({"address": "10.0.0.0", "prefix": 29}))
+            .unwrap();
+        assert!(db.add_alert_rule(pool.id, 1.5).is_err());
+        let rule = db.add_alert_rule(pool.id, 0.5).unwrap();
+        assert_eq!(vec![rule.clone()], db.get_alert_rules(Some(pool.id)).unwrap());
+        assert_eq!(Some(0.0), db.pool_utilization(&pool, &mut wasmer_env).unwrap());
+
+        let resources = ["10.0.0.1", "10.0.0.2", "10.0.0.3"].iter()
+            .map(|address| Resource::new_from_value(pool.id, json!({"address": address})))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        assert_eq!(Some(0.5), db.pool_utilization(&pool, &mut wasmer_env).unwrap());
+        // other tests evaluate rules concurrently, only rules of this pool are checked
+        db.outbox = true;
+        db.evaluate_alert_rules(&mut wasmer_env).unwrap();
+        db.outbox = false;
+        let firing = db.get_alert_rules(Some(pool.id)).unwrap().remove(0);
+        assert!(firing.firing && firing.fired_at.is_some());
+        let events = db.client.query("SELECT payload FROM outbox WHERE resource_pool=$1", &[&pool.id]).unwrap()
+            .into_iter()
+            .map(|row| row.get::<_, Value>(0))
+            .map(|event| (event["event"].clone(), event["rule"].clone(), event["utilization"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(json!("alert_fired"), json!(rule.id), json!(0.5))], events);
+        let report = db.evaluate_alert_rules(&mut wasmer_env).unwrap();
+        assert!(!report.fired.iter().any(|it| it.id == rule.id), "Alert fired twice");
+        let line = format!("alerts_fired_total{{pool=\"{}\",threshold=\"0.5\"}} 1\n", pool.id);
+        assert!(metrics::render().contains(&line), "{}", metrics::render());
+
+        db.deallocate_resource(pool.clone(), &ResourceSelector::Value(json!({"address": "10.0.0.1"}))).unwrap();
+        db.evaluate_alert_rules(&mut wasmer_env).unwrap();
+        assert!(!db.get_alert_rules(Some(pool.id)).unwrap()[0].firing);
+        db.remove_alert_rule(rule.id).unwrap();
+        assert!(db.remove_alert_rule(rule.id).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Result, anyhow, ensure};
+use postgres::Client;
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{DB, Resource, ResourcePool};
+use crate::error::AllocationError;
+
+impl DB {
+    // Makes the pool and its resources read-only, e.g. for a decommissioned site whose history must be kept.
+    // Archived pools are still found by name and id, but `list_pools` skips them unless asked to include them.
+    pub fn archive_pool(&mut self, mut pool: ResourcePool) -> Result<ResourcePool> {
+        let mut transaction = self.client.transaction()?;
+        // fails if the pool is archived already or was changed concurrently
+        Self::bump_version(&mut transaction, &mut pool)?;
+        let archived_at: SystemTime = transaction.query_one(
+            "UPDATE resource_pools SET archived_at=now() WHERE id=$1 RETURNING archived_at", &[&pool.id])?.get(0);
+        Self::record_audit(&mut transaction, Some(pool.id), "pool_archived", json!({}))?;
+        transaction.commit()?;
+        pool.archived_at = Some(archived_at);
+        Ok(pool)
+    }
+
+    // Makes an archived pool writable again, its resources are kept as they were archived.
+    pub fn unarchive_pool(&mut self, mut pool: ResourcePool) -> Result<ResourcePool> {
+        let mut transaction = self.client.transaction()?;
+        let updated = transaction.query_opt(
+            "UPDATE resource_pools SET archived_at=NULL, version=version + 1 WHERE id=$1 AND archived_at IS NOT NULL \
+            RETURNING updated_at", &[&pool.id])?
+            .ok_or_else(|| anyhow!("Resource pool '{}' is not archived", pool.name))?;
+        Self::record_audit(&mut transaction, Some(pool.id), "pool_unarchived", json!({}))?;
+        transaction.commit()?;
+        pool.version += 1;
+        pool.updated_at = updated.get(0);
+        pool.archived_at = None;
+        Ok(pool)
+    }
+
+    // Fails with `AllocationError::PoolArchived` if the pool is archived. Locks the pool row until the end
+    // of the transaction, so that it cannot be archived while its resources are being changed.
+    pub(crate) fn check_not_archived(client: &mut Client, resource_pool_id: i64) -> Result<()> {
+        let row = client.query_opt(
+            "SELECT name, archived_at IS NOT NULL FROM resource_pools WHERE id=$1 FOR SHARE", &[&resource_pool_id])?
+            .ok_or_else(|| anyhow!("Resource pool {} not found", resource_pool_id))?;
+        if row.get(1) {
+            return Err(AllocationError::PoolArchived { resource_pool: row.get(0) }.into());
+        }
+        Ok(())
+    }
+
+    // Move retired resources deleted more than `older_than` ago into `resources_archive`, stored as
+    // `Resource::as_export_json`. Expired leases are archived once `pool gc` retires them.
+    // Each batch is moved in its own transaction. Returns number of archived resources.
+    pub fn archive_pool_resources(&mut self, resource_pool_id: i64, older_than: Duration, batch_size: i64)
+                                  -> Result<u64> {
+        ensure!(batch_size > 0, "Batch size must be positive");
+        let mut archived = 0;
+        loop {
+            let mut transaction = self.client.transaction()?;
+            Self::check_not_archived(&mut transaction, resource_pool_id)?;
+            let resources = transaction.query(
+                format!("SELECT {} FROM resources WHERE resource_pool=$1 AND status = 'retired' \
+                    AND deleted_at < now() - make_interval(secs => $2) ORDER BY id LIMIT $3 FOR UPDATE",
+                        Self::RESOURCE_COLUMNS).as_str(),
+                &[&resource_pool_id, &older_than.as_secs_f64(), &batch_size])?
+                .into_iter()
+                .map(|row| Self::row_to_resource(resource_pool_id, row))
+                .collect::<Result<Vec<Resource>>>()?;
+            if resources.is_empty() {
+                break;
+            }
+            let ids = resources.iter().filter_map(|it| it.id).collect::<Vec<i64>>();
+            let exported = resources.iter().map(|it| it.as_export_json()).collect::<Vec<Value>>();
+            transaction.execute(
+                "INSERT INTO resources_archive (resource_pool, resource) SELECT $1, unnest($2::jsonb[])",
+                &[&resource_pool_id, &exported])?;
+            transaction.execute("DELETE FROM resources WHERE resource_pool=$1 AND id = ANY($2)",
+                                &[&resource_pool_id, &ids])?;
+            transaction.commit()?;
+            archived += resources.len() as u64;
+            debug!("Archived {} resources of pool {}", resources.len(), resource_pool_id);
+            if (resources.len() as i64) < batch_size {
+                break;
+            }
+        }
+        Ok(archived)
+    }
+
+    // Archived resources of the pool in the order they were archived.
+    pub fn get_archived_resources(&mut self, resource_pool_id: i64) -> Result<Vec<Value>> {
+        let rows = self.client.query(
+            "SELECT resource FROM resources_archive WHERE resource_pool=$1 ORDER BY id", &[&resource_pool_id])?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{AllocationOptions, BulkSelector, WasmerEnv};
+    use crate::pools::{DEFAULT_PAGE_SIZE, PoolFilter};
+    use crate::state::ResourceState;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_archive_pool_resources() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = (1..=4)
+            .map(|i| Resource::new_from_value(pool.id, json!({"address": format!("10.0.0.{}", i)})))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let ids = db.get_resources(pool.id).unwrap().iter().map(|it| it.id.unwrap()).collect::<Vec<_>>();
+        let (pool, _) = db.deallocate_resources(pool, &BulkSelector::Ids(ids[..3].to_vec())).unwrap();
+        assert_eq!(0, db.archive_pool_resources(pool.id, Duration::from_secs(3600), 2).unwrap());
+
+        assert_eq!(3, db.archive_pool_resources(pool.id, Duration::from_secs(0), 2).unwrap());
+        let archived = db.get_archived_resources(pool.id).unwrap();
+        assert_eq!(vec![json!({"address": "10.0.0.1"}), json!({"address": "10.0.0.2"}),
+                        json!({"address": "10.0.0.3"})],
+                   archived.iter().map(|it| it["value"].clone()).collect::<Vec<_>>());
+        assert!(archived.iter().all(|it| it["state"] == "retired" && it["deletedAt"].is_string()));
+        // archived resources cannot be restored, the pool only keeps resources in use
+        db.restore_resource(pool.clone(), ids[0]).expect_err("Resource was archived");
+        assert_eq!(vec![ResourceState::Allocated], db.get_resources_filtered(
+            pool.id, &crate::ResourceFilter { include_deleted: true, ..Default::default() }).unwrap()
+            .iter().map(|it| it.state).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn db_archive_pool() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, allocated) = db.allocate_resources(pool, &mut wasmer_env, json!({}),
+                                                      &AllocationOptions::default()).unwrap();
+        let pool = db.archive_pool(pool).unwrap();
+        assert!(pool.archived_at.is_some());
+        assert!(db.archive_pool(pool.clone()).is_err());
+
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .unwrap_err();
+        assert_eq!(Some(&AllocationError::PoolArchived { resource_pool: pool.name.clone() }),
+                   err.downcast_ref::<AllocationError>());
+        let id = db.get_resources(pool.id).unwrap()[0].id.unwrap();
+        assert!(db.update_resource_metadata(id, &json!({"site": "old"})).is_err());
+        assert!(db.deallocate_resources(pool.clone(), &BulkSelector::Ids(vec![id])).is_err());
+        // still queryable, but hidden from listings
+        assert_eq!(pool, db.get_resource_pool_by_id(pool.id).unwrap());
+        assert_eq!(vec![allocated[0].value.clone()],
+                   db.get_resources(pool.id).unwrap().into_iter().map(|it| it.value).collect::<Vec<_>>());
+        let filter = PoolFilter {
+            name_prefix: Some(pool.name.clone()), limit: DEFAULT_PAGE_SIZE, ..Default::default()
+        };
+        assert!(db.list_pools(&filter).unwrap().is_empty());
+        let archived = PoolFilter { include_archived: true, ..filter.clone() };
+        assert_eq!(vec![pool.clone()], db.list_pools(&archived).unwrap());
+
+        let pool = db.unarchive_pool(pool).unwrap();
+        assert!(db.unarchive_pool(pool.clone()).is_err());
+        assert_eq!(vec![pool.clone()], db.list_pools(&filter).unwrap());
+        db.allocate_resources(pool, &mut wasmer_env, json!({}), &AllocationOptions::default()).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::SystemTime;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use postgres::{Client, Row};
+use serde_json::{Value, json};
+
+use crate::DB;
+
+/// Entry of the append only `audit_log`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub resource_pool_id: Option<i64>,
+    pub action: String,
+    pub details: Value,
+    pub created_at: SystemTime,
+}
+
+impl AuditEntry {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "resourcePool": self.resource_pool_id,
+            "action": &self.action,
+            "details": &self.details,
+            "createdAt": DateTime::<Utc>::from(self.created_at).to_rfc3339(),
+        })
+    }
+}
+
+impl DB {
+    // Takes the client or transaction of the audited change, so that both are committed together.
+    // Returns id of the entry.
+    pub fn record_audit(client: &mut Client, resource_pool_id: Option<i64>, action: &str,
+                        details: Value) -> Result<i64> {
+        let row = client.query_one(
+            "INSERT INTO audit_log (resource_pool, action, details) VALUES ($1, $2, $3) RETURNING id",
+            &[&resource_pool_id, &action, &details])?;
+        Ok(row.get(0))
+    }
+
+    // Latest `limit` entries of the pool, oldest first.
+    pub fn get_audit_log(&mut self, resource_pool_id: i64, limit: i64) -> Result<Vec<AuditEntry>> {
+        let rows = self.client.query(
+            "SELECT * FROM (SELECT id, resource_pool, action, details, created_at FROM audit_log \
+            WHERE resource_pool=$1 ORDER BY id DESC LIMIT $2) latest ORDER BY id",
+            &[&resource_pool_id, &limit])?;
+        Ok(rows.into_iter().map(Self::row_to_audit_entry).collect())
+    }
+
+    fn row_to_audit_entry(row: Row) -> AuditEntry {
+        AuditEntry {
+            id: row.get(0),
+            resource_pool_id: row.get(1),
+            action: row.get(2),
+            details: row.get(3),
+            created_at: row.get(4),
+        }
+    }
+}
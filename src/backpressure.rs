@@ -0,0 +1,175 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{AllocationOptions, DB, Resource, ResourcePool, WasmerEnv};
+use crate::metrics::AllocationOutcome;
+use crate::timings::Timings;
+
+/// Contention at which allocations of a pool are serialized through the job queue. Writers count committed
+/// allocations and version conflicts of every pool in `pool_contention`. Once at least `min_allocations`
+/// were counted within `window` and the share of conflicts reaches `conflict_rate`, allocations of callers
+/// of `DB::allocate_or_enqueue` are enqueued as jobs for `window`, so that workers allocate them one batch
+/// at a time instead of callers re-running the strategy on every conflict.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backpressure {
+    pub conflict_rate: f64,
+    pub min_allocations: i32,
+    pub window: Duration,
+}
+
+/// Allocations and conflicts of a pool counted within the current window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolContention {
+    pub resource_pool_id: i64,
+    pub window_started_at: SystemTime,
+    pub allocations: i32,
+    pub conflicts: i32,
+    pub backpressure_until: Option<SystemTime>,
+}
+
+impl PoolContention {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "pool": self.resource_pool_id,
+            "windowStartedAt": DateTime::<Utc>::from(self.window_started_at).to_rfc3339(),
+            "allocations": self.allocations,
+            "conflicts": self.conflicts,
+            "conflictRate": if self.allocations > 0 { self.conflicts as f64 / self.allocations as f64 } else { 0.0 },
+            "backpressureUntil": self.backpressure_until.map(|until| DateTime::<Utc>::from(until).to_rfc3339()),
+        })
+    }
+}
+
+/// Outcome of `DB::allocate_or_enqueue`.
+#[derive(Debug)]
+pub enum Allocation {
+    // boxed, the pool is much larger than a job id
+    Allocated(Box<ResourcePool>, Vec<Resource>, Timings),
+    // id of the job the request waits in, the pool is under backpressure
+    Enqueued(i32),
+}
+
+// Counts of an expired window start over.
+const IN_WINDOW: &str = "c.window_started_at > now() - make_interval(secs => $3)";
+
+impl DB {
+    // As `DB::allocate_resources_with_timings`, unless the pool is under backpressure and the allocation
+    // is enqueued as in `DB::enqueue_allocation`. Dry runs are never enqueued.
+    pub fn allocate_or_enqueue(&mut self, pool: ResourcePool, wasmer_env: &mut WasmerEnv, user_input: Value,
+                               options: &AllocationOptions) -> Result<Allocation> {
+        if !options.dry_run && self.is_under_backpressure(pool.id)? {
+            let job_id = self.enqueue_allocation(pool.id, user_input, options)?;
+            info!("Pool {} is under backpressure, enqueued job {}", pool.id, job_id);
+            return Ok(Allocation::Enqueued(job_id));
+        }
+        let (pool, resources, timings) = self.allocate_resources_with_timings(pool, wasmer_env, user_input, options)?;
+        Ok(Allocation::Allocated(Box::new(pool), resources, timings))
+    }
+
+    fn is_under_backpressure(&mut self, resource_pool_id: i64) -> Result<bool> {
+        if self.backpressure.is_none() {
+            return Ok(false);
+        }
+        let row = self.client.query_opt(
+            "SELECT backpressure_until > now() FROM pool_contention WHERE resource_pool=$1", &[&resource_pool_id])?;
+        Ok(row.and_then(|row| row.get::<_, Option<bool>>(0)) == Some(true))
+    }
+
+    // Counts a committed or conflicting allocation if backpressure is configured, other outcomes are not
+    // caused by contention. Backpressure starts once the window crosses the threshold and lasts one window.
+    pub(crate) fn record_contention(&mut self, resource_pool_id: i64, outcome: AllocationOutcome) -> Result<()> {
+        let backpressure = match (&self.backpressure, outcome) {
+            (Some(backpressure), AllocationOutcome::Ok) | (Some(backpressure), AllocationOutcome::VersionConflict) =>
+                backpressure.clone(),
+            _ => return Ok(()),
+        };
+        let conflict = (outcome == AllocationOutcome::VersionConflict) as i32;
+        let window = backpressure.window.as_secs_f64();
+        self.client.execute(
+            format!("INSERT INTO pool_contention AS c (resource_pool, allocations, conflicts) VALUES ($1, 1, $2) \
+                ON CONFLICT (resource_pool) DO UPDATE SET \
+                window_started_at = CASE WHEN {0} THEN c.window_started_at ELSE now() END, \
+                allocations = CASE WHEN {0} THEN c.allocations + 1 ELSE 1 END, \
+                conflicts = CASE WHEN {0} THEN c.conflicts + $2 ELSE $2 END", IN_WINDOW).as_str(),
+            &[&resource_pool_id, &conflict, &window])?;
+        let started = self.client.query_opt(
+            "UPDATE pool_contention SET backpressure_until = now() + make_interval(secs => $2) \
+            WHERE resource_pool=$1 AND allocations >= $3 AND conflicts >= $4::float8 * allocations \
+            AND (backpressure_until IS NULL OR backpressure_until <= now()) RETURNING conflicts, allocations",
+            &[&resource_pool_id, &window, &backpressure.min_allocations, &backpressure.conflict_rate])?;
+        if let Some(row) = started {
+            warn!("Pool {} is under backpressure for {:?}: {} of {} allocations conflicted",
+                  resource_pool_id, backpressure.window, row.get::<_, i32>(0), row.get::<_, i32>(1));
+        }
+        Ok(())
+    }
+
+    // None if no allocation of the pool was counted yet.
+    pub fn get_pool_contention(&mut self, resource_pool_id: i64) -> Result<Option<PoolContention>> {
+        let row = self.client.query_opt(
+            "SELECT window_started_at, allocations, conflicts, backpressure_until FROM pool_contention \
+            WHERE resource_pool=$1", &[&resource_pool_id])?;
+        Ok(row.map(|row| PoolContention {
+            resource_pool_id,
+            window_started_at: row.get(0),
+            allocations: row.get(1),
+            conflicts: row.get(2),
+            backpressure_until: row.get(3),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jobs::JobStatus;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_backpressure() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let pool = create_random_pool(&mut db).unwrap();
+        // nothing is counted without backpressure
+        let (stale, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        assert_eq!(None, db.get_pool_contention(stale.id).unwrap());
+
+        let window = Duration::from_secs(60);
+        db.backpressure = Some(Backpressure { conflict_rate: 0.5, min_allocations: 2, window });
+        let pool = match db.allocate_or_enqueue(stale.clone(), &mut wasmer_env, json!({}), &options).unwrap() {
+            Allocation::Allocated(pool, resources, _) => {
+                assert_eq!(1, resources.len());
+                *pool
+            }
+            allocation => panic!("Unexpected {:?}", allocation),
+        };
+        assert!(db.allocate_or_enqueue(stale, &mut wasmer_env, json!({}), &options).is_err());
+        let contention = db.get_pool_contention(pool.id).unwrap().unwrap();
+        assert_eq!((2, 1), (contention.allocations, contention.conflicts));
+        assert!(contention.backpressure_until.unwrap() > SystemTime::now());
+        assert_eq!(json!(0.5), contention.as_json()["conflictRate"]);
+
+        // callers wait for a worker, dry runs are still run right away
+        let job_id = match db.allocate_or_enqueue(pool.clone(), &mut wasmer_env, json!({}), &options).unwrap() {
+            Allocation::Enqueued(job_id) => job_id,
+            allocation => panic!("Unexpected {:?}", allocation),
+        };
+        let dry_run = AllocationOptions { dry_run: true, ..AllocationOptions::default() };
+        assert!(matches!(db.allocate_or_enqueue(pool.clone(), &mut wasmer_env, json!({}), &dry_run).unwrap(),
+                         Allocation::Allocated(..)));
+        let mut batches = 0;
+        while db.get_job_status(job_id).unwrap().status != JobStatus::Done {
+            db.run_allocation_batch(&mut wasmer_env, 10).unwrap();
+            batches += 1;
+            assert!(batches < 50);
+        }
+        assert_eq!(3, db.count_resources(pool.id).unwrap());
+    }
+}
@@ -0,0 +1,285 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use chrono::Utc;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use postgres::{GenericClient, IsolationLevel};
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{DB, Resource};
+use crate::transaction::Transaction;
+
+// Archives of other formats are rejected by `DB::restore`.
+const BACKUP_FORMAT: i64 = 1;
+// Rows fetched and resources inserted per round trip.
+const BATCH_SIZE: i32 = 1000;
+// Tables copied row by row, in an order satisfying their foreign keys. Resources follow `resource_pools`
+// in the format of `resources export`.
+const STRATEGY_TABLES: [(&str, &str); 3] = [
+    ("allocation_strategies", "id"),
+    ("allocation_strategy_files", "allocation_strategy_id, path"),
+    ("allocation_strategy_tests", "allocation_strategy_id, name"),
+];
+const POOL_TABLE: (&str, &str) = ("resource_pools", "id");
+const SNAPSHOT_TABLES: [(&str, &str); 2] = [("pool_snapshots", "id"), ("pool_snapshot_resources", "snapshot")];
+// Sequences moved past the restored ids.
+const SEQUENCES: [(&str, &str); 4] = [
+    ("allocation_strategies_id_seq", "allocation_strategies"),
+    ("resource_pools_id_seq", "resource_pools"),
+    ("resources_id_seq", "resources"),
+    ("pool_snapshots_id_seq", "pool_snapshots"),
+];
+
+/// What `DB::backup` wrote or `DB::restore` read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BackupCounts {
+    pub strategies: u64,
+    pub pools: u64,
+    pub resources: u64,
+    pub snapshots: u64,
+}
+
+impl BackupCounts {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "strategies": self.strategies,
+            "pools": self.pools,
+            "resources": self.resources,
+            "snapshots": self.snapshots,
+        })
+    }
+
+    fn count(&mut self, table: &str) {
+        match table {
+            "allocation_strategies" => self.strategies += 1,
+            "resource_pools" => self.pools += 1,
+            "pool_snapshots" => self.snapshots += 1,
+            _ => {}
+        }
+    }
+}
+
+fn write_line(out: &mut dyn Write, line: &Value) -> Result<()> {
+    serde_json::to_writer(&mut *out, line)?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+impl DB {
+    // Writes a gzip compressed archive of JSON lines: a header naming the schema version, then rows of strategies,
+    // pools, resources and snapshots. Read from a single snapshot of the database, so that allocations running
+    // meanwhile are either fully contained or not at all.
+    pub fn backup<W: Write>(&mut self, out: W) -> Result<BackupCounts> {
+        self.check_not_dry_run("Backup")?;
+        let mut out = GzEncoder::new(out, Compression::default());
+        let mut transaction = self.client.build_transaction()
+            .isolation_level(IsolationLevel::RepeatableRead)
+            .read_only(true)
+            .start()?;
+        write_line(&mut out, &json!({"backup": {
+            "format": BACKUP_FORMAT,
+            "migration": Self::latest_migration(&mut transaction)?,
+            "createdAt": Utc::now().to_rfc3339(),
+        }}))?;
+        let mut counts = BackupCounts::default();
+        for (table, order_by) in STRATEGY_TABLES.iter().chain(Some(&POOL_TABLE)) {
+            Self::backup_rows(&mut transaction, &mut out, table, order_by, &mut counts)?;
+        }
+        let pool_ids: Vec<i64> = transaction.query("SELECT id FROM resource_pools ORDER BY id", &[])?
+            .into_iter().map(|row| row.get(0)).collect();
+        for pool_id in pool_ids {
+            let portal = transaction.bind(
+                format!("SELECT {} FROM resources WHERE resource_pool=$1 ORDER BY id", Self::RESOURCE_COLUMNS)
+                    .as_str(),
+                &[&pool_id])?;
+            loop {
+                let rows = transaction.query_portal(&portal, BATCH_SIZE)?;
+                if rows.is_empty() {
+                    break;
+                }
+                for row in rows {
+                    let resource = Self::row_to_resource(pool_id, row)?;
+                    write_line(&mut out, &json!({"pool": pool_id, "resource": resource.as_export_json()}))?;
+                    counts.resources += 1;
+                }
+            }
+        }
+        for (table, order_by) in SNAPSHOT_TABLES.iter() {
+            Self::backup_rows(&mut transaction, &mut out, table, order_by, &mut counts)?;
+        }
+        transaction.commit()?;
+        out.finish()?.flush()?;
+        info!("Backed up {} strategies, {} pools, {} resources and {} snapshots",
+              counts.strategies, counts.pools, counts.resources, counts.snapshots);
+        Ok(counts)
+    }
+
+    fn backup_rows(transaction: &mut postgres::Transaction, out: &mut dyn Write, table: &str, order_by: &str,
+                   counts: &mut BackupCounts) -> Result<()> {
+        let portal = transaction.bind(format!("SELECT to_jsonb(t) FROM {} t ORDER BY {}", table, order_by).as_str(),
+                                      &[])?;
+        loop {
+            let rows = transaction.query_portal(&portal, BATCH_SIZE)?;
+            if rows.is_empty() {
+                return Ok(());
+            }
+            for row in rows {
+                write_line(out, &json!({"table": table, "row": row.get::<_, Value>(0)}))?;
+                counts.count(table);
+            }
+        }
+    }
+
+    // Recreates the instance of an archive written by `backup`, keeping all ids, in a single transaction.
+    // The database must be at the same schema version and hold no resources or snapshots, its pools
+    // and strategies are replaced by the archived ones.
+    pub fn restore<R: Read>(&mut self, input: R) -> Result<BackupCounts> {
+        let mut lines = BufReader::new(GzDecoder::new(input)).lines();
+        let header: Value = serde_json::from_str(&lines.next().ok_or_else(|| anyhow!("Backup is empty"))??)
+            .context("Cannot parse header of the backup")?;
+        let header = header.get("backup").ok_or_else(|| anyhow!("Not a backup, header is missing"))?;
+        ensure!(header["format"].as_i64() == Some(BACKUP_FORMAT), "Unsupported backup format {}", header["format"]);
+        let mut transaction = self.client.transaction()?;
+        let migration = Self::latest_migration(&mut *transaction)?;
+        ensure!(header["migration"].as_str() == Some(migration.as_str()),
+                "Backup of schema version {} cannot be restored into schema version {}", header["migration"],
+                migration);
+        let is_empty: bool = transaction.query_one(
+            "SELECT NOT EXISTS (SELECT FROM resources) AND NOT EXISTS (SELECT FROM pool_snapshots)", &[])?
+            .get(0);
+        ensure!(is_empty, "Backup can only be restored into a database without resources and snapshots");
+        // e.g. `pool1` created by `db init`
+        let empty_pools: Vec<i64> = transaction.query("SELECT id FROM resource_pools", &[])?
+            .into_iter().map(|row| row.get(0)).collect();
+        for pool_id in empty_pools {
+            Self::drop_resources_partition(&mut transaction, pool_id)?;
+        }
+        transaction.batch_execute("DELETE FROM resource_pools; DELETE FROM allocation_strategies")?;
+
+        let tables = STRATEGY_TABLES.iter().chain(Some(&POOL_TABLE)).chain(SNAPSHOT_TABLES.iter())
+            .map(|(table, _)| *table)
+            .collect::<Vec<_>>();
+        let mut counts = BackupCounts::default();
+        let mut pool_ids = Vec::new();
+        let mut batch: Vec<Resource> = Vec::new();
+        for line in lines {
+            let line = line?;
+            let record: Value = serde_json::from_str(&line).context(format!("Cannot parse line '{}'", line))?;
+            if let Some(exported) = record.get("resource") {
+                let pool_id = record["pool"].as_i64().ok_or_else(|| anyhow!("Resource without pool: {}", line))?;
+                if batch.first().is_some_and(|first| first.resource_pool_id != pool_id)
+                    || batch.len() >= BATCH_SIZE as usize {
+                    Self::restore_resources(&mut transaction, &mut batch)?;
+                }
+                batch.push(Resource {
+                    id: exported["id"].as_i64(),
+                    ..Resource::restore_from_export_json(pool_id, exported.clone())?
+                });
+                counts.resources += 1;
+                continue;
+            }
+            // names are checked, so that the archive cannot inject SQL
+            let table = record["table"].as_str().filter(|table| tables.contains(table))
+                .ok_or_else(|| anyhow!("Unknown record in backup: {}", line))?;
+            Self::restore_resources(&mut transaction, &mut batch)?;
+            if table == POOL_TABLE.0 {
+                let pool_id = record["row"]["id"].as_i64().ok_or_else(|| anyhow!("Pool without id: {}", line))?;
+                Self::create_resources_partition(&mut transaction, pool_id)?;
+                pool_ids.push(pool_id);
+            }
+            transaction.execute(format!("INSERT INTO {0} SELECT * FROM jsonb_populate_record(NULL::{0}, $1)", table)
+                                    .as_str(), &[&record["row"]])
+                .context(format!("Cannot restore {} row {}", table, record["row"]))?;
+            counts.count(table);
+        }
+        Self::restore_resources(&mut transaction, &mut batch)?;
+        for pool_id in pool_ids {
+            Self::rebuild_free_ranges(&mut transaction, pool_id)?;
+        }
+        for (sequence, table) in SEQUENCES.iter() {
+            transaction.execute(format!("SELECT setval('{}', max(id)) FROM {} HAVING max(id) IS NOT NULL",
+                                        sequence, table).as_str(), &[])?;
+        }
+        transaction.commit()?;
+        info!("Restored {} strategies, {} pools, {} resources and {} snapshots",
+              counts.strategies, counts.pools, counts.resources, counts.snapshots);
+        Ok(counts)
+    }
+
+    fn restore_resources(transaction: &mut Transaction, batch: &mut Vec<Resource>) -> Result<()> {
+        if let Some(first) = batch.first() {
+            Self::insert_exported_resources(transaction, first.resource_pool_id, batch)?;
+            batch.clear();
+        }
+        Ok(())
+    }
+
+    fn latest_migration<C: GenericClient>(client: &mut C) -> Result<String> {
+        match client.query_one("SELECT max(name) FROM schema_migrations", &[])?.get(0) {
+            Some(migration) => Ok(migration),
+            None => bail!("Schema was not created by `db init`"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use serde_json::json;
+
+    use crate::{BulkSelector, ResourceFilter};
+    use crate::connect::ConnectRetry;
+    use crate::tests::{IPV4_ALLOCATION_STRATEGY_ID, initialize_logging};
+    use super::*;
+
+    fn new_schema() -> (DB, String) {
+        let schema = format!("rm_backup_{}", rand::thread_rng().gen::<u32>());
+        let params = std::env::var("DB_PARAMS").unwrap();
+        let mut db = DB::new(&params, Some(&schema), ConnectRetry::default()).unwrap();
+        db.init_schema().unwrap();
+        (db, schema)
+    }
+
+    #[test]
+    fn db_backup_and_restore() {
+        initialize_logging();
+
+        let (mut source, source_schema) = new_schema();
+        let pool = source.insert_resource_pool("backed-up", IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        let nested = source.insert_nested_resource_pool("nested", IPV4_ALLOCATION_STRATEGY_ID, Some(pool.id))
+            .unwrap();
+        let resources = vec![json!({"address": "10.0.0.1"}), json!({"address": "10.0.0.2"})].into_iter()
+            .map(|value| Resource::new_from_value(pool.id, value))
+            .collect();
+        let (pool, _) = source.insert_resources(pool, resources).unwrap();
+        source.snapshot_pool(pool.id, "before").unwrap();
+        let retired = source.get_resources(pool.id).unwrap()[0].id.unwrap();
+        source.deallocate_resources(pool, &BulkSelector::Ids(vec![retired])).unwrap();
+        let mut archive = Vec::new();
+        let counts = source.backup(&mut archive).unwrap();
+        // with `pool1` created by `db init`
+        assert_eq!(BackupCounts { strategies: 1, pools: 3, resources: 2, snapshots: 1 }, counts);
+
+        let (mut target, target_schema) = new_schema();
+        assert_eq!(counts, target.restore(archive.as_slice()).unwrap());
+        let pool = source.get_resource_pool_by_name("backed-up").unwrap();
+        assert_eq!(pool, target.get_resource_pool_by_name("backed-up").unwrap());
+        assert_eq!(Some(pool.id), target.get_resource_pool_by_name("nested").unwrap().parent_id);
+        let all = ResourceFilter { include_deleted: true, ..ResourceFilter::default() };
+        assert_eq!(source.get_resources_filtered(pool.id, &all).unwrap(),
+                   target.get_resources_filtered(pool.id, &all).unwrap());
+        assert_eq!(source.get_snapshot_resources(1).unwrap(), target.get_snapshot_resources(1).unwrap());
+        // sequences continue after the restored ids
+        assert!(target.insert_resource_pool("new", IPV4_ALLOCATION_STRATEGY_ID).unwrap().id > nested.id);
+
+        let err = target.restore(archive.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("without resources"), "{:#}", err);
+
+        let mut cleanup = DB::new_from_env().unwrap();
+        cleanup.client.batch_execute(&format!("DROP SCHEMA {} CASCADE; DROP SCHEMA {} CASCADE",
+                                              source_schema, target_schema)).unwrap();
+    }
+}
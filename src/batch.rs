@@ -0,0 +1,385 @@
+use anyhow::{Result, anyhow, bail};
+use postgres::Row;
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::canonical;
+use crate::error::AllocationError;
+use crate::host::{self, CurrentResources, PoolResources};
+use crate::jobs::AllocationJob;
+use crate::subranges::SubRange;
+use crate::{DB, Resource, WasmerEnv, metrics};
+
+/// Globals of one strategy invocation in a batch, see `WasmerEnv::invoke_batch`.
+#[derive(Debug, Clone)]
+pub struct BatchRequest {
+    pub user_input: Value,
+    pub resource_pool_properties: Value,
+    pub resource_pool: Value,
+    // embedded as `currentResources`, only needed for strategies referencing it
+    pub current_resources: Option<Vec<Value>>,
+    // embedded as `resourceSummary`, only needed for strategies referencing it
+    pub resource_summary: Option<Value>,
+}
+
+impl WasmerEnv {
+    // Evaluate `function_call` once per request in a single interpreter, so that its startup is paid only once.
+    // Every request gets its own scope with the globals of `invoke_and_parse_value`, host calls are answered
+    // by `host` with the index of the request. An exception thrown for one request does not fail the others.
+    pub(crate) fn invoke_batch(&mut self, script: &str, requests: Vec<BatchRequest>, function_call: &str,
+                               host: &mut dyn FnMut(usize, &Value) -> Result<Value>)
+                               -> Result<Vec<Result<Value>>> {
+        let count = requests.len();
+        let requests = requests.into_iter().map(|request| json!({
+            "userInput": request.user_input,
+            "resourcePoolProperties": request.resource_pool_properties,
+            "resourcePool": request.resource_pool,
+            "currentResources": request.current_resources,
+            "resourceSummary": request.resource_summary,
+        })).collect();
+        let script = Self::header() + &Self::add_js_var("batchRequests", Value::Array(requests))? + &format!("
+        const batchResults = batchRequests.map((request, idx) => {{
+            hostRequest = idx;
+            try {{
+                const result = (function (userInput, resourcePoolProperties, resourcePool, currentResources,
+                                          resourceSummary) {{
+        {}
+        return {};
+                }})(request.userInput, request.resourcePoolProperties, request.resourcePool, request.currentResources,
+                    request.resourceSummary);
+                return {{ result: result === undefined ? null : result }};
+            }} catch (e) {{
+                try {{
+                    return {{ result: strategyError(e) }};
+                }} catch (e) {{
+                    return {{ error: String(e && e.message || e) }};
+                }}
+            }}
+        }});
+        std.out.puts('\\u0002\\n');
+        std.out.puts(JSON.stringify(batchResults));
+        ", script, function_call);
+        trace!("Executing batch script:\n{}", script);
+        let output = self.invoke_js_with(&script, host)?;
+        for line in &output.logs {
+            debug!(target: "strategy", "{}", line);
+        }
+        let results = match output.result {
+            Some(Value::Array(results)) if results.len() == count => results,
+            _ => return Err(anyhow!("Batch script did not return {} results, {}: {}",
+                                    count, output.status, output.logs.join("\n"))),
+        };
+        Ok(results.into_iter().map(|mut result| match result["error"].take() {
+            Value::Null => {
+                let result = result["result"].take();
+                match AllocationError::from_envelope(&result) {
+                    Some(err) => Err(err.into()),
+                    None => Ok(result),
+                }
+            }
+            error => Err(anyhow!("{}", error.as_str().unwrap_or_default())),
+        }).collect())
+    }
+}
+
+// Jobs of a pool with the same key are allocated together, None if the job cannot be coalesced.
+fn coalescing_key(job: &AllocationJob) -> Option<Value> {
+    let mut user_input = job.user_input.as_object()?.clone();
+    if allocated_alone(job) {
+        return None;
+    }
+    if user_input.remove("resourceCount").is_some_and(|count| !count.is_u64()) {
+        return None;
+    }
+    Some(json!([user_input, job.lease.map(|lease| lease.as_secs()), job.reserve, job.owner, job.description]))
+}
+
+// Desired values, contiguous blocks and sticky keys are allocated individually, sequential strategies take
+// the former from the free list instead of the script.
+fn allocated_alone(job: &AllocationJob) -> bool {
+    let user_input = &job.user_input;
+    user_input.get("desiredValue").is_some() || user_input["contiguous"] == true
+        || user_input.get("stickyKey").is_some()
+}
+
+fn resource_count(job: &AllocationJob) -> u64 {
+    job.user_input["resourceCount"].as_u64().unwrap_or(1)
+}
+
+impl DB {
+    // Claim up to `limit` pending jobs of pools sharing the strategy of the oldest pending job and allocate
+    // them with one invocation of the strategy, a pool with a script override or hooks shares it with no other
+    // pool. Jobs of pools with hooks are allocated one by one, see `PoolHook`. Jobs of the same pool would see
+    // the same current resources, so they are claimed only if they differ just by `resourceCount` from the first
+    // job of the pool. Such jobs are coalesced into one request of their total count, inserted with one version
+    // bump and split back in order. Returns the finished jobs, empty if there is nothing to do.
+    pub fn run_allocation_batch(&mut self, wasmer_env: &mut WasmerEnv, limit: i64) -> Result<Vec<AllocationJob>> {
+        let mut transaction = self.client.transaction()?;
+        let rows = transaction.query(
+            format!("SELECT {}, (SELECT resource_pool_allocation_strategy FROM resource_pools p \
+                WHERE p.id = resource_pool), (SELECT p.id FROM resource_pools p WHERE p.id = resource_pool \
+                AND (script_override IS NOT NULL OR EXISTS (SELECT FROM pool_hooks h WHERE h.resource_pool = p.id))) \
+                AS own_pool, EXISTS (SELECT FROM pool_hooks h WHERE h.resource_pool = allocation_jobs.resource_pool) \
+                FROM allocation_jobs WHERE status = 'pending' ORDER BY id LIMIT $1 FOR UPDATE SKIP LOCKED",
+                    Self::ALLOCATION_JOB_COLUMNS).as_str(), &[&limit])?;
+        // the strategy, the pool with a script override or hooks and whether it has hooks follow columns of the job
+        let batch_of = |row: &Row| -> (Option<i64>, Option<i64>, bool) {
+            (row.get(row.len() - 3), row.get(row.len() - 2), row.get(row.len() - 1))
+        };
+        let (strategy_id, own_pool_id, hooked) = match rows.first() {
+            Some(row) => batch_of(row),
+            None => return Ok(Vec::new()),
+        };
+        // jobs of one pool with the key of the first job of the pool
+        let mut groups: Vec<(Option<Value>, Vec<AllocationJob>)> = Vec::new();
+        for row in rows {
+            if batch_of(&row) != (strategy_id, own_pool_id, hooked) {
+                continue;
+            }
+            let job = Self::row_to_allocation_job(row)?;
+            let key = if hooked { None } else { coalescing_key(&job) };
+            match groups.iter_mut().find(|(_, jobs)| jobs[0].resource_pool_id == job.resource_pool_id) {
+                Some((group_key, jobs)) if key.is_some() && *group_key == key => jobs.push(job),
+                Some(_) => {}
+                None => groups.push((key, vec![job])),
+            }
+        }
+        let groups = groups.into_iter().map(|(_, jobs)| jobs).collect::<Vec<_>>();
+        let job_ids = groups.iter().flatten().map(|job| job.id).collect::<Vec<_>>();
+        transaction.execute(
+            "UPDATE allocation_jobs SET status = 'running', started_at = now() WHERE id = ANY($1)", &[&job_ids])?;
+        transaction.commit()?;
+        debug!("Running batch of {} allocation jobs of {} pools", job_ids.len(), groups.len());
+
+        let allocated = match strategy_id {
+            Some(strategy_id) => self.allocate_batch(strategy_id, own_pool_id, hooked, &groups, wasmer_env),
+            None => Err(anyhow!("Resource pool not found")),
+        };
+        let allocated: Vec<Result<Vec<Vec<Resource>>>> = match allocated {
+            Ok(allocated) => allocated,
+            // the whole batch failed, e.g. the script did not load
+            Err(err) => groups.iter().map(|_| Err(anyhow!("{:#}", err))).collect(),
+        };
+        let mut finished = Vec::with_capacity(job_ids.len());
+        for (jobs, allocated) in groups.iter().zip(allocated) {
+            for job in jobs {
+                metrics::record_allocation(job.resource_pool_id, strategy_id.unwrap_or_default(), &allocated);
+            }
+            match allocated {
+                Ok(allocated) => for (job, resources) in jobs.iter().zip(allocated) {
+                    finished.push(self.finish_allocation_job(job.id, Ok(resources))?);
+                },
+                Err(err) => for job in jobs {
+                    finished.push(self.finish_allocation_job(job.id, Err(anyhow!("{:#}", err)))?);
+                },
+            }
+        }
+        Ok(finished)
+    }
+
+    // Resources of every job of every group, groups are jobs of one pool coalesced by `run_allocation_batch`.
+    // Groups are of `own_pool_id` only if it is set, jobs of pools with hooks are allocated alone.
+    // Groups of sequential pools pop from the free list instead of the script, as `DB::allocate_resources` does.
+    fn allocate_batch(&mut self, strategy_id: i64, own_pool_id: Option<i64>, hooked: bool,
+                      groups: &[Vec<AllocationJob>], wasmer_env: &mut WasmerEnv)
+                      -> Result<Vec<Result<Vec<Vec<Resource>>>>> {
+        let script = match own_pool_id {
+            Some(pool_id) => self.get_pool_allocation_script(pool_id)?,
+            None => self.get_allocation_script(strategy_id)?,
+        };
+        let strategy_version = self.get_strategy(strategy_id)?.version;
+        // other engines cannot share an interpreter between requests
+        wasmer_env.engine(self.get_strategy_engine(strategy_id)?)?;
+        let pools = groups.iter()
+            .map(|jobs| self.get_resource_pool_by_id(jobs[0].resource_pool_id))
+            .collect::<Result<Vec<_>>>()?;
+        // canonical values are kept by `DB::allocate_resources` only
+        let canonicalized = canonical::may_canonicalize(&script);
+        let alone = groups.iter().map(|jobs| hooked || canonicalized || allocated_alone(&jobs[0]))
+            .collect::<Vec<_>>();
+        let mut requests = Vec::with_capacity(groups.len());
+        let mut scripted = Vec::with_capacity(groups.len());
+        // addresses of groups not allocated by the script, None for the others
+        let mut popped = Vec::with_capacity(groups.len());
+        // coalesced jobs are recorded as one allocation of their total count, see `DB::replay_pool`
+        let mut recorded = Vec::with_capacity(groups.len());
+        for ((jobs, pool), alone) in groups.iter().zip(&pools).zip(&alone) {
+            if *alone {
+                continue;
+            }
+            let mut user_input = pool.user_input_with_defaults(jobs[0].user_input.clone());
+            if jobs.len() > 1 {
+                user_input["resourceCount"] = json!(jobs.iter().map(resource_count).sum::<u64>());
+            }
+            recorded.push(json!({"userInput": &user_input, "strategyVersion": strategy_version}));
+            // pools with a script override have no free list
+            let bounds = match own_pool_id {
+                Some(_) => None,
+                None => self.client.query_opt("SELECT first, last FROM free_range_bounds($1)", &[&pool.id])?
+                    .map(|row| (row.get(0), row.get(1))),
+            };
+            if bounds.is_some() {
+                popped.push(self.find_free_addresses(pool, bounds, &user_input).transpose());
+                continue;
+            }
+            popped.push(None);
+            scripted.push(pool.id);
+            let mut resources = PoolResources::new(&mut self.client, pool.id);
+            let current_resources = if script.contains("currentResources") { Some(resources.all()?) } else { None };
+            let resource_summary = if script.contains("resourceSummary") { Some(resources.summary()?) } else { None };
+            requests.push(BatchRequest {
+                user_input,
+                resource_pool_properties: pool.get_pool_properties(),
+                resource_pool: pool.as_json(),
+                current_resources,
+                resource_summary,
+            });
+        }
+        let client = &mut self.client;
+        let results = if requests.is_empty() {
+            Vec::new()
+        } else {
+            wasmer_env.invoke_batch(&script, requests, "invoke()", &mut |idx, request| {
+                let pool_id = *scripted.get(idx).ok_or_else(|| anyhow!("Unknown request {}", idx))?;
+                host::answer(&mut PoolResources::new(client, pool_id), request)
+            })?
+        };
+        let mut results = results.into_iter();
+        let mut recorded = recorded.into_iter();
+        let mut popped = popped.into_iter();
+        Ok(groups.iter().zip(pools).zip(alone).map(|((jobs, pool), alone)| {
+            if alone {
+                let (_pool, resources) = self.allocate_resources(pool, wasmer_env, jobs[0].user_input.clone(),
+                                                                 &jobs[0].options())?;
+                return Ok(vec![resources]);
+            }
+            let request = recorded.next().ok_or_else(|| anyhow!("Missing request of pool {}", pool.id))?;
+            let values = match popped.next().flatten() {
+                Some(addresses) => addresses?,
+                None => {
+                    let result = results.next().ok_or_else(|| anyhow!("Missing result of pool {}", pool.id))?;
+                    let values = result?.as_array().cloned()
+                        .ok_or_else(|| anyhow!("Script did not return an array"))?;
+                    let sub_ranges = SubRange::of_pool(&pool)?;
+                    SubRange::check_values(&sub_ranges, SubRange::selected(&pool, &sub_ranges, &request["userInput"])?,
+                                           &values)?;
+                    values
+                }
+            };
+            let counts = jobs.iter().map(resource_count).collect::<Vec<_>>();
+            if jobs.len() > 1 && values.len() as u64 != counts.iter().sum::<u64>() {
+                bail!("Script returned {} resources for {} coalesced jobs of {} resources",
+                      values.len(), jobs.len(), counts.iter().sum::<u64>());
+            }
+            let resources = Self::new_resources(&pool, values, &jobs[0].options());
+            let (_pool, mut resources) = self.insert_allocated_resources(pool, resources, Some(&request))?;
+            if jobs.len() == 1 {
+                return Ok(vec![resources]);
+            }
+            Ok(counts.iter().map(|count| resources.drain(..*count as usize).collect()).collect())
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::AllocationOptions;
+    use crate::jobs::JobStatus;
+    use crate::tests::{IPV4_ALLOCATION_STRATEGY_ID, create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn wasmer_invoke_batch() {
+        initialize_logging();
+
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let request = |n: i32| BatchRequest {
+            user_input: json!({"n": n}),
+            resource_pool_properties: json!({}),
+            resource_pool: json!({}),
+            current_resources: None,
+            resource_summary: None,
+        };
+        let mut resources = [vec![json!({"Properties": 1})], vec![json!({"Properties": 2})], vec![], vec![]];
+        let script = "function invoke() {\n\
+            if (userInput.n < 0) throw new Error('negative');\n\
+            if (userInput.n == 0) throw { code: 'ZERO', message: 'zero' };\n\
+            return [{ n: userInput.n, allocated: isAllocated(userInput.n) }];\n}";
+        let results = wasmer_env.invoke_batch(script, vec![request(1), request(1), request(-1), request(0)], "invoke()",
+                                              &mut |idx, call| host::answer(&mut resources[idx], call))
+            .unwrap();
+        assert_eq!(json!([{"n": 1, "allocated": true}]), *results[0].as_ref().unwrap());
+        assert_eq!(json!([{"n": 1, "allocated": false}]), *results[1].as_ref().unwrap());
+        assert_eq!("negative", results[2].as_ref().unwrap_err().to_string());
+        assert_eq!(Some(&AllocationError::Strategy { code: "ZERO".into(), message: "zero".into(), details: Value::Null }),
+                   results[3].as_ref().unwrap_err().downcast_ref::<AllocationError>());
+    }
+
+    #[test]
+    fn db_allocation_batch() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let first = create_random_pool(&mut db).unwrap();
+        let second = create_random_pool(&mut db).unwrap();
+        let options = AllocationOptions::default();
+        let job_ids = [first.id, first.id, second.id].iter()
+            .map(|pool_id| db.enqueue_allocation(*pool_id, json!({"resourceCount": 2}), &options).unwrap())
+            .collect::<Vec<_>>();
+        // other tests may enqueue jobs concurrently, run batches until ours are finished
+        let mut batches = 0;
+        while job_ids.iter().any(|id| db.get_job_status(*id).unwrap().status != JobStatus::Done) {
+            batches += 1;
+            assert!(batches < 50, "Jobs were not finished");
+            db.run_allocation_batch(&mut wasmer_env, 10).unwrap();
+        }
+        // jobs of the first pool were coalesced or ran in a later batch, they got distinct resources
+        let results = job_ids.iter().map(|id| db.get_job_status(*id).unwrap().result.unwrap()).collect::<Vec<_>>();
+        assert!(results.iter().all(|result| result.as_array().unwrap().len() == 2), "{:?}", results);
+        assert_ne!(results[0], results[1]);
+        assert_eq!(4, db.count_resources(first.id).unwrap());
+        assert_eq!(2, db.count_resources(second.id).unwrap());
+
+        // a desired value bypasses the batch script
+        let job_id = db.enqueue_allocation(second.id, json!({"desiredValue": {"address": "10.0.0.42"}}), &options)
+            .unwrap();
+        while db.get_job_status(job_id).unwrap().status != JobStatus::Done {
+            batches += 1;
+            assert!(batches < 50, "Job was not finished");
+            db.run_allocation_batch(&mut wasmer_env, 10).unwrap();
+        }
+        let result = db.get_job_status(job_id).unwrap().result.unwrap();
+        assert!(result.to_string().contains("\"10.0.0.42\""), "{}", result);
+    }
+    #[test]
+    fn db_allocation_batch_sub_ranges() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let pool = db.insert_resource_pool_with_properties(&name, IPV4_ALLOCATION_STRATEGY_ID, None, Some(json!({
+            "address": "10.0.0.0", "prefix": 24, "subRanges": [{"cidr": "10.0.0.0/28", "reserved": true}],
+        }))).unwrap();
+        let options = AllocationOptions::default();
+        let job_ids = (0..2)
+            .map(|_| db.enqueue_allocation(pool.id, json!({"resourceCount": 2}), &options).unwrap())
+            .collect::<Vec<_>>();
+        let mut batches = 0;
+        while job_ids.iter().any(|id| db.get_job_status(*id).unwrap().status != JobStatus::Done) {
+            batches += 1;
+            assert!(batches < 50, "Jobs were not finished");
+            db.run_allocation_batch(&mut wasmer_env, 10).unwrap();
+        }
+        // taken from the free list after the reserved sub-range
+        let values = job_ids.iter()
+            .flat_map(|id| db.get_job_status(*id).unwrap().result.unwrap().as_array().unwrap().clone())
+            .map(|resource| resource["value"]["address"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![json!("10.0.0.16"), json!("10.0.0.17"), json!("10.0.0.18"), json!("10.0.0.19")], values);
+    }
+}
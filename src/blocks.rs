@@ -0,0 +1,174 @@
+use std::net::Ipv4Addr;
+use std::time::Instant;
+
+use anyhow::{Result, anyhow, ensure};
+use serde_json::{Value, json};
+
+use crate::{DB, Resource, ResourcePool, metrics};
+use crate::error::AllocationError;
+
+/// `userInput.contiguous` allocates `resourceCount` consecutive values as a single block resource
+/// `{"first": .., "last": .., "size": ..}`. Its members are inserted as well unless `blockMembers` is false.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BlockRequest {
+    pub size: i64,
+    pub members: bool,
+}
+
+impl BlockRequest {
+    // None if the input does not ask for a block.
+    pub(crate) fn from_user_input(user_input: &Value) -> Result<Option<BlockRequest>> {
+        if user_input["contiguous"] != true {
+            return Ok(None);
+        }
+        ensure!(user_input.get("desiredValue").is_none(), "A contiguous block cannot have a desiredValue");
+        let size = user_input["resourceCount"].as_i64().unwrap_or(1);
+        ensure!(size > 0, "Size of a contiguous block must be positive, got {}", size);
+        Ok(Some(BlockRequest { size, members: user_input["blockMembers"] != false }))
+    }
+
+    // The block resource followed by its members if they are kept. Fails if the strategy did not return
+    // exactly the members of the block.
+    pub(crate) fn resources(&self, members: Vec<Resource>) -> Result<Vec<Resource>> {
+        ensure!(members.len() as i64 == self.size, "Strategy returned {} values for a contiguous block of {}",
+                members.len(), self.size);
+        let (first, last) = (&members[0], &members[members.len() - 1]);
+        let value = json!({"first": &first.value, "last": &last.value, "size": self.size});
+        let mut resources = vec![Resource { value, ..first.clone() }];
+        if self.members {
+            resources.extend(members);
+        }
+        Ok(resources)
+    }
+}
+
+impl DB {
+    // Lowest `size` consecutive free addresses of a pool with a sequential strategy, within the first of
+    // `order` ranges holding such block. Fails with the `POOL_EXHAUSTED` strategy error if no gap of the
+    // free list is large enough.
+    pub(crate) fn find_free_block(&mut self, pool: &ResourcePool, order: &[(i64, i64)], size: i64)
+                                  -> Result<Vec<Value>> {
+        for (first, last) in order {
+            let row = self.client.query_opt(
+                "SELECT greatest(first, $2) FROM free_ranges \
+                WHERE resource_pool=$1 AND least(last, $3) - greatest(first, $2) + 1 >= $4 ORDER BY first LIMIT 1",
+                &[&pool.id, first, last, &size])?;
+            if let Some(row) = row {
+                let start = row.get::<_, i64>(0);
+                return Ok((start..start + size)
+                    .map(|address| json!({"address": Ipv4Addr::from(address as u32).to_string()}))
+                    .collect());
+            }
+        }
+        Err(AllocationError::Strategy {
+            code: "POOL_EXHAUSTED".to_owned(),
+            message: format!("No contiguous block of {} free address(es)", size),
+            details: json!({"requested": size, "contiguous": true}),
+        }.into())
+    }
+
+    // Inserts the block resource and its members referencing it in one transaction, with the same
+    // errors as `DB::insert_resources`. Members follow state changes of the block.
+    pub(crate) fn insert_block(&mut self, pool: ResourcePool, resources: Vec<Resource>, request: &Value)
+                               -> Result<(ResourcePool, Vec<Resource>)> {
+        let resource_pool = pool.name.clone();
+        let started = Instant::now();
+        let inserted = self.try_insert_block(pool, resources, request)
+            .map_err(|err| Self::uniqueness_error(Self::timeout_error(&resource_pool, err)));
+        metrics::record_transaction("allocation", started.elapsed());
+        inserted
+    }
+
+    fn try_insert_block(&mut self, mut pool: ResourcePool, mut resources: Vec<Resource>, request: &Value)
+                        -> Result<(ResourcePool, Vec<Resource>)> {
+        let (chaos, outbox) = (self.chaos.clone(), self.outbox);
+        let mut transaction = self.allocation_transaction()?;
+        let (block, members) = resources.split_first_mut()
+            .ok_or_else(|| anyhow!("Cannot insert an empty block"))?;
+        let state = block.state.as_str();
+        let row = transaction.query_one(
+            "INSERT INTO resources (resource_pool, value, status, lease_expires_at, owner, description) \
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            &[&pool.id, &block.value, &state, &block.lease_expires_at, &block.owner, &block.description])?;
+        block.id = Some(row.get(0));
+        let values = members.iter().map(|member| &member.value).collect::<Vec<_>>();
+        let rows = transaction.query(
+            "INSERT INTO resources (resource_pool, value, status, lease_expires_at, owner, description, block) \
+            SELECT $1, value, $3, $4, $5, $6, $7 FROM jsonb_array_elements($2) WITH ORDINALITY member(value, n) \
+            ORDER BY n RETURNING id",
+            &[&pool.id, &json!(values), &state, &block.lease_expires_at, &block.owner, &block.description,
+                &block.id])?;
+        ensure!(rows.len() == members.len(), "Insertion of block members returned wrong number of rows");
+        for (member, row) in members.iter_mut().zip(rows) {
+            member.id = Some(row.get(0));
+        }
+        Self::bump_version(&mut transaction, &mut pool)?;
+        Self::notify_pool_event(&mut transaction, outbox, &pool, "allocated", &resources, Some(request))?;
+        chaos.before_commit(&mut transaction)?;
+        transaction.commit()?;
+        Ok((pool, resources))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::{AllocationOptions, ResourceSelector, WasmerEnv};
+    use crate::state::ResourceState;
+    use crate::strategy::StrategyFiles;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_contiguous_blocks() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let pool = create_random_pool(&mut db).unwrap();
+        let desired = json!({"desiredValue": {"address": "10.0.0.2"}});
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, desired, &options).unwrap();
+        // the gap below 10.0.0.2 is too small
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env,
+                                                      json!({"contiguous": true, "resourceCount": 4}), &options)
+            .unwrap();
+        assert_eq!(json!({"first": {"address": "10.0.0.3"}, "last": {"address": "10.0.0.6"}, "size": 4}),
+                   resources[0].value);
+        assert_eq!(5, resources.len());
+        let dry_run = AllocationOptions { dry_run: true, ..Default::default() };
+        let (_, resources_after) = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &dry_run).unwrap();
+        assert_eq!(json!({"address": "10.0.0.0"}), resources_after[0].value);
+        assert!(db.allocate_resources(pool.clone(), &mut wasmer_env,
+                                      json!({"contiguous": true, "blockMembers": false}), &options).is_err());
+
+        // deallocating the block frees its members, the pool is left with 10.0.0.2
+        let block = ResourceSelector::Id(resources[0].id.unwrap());
+        let (pool, _) = db.transition_resource(pool, &block, ResourceState::Retired).unwrap();
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+
+        let pool = db.update_pool_properties(pool, &mut wasmer_env, json!({"address": "10.0.0.0", "prefix": 30}))
+            .unwrap();
+        let err = db.allocate_resources(pool, &mut wasmer_env, json!({"contiguous": true, "resourceCount": 3}),
+                                        &options).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(),
+                         Some(AllocationError::Strategy { code, .. }) if code == "POOL_EXHAUSTED"), "{}", err);
+
+        // scripts return the members, which may be left out
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() {\n\
+            const values = []\n\
+            for (let n = 0; n < (userInput.short ? 1 : userInput.resourceCount); n++) { values.push({ vlan: n }) }\n\
+            return values\n}", None, &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let input = json!({"contiguous": true, "resourceCount": 3, "blockMembers": false});
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, input, &options).unwrap();
+        assert_eq!(vec![json!({"first": {"vlan": 0}, "last": {"vlan": 2}, "size": 3})],
+                   resources.into_iter().map(|it| it.value).collect::<Vec<_>>());
+        let input = json!({"contiguous": true, "resourceCount": 3, "short": true});
+        assert!(db.allocate_resources(pool.clone(), &mut wasmer_env, input, &options).is_err());
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+    }
+}
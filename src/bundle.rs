@@ -0,0 +1,561 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow, ensure};
+use chrono::Utc;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use postgres::{Client, Row};
+use serde_json::{Value, json};
+
+use crate::DB;
+use crate::engine::Engine;
+use crate::fixtures::StrategyTest;
+use crate::input::InputSchema;
+use crate::properties::PropertiesSchema;
+use crate::strategy::{ScriptKind, StrategyDocs, StrategyFiles};
+use crate::summary::ContextQueries;
+use crate::transaction::Transaction;
+
+// Contents of the directory of a strategy in a bundle.
+const METADATA_FILE: &str = "strategy.json";
+const SCRIPT_FILE: &str = "script.js";
+const TYPESCRIPT_FILE: &str = "script.ts";
+const TESTS_FILE: &str = "tests.json";
+const FILES_DIR: &str = "files/";
+// Size of a tar header, file contents are padded to it.
+const TAR_BLOCK: usize = 512;
+
+const BUNDLE_QUERY: &str = "SELECT id, name, script, script_kind, typescript, engine, version, sequential, \
+    input_schema, properties_schema, context_queries, description, expected_pool_properties_doc, \
+    expected_user_input_doc FROM allocation_strategies";
+
+/// Contents of a bundle by path relative to its root, e.g. `ipv4/strategy.json`.
+pub type BundleFiles = BTreeMap<String, String>;
+
+/// Strategy with everything needed to recreate it in another instance, see `DB::export_strategies`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrategyBundle {
+    pub name: String,
+    // JavaScript executed by the engine, compiled from `typescript` if set
+    pub script: String,
+    pub typescript: Option<String>,
+    pub kind: ScriptKind,
+    pub engine: Engine,
+    pub version: i32,
+    pub sequential: bool,
+    pub input_schema: Option<Value>,
+    pub properties_schema: Option<Value>,
+    pub context_queries: Option<Value>,
+    pub docs: StrategyDocs,
+    pub files: StrategyFiles,
+    pub tests: Vec<StrategyTest>,
+}
+
+/// What `DB::import_strategies` did with a strategy of a bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BundleImport {
+    pub name: String,
+    pub id: i64,
+    // `created`, `updated` or `unchanged`
+    pub outcome: &'static str,
+    pub version: i32,
+}
+
+impl BundleImport {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "name": &self.name,
+            "id": self.id,
+            "outcome": self.outcome,
+            "version": self.version,
+        })
+    }
+}
+
+impl StrategyBundle {
+    // Everything but the script, helper files and tests, in `strategy.json`.
+    fn metadata(&self) -> Value {
+        json!({
+            "kind": self.kind.as_str(),
+            "engine": self.engine.as_str(),
+            "version": self.version,
+            "sequential": self.sequential,
+            "inputSchema": &self.input_schema,
+            "propertiesSchema": &self.properties_schema,
+            "contextQueries": &self.context_queries,
+            "description": &self.docs.description,
+            "expectedPoolPropertiesDoc": &self.docs.expected_pool_properties,
+            "expectedUserInputDoc": &self.docs.expected_user_input,
+        })
+    }
+
+    // Each strategy is a directory named after it.
+    pub fn to_files(bundles: &[StrategyBundle]) -> Result<BundleFiles> {
+        let mut files = BundleFiles::new();
+        for bundle in bundles {
+            ensure!(!bundle.name.contains('/'), "Strategy '{}' cannot be bundled, its name contains '/'", bundle.name);
+            check_path(&bundle.name)?;
+            let path = |file: &str| format!("{}/{}", bundle.name, file);
+            files.insert(path(METADATA_FILE), to_pretty_json(&bundle.metadata())?);
+            files.insert(path(SCRIPT_FILE), bundle.script.clone());
+            if let Some(typescript) = &bundle.typescript {
+                files.insert(path(TYPESCRIPT_FILE), typescript.clone());
+            }
+            for (file, content) in &bundle.files {
+                check_path(file)?;
+                files.insert(path(&format!("{}{}", FILES_DIR, file)), content.clone());
+            }
+            if !bundle.tests.is_empty() {
+                let tests = bundle.tests.iter().map(StrategyTest::as_json).collect::<Vec<_>>();
+                files.insert(path(TESTS_FILE), to_pretty_json(&json!(tests))?);
+            }
+        }
+        Ok(files)
+    }
+
+    // Files in the root of the bundle, e.g. a readme, are ignored.
+    pub fn from_files(files: &BundleFiles) -> Result<Vec<StrategyBundle>> {
+        let mut strategies: BTreeMap<&str, BTreeMap<&str, &str>> = BTreeMap::new();
+        for (path, content) in files {
+            if let Some((strategy, file)) = path.split_once('/') {
+                strategies.entry(strategy).or_default().insert(file, content);
+            }
+        }
+        strategies.into_iter()
+            .map(|(name, files)| Self::from_strategy_files(name, &files)
+                .context(format!("Invalid strategy '{}' in the bundle", name)))
+            .collect()
+    }
+
+    fn from_strategy_files(name: &str, files: &BTreeMap<&str, &str>) -> Result<StrategyBundle> {
+        check_path(name)?;
+        let file = |file: &str| files.get(file).copied().ok_or_else(|| anyhow!("File '{}' not found", file));
+        let parse = |file: &str, content: &str| serde_json::from_str::<Value>(content)
+            .context(format!("'{}' is not a valid JSON", file));
+        let metadata = parse(METADATA_FILE, file(METADATA_FILE)?)?;
+        let script = file(SCRIPT_FILE)?.to_owned();
+        let text = |key: &str| metadata[key].as_str().map(str::to_owned);
+        let json = |key: &str| Some(metadata[key].clone()).filter(|value| !value.is_null());
+        let tests = match files.get(TESTS_FILE) {
+            Some(tests) => parse(TESTS_FILE, tests)?.as_array()
+                .ok_or_else(|| anyhow!("'{}' must be a JSON array", TESTS_FILE))?
+                .iter()
+                .map(StrategyTest::from_json)
+                .collect::<Result<_>>()?,
+            None => vec![],
+        };
+        Ok(StrategyBundle {
+            name: name.to_owned(),
+            kind: ScriptKind::of_strategy(metadata["kind"].as_str(), &script)?,
+            script,
+            typescript: files.get(TYPESCRIPT_FILE).map(|typescript| (*typescript).to_owned()),
+            engine: metadata["engine"].as_str().unwrap_or_else(|| Engine::QuickjsSubprocess.as_str()).parse()?,
+            version: metadata["version"].as_i64().map(i32::try_from).transpose()?.unwrap_or(1),
+            sequential: metadata["sequential"].as_bool().unwrap_or(false),
+            input_schema: json("inputSchema"),
+            properties_schema: json("propertiesSchema"),
+            context_queries: json("contextQueries"),
+            docs: StrategyDocs {
+                description: text("description"),
+                expected_pool_properties: text("expectedPoolPropertiesDoc"),
+                expected_user_input: text("expectedUserInputDoc"),
+            },
+            files: files.iter()
+                .filter_map(|(file, content)| file.strip_prefix(FILES_DIR)
+                    .map(|file| (file.to_owned(), (*content).to_owned())))
+                .collect(),
+            tests,
+        })
+    }
+}
+
+fn to_pretty_json(value: &Value) -> Result<String> {
+    Ok(serde_json::to_string_pretty(value)? + "\n")
+}
+
+// Hidden entries are skipped when reading bundles, e.g. `.git`, so they cannot be written either.
+fn check_path(path: &str) -> Result<()> {
+    ensure!(path.split('/').all(|component| !component.is_empty() && !component.starts_with('.')),
+            "'{}' cannot be bundled, expected a relative path without hidden components", path);
+    Ok(())
+}
+
+enum BundleFormat {
+    Directory,
+    Tar,
+    TarGz,
+}
+
+impl BundleFormat {
+    fn of(path: &Path) -> BundleFormat {
+        let path = path.to_string_lossy();
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            BundleFormat::TarGz
+        } else if path.ends_with(".tar") {
+            BundleFormat::Tar
+        } else {
+            BundleFormat::Directory
+        }
+    }
+}
+
+// A directory tree, or a tar archive if `path` ends with `.tar`, `.tar.gz` or `.tgz`. Directories of the
+// written strategies are replaced, other contents of an existing directory are kept.
+pub fn write_bundle(path: &Path, bundles: &[StrategyBundle]) -> Result<()> {
+    let files = StrategyBundle::to_files(bundles)?;
+    let create = || File::create(path).context(format!("Cannot create '{}'", path.display()));
+    match BundleFormat::of(path) {
+        BundleFormat::Directory => write_dir(path, &files),
+        BundleFormat::Tar => write_tar(&mut BufWriter::new(create()?), &files),
+        BundleFormat::TarGz => {
+            let mut out = GzEncoder::new(BufWriter::new(create()?), Compression::default());
+            write_tar(&mut out, &files)?;
+            out.finish()?.flush()?;
+            Ok(())
+        }
+    }
+}
+
+pub fn read_bundle(path: &Path) -> Result<Vec<StrategyBundle>> {
+    let mut files = BundleFiles::new();
+    let open = || File::open(path).context(format!("Cannot open '{}'", path.display()));
+    match BundleFormat::of(path) {
+        BundleFormat::Directory => read_dir(path, "", &mut files)?,
+        BundleFormat::Tar => read_tar(&mut BufReader::new(open()?), &mut files)?,
+        BundleFormat::TarGz => read_tar(&mut GzDecoder::new(BufReader::new(open()?)), &mut files)?,
+    }
+    StrategyBundle::from_files(&files)
+}
+
+fn write_dir(dir: &Path, files: &BundleFiles) -> Result<()> {
+    let strategies = files.keys().filter_map(|path| path.split('/').next()).collect::<BTreeSet<_>>();
+    for strategy in strategies {
+        let strategy_dir = dir.join(strategy);
+        if strategy_dir.exists() {
+            fs::remove_dir_all(&strategy_dir).context(format!("Cannot replace '{}'", strategy_dir.display()))?;
+        }
+    }
+    for (path, content) in files {
+        let file = dir.join(path);
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).context(format!("Cannot create '{}'", parent.display()))?;
+        }
+        fs::write(&file, content).context(format!("Cannot write '{}'", file.display()))?;
+    }
+    Ok(())
+}
+
+// Adds files under `root/prefix` by their path relative to `root`.
+fn read_dir(root: &Path, prefix: &str, files: &mut BundleFiles) -> Result<()> {
+    let dir = root.join(prefix);
+    for entry in fs::read_dir(&dir).context(format!("Cannot read '{}'", dir.display()))? {
+        let entry = entry?;
+        let name = entry.file_name().into_string()
+            .map_err(|name| anyhow!("Invalid file name {:?} in '{}'", name, dir.display()))?;
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        if entry.file_type()?.is_dir() {
+            read_dir(root, &path, files)?;
+        } else {
+            let content = fs::read_to_string(entry.path())
+                .context(format!("Cannot read '{}'", entry.path().display()))?;
+            files.insert(path, content);
+        }
+    }
+    Ok(())
+}
+
+fn write_tar(out: &mut dyn Write, files: &BundleFiles) -> Result<()> {
+    let mtime = Utc::now().timestamp() as u64;
+    for (path, content) in files {
+        out.write_all(&tar_header(path, content.len() as u64, mtime)?)?;
+        out.write_all(content.as_bytes())?;
+        out.write_all(&[0; TAR_BLOCK][..tar_padding(content.len())])?;
+    }
+    // end of archive
+    out.write_all(&[0; 2 * TAR_BLOCK])?;
+    Ok(())
+}
+
+fn tar_padding(size: usize) -> usize {
+    (TAR_BLOCK - size % TAR_BLOCK) % TAR_BLOCK
+}
+
+// ustar header of a regular file, paths longer than 100 bytes are split into the prefix field at a slash.
+fn tar_header(path: &str, size: u64, mtime: u64) -> Result<[u8; TAR_BLOCK]> {
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        path.match_indices('/')
+            .map(|(idx, _)| (&path[..idx], &path[idx + 1..]))
+            .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100)
+            .ok_or_else(|| anyhow!("Path '{}' is too long for a tar archive", path))?
+    };
+    let mut header = [0; TAR_BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..265].copy_from_slice(b"ustar\x0000");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // the checksum is summed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|byte| *byte as u64).sum();
+    write_octal(&mut header[148..155], checksum);
+    Ok(header)
+}
+
+// Zero padded and NUL terminated.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+// Regular files of a ustar or GNU archive, such as one created by `tar -czf`. Directories, links and
+// extended headers are skipped, as are hidden files.
+fn read_tar(input: &mut dyn Read, files: &mut BundleFiles) -> Result<()> {
+    // name of the next entry from a GNU long name entry
+    let mut long_name = None;
+    loop {
+        let mut header = [0; TAR_BLOCK];
+        input.read_exact(&mut header).context("Truncated tar archive")?;
+        if header.iter().all(|byte| *byte == 0) {
+            return Ok(());
+        }
+        let size = read_octal(&header[124..136])?;
+        let mut content = vec![0; size + tar_padding(size)];
+        input.read_exact(&mut content).context("Truncated tar archive")?;
+        content.truncate(size);
+        match header[156] {
+            b'L' => long_name = Some(read_c_string(&content)?),
+            b'0' | 0 => {
+                let path = match long_name.take() {
+                    Some(path) => path,
+                    None => {
+                        let name = read_c_string(&header[..100])?;
+                        let prefix = if &header[257..262] == b"ustar" { read_c_string(&header[345..500])? } else {
+                            String::new()
+                        };
+                        if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) }
+                    }
+                };
+                let path = path.trim_start_matches("./");
+                if !path.split('/').any(|component| component.starts_with('.')) {
+                    let content = String::from_utf8(content).context(format!("'{}' is not UTF-8", path))?;
+                    files.insert(path.to_owned(), content);
+                }
+            }
+            _ => long_name = None,
+        }
+    }
+}
+
+fn read_octal(field: &[u8]) -> Result<usize> {
+    let digits = std::str::from_utf8(field)?.trim_matches(|c| c == '\0' || c == ' ');
+    usize::from_str_radix(digits, 8).context(format!("Invalid number '{}' in a tar header", digits))
+}
+
+fn read_c_string(field: &[u8]) -> Result<String> {
+    let end = field.iter().position(|byte| *byte == 0).unwrap_or(field.len());
+    Ok(String::from_utf8(field[..end].to_vec())?)
+}
+
+impl DB {
+    // Strategies of the given names ordered by name, all of them if there are none.
+    pub fn export_strategies(&mut self, names: &[String]) -> Result<Vec<StrategyBundle>> {
+        let rows = self.client.query(
+            format!("{} WHERE cardinality($1::text[]) = 0 OR name = ANY($1) ORDER BY name", BUNDLE_QUERY).as_str(),
+            &[&names])?;
+        for name in names {
+            ensure!(rows.iter().any(|row| row.get::<_, &str>(1) == name), "Allocation strategy '{}' not found", name);
+        }
+        let mut bundles = Vec::with_capacity(rows.len());
+        for row in rows {
+            bundles.push(Self::strategy_bundle(&mut self.client, row)?);
+        }
+        Ok(bundles)
+    }
+
+    fn strategy_bundle(client: &mut Client, row: Row) -> Result<StrategyBundle> {
+        let id: i64 = row.get(0);
+        let script: String = row.get(2);
+        Ok(StrategyBundle {
+            name: row.get(1),
+            kind: ScriptKind::of_strategy(row.get(3), &script)?,
+            script,
+            typescript: row.get(4),
+            engine: row.get::<_, &str>(5).parse()?,
+            version: row.get(6),
+            sequential: row.get(7),
+            input_schema: row.get(8),
+            properties_schema: row.get(9),
+            context_queries: row.get(10),
+            docs: StrategyDocs {
+                description: row.get(11),
+                expected_pool_properties: row.get(12),
+                expected_user_input: row.get(13),
+            },
+            files: Self::get_strategy_files(client, id)?,
+            tests: Self::get_strategy_test_rows(client, id)?,
+        })
+    }
+
+    // Creates strategies missing by name and replaces the others, in one transaction. A changed script or
+    // helper file bumps the version, to at least the one in the bundle. Imports of module strategies
+    // are linked when they execute, so that a bundle may bring the strategies its modules import.
+    pub fn import_strategies(&mut self, bundles: &[StrategyBundle]) -> Result<Vec<BundleImport>> {
+        let mut transaction = self.client.transaction()?;
+        let mut imports = Vec::with_capacity(bundles.len());
+        for bundle in bundles {
+            imports.push(Self::import_strategy(&mut transaction, bundle)
+                .context(format!("Cannot import strategy '{}'", bundle.name))?);
+        }
+        transaction.commit()?;
+        Ok(imports)
+    }
+
+    fn import_strategy(transaction: &mut Transaction, bundle: &StrategyBundle) -> Result<BundleImport> {
+        bundle.engine.check_available()?;
+        bundle.input_schema.clone().map(InputSchema::from_json).transpose()?;
+        let queries = bundle.context_queries.clone().map(ContextQueries::from_json).transpose()?;
+        if let Some(queries) = &queries {
+            queries.evaluate(transaction, 0).map_err(|err| anyhow!("Invalid context queries: {}", err))?;
+        }
+        // stored normalized, so that an unchanged strategy compares equal
+        let bundle = &StrategyBundle {
+            properties_schema: bundle.properties_schema.as_ref()
+                .map(|schema| PropertiesSchema::from_json(schema).map(|schema| schema.as_json()))
+                .transpose()?,
+            ..bundle.clone()
+        };
+        let current = transaction.query_opt(format!("{} WHERE name=$1 FOR UPDATE", BUNDLE_QUERY).as_str(),
+                                            &[&bundle.name])?
+            .map(|row| Ok::<_, anyhow::Error>((row.get(0), Self::strategy_bundle(transaction, row)?)))
+            .transpose()?;
+        let mut rebuild_free_ranges = false;
+        let (id, outcome, version) = match current {
+            None => {
+                let id = Self::insert_strategy_rows(transaction, &bundle.name, &bundle.script, Some(bundle.kind),
+                                                    &bundle.files)?;
+                (id, "created", bundle.version)
+            }
+            Some((id, current)) if current == StrategyBundle { version: current.version, ..bundle.clone() } => {
+                let version = current.version;
+                return Ok(BundleImport { name: bundle.name.clone(), id, outcome: "unchanged", version });
+            }
+            Some((id, current)) => {
+                rebuild_free_ranges = current.sequential != bundle.sequential;
+                let script_changed = current.script != bundle.script || current.typescript != bundle.typescript
+                    || current.kind != bundle.kind || current.files != bundle.files;
+                if !script_changed {
+                    (id, "updated", current.version)
+                } else {
+                    transaction.execute("UPDATE allocation_strategies SET script=$2, script_kind=$3 WHERE id=$1",
+                                        &[&id, &bundle.script, &bundle.kind.as_str()])?;
+                    transaction.execute("DELETE FROM allocation_strategy_files WHERE allocation_strategy_id=$1",
+                                        &[&id])?;
+                    Self::insert_strategy_files(transaction, id, &bundle.files)?;
+                    (id, "updated", bundle.version.max(current.version + 1))
+                }
+            }
+        };
+        transaction.execute(
+            "UPDATE allocation_strategies SET typescript=$2, engine=$3, version=$4, sequential=$5, input_schema=$6, \
+            properties_schema=$7, context_queries=$8, description=$9, expected_pool_properties_doc=$10, \
+            expected_user_input_doc=$11 WHERE id=$1",
+            &[&id, &bundle.typescript, &bundle.engine.as_str(), &version, &bundle.sequential, &bundle.input_schema,
+                &bundle.properties_schema, &bundle.context_queries, &bundle.docs.description,
+                &bundle.docs.expected_pool_properties, &bundle.docs.expected_user_input])?;
+        transaction.execute("DELETE FROM allocation_strategy_tests WHERE allocation_strategy_id=$1", &[&id])?;
+        for test in &bundle.tests {
+            Self::put_strategy_test_row(transaction, id, test)?;
+        }
+        if rebuild_free_ranges {
+            let pools = transaction.query(
+                "SELECT id FROM resource_pools WHERE resource_pool_allocation_strategy=$1", &[&id])?;
+            for pool in pools {
+                Self::rebuild_free_ranges(transaction, pool.get(0))?;
+            }
+        }
+        Ok(BundleImport { name: bundle.name.clone(), id, outcome, version })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_strategy_bundles() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let files: StrategyFiles = vec![("helpers.js".to_owned(), "const offset = 1;".to_owned())]
+            .into_iter().collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() { return [{ n: offset }] }", None, &files)
+            .unwrap();
+        db.set_strategy_docs(id, &StrategyDocs { description: Some("Numbers".to_owned()), ..StrategyDocs::default() })
+            .unwrap();
+        let schema = PropertiesSchema::from_json(&json!({"start": "integer?"})).unwrap();
+        db.set_properties_schema(id, Some(&schema)).unwrap();
+        db.put_strategy_test(id, &StrategyTest {
+            name: "first".to_owned(),
+            user_input: json!({}),
+            pool_properties: json!({}),
+            current_resources: vec![],
+            expected: vec![json!({"n": 1})],
+        }).unwrap();
+        let exported = db.export_strategies(std::slice::from_ref(&name)).unwrap();
+        assert_eq!(1, exported.len());
+        assert_eq!(files, exported[0].files);
+        assert!(db.export_strategies(&[format!("{}-missing", name)]).is_err());
+
+        for file in ["bundle", "bundle.tar", "bundle.tar.gz"] {
+            let path = env::temp_dir().join(format!("rm-{}-{}", name, file));
+            write_bundle(&path, &exported).unwrap();
+            assert_eq!(exported, read_bundle(&path).unwrap(), "{}", file);
+            if path.is_dir() {
+                fs::remove_dir_all(&path).unwrap();
+            } else {
+                fs::remove_file(&path).unwrap();
+            }
+        }
+
+        let imported = db.import_strategies(&exported).unwrap();
+        assert_eq!(vec![BundleImport { name: name.clone(), id, outcome: "unchanged", version: 1 }], imported);
+        // promoted under another name, and with a changed script
+        let copy = StrategyBundle { name: format!("{}-copy", name), version: 3, ..exported[0].clone() };
+        let changed = StrategyBundle { script: "function invoke() { return [] }".to_owned(), ..exported[0].clone() };
+        let imported = db.import_strategies(&[copy.clone(), changed]).unwrap();
+        assert_eq!(("created", 3), (imported[0].outcome, imported[0].version));
+        assert_eq!(BundleImport { name: name.clone(), id, outcome: "updated", version: 2 }, imported[1]);
+        assert_eq!(vec![copy], db.export_strategies(&[format!("{}-copy", name)]).unwrap());
+
+        // nothing is imported if any strategy is invalid
+        let invalid = StrategyBundle { engine: Engine::Native, context_queries: Some(json!({"n": "SELECT nope"})),
+            ..exported[0].clone() };
+        assert!(db.import_strategies(&[invalid]).is_err());
+        let unavailable = StrategyBundle { engine: Engine::WasmModule, ..exported[0].clone() };
+        let err = db.import_strategies(&[unavailable]).unwrap_err();
+        assert!(format!("{:#}", err).contains("Engine 'wasm-module' is not available"), "{:#}", err);
+        assert_eq!(Engine::QuickjsSubprocess, db.get_strategy(id).unwrap().engine);
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{Result, bail};
+use serde_json::{Value, json};
+
+use crate::{DB, Resource, ResourcePool, WasmerEnv};
+use crate::engine::Engine;
+
+// Scripts not mentioning it are not invoked a second time.
+const CANONICALIZE: &str = "canonicalize";
+
+// Jobs of pools whose script may define `canonicalize(value)` are allocated alone, see `DB::run_allocation_batch`.
+pub(crate) fn may_canonicalize(script: &str) -> bool {
+    script.contains(CANONICALIZE)
+}
+
+// Canonical forms of the values in order, None if the script does not define `canonicalize(value)`.
+fn canonical_values(wasmer_env: &mut WasmerEnv, engine: Engine, script: &str, pool: &ResourcePool,
+                    values: Vec<&Value>) -> Result<Option<Vec<Value>>> {
+    if !may_canonicalize(script) {
+        return Ok(None);
+    }
+    let function_call = format!(
+        "typeof canonicalize === 'function' ? {}.map(value => canonicalize(value)) : JSON.stringify(null)",
+        json!(values));
+    let result = wasmer_env.engine(engine)?.invoke(script, json!({}), pool.get_pool_properties(), pool.as_json(),
+                                                   &mut vec![], &function_call)?;
+    match result {
+        Value::Null => Ok(None),
+        Value::Array(canonical) if canonical.len() == values.len() => {
+            if let Some(idx) = canonical.iter().position(Value::is_null) {
+                bail!("canonicalize() returned null for {}", values[idx]);
+            }
+            Ok(Some(canonical))
+        }
+        other => bail!("canonicalize() did not return a value for each of {} values, got {}", values.len(), other),
+    }
+}
+
+// Keeps the canonical form of every value if the strategy defines `canonicalize(value)`, so that values equal
+// in their canonical form are refused as duplicates, e.g. `{"address": "10.0.0.1/32"}` while
+// `{"address": "10.0.0.1"}` is in use.
+pub(crate) fn canonicalize_resources(wasmer_env: &mut WasmerEnv, engine: Engine, script: &str, pool: &ResourcePool,
+                                     mut resources: Vec<Resource>) -> Result<Vec<Resource>> {
+    let values = resources.iter().map(|resource| &resource.value).collect();
+    if let Some(canonical) = canonical_values(wasmer_env, engine, script, pool, values)? {
+        for (resource, canonical_value) in resources.iter_mut().zip(canonical) {
+            resource.canonical_value = Some(canonical_value);
+        }
+    }
+    Ok(resources)
+}
+
+impl DB {
+    // A resource in use has the value, or the same canonical form if the strategy defines `canonicalize(value)`.
+    pub fn resource_exists(&mut self, wasmer_env: &mut WasmerEnv, resource_pool_id: i64, value: &Value)
+                           -> Result<bool> {
+        let context = self.get_allocation_context(resource_pool_id)?;
+        let canonical_value = if may_canonicalize(&context.script) {
+            let pool = self.get_resource_pool_by_id(resource_pool_id)?;
+            canonical_values(wasmer_env, context.engine, &context.script, &pool, vec![value])?
+                .and_then(|canonical| canonical.into_iter().next())
+        } else {
+            None
+        };
+        let row = self.reader().query_one(
+            "SELECT EXISTS (SELECT 1 FROM resources WHERE resource_pool=$1 AND status <> 'retired' \
+            AND (value=$2 OR canonical_value=$3))",
+            &[&resource_pool_id, value, &canonical_value])?;
+        Ok(row.get(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::{AllocationOptions, ResourceSelector};
+    use crate::metrics::AllocationOutcome;
+    use crate::state::ResourceState;
+    use crate::strategy::StrategyFiles;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_canonical_duplicates() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() { return [{address: userInput.address}] }\n\
+            function canonicalize(value) { return {address: value.address.replace(/\\/32$/, '')} }",
+                                               None, &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let options = AllocationOptions::default();
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"address": "10.0.0.1"}),
+                                                      &options).unwrap();
+        assert_eq!(Some(json!({"address": "10.0.0.1"})), resources[0].canonical_value);
+        let result = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({"address": "10.0.0.1/32"}), &options);
+        assert_eq!(AllocationOutcome::Duplicate, AllocationOutcome::of(&result));
+        assert!(db.resource_exists(&mut wasmer_env, pool.id, &json!({"address": "10.0.0.1/32"})).unwrap());
+        assert!(!db.resource_exists(&mut wasmer_env, pool.id, &json!({"address": "10.0.0.2/32"})).unwrap());
+
+        // retired values can be allocated again in another form
+        let (pool, _) = db.transition_resource(pool, &ResourceSelector::Value(json!({"address": "10.0.0.1"})),
+                                               ResourceState::Retired).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({"address": "10.0.0.1/32"}), &options)
+            .unwrap();
+        let exported = db.get_resources(pool.id).unwrap()[0].as_export_json();
+        assert_eq!(json!({"address": "10.0.0.1"}), exported["canonicalValue"]);
+    }
+}
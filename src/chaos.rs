@@ -0,0 +1,123 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail};
+use rand::Rng;
+
+use crate::transaction::Transaction;
+
+/// Probability between 0 and 1 of an injected failure.
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+pub struct Probability(f64);
+
+impl Probability {
+    fn happens(&self) -> bool {
+        self.0 > 0.0 && rand::thread_rng().gen_bool(self.0)
+    }
+}
+
+impl FromStr for Probability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Probability> {
+        let probability = s.parse::<f64>()?;
+        if !(0.0..=1.0).contains(&probability) {
+            bail!("probability must be between 0 and 1");
+        }
+        Ok(Probability(probability))
+    }
+}
+
+impl fmt::Display for Probability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Failures injected into allocations of builds with the `chaos` feature, so that retries of clients and workers
+/// can be exercised against realistic failure rates. Other builds refuse the `RM_CHAOS_*` settings.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Chaos {
+    // upper bound of a random delay of every allocation transaction before it commits
+    pub db_latency: Option<Duration>,
+    // allocation transactions failing with a serialization failure instead of committing
+    pub abort_probability: Probability,
+    // allocations failing instead of running the strategy
+    pub script_failure_probability: Probability,
+}
+
+impl Chaos {
+    pub fn is_enabled(&self) -> bool {
+        self.db_latency.is_some() || self.abort_probability > Probability(0.0)
+            || self.script_failure_probability > Probability(0.0)
+    }
+
+    // Sleeps in Postgres, so that locks of the transaction are held as they would be by a slow database,
+    // then aborts the transaction with a real `40001` error of Postgres.
+    pub(crate) fn before_commit(&self, transaction: &mut Transaction) -> Result<()> {
+        if !cfg!(feature = "chaos") {
+            return Ok(());
+        }
+        if let Some(latency) = self.db_latency {
+            let delay = rand::thread_rng().gen_range(0.0, latency.as_secs_f64());
+            transaction.execute("SELECT pg_sleep($1)", &[&delay])?;
+        }
+        if self.abort_probability.happens() {
+            transaction.batch_execute(
+                "DO $$ BEGIN RAISE EXCEPTION 'transaction aborted by chaos mode' USING ERRCODE = '40001'; END $$")?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn before_script(&self) -> Result<()> {
+        if cfg!(feature = "chaos") && self.script_failure_probability.happens() {
+            return Err(anyhow!("Strategy failed by chaos mode"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chaos_probability() {
+        assert_eq!(Probability(0.25), "0.25".parse().unwrap());
+        assert!("1.5".parse::<Probability>().is_err());
+        assert!("-0.1".parse::<Probability>().is_err());
+        let chaos = Chaos { abort_probability: Probability(1.0), ..Chaos::default() };
+        assert!(chaos.is_enabled());
+        assert!(!Chaos::default().is_enabled());
+        assert!(Chaos::default().before_script().is_ok());
+    }
+
+    #[cfg(feature = "chaos")]
+    #[test]
+    fn db_chaos() {
+        use serde_json::json;
+
+        use crate::{AllocationOptions, DB, WasmerEnv};
+        use crate::metrics::AllocationOutcome;
+        use crate::strategy::StrategyFiles;
+        use crate::tests::{create_random_pool, initialize_logging};
+
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        db.chaos = Chaos { abort_probability: Probability(1.0), ..Chaos::default() };
+        let result = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &AllocationOptions::default());
+        assert_eq!(AllocationOutcome::DbError, AllocationOutcome::of(&result));
+        assert_eq!(0, db.count_resources(pool.id).unwrap());
+
+        db.chaos = Chaos { script_failure_probability: Probability(1.0), ..Chaos::default() };
+        let id = db.insert_allocation_strategy(&format!("chaos-{}", pool.name), "function invoke() { return [{}] }",
+                                               None, &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&format!("chaos-{}", pool.name), id).unwrap();
+        let result = db.allocate_resources(pool, &mut wasmer_env, json!({}), &AllocationOptions::default());
+        assert_eq!(AllocationOutcome::ScriptError, AllocationOutcome::of(&result));
+    }
+}
@@ -0,0 +1,2013 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use chrono::{DateTime, Utc};
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use serde_json::{Map, Value, json};
+
+use crate::backpressure::Allocation;
+use crate::bundle;
+use crate::diff::{PoolDiff, PoolState};
+use crate::connect::ConnectRetry;
+use crate::engine::Engine;
+use crate::error::AllocationError;
+use crate::fixtures::StrategyTest;
+use crate::grpc::GrpcServer;
+use crate::hooks::{HookErrorPolicy, HookStage, PoolHook};
+use crate::http::{self, Server};
+use crate::input::InputSchema;
+use crate::metadata::MetadataQuery;
+use crate::outbox::Webhook;
+use crate::pools::{DEFAULT_PAGE_SIZE, PoolFilter, PoolSort};
+use crate::progress::Progress;
+use crate::routing::DbRouter;
+use crate::properties::PropertiesSchema;
+use crate::shutdown::Shutdown;
+use crate::snapshot::RestoreMode;
+use crate::state::ResourceState;
+use crate::storage::{self, Storage};
+use crate::strategy::{ScriptKind, StrategyDocs, StrategyFiles, StrategySummary};
+use crate::summary::ContextQueries;
+use crate::supervisor::Supervisor;
+use crate::typescript::TypeScriptCompiler;
+use crate::worker::{Worker, WorkerConfig};
+use crate::{AllocationOptions, BulkSelector, DB, Resource, ResourceFilter, ResourcePool, ResourceSelector, WasmerEnv};
+
+/// Value of file arguments meaning stdin or stdout.
+const STDIO: &str = "-";
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Prototype of resource allocation using a JSONB based schema")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print shell completions or a man page to stdout
+    Completions {
+        #[arg(value_enum)]
+        target: CompletionTarget,
+    },
+    /// Allocate new resources from a pool
+    Allocate {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Number of resources to allocate, passed to the strategy as `resourceCount`
+        #[arg(long)]
+        count: Option<u32>,
+        /// Additional user input passed to the strategy. Values are parsed as JSON,
+        /// falling back to a string, e.g. `--input subnet=true`
+        #[arg(long = "input", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        inputs: Vec<(String, Value)>,
+        /// Lease duration in seconds, expired resources are reclaimed by `pool gc`
+        #[arg(long, value_name = "SECONDS")]
+        lease: Option<u64>,
+        /// Print what would be allocated without changing the pool
+        #[arg(long)]
+        dry_run: bool,
+        /// Insert resources as reserved, use `resources set-state` to allocate them later
+        #[arg(long)]
+        reserve: bool,
+        /// Principal or foreign reference owning the resources, e.g. `service:voip`
+        #[arg(long)]
+        owner: Option<String>,
+        /// Human-readable note stored with every allocated resource, e.g. `loopback for PE-router-3`
+        #[arg(long)]
+        description: Option<String>,
+        /// Only enqueue the allocation and print the job, use `jobs status` to poll it
+        #[arg(long = "async", conflicts_with = "dry_run")]
+        enqueue: bool,
+        /// Print time spent in the strategy and the database to stderr as JSON
+        #[arg(long, conflicts_with = "enqueue")]
+        timings: bool,
+        /// Allocate `--count` resources by this many per transaction, logging progress
+        #[arg(long, requires = "count", conflicts_with_all = ["dry_run", "enqueue"])]
+        batch_size: Option<u32>,
+    },
+    /// Deallocate resources of a pool
+    #[command(group(ArgGroup::new("resource").required(true).args(["id", "value", "state"])))]
+    Deallocate {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Id of the resource, can be repeated to deallocate many resources at once
+        #[arg(long)]
+        id: Vec<i64>,
+        /// Value of the resource as JSON, e.g. `{"address":"10.0.0.1"}`
+        #[arg(long)]
+        value: Option<String>,
+        /// Deallocate all resources in this state
+        #[arg(long, value_enum)]
+        state: Option<ResourceState>,
+        /// Do not ask for confirmation, also deallocates a claimed resource
+        #[arg(long)]
+        force: bool,
+        /// Deallocate many resources by this many per transaction, logging progress
+        #[arg(long, conflicts_with = "value")]
+        batch_size: Option<usize>,
+    },
+    /// Manage allocation strategies
+    Strategy {
+        #[command(subcommand)]
+        command: StrategyCommand,
+    },
+    /// Manage resource pools
+    Pool {
+        #[command(subcommand)]
+        command: PoolCommand,
+    },
+    /// Manage resources of a pool
+    Resources {
+        #[command(subcommand)]
+        command: ResourcesCommand,
+    },
+    /// Capture and inspect point-in-time copies of pools
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Inspect and run asynchronous allocations
+    Jobs {
+        #[command(subcommand)]
+        command: JobsCommand,
+    },
+    /// Manage recurring allocations, executed by `worker`
+    Schedule {
+        #[command(subcommand)]
+        command: ScheduleCommand,
+    },
+    /// Manage groups of pools that never have the same value in use
+    UniquenessGroup {
+        #[command(subcommand)]
+        command: UniquenessGroupCommand,
+    },
+    /// Write strategies, pools, resources and snapshots as one gzip compressed archive, prints counts to stderr
+    Backup {
+        /// Archive file, `-` for stdout
+        #[arg(long, default_value = STDIO)]
+        output: String,
+    },
+    /// Recreate the instance of a `backup` archive in a database without resources, prints counts as JSON
+    Restore {
+        /// Archive file, `-` for stdin
+        #[arg(long, default_value = STDIO)]
+        file: String,
+    },
+    /// Recreate strategies, allocating pools with their properties and claimed resources of a FRINX
+    /// resource-manager database, prints what was imported and skipped as JSON
+    Import {
+        /// Connection string of the resource-manager database, e.g. `host=localhost user=postgres dbname=rm`
+        #[arg(long)]
+        from_rm: String,
+    },
+    /// Print history of a pool as JSON lines
+    Audit {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Number of latest entries to print
+        #[arg(long, default_value_t = 100)]
+        limit: i64,
+    },
+    /// Re-execute allocations and state changes of a pool recorded in its audit log against a new pool,
+    /// prints where the outcome differs as JSON and fails if it does
+    Replay {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Name of the new pool
+        #[arg(long)]
+        into: String,
+        /// Roll the new pool back once replayed, only the report is kept
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Create or check the database schema
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Strategies, pools and allocations stored in a SQLite file instead of Postgres, for labs and demos.
+    /// Hooks, leases, sticky keys and other features of `allocate` need Postgres
+    Lab {
+        /// SQLite file, created on first use. Needs a build with the `sqlite` feature, Postgres is used without it
+        #[arg(long, value_name = "FILE")]
+        sqlite: Option<String>,
+        #[command(subcommand)]
+        command: LabCommand,
+    },
+    /// Serve the JSON API over HTTP until SIGTERM or SIGINT
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Also serve the gRPC service of proto/resources.proto on this address, e.g. `127.0.0.1:50051`
+        #[arg(long)]
+        grpc_listen: Option<String>,
+        /// Number of threads handling requests, each with its own database connection
+        #[arg(long, default_value_t = 4)]
+        threads: usize,
+        /// Seconds requests being handled may take to finish after SIGTERM or SIGINT
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        drain_timeout: u64,
+    },
+    /// Run schedules, allocation jobs and pool gc until SIGTERM or SIGINT. Replicas elect a single leader for gc
+    Worker {
+        #[command(flatten)]
+        worker: WorkerArgs,
+        /// Serve `GET /metrics` of the worker on this address, e.g. `0.0.0.0:9100`
+        #[arg(long)]
+        metrics_address: Option<String>,
+        /// Seconds the current tick and the last delivery of the outbox may take to finish after SIGTERM or SIGINT
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        drain_timeout: u64,
+    },
+    /// Run the HTTP server and a worker in one process until SIGTERM or SIGINT, restarting them when they crash.
+    /// `GET /readyz` reports their health
+    Daemon {
+        /// Address of the JSON API, `/metrics` and `/readyz`
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Number of threads handling requests, each with its own database connection
+        #[arg(long, default_value_t = 4)]
+        threads: usize,
+        #[command(flatten)]
+        worker: WorkerArgs,
+        /// Seconds requests, the current tick and the last delivery of the outbox may take to finish after SIGTERM
+        /// or SIGINT
+        #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+        drain_timeout: u64,
+    },
+}
+
+/// Options of `worker` and `daemon`.
+#[derive(Args, Debug)]
+pub struct WorkerArgs {
+    /// Seconds between checks for pending allocation jobs
+    #[arg(long, value_name = "SECONDS", default_value_t = 5)]
+    poll_interval: u64,
+    /// Seconds between gc runs
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    gc_interval: u64,
+    /// Number of resources deleted per transaction
+    #[arg(long, default_value_t = 1000)]
+    batch_size: i64,
+    /// Maximum number of allocation jobs of pools with the same strategy executed by one script invocation
+    #[arg(long, default_value_t = 10)]
+    job_batch_size: i64,
+    /// Seconds after which allocation jobs still running, e.g. of a crashed worker, are failed by the leader
+    #[arg(long, value_name = "SECONDS", default_value_t = 600)]
+    job_timeout: u64,
+    /// Seconds deallocated resources are kept before they are purged
+    #[arg(long, value_name = "SECONDS", default_value_t = 7 * 24 * 3600)]
+    retention: u64,
+    /// Seconds between utilization samples of all pools
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    stats_interval: u64,
+    /// Seconds utilization samples are kept
+    #[arg(long, value_name = "SECONDS", default_value_t = 90 * 24 * 3600)]
+    stats_retention: u64,
+    /// Deliver pool events of the outbox written with `RM_OUTBOX=true` to this `http://` URL
+    #[arg(long, value_name = "URL")]
+    outbox_webhook: Option<Webhook>,
+    /// Maximum number of outbox events sent by one webhook request
+    #[arg(long, default_value_t = 100)]
+    outbox_batch_size: i64,
+}
+
+impl WorkerArgs {
+    fn config(&self) -> WorkerConfig {
+        WorkerConfig {
+            poll_interval: Duration::from_secs(self.poll_interval),
+            gc_interval: Duration::from_secs(self.gc_interval),
+            gc_batch_size: self.batch_size,
+            job_batch_size: self.job_batch_size,
+            job_timeout: Duration::from_secs(self.job_timeout),
+            retention: Duration::from_secs(self.retention),
+            stats_interval: Duration::from_secs(self.stats_interval),
+            stats_retention: Duration::from_secs(self.stats_retention),
+            outbox_webhook: self.outbox_webhook.clone(),
+            outbox_batch_size: self.outbox_batch_size,
+            ..WorkerConfig::default()
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StrategyCommand {
+    /// Store a JavaScript strategy, prints its id
+    Create {
+        /// Name of the strategy, modules import other module strategies by it
+        #[arg(long)]
+        name: String,
+        /// Script file, `.ts` and `.mts` files are compiled with `tsc` from `TSC_BIN`
+        #[arg(long)]
+        file: String,
+        /// `script` or `module`, detected from the script if not set
+        #[arg(long)]
+        kind: Option<ScriptKind>,
+        /// Helper file stored with the strategy, imported by modules as `./<file name>`
+        /// and evaluated before plain scripts. Can be repeated
+        #[arg(long)]
+        include: Vec<String>,
+    },
+    /// Print strategies with their language, engine, version and number of pools using them as JSON lines
+    List,
+    /// Print a strategy with its documentation of pool properties and user input it expects
+    Show {
+        #[arg(long)]
+        id: i64,
+    },
+    /// Document how to call a strategy. Omitted fields are kept, empty ones are cleared
+    SetDocs {
+        #[arg(long)]
+        id: i64,
+        /// What the strategy allocates
+        #[arg(long)]
+        description: Option<String>,
+        /// Keys of pool properties the strategy reads, e.g. `address` and `prefix` of the root prefix
+        #[arg(long)]
+        pool_properties: Option<String>,
+        /// Keys of the user input the strategy reads, e.g. optional `resourceCount`
+        #[arg(long)]
+        user_input: Option<String>,
+    },
+    /// Execute a strategy, and all pools using it, with another engine
+    SetEngine {
+        #[arg(long)]
+        id: i64,
+        /// `quickjs-subprocess`, `quickjs-embedded`, `wasm-module` and `native` are not available in this build
+        #[arg(long)]
+        engine: Engine,
+    },
+    /// Declare a JSON Schema of the user input of a strategy, checked before allocating and enqueueing
+    SetInputSchema {
+        #[arg(long)]
+        id: i64,
+        /// e.g. `{"type":"object","properties":{"resourceCount":{"type":"integer","minimum":1}}}`.
+        /// Omit to accept any input
+        #[arg(long)]
+        schema: Option<String>,
+    },
+    /// Declare keys and types of properties of pools using a strategy, checked when pools are created
+    /// or configured
+    SetPropertiesSchema {
+        #[arg(long)]
+        id: i64,
+        /// JSON object of types by key, e.g. `{"address":"string","prefix":"integer"}`. Types are `string`,
+        /// `number`, `integer`, `boolean`, `object` and `array`, optional with `?`. Omit to accept any properties
+        #[arg(long)]
+        schema: Option<String>,
+    },
+    /// Store a named test case of a strategy, replacing the one of the same name
+    AddTest {
+        #[arg(long)]
+        id: i64,
+        #[arg(long)]
+        name: String,
+        /// User input as JSON object
+        #[arg(long, default_value = "{}")]
+        input: String,
+        /// Pool properties as JSON, e.g. `{"address":"10.0.0.0","prefix":24}`
+        #[arg(long, default_value = "{}")]
+        properties: String,
+        /// JSON array of values in use by the pool
+        #[arg(long, default_value = "[]")]
+        resources: String,
+        /// JSON array of values the strategy must return
+        #[arg(long)]
+        expected: String,
+    },
+    /// Print test cases of a strategy as JSON lines
+    ListTests {
+        #[arg(long)]
+        id: i64,
+    },
+    /// Delete a test case of a strategy
+    RemoveTest {
+        #[arg(long)]
+        id: i64,
+        #[arg(long)]
+        name: String,
+    },
+    /// Run test cases of a strategy without touching pools, prints results as JSON lines and fails if any failed
+    Test {
+        #[arg(long)]
+        id: i64,
+    },
+    /// Write strategies with their scripts, helper files, metadata and test cases as a directory per strategy,
+    /// e.g. to keep them in git. Directories of the written strategies are replaced
+    Export {
+        /// Directory, or a tar archive if it ends with `.tar`, `.tar.gz` or `.tgz`
+        #[arg(long)]
+        path: String,
+        /// Name of a strategy to export, all of them if not set. Can be repeated
+        #[arg(long)]
+        name: Vec<String>,
+    },
+    /// Create strategies of an exported bundle missing by name and replace the others, all or nothing.
+    /// Prints what happened to each strategy as JSON lines
+    Import {
+        /// Directory, or a tar archive if it ends with `.tar`, `.tar.gz` or `.tgz`
+        #[arg(long)]
+        path: String,
+    },
+    /// Declare SQL queries summarizing resources of a pool, embedded into the script as `resourceSummary`
+    SetContextQueries {
+        #[arg(long)]
+        id: i64,
+        /// JSON object of select lists evaluated over resources in use, e.g. `{"lastAddress":"SELECT max(ip)"}`.
+        /// Omit to compute no summary
+        #[arg(long)]
+        queries: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DbCommand {
+    /// Create tables, indexes and built-in strategies, or apply migrations missing since the last run
+    Init,
+    /// Compare the live schema with the migrations, fails if they differ
+    Verify,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum LabCommand {
+    /// Store a strategy from a JavaScript file and print its id
+    CreateStrategy {
+        /// Unique name of the strategy
+        #[arg(long)]
+        name: String,
+        /// Script defining `invoke()`
+        #[arg(long)]
+        file: String,
+    },
+    /// Create a resource pool
+    CreatePool {
+        /// Unique name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Id of the allocation strategy
+        #[arg(long)]
+        strategy_id: i64,
+        /// Properties passed to the strategy as JSON, e.g. `{"address":"10.0.0.0","prefix":24}`
+        #[arg(long)]
+        properties: Option<String>,
+    },
+    /// Delete a pool without resources in use
+    DeletePool {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Allocate new resources from a pool and print their values
+    Allocate {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Number of resources to allocate, passed to the strategy as `resourceCount`
+        #[arg(long)]
+        count: Option<u32>,
+        /// Additional user input passed to the strategy, as in `allocate`
+        #[arg(long = "input", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        inputs: Vec<(String, Value)>,
+    },
+    /// Deallocate a resource of a pool
+    #[command(group(ArgGroup::new("resource").required(true).args(["id", "value"])))]
+    Deallocate {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Id of the resource
+        #[arg(long)]
+        id: Option<i64>,
+        /// Value of the resource as JSON, e.g. `{"address":"10.0.0.1"}`
+        #[arg(long)]
+        value: Option<String>,
+    },
+    /// List resources in use of a pool
+    Resources {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ScheduleCommand {
+    /// Allocate from a pool whenever the cron expression fires
+    Create {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Name of the schedule, unique within the pool
+        #[arg(long)]
+        name: String,
+        /// Cron expression including seconds, in UTC, e.g. `0 0 2 * * *` for every night at 2:00
+        #[arg(long)]
+        cron: String,
+        /// Number of resources to allocate, passed to the strategy as `resourceCount`
+        #[arg(long)]
+        count: Option<u32>,
+        /// Additional user input passed to the strategy, see `allocate --input`
+        #[arg(long = "input", value_name = "KEY=VALUE", value_parser = parse_key_value)]
+        inputs: Vec<(String, Value)>,
+        /// Lease duration in seconds of the allocated resources
+        #[arg(long, value_name = "SECONDS")]
+        lease: Option<u64>,
+    },
+    /// Print schedules of a pool as JSON lines
+    List {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Delete a schedule
+    Delete {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Name of the schedule
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UniquenessGroupCommand {
+    /// Create an empty group
+    Create {
+        /// Name of the group
+        #[arg(long)]
+        name: String,
+    },
+    /// Print groups and their pools as JSON lines
+    List,
+    /// Add a pool to the group, fails if a value in use by the pool is in use by another pool of the group
+    AddPool {
+        /// Name of the group
+        #[arg(long)]
+        group: String,
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Remove a pool from the group
+    RemovePool {
+        /// Name of the group
+        #[arg(long)]
+        group: String,
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Delete the group, its pools are kept
+    Delete {
+        /// Name of the group
+        #[arg(long)]
+        name: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum SnapshotCommand {
+    /// Copy a pool and its resources in use, prints the snapshot
+    Create {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Description of the snapshot, e.g. `before cleanup`
+        #[arg(long)]
+        label: String,
+    },
+    /// Print snapshots of a pool as JSON lines
+    List {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Print resources of a snapshot as JSON lines
+    Show {
+        #[arg(long)]
+        id: i32,
+    },
+    /// Reset the snapshotted pool to the snapshot, or copy the snapshot into a new pool
+    Restore {
+        #[arg(long)]
+        id: i32,
+        /// Create a new pool instead of retiring resources of the snapshotted pool
+        #[arg(long)]
+        new_pool: Option<String>,
+        /// Do not ask for confirmation when replacing resources
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum JobsCommand {
+    /// Print an allocation job as JSON
+    Status {
+        #[arg(long)]
+        id: i32,
+    },
+    /// Run pending allocation jobs until there are none left
+    Run,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum PoolCommand {
+    /// Create an empty pool
+    Create {
+        /// Name of the new pool
+        #[arg(long)]
+        pool: String,
+        /// Allocation strategy of the new pool
+        #[arg(long)]
+        strategy_id: i64,
+        /// Name of the pool the new pool is nested in
+        #[arg(long)]
+        parent: Option<String>,
+        /// Properties passed to the strategy as JSON, e.g. `{"address":"10.0.0.0","prefix":24}`
+        #[arg(long)]
+        properties: Option<String>,
+        /// Tenant owning the pool, it is created in the shard of the tenant if `RM_DB_ROUTING` routes it
+        #[arg(long)]
+        tenant: Option<String>,
+    },
+    /// Move deallocated resources into the archive table
+    Archive {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Only resources deallocated more than this number of seconds ago
+        #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+        older_than: u64,
+        /// Number of resources moved per transaction
+        #[arg(long, default_value_t = 1000)]
+        batch_size: i64,
+    },
+    /// Print resources added, removed and changed between two states of a pool
+    Diff {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// `current`, `snapshot:<id>` or `version:<version>`
+        #[arg(long)]
+        from: PoolState,
+        #[arg(long, default_value = "current")]
+        to: PoolState,
+        /// Print the diff as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Delete a pool without resources in use
+    Delete {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Do not ask for confirmation
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print a pool with its ancestors, nested pools and their resource counts
+    Tree {
+        /// Name of the root pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Print ranges of free addresses of a pool with a sequential strategy as JSON lines
+    FreeRanges {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Import resources from JSON lines produced by `resources export`
+    Import {
+        /// Name of the target pool
+        #[arg(long)]
+        pool: String,
+        /// Create the pool with this allocation strategy if it does not exist
+        #[arg(long)]
+        strategy_id: Option<i64>,
+        /// Input file, `-` for stdin
+        #[arg(long, default_value = STDIO)]
+        file: String,
+        /// Number of resources inserted per transaction
+        #[arg(long, default_value_t = 1000)]
+        batch_size: usize,
+    },
+    /// Change settings of a pool
+    #[command(group(ArgGroup::new("settings").required(true).multiple(true)
+        .args(["deallocation_safety_period", "properties", "default_input", "script_override",
+            "clear_script_override"])))]
+    Configure {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Seconds a deallocated resource stays in quarantine, 0 deletes it immediately
+        #[arg(long, value_name = "SECONDS")]
+        deallocation_safety_period: Option<i32>,
+        /// Properties passed to the strategy as JSON. Rejected if current resources would not fit
+        #[arg(long)]
+        properties: Option<String>,
+        /// JSON object merged under the input of every allocation, e.g. `{"subnet": true}`. `{}` removes it
+        #[arg(long, value_name = "JSON")]
+        default_input: Option<String>,
+        /// Script file allocating from this pool instead of the script of its strategy, linked with files
+        /// of the strategy. Listed as `scriptOverridden`
+        #[arg(long, value_name = "FILE", conflicts_with = "clear_script_override")]
+        script_override: Option<String>,
+        /// Allocate from the pool with the script of its strategy again
+        #[arg(long)]
+        clear_script_override: bool,
+        /// Tenant owning the pool, used by `pool list --tenant`
+        #[arg(long)]
+        tenant: Option<String>,
+        /// Tag of the pool, replaces all current tags. Can be repeated
+        #[arg(long)]
+        tag: Vec<String>,
+    },
+    /// Print pools as JSON lines, ordered by name
+    List {
+        /// Only pools with names starting with the prefix
+        #[arg(long)]
+        name_prefix: Option<String>,
+        /// Only pools using the allocation strategy
+        #[arg(long)]
+        strategy_id: Option<i64>,
+        #[arg(long)]
+        tag: Option<String>,
+        #[arg(long)]
+        tenant: Option<String>,
+        /// `id` or `name`, `-id` and `-name` sort in descending order
+        #[arg(long, default_value = "name", allow_hyphen_values = true)]
+        sort: PoolSort,
+        #[arg(long, default_value_t = DEFAULT_PAGE_SIZE)]
+        limit: i64,
+        #[arg(long, default_value_t = 0)]
+        offset: i64,
+        /// Include archived pools
+        #[arg(long)]
+        include_archived: bool,
+    },
+    /// Archive a pool: it stays queryable, but it and its resources cannot be changed until it is recommissioned
+    Decommission {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Make an archived pool writable again
+    Recommission {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Remove expired leases and resources past their quarantine period
+    Gc {
+        /// Name of the pool, all pools are collected if not set
+        #[arg(long)]
+        pool: Option<String>,
+        /// Number of resources deleted per transaction
+        #[arg(long, default_value_t = 1000)]
+        batch_size: i64,
+        /// Seconds deallocated resources are kept before they are purged
+        #[arg(long, value_name = "SECONDS", default_value_t = 7 * 24 * 3600)]
+        retention: u64,
+    },
+    /// Delete pools without resources in use and without activity, retired resources are archived first
+    Prune {
+        /// Only pools idle for more than this number of seconds
+        #[arg(long, value_name = "SECONDS", default_value_t = 90 * 24 * 3600)]
+        idle_for: u64,
+        /// Number of resources archived per transaction
+        #[arg(long, default_value_t = 1000)]
+        batch_size: i64,
+        /// Only print idle pools
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Alert when utilization of a pool reaches the threshold, evaluated by the leading worker
+    AddAlert {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Fraction of the capacity in use, e.g. `0.9` for 90%
+        #[arg(long)]
+        threshold: f64,
+    },
+    /// Print alert rules of a pool as JSON lines, all rules if no pool is set
+    Alerts {
+        /// Name of the pool
+        #[arg(long)]
+        pool: Option<String>,
+    },
+    /// Delete an alert rule
+    RemoveAlert {
+        /// Id of the alert rule
+        #[arg(long)]
+        id: i32,
+    },
+    /// Run a script before or after the strategy of every allocation from the pool, replaces the hook
+    /// of the same stage
+    SetHook {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// `pre_allocation` runs `preAllocate()` returning the user input, `post_allocation` runs
+        /// `postAllocate(values)` returning the values to insert
+        #[arg(long)]
+        stage: HookStage,
+        /// Script file
+        #[arg(long)]
+        file: String,
+        /// The hook is killed and fails after this
+        #[arg(long, default_value_t = 1000)]
+        timeout_ms: u64,
+        /// `fail` fails the allocation if the hook fails, `skip` allocates as if it did not exist
+        #[arg(long, default_value = "fail")]
+        on_error: HookErrorPolicy,
+    },
+    /// Print hooks of a pool as JSON lines
+    Hooks {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Delete the hook of a pool at the stage
+    RemoveHook {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        #[arg(long)]
+        stage: HookStage,
+    },
+    /// Print allocations and version conflicts of a pool counted for backpressure as JSON
+    Contention {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Print a pool with its utilization and predicted exhaustion as JSON
+    Describe {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ResourcesCommand {
+    /// Print a resource of any pool as JSON, including its metadata
+    Show {
+        #[arg(long)]
+        id: i64,
+    },
+    /// Change metadata of a resource, its value stays the same
+    UpdateMetadata {
+        #[arg(long)]
+        id: i64,
+        /// JSON merge patch, e.g. `{"owner":"team-a","labels":{"env":null}}` removes the `env` label
+        #[arg(long)]
+        patch: String,
+    },
+    /// Change the owner of a resource in use
+    Transfer {
+        #[arg(long)]
+        id: i64,
+        /// New owner, the resource has no owner if omitted
+        #[arg(long)]
+        owner: Option<String>,
+    },
+    /// Extend the lease of a resource or of all resources of an owner, up to `RM_MAX_LEASE_LIFETIME_SECS`
+    RenewLease {
+        #[arg(long, required_unless_present = "owner", conflicts_with = "owner")]
+        id: Option<i64>,
+        /// Renew unexpired leases of all resources in use of the owner
+        #[arg(long)]
+        owner: Option<String>,
+        /// Seconds added to the current expiration
+        #[arg(long, value_name = "SECONDS")]
+        extend_by: u64,
+    },
+    /// Print resources in use of an owner across all pools, as JSON lines
+    Owned {
+        #[arg(long)]
+        owner: String,
+        /// Maximum number of printed resources
+        #[arg(long, default_value_t = DEFAULT_PAGE_SIZE)]
+        limit: i64,
+    },
+    /// Print resources of a pool as JSON lines
+    List {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Include deallocated resources that were not purged yet
+        #[arg(long)]
+        include_deleted: bool,
+        /// Only resources allocated after the time, e.g. `2024-05-01T00:00:00Z`
+        #[arg(long, conflicts_with = "cidr")]
+        allocated_after: Option<DateTime<Utc>>,
+        /// Only resources allocated before the time
+        #[arg(long, conflicts_with = "cidr")]
+        allocated_before: Option<DateTime<Utc>>,
+        /// Only resources with an address within the CIDR, e.g. `10.0.1.0/24`
+        #[arg(long, conflicts_with = "include_deleted")]
+        cidr: Option<String>,
+    },
+    /// Print resources in use whose metadata match, as JSON lines
+    Search {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// JSON the metadata contain, e.g. `{"labels":{"service":"voip"}}`
+        #[arg(long, required_unless_present = "path", conflicts_with = "path")]
+        contains: Option<String>,
+        /// jsonpath predicate on the metadata, e.g. `$.labels.service == "voip"`
+        #[arg(long)]
+        path: Option<String>,
+        /// Maximum number of printed resources
+        #[arg(long, default_value_t = DEFAULT_PAGE_SIZE)]
+        limit: i64,
+    },
+    /// Print archived resources of a pool as JSON lines
+    Archived {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+    },
+    /// Undo deallocation of a resource that was not purged yet
+    Restore {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Id of the resource
+        #[arg(long)]
+        id: i64,
+    },
+    /// Move a resource to another lifecycle state
+    SetState {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Id of the resource
+        #[arg(long)]
+        id: i64,
+        #[arg(value_enum)]
+        state: ResourceState,
+    },
+    /// Write all resources of a pool as JSON lines
+    Export {
+        /// Name of the pool
+        #[arg(long)]
+        pool: String,
+        /// Output file, `-` for stdout
+        #[arg(long, default_value = STDIO)]
+        output: String,
+        /// Number of resources fetched per round trip
+        #[arg(long, default_value_t = 1000)]
+        batch_size: i32,
+    },
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum CompletionTarget {
+    Bash,
+    Zsh,
+    Fish,
+    Man,
+}
+
+impl Cli {
+    pub fn run(self) -> Result<()> {
+        match self.command {
+            Command::Completions { target } => write_completions(target, &mut io::stdout()),
+            Command::Allocate {
+                pool, count, inputs, lease, dry_run, reserve, owner, description, enqueue, timings, batch_size,
+            } => {
+                let options = AllocationOptions {
+                    lease: lease.map(Duration::from_secs), dry_run, reserve, owner, description,
+                };
+                let mut db = DB::new_for_pool(&pool)?;
+                if enqueue {
+                    let pool = db.get_resource_pool_by_name(&pool)?;
+                    let job_id = db.enqueue_allocation(pool.id, user_input(count, inputs), &options)?;
+                    println!("{}", db.get_job_status(job_id)?.as_json());
+                    return Ok(());
+                }
+                allocate(&mut db, &pool, user_input(count, inputs), &options, timings, batch_size)
+            }
+            Command::Deallocate { pool, id, value, state, force, batch_size } =>
+                deallocate(&mut DB::new_for_pool(&pool)?, &pool, id, value, state, force, batch_size),
+            Command::Pool { command: PoolCommand::Create { pool, strategy_id, parent, properties, tenant } } => {
+                let mut db = DbRouter::from_env()?.into_new_pool_db(&pool, tenant.as_deref())?;
+                let parent_id = parent.map(|parent| db.get_resource_pool_by_name(&parent))
+                    .transpose()?.map(|parent| parent.id);
+                let properties = properties.map(|properties| serde_json::from_str(&properties)
+                    .context(format!("Properties '{}' are not a valid JSON", properties))).transpose()?;
+                let pool = db.insert_resource_pool_with_properties(&pool, strategy_id, parent_id, properties)?;
+                if tenant.is_some() {
+                    db.set_pool_labels(pool, tenant, vec![])?;
+                }
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::Archive { pool, older_than, batch_size } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let archived = db.archive_pool_resources(pool.id, Duration::from_secs(older_than), batch_size)?;
+                println!("{}: {} archived", pool.name, archived);
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::Diff { pool, from, to, json } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let diff = db.diff_pool(pool.id, from, to)?;
+                if json {
+                    println!("{}", diff.as_json());
+                } else {
+                    print_pool_diff(&diff, from, to);
+                }
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::Delete { pool, force } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                if !force && !confirm(&format!("Delete pool '{}'?", pool.name))? {
+                    bail!("Deletion cancelled");
+                }
+                db.delete_resource_pool(pool)
+            }
+            Command::Pool { command: PoolCommand::Tree { pool } } =>
+                print_pool_tree(&mut DB::new_for_pool(&pool)?, &pool),
+            Command::Pool { command: PoolCommand::FreeRanges { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let ranges = db.get_free_ranges(pool.id)?;
+                print_json_lines(&ranges.iter().map(|range| range.as_json()).collect::<Vec<_>>())
+            }
+            Command::Pool { command: PoolCommand::Import { pool, strategy_id, file, batch_size } } =>
+                import_pool(&mut DbRouter::from_env()?.into_new_pool_db(&pool, None)?, &pool, strategy_id, &file,
+                            batch_size),
+            Command::Pool { command: PoolCommand::Configure {
+                pool, deallocation_safety_period, properties, default_input, script_override, clear_script_override,
+                tenant, tag,
+            } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let mut pool = db.get_resource_pool_by_name(&pool)?;
+                if let Some(deallocation_safety_period) = deallocation_safety_period {
+                    pool = db.set_deallocation_safety_period(pool, deallocation_safety_period)?;
+                }
+                if let Some(default_input) = default_input {
+                    let default_input = serde_json::from_str(&default_input)
+                        .context(format!("Default input '{}' is not a valid JSON", default_input))?;
+                    pool = db.set_default_user_input(pool, default_input)?;
+                }
+                if let Some(file) = script_override {
+                    let script = std::fs::read_to_string(&file).context(format!("Cannot read '{}'", file))?;
+                    pool = db.set_script_override(pool, Some(&script))?;
+                } else if clear_script_override {
+                    pool = db.set_script_override(pool, None)?;
+                }
+                if tenant.is_some() || !tag.is_empty() {
+                    let tenant = tenant.or_else(|| pool.tenant.clone());
+                    let tags = if tag.is_empty() { pool.tags.clone() } else { tag };
+                    pool = db.set_pool_labels(pool, tenant, tags)?;
+                }
+                if let Some(properties) = properties {
+                    update_pool_properties(&mut db, pool, &properties)?;
+                }
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::List {
+                name_prefix, strategy_id, tag, tenant, sort, limit, offset, include_archived,
+            } } => {
+                let filter = PoolFilter {
+                    name_prefix, allocation_strategy_id: strategy_id, tag, tenant, sort, after: None, limit, offset,
+                    include_archived,
+                };
+                let mut db = match &filter.tenant {
+                    Some(tenant) => DbRouter::from_env()?.into_tenant_db(tenant)?,
+                    None => DB::new_from_env()?,
+                };
+                let pools = db.list_pools(&filter)?;
+                print_json_lines(&pools.iter().map(|pool| pool.as_export_json()).collect::<Vec<_>>())
+            }
+            Command::Pool { command: PoolCommand::Decommission { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                db.archive_pool(pool)?;
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::Recommission { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                db.unarchive_pool(pool)?;
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::Gc { pool, batch_size, retention } } =>
+                gc(&mut DB::new_from_env()?, pool, batch_size, Duration::from_secs(retention)),
+            Command::Pool { command: PoolCommand::Prune { idle_for, batch_size, dry_run } } =>
+                prune(&mut DB::new_from_env()?, Duration::from_secs(idle_for), batch_size, dry_run),
+            Command::Pool { command: PoolCommand::AddAlert { pool, threshold } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                println!("{}", db.add_alert_rule(pool.id, threshold)?.as_json());
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::Alerts { pool } } => {
+                let mut db = match &pool {
+                    Some(pool) => DB::new_for_pool(pool)?,
+                    None => DB::new_from_env()?,
+                };
+                let pool_id = pool.map(|pool| db.get_resource_pool_by_name(&pool)).transpose()?.map(|pool| pool.id);
+                let rules = db.get_alert_rules(pool_id)?;
+                print_json_lines(&rules.iter().map(|rule| rule.as_json()).collect::<Vec<_>>())
+            }
+            Command::Pool { command: PoolCommand::RemoveAlert { id } } => DB::new_from_env()?.remove_alert_rule(id),
+            Command::Pool { command: PoolCommand::SetHook { pool, stage, file, timeout_ms, on_error } } => {
+                let script = std::fs::read_to_string(&file).context(format!("Cannot read '{}'", file))?;
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let timeout = Duration::from_millis(timeout_ms);
+                db.set_pool_hook(pool.id, &PoolHook { stage, script, timeout, on_error })
+            }
+            Command::Pool { command: PoolCommand::Hooks { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let hooks = db.get_pool_hooks(pool.id)?;
+                print_json_lines(&hooks.iter().map(|hook| hook.as_json()).collect::<Vec<_>>())
+            }
+            Command::Pool { command: PoolCommand::RemoveHook { pool, stage } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                db.remove_pool_hook(pool.id, stage)
+            }
+            Command::Pool { command: PoolCommand::Contention { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                match db.get_pool_contention(pool.id)? {
+                    Some(contention) => println!("{}", contention.as_json()),
+                    None => println!("{}", json!({"pool": pool.id, "allocations": 0, "conflicts": 0})),
+                }
+                Ok(())
+            }
+            Command::Pool { command: PoolCommand::Describe { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let mut wasmer_env = WasmerEnv::new()?;
+                let mut description = pool.as_export_json();
+                description["utilization"] = json!(db.pool_utilization(&pool, &mut wasmer_env)?);
+                description["predictedExhaustion"] = json!(db.predict_exhaustion(pool.id, &mut wasmer_env)?
+                    .map(|exhaustion| DateTime::<Utc>::from(exhaustion).to_rfc3339()));
+                println!("{}", description);
+                Ok(())
+            }
+            Command::Resources { command: ResourcesCommand::List {
+                pool, include_deleted, allocated_after, allocated_before, cidr,
+            } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let resources = match cidr {
+                    Some(cidr) => db.find_resources_in_cidr(pool.id, &cidr)?,
+                    None => db.get_resources_filtered(pool.id, &ResourceFilter {
+                        include_deleted,
+                        allocated_after: allocated_after.map(SystemTime::from),
+                        allocated_before: allocated_before.map(SystemTime::from),
+                    })?,
+                };
+                print_resources(&resources)
+            }
+            Command::Resources { command: ResourcesCommand::Search { pool, contains, path, limit } } => {
+                let query = match (contains, path) {
+                    (Some(contains), _) => MetadataQuery::Contains(serde_json::from_str(&contains)
+                        .context(format!("'{}' is not a valid JSON", contains))?),
+                    (None, Some(path)) => MetadataQuery::Path(path),
+                    (None, None) => bail!("Either --contains or --path is required"),
+                };
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                print_json_lines(&db.search_resources(pool.id, &query, None, limit)?.iter()
+                    .map(Resource::as_detail_json).collect::<Vec<_>>())
+            }
+            Command::Resources { command: ResourcesCommand::Archived { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                print_json_lines(&db.get_archived_resources(pool.id)?)
+            }
+            Command::Resources { command: ResourcesCommand::Show { id } } =>
+                print_json_lines(&[DB::new_from_env()?.get_resource(id)?.as_detail_json()]),
+            Command::Resources { command: ResourcesCommand::UpdateMetadata { id, patch } } => {
+                let patch = serde_json::from_str(&patch).context(format!("Patch '{}' is not a valid JSON", patch))?;
+                print_json_lines(&[DB::new_from_env()?.update_resource_metadata(id, &patch)?.as_detail_json()])
+            }
+            Command::Resources { command: ResourcesCommand::Transfer { id, owner } } =>
+                print_json_lines(&[DB::new_from_env()?.transfer_resource(id, owner.as_deref())?.as_detail_json()]),
+            Command::Resources { command: ResourcesCommand::RenewLease { id, owner, extend_by } } => {
+                let mut db = DB::new_from_env()?;
+                let extend_by = Duration::from_secs(extend_by);
+                let renewed = match (id, owner) {
+                    (Some(id), _) => vec![db.renew_lease(id, extend_by)?],
+                    (None, Some(owner)) => db.renew_leases_by_owner(&owner, extend_by)?,
+                    (None, None) => unreachable!("clap requires id or owner"),
+                };
+                print_json_lines(&renewed.iter().map(Resource::as_detail_json).collect::<Vec<_>>())
+            }
+            Command::Resources { command: ResourcesCommand::Owned { owner, limit } } =>
+                print_json_lines(&DB::new_from_env()?.find_owned_resources(&owner, None, limit)?.iter()
+                    .map(Resource::as_detail_json).collect::<Vec<_>>()),
+            Command::Resources { command: ResourcesCommand::Restore { pool, id } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let (_pool, resource) = db.restore_resource(pool, id)?;
+                print_resources(&[resource])
+            }
+            Command::Resources { command: ResourcesCommand::SetState { pool, id, state } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let (_pool, resource) = db.transition_resource(pool, &ResourceSelector::Id(id), state)?;
+                print_resources(&[resource])
+            }
+            Command::Resources { command: ResourcesCommand::Export { pool, output, batch_size } } =>
+                export_resources(&mut DB::new_for_pool(&pool)?, &pool, &output, batch_size),
+            Command::Snapshot { command: SnapshotCommand::Create { pool, label } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                println!("{}", db.snapshot_pool(pool.id, &label)?.as_json());
+                Ok(())
+            }
+            Command::Snapshot { command: SnapshotCommand::List { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                for snapshot in db.list_snapshots(pool.id)? {
+                    println!("{}", snapshot.as_json());
+                }
+                Ok(())
+            }
+            Command::Snapshot { command: SnapshotCommand::Show { id } } =>
+                print_json_lines(&DB::new_from_env()?.get_snapshot_resources(id)?),
+            Command::Snapshot { command: SnapshotCommand::Restore { id, new_pool, force } } => {
+                let mut db = DB::new_from_env()?;
+                let mode = match new_pool {
+                    Some(name) => RestoreMode::NewPool(name),
+                    None => RestoreMode::Replace,
+                };
+                if mode == RestoreMode::Replace && !force &&
+                    !confirm(&format!("Retire all resources in use and restore snapshot {}?", id))? {
+                    bail!("Restore cancelled");
+                }
+                let pool = db.restore_snapshot(id, &mode)?;
+                println!("{}", pool.as_export_json());
+                Ok(())
+            }
+            Command::Jobs { command: JobsCommand::Status { id } } => {
+                println!("{}", DB::new_from_env()?.get_job_status(id)?.as_json());
+                Ok(())
+            }
+            Command::Schedule { command: ScheduleCommand::Create { pool, name, cron, count, inputs, lease } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let schedule = db.create_schedule(&pool, &name, &cron, user_input(count, inputs),
+                                                  lease.map(Duration::from_secs))?;
+                println!("{}", schedule.as_json());
+                Ok(())
+            }
+            Command::Schedule { command: ScheduleCommand::List { pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                for schedule in db.get_schedules(pool.id)? {
+                    println!("{}", schedule.as_json());
+                }
+                Ok(())
+            }
+            Command::Schedule { command: ScheduleCommand::Delete { pool, name } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                db.delete_schedule(&pool, &name)
+            }
+            Command::UniquenessGroup { command: UniquenessGroupCommand::Create { name } } => {
+                println!("{}", DB::new_from_env()?.create_uniqueness_group(&name)?.as_json());
+                Ok(())
+            }
+            Command::UniquenessGroup { command: UniquenessGroupCommand::List } => {
+                let groups = DB::new_from_env()?.get_uniqueness_groups()?;
+                print_json_lines(&groups.iter().map(|group| group.as_json()).collect::<Vec<_>>())
+            }
+            Command::UniquenessGroup { command: UniquenessGroupCommand::AddPool { group, pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let group = db.get_uniqueness_group(&group)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                db.add_pool_to_uniqueness_group(&group, &pool)
+            }
+            Command::UniquenessGroup { command: UniquenessGroupCommand::RemovePool { group, pool } } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let group = db.get_uniqueness_group(&group)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                db.remove_pool_from_uniqueness_group(&group, &pool)
+            }
+            Command::UniquenessGroup { command: UniquenessGroupCommand::Delete { name } } =>
+                DB::new_from_env()?.delete_uniqueness_group(&name),
+            Command::Audit { pool, limit } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                for entry in db.get_audit_log(pool.id, limit)? {
+                    println!("{}", entry.as_json());
+                }
+                Ok(())
+            }
+            Command::Replay { pool, into, dry_run } => {
+                let mut db = DB::new_for_pool(&pool)?;
+                let pool = db.get_resource_pool_by_name(&pool)?;
+                let mut wasmer_env = WasmerEnv::new()?;
+                let mut replay = |db: &mut DB| db.replay_pool(&pool, &into, &mut wasmer_env);
+                let report = if dry_run { db.dry_run(replay)? } else { replay(&mut db)? };
+                println!("{}", report.as_json());
+                ensure!(report.mismatches.is_empty(), "Replay of {} differs in {} events", pool.name,
+                        report.mismatches.len());
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::Create { name, file, kind, include } } => {
+                let read = |file: &str| std::fs::read_to_string(file).context(format!("Cannot read '{}'", file));
+                let script = read(&file)?;
+                let mut files = StrategyFiles::new();
+                for file in &include {
+                    let path = Path::new(file).file_name().and_then(|it| it.to_str())
+                        .ok_or_else(|| anyhow!("Invalid file name '{}'", file))?;
+                    ensure!(files.insert(path.to_owned(), read(file)?).is_none(), "Duplicate file name '{}'", path);
+                }
+                let mut db = DB::new_from_env()?;
+                let id = if file.ends_with(".ts") || file.ends_with(".mts") {
+                    db.insert_typescript_strategy(&name, &script, kind, &files, &TypeScriptCompiler::new()?)?
+                } else {
+                    if kind.unwrap_or_else(|| ScriptKind::detect(&script)) == ScriptKind::Module {
+                        // fail early on invalid imports and exports
+                        db.module_to_script(&name, &script, &files)?;
+                    }
+                    db.insert_allocation_strategy(&name, &script, kind, &files)?
+                };
+                println!("{}", id);
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::List } => {
+                let strategies = DB::new_from_env()?.list_strategies(None, None)?;
+                print_json_lines(&strategies.iter().map(|strategy| strategy.as_json()).collect::<Vec<_>>())
+            }
+            Command::Strategy { command: StrategyCommand::Show { id } } => {
+                print_strategy(&DB::new_from_env()?.get_strategy(id)?);
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::SetDocs {
+                id, description, pool_properties, user_input,
+            } } => {
+                DB::new_from_env()?.set_strategy_docs(id, &StrategyDocs {
+                    description,
+                    expected_pool_properties: pool_properties,
+                    expected_user_input: user_input,
+                })?;
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::SetEngine { id, engine } } => {
+                DB::new_from_env()?.set_strategy_engine(id, engine)?;
+                println!("Strategy {} uses engine {}", id, engine);
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::SetInputSchema { id, schema } } => {
+                let schema = schema.map(|schema| serde_json::from_str(&schema)
+                    .context(format!("Schema '{}' is not a valid JSON", schema))
+                    .and_then(InputSchema::from_json)).transpose()?;
+                DB::new_from_env()?.set_input_schema(id, schema.as_ref())?;
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::SetPropertiesSchema { id, schema } } => {
+                let schema = schema.map(|schema| serde_json::from_str(&schema)
+                    .context(format!("Schema '{}' is not a valid JSON", schema))
+                    .and_then(|schema| PropertiesSchema::from_json(&schema))).transpose()?;
+                DB::new_from_env()?.set_properties_schema(id, schema.as_ref())?;
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::SetContextQueries { id, queries } } => {
+                let queries = queries.map(|queries| serde_json::from_str(&queries)
+                    .context(format!("Queries '{}' are not a valid JSON", queries))
+                    .and_then(ContextQueries::from_json)).transpose()?;
+                DB::new_from_env()?.set_context_queries(id, queries.as_ref())?;
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::AddTest {
+                id, name, input, properties, resources, expected,
+            } } => {
+                let parse = |what: &str, json: &str| serde_json::from_str::<Value>(json)
+                    .context(format!("{} '{}' is not a valid JSON", what, json));
+                let test = StrategyTest {
+                    name,
+                    user_input: parse("Input", &input)?,
+                    pool_properties: parse("Properties", &properties)?,
+                    current_resources: serde_json::from_value(parse("Resources", &resources)?)
+                        .context("Resources must be a JSON array")?,
+                    expected: serde_json::from_value(parse("Expected", &expected)?)
+                        .context("Expected must be a JSON array")?,
+                };
+                DB::new_from_env()?.put_strategy_test(id, &test)
+            }
+            Command::Strategy { command: StrategyCommand::ListTests { id } } => {
+                let tests = DB::new_from_env()?.get_strategy_tests(id)?;
+                print_json_lines(&tests.iter().map(|test| test.as_json()).collect::<Vec<_>>())
+            }
+            Command::Strategy { command: StrategyCommand::RemoveTest { id, name } } =>
+                DB::new_from_env()?.delete_strategy_test(id, &name),
+            Command::Strategy { command: StrategyCommand::Test { id } } => {
+                let results = DB::new_from_env()?.run_strategy_tests(id, &mut WasmerEnv::new()?)?;
+                print_json_lines(&results.iter().map(|result| result.as_json()).collect::<Vec<_>>())?;
+                let failed = results.iter().filter(|result| !result.passed).count();
+                ensure!(failed == 0, "{} of {} tests of strategy {} failed", failed, results.len(), id);
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::Export { path, name } } => {
+                let bundles = DB::new_from_env()?.export_strategies(&name)?;
+                bundle::write_bundle(Path::new(&path), &bundles)?;
+                eprintln!("Exported {} strategies to '{}'", bundles.len(), path);
+                Ok(())
+            }
+            Command::Strategy { command: StrategyCommand::Import { path } } => {
+                let bundles = bundle::read_bundle(Path::new(&path))?;
+                let imports = DB::new_from_env()?.import_strategies(&bundles)?;
+                print_json_lines(&imports.iter().map(|import| import.as_json()).collect::<Vec<_>>())
+            }
+            Command::Backup { output } => {
+                let mut db = DB::new_from_env()?;
+                let counts = if output == STDIO {
+                    db.backup(io::stdout().lock())?
+                } else {
+                    db.backup(BufWriter::new(File::create(&output).context(format!("Cannot create '{}'", output))?))?
+                };
+                eprintln!("{}", counts.as_json());
+                Ok(())
+            }
+            Command::Restore { file } => {
+                let mut db = DB::new_from_env()?;
+                let counts = if file == STDIO {
+                    db.restore(io::stdin().lock())?
+                } else {
+                    db.restore(File::open(&file).context(format!("Cannot open '{}'", file))?)?
+                };
+                println!("{}", counts.as_json());
+                Ok(())
+            }
+            Command::Import { from_rm } => {
+                let mut upstream = ConnectRetry::default().connect(&from_rm)
+                    .context("Cannot connect to resource-manager")?;
+                let report = DB::new_from_env()?.import_from_resource_manager(&mut upstream)?;
+                println!("{}", report.as_json());
+                Ok(())
+            }
+            Command::Db { command: DbCommand::Init } => {
+                for migration in DB::new_from_env()?.init_schema()? {
+                    println!("Applied {}", migration);
+                }
+                Ok(())
+            }
+            Command::Db { command: DbCommand::Verify } => verify_schema(&mut DB::new_from_env()?),
+            Command::Lab { sqlite, command } => lab(storage::open(sqlite.as_deref())?.as_mut(), command),
+            Command::Serve { listen, grpc_listen, threads, drain_timeout } => {
+                verify_schema(&mut DB::new_from_env()?)?;
+                let server = Server::bind(&listen)?;
+                let grpc = grpc_listen.map(|grpc_listen| GrpcServer::bind(&grpc_listen)).transpose()?;
+                let shutdown = Shutdown::on_signals()?;
+                shutdown.exit_after(Duration::from_secs(drain_timeout))?;
+                let grpc = grpc.map(|grpc| thread::spawn(move || grpc.run(shutdown)));
+                server.run(threads, shutdown)?;
+                match grpc {
+                    Some(grpc) => grpc.join().map_err(|_| anyhow!("gRPC server panicked"))?,
+                    None => Ok(()),
+                }
+            }
+            Command::Worker { worker, metrics_address, drain_timeout } => {
+                if let Some(metrics_address) = metrics_address {
+                    http::serve_metrics(&metrics_address)?;
+                }
+                let mut db = DB::new_from_env()?;
+                verify_schema(&mut db)?;
+                let shutdown = Shutdown::on_signals()?;
+                shutdown.exit_after(Duration::from_secs(drain_timeout))?;
+                let config = WorkerConfig { drain_timeout: Duration::from_secs(drain_timeout), ..worker.config() };
+                Worker::new(db, WasmerEnv::new()?, config).run(shutdown)
+            }
+            Command::Daemon { listen, threads, worker, drain_timeout } => {
+                verify_schema(&mut DB::new_from_env()?)?;
+                let shutdown = Shutdown::on_signals()?;
+                shutdown.exit_after(Duration::from_secs(drain_timeout))?;
+                let mut supervisor = Supervisor::new(shutdown);
+                let health = supervisor.health();
+                supervisor.spawn("http", move || {
+                    Server::bind(&listen)?.with_health(health.clone()).run(threads, shutdown)
+                })?;
+                let config = WorkerConfig { drain_timeout: Duration::from_secs(drain_timeout), ..worker.config() };
+                supervisor.spawn("worker", move || {
+                    Worker::new(DB::new_from_env()?, WasmerEnv::new()?, config.clone()).run(shutdown)
+                })?;
+                supervisor.join()
+            }
+            Command::Jobs { command: JobsCommand::Run } => {
+                let mut db = DB::new_from_env()?;
+                let mut wasmer_env = WasmerEnv::new()?;
+                while let Some(job) = db.run_next_allocation_job(&mut wasmer_env)? {
+                    println!("{}", job.as_json());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn write_completions(target: CompletionTarget, out: &mut dyn Write) -> Result<()> {
+    let mut command = Cli::command();
+    let shell = match target {
+        CompletionTarget::Bash => Shell::Bash,
+        CompletionTarget::Zsh => Shell::Zsh,
+        CompletionTarget::Fish => Shell::Fish,
+        CompletionTarget::Man => {
+            clap_mangen::Man::new(command).render(out)?;
+            return Ok(());
+        }
+    };
+    let bin_name = command.get_name().to_owned();
+    clap_complete::generate(shell, &mut command, bin_name, out);
+    Ok(())
+}
+
+fn parse_key_value(arg: &str) -> Result<(String, Value), String> {
+    let (key, value) = arg.split_once('=')
+        .ok_or_else(|| format!("Expected KEY=VALUE, got '{}'", arg))?;
+    if key.is_empty() {
+        return Err(format!("Empty key in '{}'", arg));
+    }
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_owned()));
+    Ok((key.to_owned(), value))
+}
+
+fn user_input(count: Option<u32>, inputs: Vec<(String, Value)>) -> Value {
+    let mut user_input = inputs.into_iter().collect::<Map<String, Value>>();
+    if let Some(count) = count {
+        user_input.insert("resourceCount".to_owned(), count.into());
+    }
+    Value::Object(user_input)
+}
+
+// Allocates `resourceCount` resources by `batch_size` per allocation, each in its own transaction, or all at once.
+fn allocate(db: &mut DB, pool_name: &str, user_input: Value, options: &AllocationOptions, print_timings: bool,
+            batch_size: Option<u32>) -> Result<()> {
+    ensure!(batch_size != Some(0), "Batch size must be positive");
+    let mut pool = db.get_resource_pool_by_name(pool_name)?;
+    let mut wasmer_env = WasmerEnv::new()?;
+    let count = user_input["resourceCount"].as_u64();
+    let mut progress = Progress::new(&format!("Allocating from pool '{}'", pool_name), count);
+    let mut out = BufWriter::new(io::stdout());
+    let mut remaining = count.unwrap_or(1);
+    while remaining > 0 {
+        let batch = batch_size.map_or(remaining, |batch_size| remaining.min(batch_size as u64));
+        let mut user_input = user_input.clone();
+        if count.is_some() {
+            user_input["resourceCount"] = batch.into();
+        }
+        remaining -= batch;
+        match db.allocate_or_enqueue(pool.clone(), &mut wasmer_env, user_input, options)? {
+            Allocation::Allocated(allocated, resources, timings) => {
+                if print_timings {
+                    eprintln!("{}", timings.as_json());
+                }
+                for resource in &resources {
+                    serde_json::to_writer(&mut out, &resource.value)?;
+                    out.write_all(b"\n")?;
+                }
+                progress.inc(resources.len() as u64);
+                pool = *allocated;
+            }
+            Allocation::Enqueued(job_id) => {
+                eprintln!("Pool '{}' is under backpressure, the allocation was enqueued", pool_name);
+                out.flush()?;
+                println!("{}", db.get_job_status(job_id)?.as_json());
+            }
+        }
+    }
+    out.flush()?;
+    progress.finish();
+    Ok(())
+}
+
+fn deallocate(db: &mut DB, pool_name: &str, ids: Vec<i64>, value: Option<String>,
+              state: Option<ResourceState>, force: bool, batch_size: Option<usize>) -> Result<()> {
+    let pool = db.get_resource_pool_by_name(pool_name)?;
+    let bulk_selector = match (ids.len(), state) {
+        (_, Some(state)) => Some(BulkSelector::State(state)),
+        (0, None) | (1, None) => None,
+        (_, None) => Some(BulkSelector::Ids(ids.clone())),
+    };
+    if let Some(bulk_selector) = bulk_selector {
+        let description = match &bulk_selector {
+            BulkSelector::Ids(ids) => format!("{} resources", ids.len()),
+            BulkSelector::State(state) => format!("all {} resources", state),
+        };
+        if !force && !confirm(&format!("Deallocate {} from pool '{}'?", description, pool.name))? {
+            bail!("Deallocation cancelled");
+        }
+        let resources = deallocate_in_batches(db, pool, bulk_selector, batch_size)?;
+        return print_resources(&resources);
+    }
+    let selector = match (ids.first(), value) {
+        (Some(id), _) => ResourceSelector::Id(*id),
+        (None, Some(value)) => ResourceSelector::Value(Resource::new_from_str(pool.id, &value)
+            .context(format!("Value '{}' is not a valid JSON", value))?.value),
+        (None, None) => bail!("Either --id, --value or --state must be set"),
+    };
+    if !force && !confirm(&format!("Deallocate resource {} from pool '{}'?", selector, pool.name))? {
+        bail!("Deallocation cancelled");
+    }
+    let deallocated = if force {
+        db.force_deallocate_resource(pool, &selector)
+    } else {
+        db.deallocate_resource(pool, &selector)
+    };
+    let (_pool, resource) = match deallocated {
+        Err(err) if matches!(err.downcast_ref(), Some(AllocationError::ResourceClaimed { .. })) =>
+            bail!("{}, use --force to deallocate it anyway", err),
+        deallocated => deallocated?,
+    };
+    print_resources(&[resource])
+}
+
+// Deallocates resources by `batch_size` per transaction, or all at once. Resources of a state are those
+// in the state before the first batch.
+fn deallocate_in_batches(db: &mut DB, mut pool: ResourcePool, selector: BulkSelector, batch_size: Option<usize>)
+                         -> Result<Vec<Resource>> {
+    ensure!(batch_size != Some(0), "Batch size must be positive");
+    let label = format!("Deallocating from pool '{}'", pool.name);
+    let ids = match (selector, batch_size) {
+        (BulkSelector::Ids(ids), _) => ids,
+        (BulkSelector::State(state), Some(batch_size)) => {
+            let mut ids = vec![];
+            db.stream_resources(pool.id, batch_size.min(i32::MAX as usize) as i32, |resources| {
+                ids.extend(resources.iter().filter(|it| it.state == state).filter_map(|it| it.id));
+                Ok(())
+            })?;
+            ids
+        }
+        (selector, None) => {
+            let mut progress = Progress::new(&label, None);
+            let (_pool, resources) = db.deallocate_resources(pool, &selector)?;
+            progress.inc(resources.len() as u64);
+            progress.finish();
+            return Ok(resources);
+        }
+    };
+    let mut progress = Progress::new(&label, Some(ids.len() as u64));
+    let mut deallocated = Vec::with_capacity(ids.len());
+    for batch in ids.chunks(batch_size.unwrap_or(ids.len()).max(1)) {
+        let (updated, resources) = db.deallocate_resources(pool, &BulkSelector::Ids(batch.to_vec()))?;
+        progress.inc(resources.len() as u64);
+        deallocated.extend(resources);
+        pool = updated;
+    }
+    progress.finish();
+    Ok(deallocated)
+}
+
+fn lab(storage: &mut dyn Storage, command: LabCommand) -> Result<()> {
+    match command {
+        LabCommand::CreateStrategy { name, file } => {
+            let script = std::fs::read_to_string(&file).context(format!("Cannot read '{}'", file))?;
+            println!("{}", storage.insert_allocation_strategy(&name, &script)?);
+            Ok(())
+        }
+        LabCommand::CreatePool { pool, strategy_id, properties } => {
+            let properties = properties.map(|properties| serde_json::from_str(&properties)
+                .context(format!("Properties '{}' are not a valid JSON", properties))).transpose()?;
+            storage.insert_resource_pool_with_properties(&pool, strategy_id, properties)?;
+            Ok(())
+        }
+        LabCommand::DeletePool { pool } => {
+            let pool = storage.get_resource_pool_by_name(&pool)?;
+            storage.delete_resource_pool(pool)
+        }
+        LabCommand::Allocate { pool, count, inputs } => {
+            let pool = storage.get_resource_pool_by_name(&pool)?;
+            let (_, resources) = storage.allocate_resources(pool, &mut WasmerEnv::new()?, user_input(count, inputs),
+                                                            &AllocationOptions::default())?;
+            print_json_lines(&resources.into_iter().map(|resource| resource.value).collect::<Vec<_>>())
+        }
+        LabCommand::Deallocate { pool, id, value } => {
+            let pool = storage.get_resource_pool_by_name(&pool)?;
+            let selector = match (id, value) {
+                (Some(id), _) => ResourceSelector::Id(id),
+                (None, Some(value)) => ResourceSelector::Value(serde_json::from_str(&value)
+                    .context(format!("Value '{}' is not a valid JSON", value))?),
+                (None, None) => bail!("Either --id or --value must be set"),
+            };
+            let (_, resource) = storage.deallocate_resource(pool, &selector)?;
+            print_resources(std::slice::from_ref(&resource))
+        }
+        LabCommand::Resources { pool } => {
+            let pool = storage.get_resource_pool_by_name(&pool)?;
+            print_resources(&storage.get_resources(pool.id)?)
+        }
+    }
+}
+
+fn print_resources(resources: &[Resource]) -> Result<()> {
+    print_json_lines(&resources.iter().map(|it| it.as_export_json()).collect::<Vec<_>>())
+}
+
+fn print_json_lines(values: &[Value]) -> Result<()> {
+    let mut out = BufWriter::new(io::stdout());
+    for value in values {
+        serde_json::to_writer(&mut out, value)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn gc(db: &mut DB, pool_name: Option<String>, batch_size: i64, retention: Duration) -> Result<()> {
+    let pools = match pool_name {
+        Some(pool_name) => vec![db.get_resource_pool_by_name(&pool_name)?],
+        None => db.find_pools_to_gc(retention)?.into_iter()
+            .map(|id| db.get_resource_pool_by_id(id))
+            .collect::<Result<Vec<ResourcePool>>>()?,
+    };
+    for pool in pools {
+        let (pool, report) = db.gc_pool(pool, batch_size, retention)?;
+        println!("{}: {} expired leases, {} released from quarantine, {} purged",
+                 pool.name, report.expired_leases, report.released_from_quarantine, report.purged);
+    }
+    Ok(())
+}
+
+fn prune(db: &mut DB, idle_for: Duration, batch_size: i64, dry_run: bool) -> Result<()> {
+    for pool in db.find_idle_pools(SystemTime::now() - idle_for)? {
+        if dry_run {
+            println!("{}: idle", pool.name);
+            continue;
+        }
+        let name = pool.name.clone();
+        let archived = db.prune_pool(pool, batch_size)?;
+        println!("{}: deleted, {} archived", name, archived);
+    }
+    Ok(())
+}
+
+fn update_pool_properties(db: &mut DB, pool: ResourcePool, properties: &str) -> Result<ResourcePool> {
+    let properties = serde_json::from_str(properties)
+        .context(format!("Properties '{}' are not a valid JSON", properties))?;
+    db.update_pool_properties(pool, &mut WasmerEnv::new()?, properties)
+}
+
+fn verify_schema(db: &mut DB) -> Result<()> {
+    let drift = db.verify_schema()?;
+    for difference in &drift {
+        eprintln!("{}", difference);
+    }
+    if !drift.is_empty() {
+        bail!("Schema differs from the migrations in {} places, see `db verify`", drift.len());
+    }
+    Ok(())
+}
+
+fn print_pool_diff(diff: &PoolDiff, from: PoolState, to: PoolState) {
+    if diff.is_empty() {
+        println!("No differences between {} and {}", from, to);
+        return;
+    }
+    println!("--- {}\n+++ {}", from, to);
+    for removed in &diff.removed {
+        println!("- {} {}", removed["value"], removed["state"].as_str().unwrap_or_default());
+    }
+    for added in &diff.added {
+        println!("+ {} {}", added["value"], added["state"].as_str().unwrap_or_default());
+    }
+    for change in &diff.changed {
+        println!("~ {} {} -> {}", change.value, change.from, change.to);
+    }
+    println!("{} added, {} removed, {} changed", diff.added.len(), diff.removed.len(), diff.changed.len());
+}
+
+fn print_strategy(strategy: &StrategySummary) {
+    println!("{} (id {}, version {})", strategy.name, strategy.id, strategy.version);
+    println!("{} {}, engine {}, used by {} pools", strategy.language, strategy.kind, strategy.engine,
+             strategy.pool_count);
+    let undocumented = "undocumented";
+    println!("\n{}", strategy.docs.description.as_deref().unwrap_or("No description"));
+    println!("\nPool properties:\n  {}", strategy.docs.expected_pool_properties.as_deref().unwrap_or(undocumented));
+    println!("\nUser input:\n  {}", strategy.docs.expected_user_input.as_deref().unwrap_or(undocumented));
+}
+
+fn print_pool_tree(db: &mut DB, pool_name: &str) -> Result<()> {
+    let pool = db.get_resource_pool_by_name(pool_name)?;
+    let ancestors = db.get_ancestors(pool.id)?;
+    if !ancestors.is_empty() {
+        let path = ancestors.iter().rev().map(|ancestor| ancestor.name.as_str()).collect::<Vec<_>>();
+        println!("Nested in {}", path.join(" > "));
+    }
+    for node in db.get_pool_tree(pool.id)? {
+        println!("{}{}: {} resources, {} in subtree", "  ".repeat(node.depth as usize),
+                 node.pool.name, node.resource_count, node.subtree_resource_count);
+    }
+    Ok(())
+}
+
+fn confirm(question: &str) -> Result<bool> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        bail!("Cannot ask for confirmation, stdin is not a terminal. Use --force");
+    }
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+fn export_resources(db: &mut DB, pool_name: &str, output: &str, batch_size: i32) -> Result<()> {
+    let pool = db.get_resource_pool_by_name(pool_name)?;
+    let mut out: Box<dyn Write> = if output == STDIO {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        Box::new(BufWriter::new(File::create(output)
+            .context(format!("Cannot create '{}'", output))?))
+    };
+    let total = db.count_resources(pool.id)? as u64;
+    let mut progress = Progress::new(&format!("Exporting pool '{}'", pool.name), Some(total));
+    db.stream_resources(pool.id, batch_size, |resources| {
+        for resource in &resources {
+            serde_json::to_writer(&mut out, &resource.as_export_json())?;
+            out.write_all(b"\n")?;
+        }
+        progress.inc(resources.len() as u64);
+        Ok(())
+    })?;
+    out.flush()?;
+    progress.finish();
+    Ok(())
+}
+
+fn import_pool(db: &mut DB, pool_name: &str, strategy_id: Option<i64>, file: &str,
+               batch_size: usize) -> Result<()> {
+    let mut pool = match (db.find_resource_pool_by_name(pool_name)?, strategy_id) {
+        (Some(pool), _) => pool,
+        (None, Some(strategy_id)) => db.insert_resource_pool(pool_name, strategy_id)?,
+        (None, None) => return Err(anyhow!(
+            "Resource pool '{}' not found, use --strategy-id to create it", pool_name)),
+    };
+    let (input, total): (Box<dyn BufRead>, Option<u64>) = if file == STDIO {
+        (Box::new(BufReader::new(io::stdin())), None)
+    } else {
+        let open = || File::open(file).context(format!("Cannot open '{}'", file));
+        // count lines upfront so that progress can estimate the remaining time
+        let total = BufReader::new(open()?).lines().count() as u64;
+        (Box::new(BufReader::new(open()?)), Some(total))
+    };
+    let mut progress = Progress::new(&format!("Importing pool '{}'", pool.name), total);
+    let mut batch = Vec::with_capacity(batch_size);
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let exported: Value = serde_json::from_str(&line)
+            .context(format!("Cannot parse line '{}'", line))?;
+        batch.push(Resource::new_from_export_json(pool.id, exported)?);
+        if batch.len() >= batch_size {
+            pool = insert_batch(db, pool, &mut batch, &mut progress)?;
+        }
+    }
+    if !batch.is_empty() {
+        insert_batch(db, pool, &mut batch, &mut progress)?;
+    }
+    progress.finish();
+    Ok(())
+}
+
+fn insert_batch(db: &mut DB, pool: ResourcePool, batch: &mut Vec<Resource>,
+                progress: &mut Progress) -> Result<ResourcePool> {
+    let (pool, inserted) = db.insert_resources(pool, std::mem::take(batch))?;
+    progress.inc(inserted.len() as u64);
+    Ok(pool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use serde_json::json;
+
+    #[test]
+    fn cli_definition_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn completions_are_generated() {
+        for target in CompletionTarget::value_variants() {
+            let mut out = Vec::new();
+            write_completions(*target, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains("completions"), "{:?} output does not mention subcommands", target);
+        }
+    }
+
+    #[test]
+    fn user_input_from_flags() {
+        let cli = Cli::try_parse_from(vec!["rm", "allocate", "--pool", "p", "--count", "3",
+                                           "--input", "subnet=true", "--input", "name=a=b"]).unwrap();
+        match cli.command {
+            Command::Allocate { pool, count, inputs, .. } => {
+                assert_eq!("p", pool);
+                assert_eq!(json!({"resourceCount": 3, "subnet": true, "name": "a=b"}),
+                           user_input(count, inputs));
+            }
+            other => panic!("Unexpected command {:?}", other),
+        }
+        assert!(Cli::try_parse_from(vec!["rm", "allocate", "--pool", "p", "--input", "=1"]).is_err());
+        assert!(Cli::try_parse_from(vec!["rm", "deallocate", "--pool", "p"]).is_err());
+        assert!(Cli::try_parse_from(vec!["rm", "deallocate", "--pool", "p", "--id", "1",
+                                         "--value", "{}"]).is_err());
+        match Cli::try_parse_from(vec!["rm", "deallocate", "--pool", "p", "--id", "1", "--id", "2"])
+            .unwrap().command {
+            Command::Deallocate { id, .. } => assert_eq!(vec![1, 2], id),
+            other => panic!("Unexpected command {:?}", other),
+        }
+        assert_eq!(json!({}), user_input(None, vec![]));
+    }
+
+    #[test]
+    fn export_and_import_pool() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = (0..5)
+            .map(|idx| Resource::new_from_value(pool.id, json!({"address": format!("1.1.1.{}", idx)})))
+            .collect();
+        db.insert_resources(pool.clone(), resources).unwrap();
+
+        let file = std::env::temp_dir().join(format!("{}.jsonl", pool.name));
+        let file = file.to_str().unwrap();
+        export_resources(&mut db, &pool.name, file, 2).unwrap();
+
+        let imported_name = format!("{}-imported", pool.name);
+        import_pool(&mut db, &imported_name, Some(pool.allocation_strategy_id), file, 2).unwrap();
+        std::fs::remove_file(file).unwrap();
+        let imported = db.get_resource_pool_by_name(&imported_name).unwrap();
+        // one version bump per batch
+        assert_eq!(3, imported.version);
+        let values = |db: &mut DB, pool_id| db.get_resources(pool_id).unwrap().into_iter()
+            .map(|it| it.value).collect::<Vec<Value>>();
+        assert_eq!(values(&mut db, pool.id), values(&mut db, imported.id));
+    }
+
+    #[test]
+    fn bulk_allocate_and_deallocate_in_batches() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        allocate(&mut db, &pool.name, json!({"resourceCount": 5}), &AllocationOptions::default(), false, Some(2))
+            .unwrap();
+        // one version bump per batch
+        let pool = db.get_resource_pool_by_id(pool.id).unwrap();
+        assert_eq!((3, 5), (pool.version, db.count_resources(pool.id).unwrap()));
+
+        let deallocated = deallocate_in_batches(&mut db, pool.clone(), BulkSelector::State(ResourceState::Allocated),
+                                                Some(2)).unwrap();
+        assert_eq!(5, deallocated.len());
+        assert_eq!(pool.version + 3, db.get_resource_pool_by_id(pool.id).unwrap().version);
+        assert!(deallocate_in_batches(&mut db, pool, BulkSelector::Ids(vec![]), Some(0)).is_err());
+    }
+
+    #[test]
+    fn lab_commands() {
+        initialize_logging();
+
+        assert!(Cli::try_parse_from(vec!["rm", "lab", "deallocate", "--pool", "p"]).is_err());
+        let mut db = DB::new_from_env().unwrap();
+        let name = format!("{}-lab", create_random_pool(&mut db).unwrap().name);
+        let parse = |args: &[&str]| match Cli::try_parse_from([&["rm", "lab"], args].concat()).unwrap().command {
+            Command::Lab { sqlite: None, command } => command,
+            other => panic!("Unexpected command {:?}", other),
+        };
+        lab(&mut db, parse(&["create-pool", "--pool", &name, "--strategy-id", "1"])).unwrap();
+        lab(&mut db, parse(&["allocate", "--pool", &name, "--count", "2"])).unwrap();
+        let pool = db.get_resource_pool_by_name(&name).unwrap();
+        let resources = db.get_resources(pool.id).unwrap();
+        assert_eq!(2, resources.len());
+        let id = resources[0].id.unwrap().to_string();
+        lab(&mut db, parse(&["deallocate", "--pool", &name, "--id", &id])).unwrap();
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+        assert!(lab(&mut db, parse(&["delete-pool", "--pool", &name])).is_err());
+    }
+}
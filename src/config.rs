@@ -0,0 +1,202 @@
+use std::env;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+
+use crate::backpressure::Backpressure;
+use crate::chaos::Chaos;
+use crate::connect::ConnectRetry;
+use crate::logging::LogFormat;
+use crate::routing::RoutingTable;
+use crate::timeout::TransactionTimeouts;
+
+const PREFIX: &str = "RM_";
+
+/// Settings of the process read from `RM_`-prefixed env vars, e.g. `RM_DB_PARAMS`. Names without the prefix
+/// are still read if the prefixed variable is not set, so that existing deployments keep working.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub db_params: String,
+    // None to use the search_path of the connection
+    pub db_schema: Option<String>,
+    pub db_replica_params: Option<String>,
+    // None to keep every pool in the database of `db_params`
+    pub db_routing: Option<RoutingTable>,
+    pub connect_retry: ConnectRetry,
+    pub timeouts: TransactionTimeouts,
+    // None to discover wasmer and quickJS, see `WasmerEnv::discover`
+    pub wasmer_bin: Option<String>,
+    pub wasmer_js: Option<String>,
+    pub wasmer_max_output_bytes: u64,
+    // None to sign cursors with a random secret
+    pub cursor_secret: Option<String>,
+    pub log_format: LogFormat,
+    // only needed to save TypeScript strategies
+    pub tsc_bin: Option<String>,
+    // turns maintenance mode of the instance on at startup, see `DB::set_maintenance`
+    pub maintenance: bool,
+    // None to renew leases without a limit, see `DB::renew_lease`
+    pub max_lease_lifetime: Option<Duration>,
+    // pool events are written to the outbox drained by the leading worker, see `DB::drain_outbox`
+    pub outbox: bool,
+    // None to allocate regardless of contention, see `Backpressure`
+    pub backpressure: Option<Backpressure>,
+    // allocations of a pool wait for their turn instead of conflicting, see `DB::allocate_in_turn`
+    pub fair_allocation: bool,
+    pub chaos: Chaos,
+}
+
+impl Config {
+    // Reads and validates every setting, all missing and invalid variables are reported together.
+    pub fn from_env() -> Result<Config> {
+        Self::read(|var| env::var(var))
+    }
+
+    fn read(lookup: impl Fn(&str) -> Result<String, env::VarError>) -> Result<Config> {
+        let mut reader = Reader { lookup, errors: vec![] };
+        let db_params = reader.required("DB_PARAMS");
+        let mut connect_retry = ConnectRetry::default();
+        if let Some(retries) = reader.parse("DB_CONNECT_RETRIES") {
+            connect_retry.retries = retries;
+        }
+        if let Some(millis) = reader.parse("DB_CONNECT_BACKOFF_MS") {
+            connect_retry.initial_backoff = Duration::from_millis(millis);
+        }
+        let config = Config {
+            db_params: db_params.unwrap_or_default(),
+            db_schema: reader.string("DB_SCHEMA"),
+            db_replica_params: reader.string("DB_REPLICA_PARAMS"),
+            db_routing: reader.parse("DB_ROUTING"),
+            connect_retry,
+            timeouts: TransactionTimeouts {
+                statement_timeout: reader.parse("DB_STATEMENT_TIMEOUT_MS").map(Duration::from_millis),
+                lock_timeout: reader.parse("DB_LOCK_TIMEOUT_MS").map(Duration::from_millis),
+            },
+            wasmer_bin: reader.string("WASMER_BIN"),
+            wasmer_js: reader.string("WASMER_JS"),
+            wasmer_max_output_bytes: reader.parse("WASMER_MAX_OUTPUT_BYTES").unwrap_or(64 * 1024 * 1024),
+            cursor_secret: reader.string("CURSOR_SECRET").filter(|secret| !secret.is_empty()),
+            log_format: reader.parse("LOG_FORMAT").unwrap_or(LogFormat::Text),
+            tsc_bin: reader.string("TSC_BIN"),
+            maintenance: reader.parse("MAINTENANCE").unwrap_or(false),
+            max_lease_lifetime: reader.parse("MAX_LEASE_LIFETIME_SECS").map(Duration::from_secs),
+            outbox: reader.parse("OUTBOX").unwrap_or(false),
+            backpressure: reader.parse("BACKPRESSURE_CONFLICT_RATE").map(|conflict_rate| Backpressure {
+                conflict_rate,
+                min_allocations: reader.parse("BACKPRESSURE_MIN_ALLOCATIONS").unwrap_or(20),
+                window: Duration::from_secs(reader.parse("BACKPRESSURE_WINDOW_SECS").unwrap_or(60)),
+            }),
+            fair_allocation: reader.parse("FAIR_ALLOCATION").unwrap_or(false),
+            chaos: Chaos {
+                db_latency: reader.parse("CHAOS_DB_LATENCY_MS").map(Duration::from_millis),
+                abort_probability: reader.parse("CHAOS_ABORT_PROBABILITY").unwrap_or_default(),
+                script_failure_probability: reader.parse("CHAOS_SCRIPT_FAILURE_PROBABILITY").unwrap_or_default(),
+            },
+        };
+        if config.chaos.is_enabled() && !cfg!(feature = "chaos") {
+            reader.errors.push(format!("{}CHAOS_* settings require a build with the chaos feature", PREFIX));
+        }
+        if let Some(backpressure) = &config.backpressure {
+            if !(backpressure.conflict_rate > 0.0 && backpressure.conflict_rate <= 1.0) {
+                reader.errors.push(format!("{}BACKPRESSURE_CONFLICT_RATE must be above 0 and at most 1", PREFIX));
+            }
+            if backpressure.min_allocations < 1 || backpressure.window.as_secs() == 0 {
+                reader.errors.push(format!("{}BACKPRESSURE_MIN_ALLOCATIONS and {}BACKPRESSURE_WINDOW_SECS \
+                    must be positive", PREFIX, PREFIX));
+            }
+        }
+        if !reader.errors.is_empty() {
+            bail!("Invalid configuration:\n  {}", reader.errors.join("\n  "));
+        }
+        Ok(config)
+    }
+}
+
+// Collects errors of all variables instead of failing on the first one.
+struct Reader<L> {
+    lookup: L,
+    errors: Vec<String>,
+}
+
+impl<L: Fn(&str) -> Result<String, env::VarError>> Reader<L> {
+    // Name and value of the prefixed variable, or of the unprefixed one.
+    fn lookup(&mut self, name: &str) -> Option<(String, String)> {
+        for var in [format!("{}{}", PREFIX, name), name.to_owned()] {
+            match (self.lookup)(&var) {
+                Ok(value) => return Some((var, value)),
+                Err(env::VarError::NotUnicode(_)) => {
+                    self.errors.push(format!("{} is not valid unicode", var));
+                    return None;
+                }
+                Err(env::VarError::NotPresent) => {}
+            }
+        }
+        None
+    }
+
+    fn string(&mut self, name: &str) -> Option<String> {
+        self.lookup(name).map(|(_, value)| value)
+    }
+
+    fn required(&mut self, name: &str) -> Option<String> {
+        let value = self.string(name);
+        if value.is_none() {
+            self.errors.push(format!("{}{} is not set", PREFIX, name));
+        }
+        value
+    }
+
+    fn parse<T: FromStr>(&mut self, name: &str) -> Option<T> where T::Err: Display {
+        let (var, value) = self.lookup(name)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(err) => {
+                self.errors.push(format!("{}='{}': {}", var, value, err));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn read(vars: &[(&str, &str)]) -> Result<Config> {
+        let vars = vars.iter().map(|(var, value)| (var.to_string(), value.to_string())).collect::<HashMap<_, _>>();
+        Config::read(|var| vars.get(var).cloned().ok_or(env::VarError::NotPresent))
+    }
+
+    #[test]
+    fn config_read() {
+        let config = read(&[("RM_DB_PARAMS", "dbname=new"), ("DB_PARAMS", "dbname=old"),
+                            ("DB_LOCK_TIMEOUT_MS", "100"), ("RM_LOG_FORMAT", "json"),
+                            ("RM_MAINTENANCE", "true"), ("RM_MAX_LEASE_LIFETIME_SECS", "3600"),
+                            ("RM_OUTBOX", "true"), ("RM_BACKPRESSURE_CONFLICT_RATE", "0.3"),
+                            ("RM_FAIR_ALLOCATION", "true")]).unwrap();
+        assert_eq!("dbname=new", config.db_params);
+        assert_eq!(Some(Duration::from_millis(100)), config.timeouts.lock_timeout);
+        assert_eq!(LogFormat::Json, config.log_format);
+        assert_eq!(ConnectRetry::default(), config.connect_retry);
+        assert!(config.maintenance);
+        assert!(config.outbox);
+        assert!(config.fair_allocation);
+        assert_eq!(Some(Backpressure { conflict_rate: 0.3, min_allocations: 20, window: Duration::from_secs(60) }),
+                   config.backpressure);
+        assert_eq!(Some(Duration::from_secs(3600)), config.max_lease_lifetime);
+        assert!(!config.chaos.is_enabled());
+
+        let err = read(&[("RM_DB_CONNECT_RETRIES", "-1"), ("WASMER_MAX_OUTPUT_BYTES", "1MB")]).unwrap_err();
+        assert_eq!("Invalid configuration:\n  RM_DB_PARAMS is not set\n  \
+                   RM_DB_CONNECT_RETRIES='-1': invalid digit found in string\n  \
+                   WASMER_MAX_OUTPUT_BYTES='1MB': invalid digit found in string", err.to_string());
+        let err = read(&[("RM_DB_PARAMS", "dbname=new"), ("RM_CHAOS_ABORT_PROBABILITY", "2")]).unwrap_err();
+        assert!(err.to_string().contains("probability must be between 0 and 1"), "{}", err);
+        let err = read(&[("RM_DB_PARAMS", "dbname=new"), ("RM_BACKPRESSURE_CONFLICT_RATE", "0")]).unwrap_err();
+        assert!(err.to_string().contains("RM_BACKPRESSURE_CONFLICT_RATE must be above 0"), "{}", err);
+    }
+}
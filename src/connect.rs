@@ -0,0 +1,86 @@
+use std::error::Error as _;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use postgres::error::SqlState;
+use postgres::{Client, NoTls};
+use tracing::*;
+
+use crate::error::AllocationError;
+
+/// How `DB` waits for Postgres that is not reachable yet, e.g. while it is starting or restarting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectRetry {
+    // 0 to fail on the first transient error
+    pub retries: u32,
+    // doubled after every attempt up to `max_backoff`
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for ConnectRetry {
+    fn default() -> ConnectRetry {
+        ConnectRetry { retries: 0, initial_backoff: Duration::from_millis(500), max_backoff: Duration::from_secs(30) }
+    }
+}
+
+impl ConnectRetry {
+    // Transient errors are retried, configuration errors such as a wrong password or a missing
+    // database fail immediately. Fails with `AllocationError::DatabaseUnavailable` once retries are exhausted.
+    pub fn connect(&self, params: &str) -> Result<Client> {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match Client::connect(params, NoTls) {
+                Ok(client) => return Ok(client),
+                Err(err) if is_transient(&err) && attempt <= self.retries => {
+                    warn!("Cannot connect to the database (attempt {}), retrying in {:?}: {}", attempt, backoff, err);
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.max_backoff);
+                }
+                Err(err) if is_transient(&err) =>
+                    return Err(AllocationError::DatabaseUnavailable { attempts: attempt, reason: err.to_string() }.into()),
+                Err(err) => return Err(err).context("Cannot connect to the database"),
+            }
+        }
+    }
+}
+
+// Connectivity problems and a server that is starting or shutting down, as opposed to invalid configuration.
+pub fn is_transient(err: &postgres::Error) -> bool {
+    match err.code() {
+        Some(code) => [SqlState::CANNOT_CONNECT_NOW, SqlState::TOO_MANY_CONNECTIONS, SqlState::ADMIN_SHUTDOWN,
+            SqlState::CRASH_SHUTDOWN].contains(code),
+        None => err.source().is_some_and(|source| source.is::<std::io::Error>()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn connect_retry() {
+        let retry = ConnectRetry { retries: 2, initial_backoff: Duration::from_millis(20), ..ConnectRetry::default() };
+        let started = Instant::now();
+        // nothing listens on port 1
+        let err = retry.connect("host=localhost port=1 user=postgres").err().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        match err.downcast_ref::<AllocationError>() {
+            Some(AllocationError::DatabaseUnavailable { attempts, .. }) => assert_eq!(3, *attempts),
+            other => panic!("Unexpected error {:?}", other),
+        }
+
+        let params = std::env::var("DB_PARAMS").unwrap();
+        let retry = ConnectRetry { retries: 5, initial_backoff: Duration::from_secs(10), ..ConnectRetry::default() };
+        let started = Instant::now();
+        let err = retry.connect(&format!("{} dbname=rm-poc-missing", params)).err().unwrap();
+        assert_eq!(None, err.downcast_ref::<AllocationError>());
+        assert!(started.elapsed() < retry.initial_backoff);
+        assert!(retry.connect(&params).is_ok());
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::{Result, anyhow};
+use serde_json::Value;
+
+use crate::DB;
+use crate::engine::Engine;
+use crate::hooks::{HookStage, POOL_HOOKS_JSON, PoolHook};
+use crate::input::InputSchema;
+use crate::reload::ScriptCache;
+use crate::strategy::StrategyFiles;
+
+/// What the read phase of an allocation needs about the pool and its strategy, see `DB::get_allocation_context`.
+pub(crate) struct AllocationContext {
+    // bundled or linked script of the strategy
+    pub script: String,
+    pub engine: Engine,
+    pub strategy_version: i32,
+    pub pre_allocation_hook: Option<PoolHook>,
+    pub post_allocation_hook: Option<PoolHook>,
+    pub input_schema: Option<InputSchema>,
+    // addresses of pools with a sequential strategy and no script override, see `DB::find_free_addresses`
+    pub free_range_bounds: Option<(i64, i64)>,
+}
+
+impl DB {
+    // Pool row, script of the strategy or override of the pool with files of the strategy, engine, hooks,
+    // input schema and free-list bounds in one round trip, instead of a query each. Only modules importing other
+    // strategies query for them, unless the linked script is in the script cache of the server.
+    pub(crate) fn get_allocation_context(&mut self, resource_pool_id: i64) -> Result<AllocationContext> {
+        let row = self.client.query_opt(
+            format!("SELECT s.name, s.script, s.script_kind, s.engine, s.input_schema, \
+            (SELECT coalesce(jsonb_object_agg(f.path, f.content), '{{}}') FROM allocation_strategy_files f \
+            WHERE f.allocation_strategy_id = s.id), bounds.first, bounds.last, s.version, s.id, \
+            p.script_override, {} \
+            FROM resource_pools p JOIN allocation_strategies s ON s.id = p.resource_pool_allocation_strategy \
+            LEFT JOIN LATERAL free_range_bounds(p.id) bounds ON p.script_override IS NULL WHERE p.id=$1",
+                    POOL_HOOKS_JSON).as_str(),
+            &[&resource_pool_id])?
+            .ok_or_else(|| anyhow!("Resource pool {} not found", resource_pool_id))?;
+        let files: StrategyFiles = serde_json::from_value(row.get::<_, Value>(5))?;
+        let (strategy_id, strategy_version) = (row.get(9), row.get(8));
+        // strategies changed by a dry run may differ from committed ones of the same version
+        let script_cache = self.script_cache.as_ref().filter(|_| !self.client.is_dry_run());
+        let cached = script_cache.and_then(|cache| cache.get(strategy_id, strategy_version));
+        let script = match (row.get::<_, Option<&str>>(10), cached) {
+            // not shared with other pools of the strategy, so not cached
+            (Some(script_override), _) => self.strategy_script(row.get(0), script_override, None, &files)?,
+            (None, Some(script)) => script,
+            (None, None) => {
+                let generation = script_cache.map(ScriptCache::generation);
+                let script = self.strategy_script(row.get(0), row.get(1), row.get(2), &files)?;
+                if let (Some(cache), Some(generation)) = (&self.script_cache, generation) {
+                    cache.insert(generation, strategy_id, strategy_version, script.clone());
+                }
+                script
+            }
+        };
+        let bounds: (Option<i64>, Option<i64>) = (row.get(6), row.get(7));
+        let hooks = row.get::<_, Value>(11).as_array().into_iter().flatten()
+            .map(PoolHook::from_json)
+            .collect::<Result<Vec<_>>>()?;
+        let hook = |stage| hooks.iter().find(|hook| hook.stage == stage).cloned();
+        Ok(AllocationContext {
+            script,
+            engine: row.get::<_, &str>(3).parse()?,
+            strategy_version,
+            pre_allocation_hook: hook(HookStage::PreAllocation),
+            post_allocation_hook: hook(HookStage::PostAllocation),
+            input_schema: row.get::<_, Option<Value>>(4).map(InputSchema::from_json).transpose()?,
+            free_range_bounds: match bounds {
+                (Some(first), Some(last)) => Some((first, last)),
+                _ => None,
+            },
+        })
+    }
+}
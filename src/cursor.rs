@@ -0,0 +1,85 @@
+use anyhow::{Result, anyhow, ensure};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use tracing::*;
+
+use crate::config::Config;
+
+/// Signs cursors of listings, so that clients cannot forge positions or reuse a cursor with another filter.
+///
+/// The secret is read from `RM_CURSOR_SECRET`, replicas of the server must share it. Without it a random secret is
+/// generated and cursors are only valid until the server restarts.
+#[derive(Clone)]
+pub struct CursorKey {
+    secret: Vec<u8>,
+}
+
+impl CursorKey {
+    pub fn new(secret: &[u8]) -> CursorKey {
+        CursorKey { secret: secret.to_vec() }
+    }
+
+    pub fn from_config(config: &Config) -> CursorKey {
+        match &config.cursor_secret {
+            Some(secret) => CursorKey::new(secret.as_bytes()),
+            None => {
+                warn!("RM_CURSOR_SECRET is not set, cursors are invalidated by a restart");
+                CursorKey::new(&rand::thread_rng().gen::<[u8; 32]>())
+            }
+        }
+    }
+
+    fn sign(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(payload);
+        mac
+    }
+
+    // Opaque cursor continuing after `position`, valid only for listings with the same `filter`.
+    pub fn encode(&self, filter: &str, position: Value) -> String {
+        let payload = json!({"filter": filter_hash(filter), "after": position}).to_string();
+        let signature = self.sign(payload.as_bytes()).finalize().into_bytes();
+        format!("{}.{}", base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+                base64::encode_config(signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    // Position stored in the cursor, fails if the cursor was not signed by this key or the filter changed.
+    pub fn decode(&self, filter: &str, cursor: &str) -> Result<Value> {
+        let invalid = || anyhow!("Invalid cursor '{}'", cursor);
+        let (payload, signature) = cursor.split_once('.').ok_or_else(invalid)?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| invalid())?;
+        self.sign(&payload).verify(&signature).map_err(|_| invalid())?;
+        let mut payload: Value = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+        ensure!(payload["filter"] == filter_hash(filter), "Cursor '{}' belongs to another filter", cursor);
+        Ok(payload["after"].take())
+    }
+}
+
+fn filter_hash(filter: &str) -> String {
+    Sha256::digest(filter.as_bytes())[..8].iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_round_trip() {
+        let key = CursorKey::new(b"secret");
+        let cursor = key.encode("pools?tag=edge", json!({"id": 7}));
+        assert_eq!(json!({"id": 7}), key.decode("pools?tag=edge", &cursor).unwrap());
+
+        let err = key.decode("pools?tag=prod", &cursor).unwrap_err();
+        assert!(err.to_string().contains("another filter"), "{}", err);
+        assert!(CursorKey::new(b"other").decode("pools?tag=edge", &cursor).is_err());
+        let (payload, signature) = cursor.split_once('.').unwrap();
+        let forged = base64::encode_config(
+            json!({"filter": filter_hash("pools?tag=edge"), "after": {"id": 1}}).to_string(), base64::URL_SAFE_NO_PAD);
+        assert_ne!(payload, forged);
+        assert!(key.decode("pools?tag=edge", &format!("{}.{}", forged, signature)).is_err());
+        assert!(key.decode("pools?tag=edge", "garbage").is_err());
+    }
+}
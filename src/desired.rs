@@ -0,0 +1,141 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{Result, ensure};
+use postgres::error::SqlState;
+use serde_json::{Value, json};
+
+use crate::{DB, ResourcePool};
+use crate::error::AllocationError;
+
+// `userInput.desiredValue` requests exactly one value, e.g. `{"address": "10.0.0.42"}`, None if missing.
+pub(crate) fn desired_value(user_input: &Value) -> Result<Option<&Value>> {
+    let desired = match user_input.get("desiredValue") {
+        None | Some(Value::Null) => return Ok(None),
+        Some(desired) => desired,
+    };
+    ensure!(desired.is_object(), "desiredValue must be a JSON object, e.g. {{\"address\": \"10.0.0.42\"}}");
+    let count = &user_input["resourceCount"];
+    ensure!(count.is_null() || *count == 1, "desiredValue cannot be combined with resourceCount {}", count);
+    Ok(Some(desired))
+}
+
+fn unavailable(pool: &ResourcePool, desired: &Value, reason: String) -> anyhow::Error {
+    AllocationError::ValueUnavailable { resource_pool: pool.name.clone(), value: desired.clone(), reason }.into()
+}
+
+impl DB {
+    // The desired address of a pool with a sequential strategy if it lies within `first..=last`, outside of
+    // reserved sub-ranges unless selected, and is on the free list. Fails with `AllocationError::ValueUnavailable`
+    // otherwise.
+    pub(crate) fn find_desired_address(&mut self, pool: &ResourcePool, (first, last): (i64, i64),
+                                       order: &[(i64, i64)], desired: &Value) -> Result<Vec<Value>> {
+        let address = match desired["address"].as_str().and_then(|address| address.parse::<Ipv4Addr>().ok()) {
+            Some(address) => i64::from(u32::from(address)),
+            None => return Err(unavailable(pool, desired, "address is not a valid IPv4 address".to_owned())),
+        };
+        if address < first || address > last {
+            return Err(unavailable(pool, desired, format!("address is outside of {} - {}",
+                                                          Ipv4Addr::from(first as u32), Ipv4Addr::from(last as u32))));
+        }
+        if !order.iter().any(|(first, last)| *first <= address && address <= *last) {
+            return Err(unavailable(pool, desired, "address is in a reserved or not selected sub-range".to_owned()));
+        }
+        let free = self.client.query_opt(
+            "SELECT 1 FROM free_ranges WHERE resource_pool=$1 AND first <= $2 AND last >= $2",
+            &[&pool.id, &address])?;
+        if free.is_none() {
+            return Err(unavailable(pool, desired, "address is already in use".to_owned()));
+        }
+        Ok(vec![json!({"address": Ipv4Addr::from(address as u32).to_string()})])
+    }
+
+    // Scripts receive the desired value in `userInput` and either return exactly it or throw
+    // `{code: 'VALUE_UNAVAILABLE', message}`. Both the error and any other result fail with
+    // `AllocationError::ValueUnavailable`, the latter as the strategy does not support desired values.
+    pub(crate) fn check_desired_result(pool: &ResourcePool, desired: &Value, result: Result<Vec<Value>>)
+                                       -> Result<Vec<Value>> {
+        match result {
+            Ok(values) if values.len() == 1 && values[0] == *desired => Ok(values),
+            Ok(values) => Err(unavailable(pool, desired, format!("strategy returned {} instead", json!(values)))),
+            Err(err) => match err.downcast_ref::<AllocationError>() {
+                Some(AllocationError::Strategy { code, message, .. }) if code == "VALUE_UNAVAILABLE" =>
+                    Err(unavailable(pool, desired, message.clone())),
+                _ => Err(err),
+            },
+        }
+    }
+
+    // Converts the unique violation of inserting a desired value in use by the pool, e.g. one the script
+    // did not check, into `AllocationError::ValueUnavailable`.
+    pub(crate) fn desired_insert_error(pool: &ResourcePool, desired: &Value, err: anyhow::Error) -> anyhow::Error {
+        match err.downcast_ref::<postgres::Error>().and_then(|err| err.code()) {
+            Some(code) if *code == SqlState::UNIQUE_VIOLATION =>
+                unavailable(pool, desired, "value is already in use".to_owned()),
+            _ => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::{AllocationOptions, WasmerEnv};
+    use crate::strategy::StrategyFiles;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    fn reason(err: anyhow::Error) -> String {
+        match err.downcast_ref::<AllocationError>() {
+            Some(AllocationError::ValueUnavailable { reason, .. }) => reason.clone(),
+            _ => panic!("Expected ValueUnavailable, got {:#}", err),
+        }
+    }
+
+    #[test]
+    fn db_desired_value() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let desired = |address: &str| json!({"desiredValue": {"address": address}});
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, desired("10.0.0.42"),
+                                                      &AllocationOptions::default()).unwrap();
+        assert_eq!(json!({"address": "10.0.0.42"}), resources[0].value);
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env, desired("10.0.0.42"),
+                                        &AllocationOptions::default()).unwrap_err();
+        assert_eq!("address is already in use", reason(err));
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env, desired("11.0.0.1"),
+                                        &AllocationOptions::default()).unwrap_err();
+        assert!(reason(err).contains("outside"));
+        assert!(db.allocate_resources(pool.clone(), &mut wasmer_env,
+                                      json!({"desiredValue": {"address": "10.0.0.1"}, "resourceCount": 2}),
+                                      &AllocationOptions::default()).is_err());
+        // the next allocation skips the desired address
+        let (_, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 43}),
+                                                   &AllocationOptions::default()).unwrap();
+        assert_eq!(json!({"address": "10.0.0.43"}), resources[42].value);
+
+        // a script returning desired values below 10 without checking them
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() {\n\
+            const desired = userInput.desiredValue\n\
+            if (desired && desired.n < 0) { throw {code: 'VALUE_UNAVAILABLE', message: 'negative'} }\n\
+            if (desired && desired.n < 10) { return [desired] }\n\
+            return [{ n: 10 + currentResources.length }]\n}", None, &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({"desiredValue": {"n": 0}}),
+                                              &AllocationOptions::default()).unwrap();
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({"desiredValue": {"n": 0}}),
+                                        &AllocationOptions::default()).unwrap_err();
+        assert_eq!("value is already in use", reason(err));
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({"desiredValue": {"n": 20}}),
+                                        &AllocationOptions::default()).unwrap_err();
+        assert!(reason(err).contains("strategy returned"));
+        let err = db.allocate_resources(pool, &mut wasmer_env, json!({"desiredValue": {"n": -1}}),
+                                        &AllocationOptions::default()).unwrap_err();
+        assert_eq!("negative", reason(err));
+    }
+}
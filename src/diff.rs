@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, ensure};
+use serde_json::{Value, json};
+
+use crate::DB;
+
+/// State of a pool compared by `DB::diff_pool`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolState {
+    Current,
+    Snapshot(i32),
+    // latest snapshot taken at this pool version, or the current state if the pool is still at it
+    Version(i32),
+}
+
+impl fmt::Display for PoolState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolState::Current => write!(f, "current"),
+            PoolState::Snapshot(id) => write!(f, "snapshot:{}", id),
+            PoolState::Version(version) => write!(f, "version:{}", version),
+        }
+    }
+}
+
+impl FromStr for PoolState {
+    type Err = anyhow::Error;
+
+    // `current`, `snapshot:<id>` or `version:<version>`
+    fn from_str(s: &str) -> Result<PoolState> {
+        let parse = |number: &str| number.parse::<i32>()
+            .map_err(|_| anyhow!("Invalid pool state '{}'", s));
+        match s.split_once(':') {
+            None if s == "current" => Ok(PoolState::Current),
+            Some(("snapshot", id)) => parse(id).map(PoolState::Snapshot),
+            Some(("version", version)) => parse(version).map(PoolState::Version),
+            _ => Err(anyhow!("Invalid pool state '{}', expected current, snapshot:<id> or version:<version>", s)),
+        }
+    }
+}
+
+/// Resource whose value is in both states of `PoolDiff`, but with a different state or timestamps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceChange {
+    pub value: Value,
+    // `Resource::as_export_json` without id, creation and change times and value
+    pub from: Value,
+    pub to: Value,
+}
+
+/// Resources are matched by value, ids and times of creation and last change differ between restored
+/// or imported pools.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolDiff {
+    // `Resource::as_export_json` without id and creation and change times, ordered by value
+    pub added: Vec<Value>,
+    pub removed: Vec<Value>,
+    pub changed: Vec<ResourceChange>,
+}
+
+impl PoolDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    fn new(from: Vec<Value>, to: Vec<Value>) -> PoolDiff {
+        let by_value = |resources: Vec<Value>| resources.into_iter()
+            .map(|mut exported| {
+                if let Some(object) = exported.as_object_mut() {
+                    for key in ["id", "createdAt", "updatedAt"] {
+                        object.remove(key);
+                    }
+                }
+                // keys of serde_json objects are sorted, so the serialized value is canonical
+                (exported["value"].to_string(), exported)
+            })
+            .collect::<BTreeMap<String, Value>>();
+        let from = by_value(from);
+        let mut to = by_value(to);
+        let mut diff = PoolDiff::default();
+        for (key, from) in from {
+            match to.remove(&key) {
+                None => diff.removed.push(from),
+                Some(to) if to != from => {
+                    let without_value = |mut exported: Value| {
+                        let value = exported.as_object_mut().and_then(|object| object.remove("value"));
+                        (value.unwrap_or(Value::Null), exported)
+                    };
+                    let (value, from) = without_value(from);
+                    let (_, to) = without_value(to);
+                    diff.changed.push(ResourceChange { value, from, to });
+                }
+                Some(_) => {}
+            }
+        }
+        diff.added = to.into_values().collect();
+        diff
+    }
+
+    pub fn as_json(&self) -> Value {
+        let changed = self.changed.iter()
+            .map(|change| json!({"value": &change.value, "from": &change.from, "to": &change.to}))
+            .collect::<Vec<_>>();
+        json!({"added": &self.added, "removed": &self.removed, "changed": changed})
+    }
+}
+
+impl DB {
+    // Compare resources in use of two states of the pool.
+    pub fn diff_pool(&mut self, resource_pool_id: i64, from: PoolState, to: PoolState) -> Result<PoolDiff> {
+        let from = self.get_pool_state(resource_pool_id, from)?;
+        let to = self.get_pool_state(resource_pool_id, to)?;
+        Ok(PoolDiff::new(from, to))
+    }
+
+    // Resources in use as `Resource::as_export_json`.
+    fn get_pool_state(&mut self, resource_pool_id: i64, state: PoolState) -> Result<Vec<Value>> {
+        let snapshot_id = match state {
+            PoolState::Current => None,
+            PoolState::Snapshot(snapshot_id) => {
+                let snapshot = self.get_snapshot(snapshot_id)?;
+                ensure!(snapshot.resource_pool_id == Some(resource_pool_id),
+                        "Snapshot {} was not taken from pool {}", snapshot_id, resource_pool_id);
+                Some(snapshot_id)
+            }
+            PoolState::Version(version) => {
+                let pool = self.get_resource_pool_by_id(resource_pool_id)?;
+                if pool.version == version {
+                    None
+                } else {
+                    let row = self.client.query_opt(
+                        "SELECT id FROM pool_snapshots WHERE resource_pool=$1 AND version=$2 ORDER BY id DESC LIMIT 1",
+                        &[&resource_pool_id, &version])?
+                        .ok_or_else(|| anyhow!("No snapshot of pool '{}' at version {}", pool.name, version))?;
+                    Some(row.get(0))
+                }
+            }
+        };
+        match snapshot_id {
+            Some(snapshot_id) => self.get_snapshot_resources(snapshot_id),
+            None => Ok(self.get_resources(resource_pool_id)?.iter().map(|it| it.as_export_json()).collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Resource, ResourceSelector};
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn pool_state_from_str() {
+        assert_eq!(PoolState::Current, "current".parse::<PoolState>().unwrap());
+        assert_eq!(PoolState::Snapshot(3), "snapshot:3".parse::<PoolState>().unwrap());
+        assert_eq!(PoolState::Version(7), "version:7".parse::<PoolState>().unwrap());
+        assert!("snapshot:x".parse::<PoolState>().is_err());
+        assert!("latest".parse::<PoolState>().is_err());
+    }
+
+    #[test]
+    fn db_diff_pool() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let pool = db.set_deallocation_safety_period(pool, 60).unwrap();
+        let resources = vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"})),
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.2"})),
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.3"})),
+        ];
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let snapshot = db.snapshot_pool(pool.id, "before").unwrap();
+        let (pool, _) = db.deallocate_resource(pool, &ResourceSelector::Value(json!({"address": "10.0.0.2"})))
+            .unwrap();
+        let pool = db.set_deallocation_safety_period(pool, 0).unwrap();
+        let (pool, _) = db.deallocate_resource(pool, &ResourceSelector::Value(json!({"address": "10.0.0.3"})))
+            .unwrap();
+        db.insert_resources(pool.clone(), vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.4"}))]).unwrap();
+
+        let diff = db.diff_pool(pool.id, PoolState::Snapshot(snapshot.id), PoolState::Current).unwrap();
+        assert_eq!(vec![json!({"value": {"address": "10.0.0.4"}, "state": "allocated"})], diff.added);
+        assert_eq!(vec![json!({"value": {"address": "10.0.0.3"}, "state": "allocated"})], diff.removed);
+        assert_eq!(1, diff.changed.len());
+        assert_eq!(json!({"address": "10.0.0.2"}), diff.changed[0].value);
+        assert_eq!(json!({"state": "allocated"}), diff.changed[0].from);
+        assert_eq!(json!("bench"), diff.changed[0].to["state"]);
+        assert_eq!(diff, db.diff_pool(pool.id, PoolState::Version(snapshot.version), PoolState::Current).unwrap());
+
+        let reverse = db.diff_pool(pool.id, PoolState::Current, PoolState::Snapshot(snapshot.id)).unwrap();
+        assert_eq!(diff.added, reverse.removed);
+        assert!(db.diff_pool(pool.id, PoolState::Current, PoolState::Current).unwrap().is_empty());
+        assert!(db.diff_pool(pool.id, PoolState::Version(snapshot.version + 1), PoolState::Current).is_err());
+        let other = create_random_pool(&mut db).unwrap();
+        assert!(db.diff_pool(other.id, PoolState::Snapshot(snapshot.id), PoolState::Current).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::json;
+
+use crate::WasmerEnv;
+use crate::config::Config;
+
+const QJS_WASM: &str = ".wasmer/globals/wapm_packages/_/quickjs@0.0.3/build/qjs.wasm";
+
+// Where a candidate path comes from, shown when nothing is found.
+type Candidate = (String, PathBuf);
+
+fn home() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+// Used if `WASMER_BIN` is not set: `wasmer` on the PATH, then locations of the wasmer installer.
+fn wasmer_bin_candidates() -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    if let Some(path) = env::var_os("PATH") {
+        candidates.extend(env::split_paths(&path).map(|dir| ("PATH".to_owned(), dir.join("wasmer"))));
+    }
+    if let Some(dir) = env::var_os("WASMER_DIR") {
+        candidates.push(("WASMER_DIR".to_owned(), Path::new(&dir).join("bin/wasmer")));
+    }
+    if let Some(home) = home() {
+        candidates.push(("default".to_owned(), home.join(".wasmer/bin/wasmer")));
+    }
+    candidates
+}
+
+// Used if `WASMER_JS` is not set: quickjs installed by `wasmer install quickjs`.
+fn wasmer_js_candidates() -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    if let Some(dir) = env::var_os("WASMER_DIR") {
+        candidates.push(("WASMER_DIR".to_owned(),
+                         Path::new(&dir).join(QJS_WASM.trim_start_matches(".wasmer/"))));
+    }
+    if let Some(home) = home() {
+        candidates.push(("default".to_owned(), home.join(QJS_WASM)));
+    }
+    candidates
+}
+
+// The configured path of the env.var wins if it is set, otherwise the first existing candidate. Paths are made
+// absolute, because scripts run in their own working directory.
+fn find_file(what: &str, var: &str, configured: Option<&str>, candidates: Vec<Candidate>) -> Result<String> {
+    let resolve = |path: &Path| fs::canonicalize(path).map(|path| path.to_string_lossy().into_owned());
+    if let Some(path) = configured {
+        return resolve(Path::new(path)).context(format!("Cannot find {} at {}={:?}", what, var, path));
+    }
+    let mut tried = vec![format!("{} not set", var)];
+    for (source, path) in candidates {
+        if path.is_file() {
+            return Ok(resolve(&path)?);
+        }
+        tried.push(format!("{} ({})", path.display(), source));
+    }
+    bail!("Cannot find {}, tried: {}", what, tried.join(", "))
+}
+
+impl WasmerEnv {
+    pub(crate) fn discover(config: &Config) -> Result<(String, String)> {
+        Ok((find_file("wasmer", "RM_WASMER_BIN", config.wasmer_bin.as_deref(), wasmer_bin_candidates())?,
+            find_file("quickjs wasm", "RM_WASMER_JS", config.wasmer_js.as_deref(), wasmer_js_candidates())?))
+    }
+
+    // Check that wasmer runs and quickJS evaluates a script.
+    pub(crate) fn validate(&mut self) -> Result<()> {
+        let answer = self.invoke_and_parse_value("", json!({}), json!({}), json!({}), &mut vec![], "6 * 7")
+            .context(format!("Wasmer {} did not run quickJS {}", self.wasmer_bin, self.wasmer_js))?;
+        ensure!(answer == 42, "QuickJS {} answered {} instead of 42", self.wasmer_js, answer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_wasmer_files() {
+        let missing = env::temp_dir().join("rm-discover-missing");
+        let found = find_file("test binary", "RM_DISCOVER_UNSET", None, vec![
+            ("first".to_owned(), missing.clone()),
+            ("second".to_owned(), env::current_exe().unwrap()),
+        ]).unwrap();
+        assert_eq!(env::current_exe().unwrap().canonicalize().unwrap().to_string_lossy(), found);
+
+        let err = find_file("wasmer", "RM_DISCOVER_UNSET", None, vec![("PATH".to_owned(), missing.clone())]).unwrap_err();
+        assert_eq!(format!("Cannot find wasmer, tried: RM_DISCOVER_UNSET not set, {} (PATH)", missing.display()),
+                   err.to_string());
+    }
+
+    #[test]
+    fn wasmer_validate() {
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        wasmer_env.validate().unwrap();
+        wasmer_env.wasmer_js = "/dev/null".to_owned();
+        wasmer_env.wasmer_bin = "/bin/true".to_owned();
+        assert!(wasmer_env.validate().is_err());
+    }
+}
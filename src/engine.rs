@@ -0,0 +1,172 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, bail, ensure};
+use serde_json::Value;
+
+use crate::host::CurrentResources;
+use crate::{DB, WasmerEnv};
+
+/// Engine executing the script of a strategy, stored in `allocation_strategies.engine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    // `wasmer` process running quickJS per invocation
+    QuickjsSubprocess,
+    // quickJS linked into this process
+    QuickjsEmbedded,
+    // strategy compiled to a wasm module exporting `invoke`
+    WasmModule,
+    // strategy implemented in Rust, the script names it
+    Native,
+}
+
+const ENGINES: [Engine; 4] = [Engine::QuickjsSubprocess, Engine::QuickjsEmbedded, Engine::WasmModule, Engine::Native];
+
+impl Engine {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Engine::QuickjsSubprocess => "quickjs-subprocess",
+            Engine::QuickjsEmbedded => "quickjs-embedded",
+            Engine::WasmModule => "wasm-module",
+            Engine::Native => "native",
+        }
+    }
+
+    // Only `quickjs-subprocess` is a part of this build, the others are reserved for faster engines.
+    pub fn check_available(&self) -> Result<()> {
+        ensure!(*self == Engine::QuickjsSubprocess, "Engine '{}' is not available in this build", self);
+        Ok(())
+    }
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Engine {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Engine> {
+        ENGINES.iter()
+            .find(|engine| engine.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown engine '{}'", s))
+    }
+}
+
+/// Runs `function_call` against the script of a strategy with the same globals as `WasmerEnv`.
+pub trait StrategyEngine {
+    fn invoke(&mut self, script: &str, user_input: Value, resource_pool_properties: Value, resource_pool: Value,
+              current_resources: &mut dyn CurrentResources, function_call: &str) -> Result<Value>;
+
+    fn invoke_and_parse(&mut self, script: &str, user_input: Value, resource_pool_properties: Value,
+                        resource_pool: Value, current_resources: &mut dyn CurrentResources, function_call: &str)
+                        -> Result<Vec<Value>> {
+        let val = self.invoke(script, user_input, resource_pool_properties,
+                              resource_pool, current_resources, function_call)?;
+        val.as_array()
+            .ok_or(anyhow!("Script did not return an array"))
+            .map(|vec| vec.to_owned())
+    }
+}
+
+impl StrategyEngine for WasmerEnv {
+    fn invoke(&mut self, script: &str, user_input: Value, resource_pool_properties: Value, resource_pool: Value,
+              current_resources: &mut dyn CurrentResources, function_call: &str) -> Result<Value> {
+        self.invoke_and_parse_value(script, user_input, resource_pool_properties, resource_pool,
+                                    current_resources, function_call)
+    }
+}
+
+impl WasmerEnv {
+    // Engines other than the subprocess are not part of this build yet, strategies assigned to them fail.
+    pub(crate) fn engine(&mut self, engine: Engine) -> Result<&mut dyn StrategyEngine> {
+        match engine {
+            Engine::QuickjsSubprocess => Ok(self),
+            other => bail!("Engine '{}' is not available in this build", other),
+        }
+    }
+}
+
+impl DB {
+    pub fn get_strategy_engine(&mut self, allocation_strategy_id: i64) -> Result<Engine> {
+        let row = self.client.query_one(
+            "SELECT engine FROM allocation_strategies WHERE id=$1", &[&allocation_strategy_id])?;
+        row.get::<_, &str>(0).parse()
+    }
+
+    // Moves the strategy, and so all pools using it, to another engine. Engines that are not a part of the build
+    // are refused instead of failing every allocation of the strategy.
+    pub fn set_strategy_engine(&mut self, allocation_strategy_id: i64, engine: Engine) -> Result<()> {
+        engine.check_available()?;
+        let updated = self.client.execute(
+            "UPDATE allocation_strategies SET engine=$2 WHERE id=$1", &[&allocation_strategy_id, &engine.as_str()])?;
+        if updated == 0 {
+            bail!("Allocation strategy {} not found", allocation_strategy_id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::AllocationOptions;
+    use crate::error::AllocationError;
+    use crate::strategy::StrategyFiles;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_strategy_engine() {
+        initialize_logging();
+
+        assert_eq!(Engine::WasmModule, "wasm-module".parse().unwrap());
+        assert!("v8".parse::<Engine>().is_err());
+
+        let mut db = DB::new_from_env().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let strategy_id = db.insert_allocation_strategy(&name, "function invoke() { return [{ n: 1 }] }", None,
+                                                        &StrategyFiles::new()).unwrap();
+        assert_eq!(Engine::QuickjsSubprocess, db.get_strategy_engine(strategy_id).unwrap());
+        let pool = db.insert_resource_pool(&name, strategy_id).unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let dry_run = AllocationOptions { dry_run: true, ..AllocationOptions::default() };
+        let (_, resources) = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &dry_run).unwrap();
+        assert_eq!(1, resources.len());
+
+        let err = db.set_strategy_engine(strategy_id, Engine::Native).unwrap_err();
+        assert_eq!("Engine 'native' is not available in this build", err.to_string());
+        db.set_strategy_engine(strategy_id, Engine::QuickjsSubprocess).unwrap();
+        // assigned by a build having the engine
+        db.client.execute("UPDATE allocation_strategies SET engine='native' WHERE id=$1", &[&strategy_id]).unwrap();
+        let err = db.allocate_resources(pool, &mut wasmer_env, json!({}), &dry_run).unwrap_err();
+        assert_eq!("Engine 'native' is not available in this build", err.to_string());
+    }
+
+    #[test]
+    fn wasmer_strategy_errors() {
+        initialize_logging();
+
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let mut invoke = |function_call: &str| wasmer_env.invoke_and_parse("", json!({}), json!({}), json!({}),
+                                                                           &mut vec![], function_call).unwrap_err();
+        let exhausted = AllocationError::Strategy {
+            code: "POOL_EXHAUSTED".into(),
+            message: "No free addresses".into(),
+            details: json!({"free": 0}),
+        };
+        let err = invoke("(() => { throw { code: 'POOL_EXHAUSTED', message: 'No free addresses', details: { free: 0 } } })()");
+        assert_eq!(Some(&exhausted), err.downcast_ref::<AllocationError>());
+        let err = invoke("({ error: { code: 'POOL_EXHAUSTED', message: 'No free addresses', details: { free: 0 } } })");
+        assert_eq!(Some(&exhausted), err.downcast_ref::<AllocationError>());
+        // other exceptions fail the script as before
+        let err = invoke("(() => { throw new Error('bad input') })()");
+        assert_eq!(None, err.downcast_ref::<AllocationError>());
+    }
+}
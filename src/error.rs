@@ -0,0 +1,121 @@
+use std::fmt;
+
+use serde_json::Value;
+
+use crate::state::ResourceState;
+
+/// Errors callers may want to handle programmatically. They are returned wrapped in
+/// `anyhow::Error`, use `downcast_ref::<AllocationError>()` to inspect them.
+#[derive(Debug, PartialEq)]
+pub enum AllocationError {
+    ResourceNotFound { resource_pool: String, resource: String },
+    IllegalTransition { resource: String, from: ResourceState, to: ResourceState },
+    // claimed resources are deallocated only when forced, see `DB::force_deallocate_resource`
+    ResourceClaimed { resource_pool: String, resource: String },
+    InvalidPoolProperties { resource_pool: String, reason: String },
+    // `userInput` does not match the strategy's `InputSchema`
+    InvalidUserInput { resource_pool: String, errors: Vec<FieldError> },
+    // Postgres was not reachable, see `ConnectRetry`
+    DatabaseUnavailable { attempts: u32, reason: String },
+    // `statement_timeout` or `lock_timeout` of an allocation transaction, see `TransactionTimeouts`
+    Timeout { resource_pool: String, reason: String },
+    // a strategy failed to compile or link when it was saved
+    InvalidStrategy { strategy: String, reason: String },
+    // thrown by the strategy as `{code, message, details}` or returned as `{error: {code, message, details}}`
+    Strategy { code: String, message: String, details: Value },
+    // a script wrote more than `WasmerEnv::max_output_bytes` to stdout
+    OutputTooLarge { limit: u64 },
+    // the pool was changed concurrently, its version is no longer `expected`
+    VersionConflict { resource_pool: String, expected: i32 },
+    // the pool and its resources are read-only until it is unarchived
+    PoolArchived { resource_pool: String },
+    // the value is in use by `resource_pool`, another pool of the uniqueness group
+    UniquenessConflict { uniqueness_group: String, resource_pool: String, value: Value },
+    // `userInput.desiredValue` is not valid within the pool properties or is already in use
+    ValueUnavailable { resource_pool: String, value: Value, reason: String },
+    // writes are refused while the process is in maintenance mode, reads keep working
+    ServiceReadOnly,
+    // a hook of the pool failing its allocations on errors failed or timed out, see `PoolHook`
+    HookFailed { resource_pool: String, hook: String, reason: String },
+    // the resource has no lease or it has expired, see `DB::renew_lease`
+    LeaseExpired { resource: String },
+    // the lease cannot be extended past `max_lease_lifetime` counted from creation of the resource
+    LeaseLifetimeExceeded { resource: String },
+}
+
+/// Value of `userInput` failing a keyword of the schema, `field` is its path, e.g. `ports[0]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.field.is_empty() {
+            f.write_str(&self.message)
+        } else {
+            write!(f, "{} {}", self.field, self.message)
+        }
+    }
+}
+
+impl fmt::Display for AllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocationError::ResourceNotFound { resource_pool, resource } =>
+                write!(f, "Resource {} not found in pool '{}'", resource, resource_pool),
+            AllocationError::IllegalTransition { resource, from, to } =>
+                write!(f, "Resource {} cannot be moved from {} to {}", resource, from, to),
+            AllocationError::ResourceClaimed { resource_pool, resource } =>
+                write!(f, "Resource {} of pool '{}' is claimed", resource, resource_pool),
+            AllocationError::InvalidPoolProperties { resource_pool, reason } =>
+                write!(f, "Invalid properties of pool '{}': {}", resource_pool, reason),
+            AllocationError::InvalidUserInput { resource_pool, errors } => {
+                let errors = errors.iter().map(FieldError::to_string).collect::<Vec<_>>();
+                write!(f, "Invalid input of pool '{}': {}", resource_pool, errors.join(", "))
+            }
+            AllocationError::DatabaseUnavailable { attempts, reason } =>
+                write!(f, "Database unavailable after {} attempts: {}", attempts, reason),
+            AllocationError::Timeout { resource_pool, reason } =>
+                write!(f, "Allocation in pool '{}' was cancelled by {}", resource_pool, reason),
+            AllocationError::InvalidStrategy { strategy, reason } =>
+                write!(f, "Invalid strategy '{}': {}", strategy, reason),
+            AllocationError::Strategy { code, message, .. } =>
+                write!(f, "Strategy failed with {}: {}", code, message),
+            AllocationError::OutputTooLarge { limit } =>
+                write!(f, "Script output exceeded the limit of {} bytes", limit),
+            AllocationError::VersionConflict { resource_pool, expected } =>
+                write!(f, "Pool '{}' was modified concurrently, expected version {}", resource_pool, expected),
+            AllocationError::PoolArchived { resource_pool } =>
+                write!(f, "Pool '{}' is archived and cannot be changed", resource_pool),
+            AllocationError::UniquenessConflict { uniqueness_group, resource_pool, value } =>
+                write!(f, "Value {} is in use by pool '{}' of uniqueness group '{}'", value, resource_pool,
+                       uniqueness_group),
+            AllocationError::ValueUnavailable { resource_pool, value, reason } =>
+                write!(f, "Value {} is not available in pool '{}': {}", value, resource_pool, reason),
+            AllocationError::ServiceReadOnly =>
+                f.write_str("Service is read-only during maintenance, retry later"),
+            AllocationError::HookFailed { resource_pool, hook, reason } =>
+                write!(f, "The {} hook of pool '{}' failed: {}", hook, resource_pool, reason),
+            AllocationError::LeaseExpired { resource } =>
+                write!(f, "Resource {} has no unexpired lease", resource),
+            AllocationError::LeaseLifetimeExceeded { resource } =>
+                write!(f, "Lease of resource {} has reached the maximum lifetime", resource),
+        }
+    }
+}
+
+impl AllocationError {
+    // Error envelope of a strategy result, None for any other value.
+    pub fn from_envelope(result: &Value) -> Option<AllocationError> {
+        let error = result.as_object()?.get("error")?;
+        Some(AllocationError::Strategy {
+            code: error["code"].as_str()?.to_owned(),
+            message: error["message"].as_str().unwrap_or_default().to_owned(),
+            details: error["details"].clone(),
+        })
+    }
+}
+
+impl std::error::Error for AllocationError {}
@@ -0,0 +1,182 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use postgres::fallible_iterator::FallibleIterator;
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{DB, Resource, ResourcePool};
+use crate::shutdown::Shutdown;
+use crate::transaction::Transaction;
+
+// Channel of `NOTIFY`, suffixed with the schema so that instances sharing a database do not see each other's events.
+// Payloads are JSON objects described by `DB::notify_pool_event`.
+const CHANNEL_PREFIX: &str = "pool_events.";
+// Postgres refuses payloads of 8000 bytes and more, larger events are sent without resources.
+const MAX_PAYLOAD_BYTES: usize = 7900;
+// How often the listening thread checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// Events are recorded in the audit log as `resources_<event>`, ids of the entries identify events.
+const AUDIT_ACTION_PREFIX: &str = "resources_";
+
+impl DB {
+    // Records the event in the audit log and notifies subscribers of the pool once the transaction commits:
+    // `allocated` for inserted resources, `deallocated` for resources moved to bench or retired
+    // and `state_changed` for other transitions. The `request` of an allocation is only kept in the audit log,
+    // see `DB::replay_pool`. With `outbox` the event is also written to the outbox, see `DB::drain_outbox`.
+    pub(crate) fn notify_pool_event(transaction: &mut Transaction, outbox: bool, pool: &ResourcePool, event: &str,
+                                    resources: &[Resource], request: Option<&Value>) -> Result<()> {
+        let mut details = json!({
+            "version": pool.version,
+            "resources": resources.iter()
+                .map(|resource| json!({"id": resource.id, "value": resource.value, "state": resource.state.as_str()}))
+                .collect::<Vec<_>>(),
+        });
+        if let Some(request) = request {
+            details["request"] = request.clone();
+        }
+        let id = Self::record_audit(transaction, Some(pool.id), &format!("{}{}", AUDIT_ACTION_PREFIX, event),
+                                    details.clone())?;
+        Self::publish_pool_event(transaction, outbox, id, pool.id, Self::pool_event_json(id, pool.id, event, details))
+    }
+
+    // Notifies subscribers of the pool of the event once the transaction commits, with `outbox` it is also written
+    // to the outbox. The id of the audit log entry of the event identifies it.
+    pub(crate) fn publish_pool_event(transaction: &mut Transaction, outbox: bool, id: i64, resource_pool_id: i64,
+                                     mut payload: Value) -> Result<()> {
+        if outbox {
+            transaction.execute("INSERT INTO outbox (id, resource_pool, payload) VALUES ($1, $2, $3)",
+                                &[&id, &resource_pool_id, &payload])?;
+        }
+        if payload.to_string().len() > MAX_PAYLOAD_BYTES {
+            payload["resources"] = json!([]);
+            payload["truncated"] = json!(true);
+        }
+        transaction.execute("SELECT pg_notify($1 || current_schema(), $2)", &[&CHANNEL_PREFIX, &payload.to_string()])?;
+        Ok(())
+    }
+
+    fn pool_event_json(id: i64, resource_pool_id: i64, event: &str, details: Value) -> Value {
+        json!({
+            "id": id,
+            "pool": resource_pool_id,
+            "version": details["version"],
+            "event": event,
+            "resources": details["resources"],
+        })
+    }
+
+    // Events of the pool recorded after the audit log entry `after_id`, oldest first. Unlike notifications
+    // these are never truncated. Read from the primary, a lagging replica would lose events of resumed streams.
+    pub fn get_pool_events(&mut self, resource_pool_id: i64, after_id: i64, limit: i64) -> Result<Vec<Value>> {
+        let rows = self.client.query(
+            "SELECT id, action, details FROM audit_log WHERE resource_pool=$1 AND id > $2 \
+            AND starts_with(action, $3) ORDER BY id LIMIT $4",
+            &[&resource_pool_id, &after_id, &AUDIT_ACTION_PREFIX, &limit])?;
+        Ok(rows.into_iter()
+            .map(|row| {
+                let action: &str = row.get(1);
+                Self::pool_event_json(row.get(0), resource_pool_id, &action[AUDIT_ACTION_PREFIX.len()..], row.get(2))
+            })
+            .collect())
+    }
+}
+
+type Subscribers = Arc<Mutex<Vec<(i64, Sender<Value>)>>>;
+
+/// Forwards events of `NOTIFY "pool_events.<schema>"` to subscribers of the pool, using one listening connection.
+#[derive(Clone)]
+pub struct PoolEvents {
+    subscribers: Subscribers,
+}
+
+impl PoolEvents {
+    // Listens until shutdown is requested. Events committed after this returns are delivered.
+    pub fn start(shutdown: Shutdown) -> Result<PoolEvents> {
+        let mut db = DB::new_from_env()?;
+        let schema: String = db.client.query_one("SELECT current_schema()", &[])?.get(0);
+        db.client.batch_execute(&format!("LISTEN \"{}{}\"", CHANNEL_PREFIX, schema.replace('"', "\"\"")))?;
+        let subscribers = Subscribers::default();
+        let dispatched = subscribers.clone();
+        thread::Builder::new().name("events".to_owned()).spawn(move || {
+            while !shutdown.is_requested() {
+                let mut notifications = db.client.notifications();
+                let mut received = notifications.timeout_iter(POLL_INTERVAL);
+                loop {
+                    match received.next() {
+                        Ok(Some(notification)) => Self::dispatch(&dispatched, notification.payload()),
+                        Ok(None) => break,
+                        Err(err) => {
+                            error!("Cannot receive pool events: {}", err);
+                            return;
+                        }
+                    }
+                }
+            }
+        })?;
+        Ok(PoolEvents { subscribers })
+    }
+
+    fn dispatch(subscribers: &Subscribers, payload: &str) {
+        let event: Value = match serde_json::from_str(payload) {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("Invalid pool event '{}': {}", payload, err);
+                return;
+            }
+        };
+        let pool_id = event["pool"].as_i64();
+        let mut subscribers = subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // receivers of closed subscriptions are dropped
+        subscribers.retain(|(subscribed, sender)| {
+            Some(*subscribed) != pool_id || sender.send(event.clone()).is_ok()
+        });
+    }
+
+    // Events of the pool until the receiver is dropped.
+    pub fn subscribe(&self, resource_pool_id: i64) -> Receiver<Value> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push((resource_pool_id, sender));
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{AllocationOptions, BulkSelector, ResourceState, WasmerEnv};
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_pool_events() {
+        initialize_logging();
+
+        let events = PoolEvents::start(Shutdown::new()).unwrap();
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let other = create_random_pool(&mut db).unwrap();
+        let receiver = events.subscribe(pool.id);
+        db.allocate_resources(other, &mut wasmer_env, json!({}), &AllocationOptions::default()).unwrap();
+        let (pool, allocated) = db.allocate_resources(pool, &mut wasmer_env, json!({}),
+                                                      &AllocationOptions::default()).unwrap();
+        db.deallocate_resources(pool.clone(), &BulkSelector::State(ResourceState::Allocated)).unwrap();
+
+        let event = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((json!(pool.id), json!("allocated"), &allocated[0].value),
+                   (event["pool"].clone(), event["event"].clone(), &event["resources"][0]["value"]));
+        let deallocated = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((json!("deallocated"), json!("retired")),
+                   (deallocated["event"].clone(), deallocated["resources"][0]["state"].clone()));
+
+        assert_eq!(vec![event.clone(), deallocated.clone()], db.get_pool_events(pool.id, 0, 10).unwrap());
+        let first_id = event["id"].as_i64().unwrap();
+        assert_eq!(vec![deallocated], db.get_pool_events(pool.id, first_id, 10).unwrap());
+    }
+}
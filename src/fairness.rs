@@ -0,0 +1,112 @@
+use anyhow::Result;
+use serde_json::Value;
+use tracing::*;
+
+use crate::{AllocationOptions, DB, Resource, ResourcePool, WasmerEnv};
+use crate::timings::Timings;
+
+// First half of the advisory lock keys of allocation queues, the second one is the lower half of the pool id,
+// pools 2^32 ids apart share a queue.
+const QUEUE_LOCK_CLASS: i32 = 0x726d_7175;
+
+// Advisory locks are database wide, the class is combined with the schema so that instances do not share queues.
+const QUEUE_LOCK_KEY: &str = "$1 # coalesce(hashtext(current_schema()), 0), (($2::bigint << 32) >> 32)::int";
+
+impl DB {
+    // With `fair_allocation`, allocations of a pool wait for their turn in the order they arrived and each is served
+    // against the current version of the pool, instead of failing with `AllocationError::VersionConflict` while
+    // callers retrying sooner get ahead. The queue is a session level advisory lock of the pool, Postgres grants
+    // waiters of a lock in the order they requested it. Dry runs do not wait.
+    pub(crate) fn allocate_in_turn(&mut self, pool: ResourcePool, wasmer_env: &mut WasmerEnv, user_input: Value,
+                                   options: &AllocationOptions, timings: &mut Timings)
+                                   -> Result<(ResourcePool, Vec<Resource>)> {
+        if !self.fair_allocation || options.dry_run {
+            return self.try_allocate_resources(pool, wasmer_env, user_input, options, timings);
+        }
+        let resource_pool_id = pool.id;
+        timings.db_read("allocation_queue", || self.wait_for_turn(&pool))?;
+        let result = self.get_resource_pool_by_id(resource_pool_id)
+            .and_then(|pool| self.try_allocate_resources(pool, wasmer_env, user_input, options, timings));
+        // the lock is released with the session if the connection is lost
+        if let Err(err) = self.end_turn(resource_pool_id) {
+            warn!("Cannot end the turn of pool {}: {:#}", resource_pool_id, err);
+        }
+        result
+    }
+
+    // Waiting is limited by the configured `lock_timeout`, exceeding it fails with `AllocationError::Timeout`.
+    fn wait_for_turn(&mut self, pool: &ResourcePool) -> Result<()> {
+        let mut transaction = self.client.transaction()?;
+        if let Some(lock_timeout) = self.timeouts.lock_timeout {
+            transaction.execute("SELECT set_config('lock_timeout', $1, true)",
+                                &[&format!("{}ms", lock_timeout.as_millis())])?;
+        }
+        // held after the commit, session level locks do not end with the transaction
+        transaction.execute(format!("SELECT pg_advisory_lock({})", QUEUE_LOCK_KEY).as_str(),
+                            &[&QUEUE_LOCK_CLASS, &pool.id])
+            .map_err(|err| Self::timeout_error(&pool.name, err.into()))?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn end_turn(&mut self, resource_pool_id: i64) -> Result<()> {
+        self.client.execute(format!("SELECT pg_advisory_unlock({})", QUEUE_LOCK_KEY).as_str(),
+                            &[&QUEUE_LOCK_CLASS, &resource_pool_id])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    // Allocations of other connections waiting in the queue of the pool.
+    fn waiting(db: &mut DB, resource_pool_id: i64) -> i64 {
+        db.client.query_one("SELECT count(*) FROM pg_locks WHERE locktype = 'advisory' AND NOT granted \
+                            AND classid::int = $1 # coalesce(hashtext(current_schema()), 0) \
+                            AND objid::int = (($2::bigint << 32) >> 32)::int \
+                            AND objsubid = 2", &[&QUEUE_LOCK_CLASS, &resource_pool_id]).unwrap().get(0)
+    }
+
+    #[test]
+    fn db_fair_allocation() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let stale = create_random_pool(&mut db).unwrap();
+        db.fair_allocation = true;
+        db.allocate_resources(stale.clone(), &mut wasmer_env, json!({}), &options).unwrap();
+        // served against the current version
+        let (pool, _) = db.allocate_resources(stale.clone(), &mut wasmer_env, json!({}), &options).unwrap();
+        assert_eq!(stale.version + 2, pool.version);
+
+        db.wait_for_turn(&pool).unwrap();
+        let mut handles = vec![];
+        for arrived in 1..=3 {
+            let stale = stale.clone();
+            handles.push(thread::spawn(move || {
+                let mut db = DB::new_from_env().unwrap();
+                db.fair_allocation = true;
+                let mut wasmer_env = WasmerEnv::new().unwrap();
+                let (_, resources) = db.allocate_resources(stale, &mut wasmer_env, json!({}),
+                                                           &AllocationOptions::default()).unwrap();
+                resources[0].value.clone()
+            }));
+            while waiting(&mut db, pool.id) < arrived {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        db.end_turn(pool.id).unwrap();
+        let values = handles.into_iter().map(|handle| handle.join().unwrap()).collect::<Vec<_>>();
+        assert_eq!(vec![json!({"address": "10.0.0.2"}), json!({"address": "10.0.0.3"}),
+                        json!({"address": "10.0.0.4"})], values);
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use postgres::{Client, Row};
+use serde_json::{Value, json};
+
+use crate::{DB, WasmerEnv};
+
+/// Named test case of a strategy: input of one invocation and the values it must return.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrategyTest {
+    pub name: String,
+    pub user_input: Value,
+    pub pool_properties: Value,
+    // values in use by the pool
+    pub current_resources: Vec<Value>,
+    pub expected: Vec<Value>,
+}
+
+impl StrategyTest {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "name": &self.name,
+            "userInput": &self.user_input,
+            "poolProperties": &self.pool_properties,
+            "currentResources": &self.current_resources,
+            "expected": &self.expected,
+        })
+    }
+
+    // Inverse of `as_json`, missing input and properties are empty objects and missing resources none.
+    pub fn from_json(test: &Value) -> Result<StrategyTest> {
+        let name = test["name"].as_str().ok_or_else(|| anyhow!("Test '{}' has no name", test))?;
+        let field = |key: &str, default: Value| test.get(key).cloned().unwrap_or(default);
+        let array = |key: &str, default: Value| serde_json::from_value(field(key, default))
+            .context(format!("{} of test '{}' must be a JSON array", key, name));
+        Ok(StrategyTest {
+            name: name.to_owned(),
+            user_input: field("userInput", json!({})),
+            pool_properties: field("poolProperties", json!({})),
+            current_resources: array("currentResources", json!([]))?,
+            expected: array("expected", Value::Null)?,
+        })
+    }
+
+    fn from_row(row: Row) -> Result<StrategyTest> {
+        Ok(StrategyTest {
+            name: row.get(0),
+            user_input: row.get(1),
+            pool_properties: row.get(2),
+            current_resources: serde_json::from_value(row.get(3))?,
+            expected: serde_json::from_value(row.get(4))?,
+        })
+    }
+}
+
+/// Outcome of a test case run by `DB::run_strategy_tests`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrategyTestResult {
+    pub name: String,
+    pub passed: bool,
+    // values returned by the script, None if it failed
+    pub actual: Option<Vec<Value>>,
+    pub error: Option<String>,
+}
+
+impl StrategyTestResult {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "name": &self.name,
+            "passed": self.passed,
+            "actual": &self.actual,
+            "error": &self.error,
+        })
+    }
+}
+
+impl DB {
+    // Replaces the test case of the same name.
+    pub fn put_strategy_test(&mut self, allocation_strategy_id: i64, test: &StrategyTest) -> Result<()> {
+        self.get_strategy(allocation_strategy_id)?;
+        Self::put_strategy_test_row(&mut self.client, allocation_strategy_id, test)
+    }
+
+    pub(crate) fn put_strategy_test_row(client: &mut Client, allocation_strategy_id: i64,
+                                        test: &StrategyTest) -> Result<()> {
+        ensure!(!test.name.is_empty(), "Name of the test must not be empty");
+        ensure!(test.user_input.is_object(), "User input of test '{}' must be a JSON object", test.name);
+        client.execute(
+            "INSERT INTO allocation_strategy_tests \
+            (allocation_strategy_id, name, user_input, pool_properties, current_resources, expected) \
+            VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT (allocation_strategy_id, name) DO UPDATE SET \
+            user_input = excluded.user_input, pool_properties = excluded.pool_properties, \
+            current_resources = excluded.current_resources, expected = excluded.expected",
+            &[&allocation_strategy_id, &test.name, &test.user_input, &test.pool_properties,
+                &json!(test.current_resources), &json!(test.expected)])?;
+        Ok(())
+    }
+
+    pub fn delete_strategy_test(&mut self, allocation_strategy_id: i64, name: &str) -> Result<()> {
+        if self.client.execute("DELETE FROM allocation_strategy_tests WHERE allocation_strategy_id=$1 AND name=$2",
+                               &[&allocation_strategy_id, &name])? == 0 {
+            bail!("Test '{}' of allocation strategy {} not found", name, allocation_strategy_id);
+        }
+        Ok(())
+    }
+
+    // Ordered by name.
+    pub fn get_strategy_tests(&mut self, allocation_strategy_id: i64) -> Result<Vec<StrategyTest>> {
+        Self::get_strategy_test_rows(&mut self.client, allocation_strategy_id)
+    }
+
+    pub(crate) fn get_strategy_test_rows(client: &mut Client, allocation_strategy_id: i64)
+                                         -> Result<Vec<StrategyTest>> {
+        let rows = client.query(
+            "SELECT name, user_input, pool_properties, current_resources, expected FROM allocation_strategy_tests \
+            WHERE allocation_strategy_id=$1 ORDER BY name", &[&allocation_strategy_id])?;
+        rows.into_iter().map(StrategyTest::from_row).collect()
+    }
+
+    // Invokes the stored script with the engine of the strategy once per test case, pools are not touched.
+    // A failing case does not stop the others, errors of the strategy itself, e.g. a missing engine, fail the run.
+    pub fn run_strategy_tests(&mut self, allocation_strategy_id: i64, wasmer_env: &mut WasmerEnv)
+                              -> Result<Vec<StrategyTestResult>> {
+        let strategy = self.get_strategy(allocation_strategy_id)?;
+        let script = self.get_allocation_script(allocation_strategy_id)?;
+        let engine = wasmer_env.engine(strategy.engine)?;
+        let tests = self.get_strategy_tests(allocation_strategy_id)?;
+        Ok(tests.into_iter()
+            .map(|test| {
+                let mut current_resources = test.current_resources.iter()
+                    .map(|value| json!({"Properties": value}))
+                    .collect::<Vec<_>>();
+                match engine.invoke_and_parse(&script, test.user_input, test.pool_properties, json!({}),
+                                              &mut current_resources, "invoke()") {
+                    Ok(actual) => StrategyTestResult {
+                        name: test.name,
+                        passed: actual == test.expected,
+                        actual: Some(actual),
+                        error: None,
+                    },
+                    Err(err) => StrategyTestResult {
+                        name: test.name,
+                        passed: false,
+                        actual: None,
+                        error: Some(format!("{:#}", err)),
+                    },
+                }
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::strategy::StrategyFiles;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_strategy_tests() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() {\n\
+            if (userInput.fail) { throw 'failed' }\n\
+            return [{ n: resourcePoolProperties.start + currentResources.length }]\n}", None,
+                                               &StrategyFiles::new()).unwrap();
+        let test = |name: &str, expected: Value| StrategyTest {
+            name: name.to_owned(),
+            user_input: json!({}),
+            pool_properties: json!({"start": 10}),
+            current_resources: vec![json!({"n": 10})],
+            expected: serde_json::from_value(expected).unwrap(),
+        };
+        db.put_strategy_test(id, &test("next", json!([{"n": 10}]))).unwrap();
+        // replaced by name
+        db.put_strategy_test(id, &test("next", json!([{"n": 11}]))).unwrap();
+        db.put_strategy_test(id, &test("wrong", json!([{"n": 12}]))).unwrap();
+        db.put_strategy_test(id, &StrategyTest { user_input: json!({"fail": true}), ..test("failing", json!([])) })
+            .unwrap();
+        assert_eq!(3, db.get_strategy_tests(id).unwrap().len());
+
+        let results = db.run_strategy_tests(id, &mut wasmer_env).unwrap();
+        let outcomes = results.iter().map(|result| (result.name.as_str(), result.passed)).collect::<Vec<_>>();
+        assert_eq!(vec![("failing", false), ("next", true), ("wrong", false)], outcomes);
+        assert!(results[0].error.as_ref().unwrap().contains("failed"), "{:?}", results[0]);
+        assert_eq!(Some(vec![json!({"n": 11})]), results[2].actual);
+
+        db.delete_strategy_test(id, "wrong").unwrap();
+        assert!(db.delete_strategy_test(id, "wrong").is_err());
+        assert!(db.put_strategy_test(i64::MAX, &test("next", json!([]))).is_err());
+    }
+}
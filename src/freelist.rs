@@ -0,0 +1,147 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{Result, ensure};
+use postgres::Client;
+use serde_json::{Value, json};
+
+use crate::{DB, ResourcePool};
+use crate::blocks::BlockRequest;
+use crate::desired::desired_value;
+use crate::error::AllocationError;
+use crate::subranges::SubRange;
+
+/// Inclusive range of free addresses of a pool with a sequential strategy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeRange {
+    pub first: Ipv4Addr,
+    pub last: Ipv4Addr,
+}
+
+impl FreeRange {
+    pub fn as_json(&self) -> Value {
+        json!({"first": self.first.to_string(), "last": self.last.to_string()})
+    }
+}
+
+impl DB {
+    // Lowest free addresses of a pool with a sequential strategy, the same ones its script would return,
+    // taken from sub-ranges of the pool in their order, see `SubRange::allocation_order`.
+    // The free list is read outside of the insert transaction like resources read by scripts, allocations
+    // racing for the same addresses fail on the pool version. `bounds` are addresses allocated by the strategy,
+    // see `AllocationContext`, None if it is not sequential.
+    pub(crate) fn find_free_addresses(&mut self, pool: &ResourcePool, bounds: Option<(i64, i64)>, user_input: &Value)
+                                      -> Result<Option<Vec<Value>>> {
+        let (mut first, mut last) = match bounds {
+            Some(bounds) => bounds,
+            None => return Ok(None),
+        };
+        // subnet address and broadcast are not assignable
+        if user_input["subnet"] == true {
+            first += 1;
+            last -= 1;
+        }
+        let sub_ranges = SubRange::of_pool(pool)?;
+        let selected = SubRange::selected(pool, &sub_ranges, user_input)?;
+        let order = SubRange::allocation_order(&sub_ranges, selected, first, last);
+        if let Some(desired) = desired_value(user_input)? {
+            return self.find_desired_address(pool, (first, last), &order, desired).map(Some);
+        }
+        if let Some(block) = BlockRequest::from_user_input(user_input)? {
+            ensure!(block.members, "Members of blocks of pool '{}' are kept on its free list", pool.name);
+            return self.find_free_block(pool, &order, block.size).map(Some);
+        }
+        let count = user_input["resourceCount"].as_i64().unwrap_or(1);
+        let mut addresses = Vec::new();
+        for (first, last) in order {
+            let missing = count - addresses.len() as i64;
+            if missing == 0 {
+                break;
+            }
+            // every range holds at least one address
+            let rows = self.client.query(
+                "SELECT greatest(first, $2), least(last, $3) FROM free_ranges \
+                WHERE resource_pool=$1 AND last >= $2 AND first <= $3 ORDER BY first LIMIT $4",
+                &[&pool.id, &first, &last, &missing])?;
+            addresses.extend(rows.iter()
+                .flat_map(|row| row.get::<_, i64>(0)..=row.get::<_, i64>(1))
+                .take(missing as usize)
+                .map(|address| json!({"address": Ipv4Addr::from(address as u32).to_string()})));
+        }
+        if (addresses.len() as i64) < count {
+            return Err(AllocationError::Strategy {
+                code: "POOL_EXHAUSTED".to_owned(),
+                message: format!("Insufficient capacity to allocate {} new address(es)", count),
+                details: json!({"requested": count, "free": addresses.len()}),
+            }.into());
+        }
+        Ok(Some(addresses))
+    }
+
+    // Recomputes the free list from resources in use, no-op for pools without a sequential strategy.
+    pub(crate) fn rebuild_free_ranges(client: &mut Client, resource_pool_id: i64) -> Result<()> {
+        client.execute("SELECT rebuild_free_ranges($1)", &[&resource_pool_id])?;
+        Ok(())
+    }
+
+    pub fn get_free_ranges(&mut self, resource_pool_id: i64) -> Result<Vec<FreeRange>> {
+        let rows = self.reader().query(
+            "SELECT first, last FROM free_ranges WHERE resource_pool=$1 ORDER BY first", &[&resource_pool_id])?;
+        Ok(rows.into_iter()
+            .map(|row| FreeRange {
+                first: Ipv4Addr::from(row.get::<_, i64>(0) as u32),
+                last: Ipv4Addr::from(row.get::<_, i64>(1) as u32),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AllocationOptions, ResourceSelector, WasmerEnv};
+    use crate::state::ResourceState;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    fn range(first: &str, last: &str) -> FreeRange {
+        FreeRange { first: first.parse().unwrap(), last: last.parse().unwrap() }
+    }
+
+    #[test]
+    fn db_free_ranges() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        assert_eq!(vec![range("10.0.0.0", "10.255.255.255")], db.get_free_ranges(pool.id).unwrap());
+
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 3}),
+                                                      &AllocationOptions::default()).unwrap();
+        let addresses = resources.iter().map(|it| it.value["address"].clone()).collect::<Vec<_>>();
+        assert_eq!(vec!["10.0.0.0", "10.0.0.1", "10.0.0.2"], addresses);
+        assert_eq!(vec![range("10.0.0.3", "10.255.255.255")], db.get_free_ranges(pool.id).unwrap());
+
+        // the gap is allocated first
+        let (pool, _) = db.transition_resource(pool, &ResourceSelector::Value(json!({"address": "10.0.0.1"})),
+                                               ResourceState::Retired).unwrap();
+        assert_eq!(vec![range("10.0.0.1", "10.0.0.1"), range("10.0.0.3", "10.255.255.255")],
+                   db.get_free_ranges(pool.id).unwrap());
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 2}),
+                                                      &AllocationOptions::default()).unwrap();
+        let addresses = resources.iter().map(|it| it.value["address"].clone()).collect::<Vec<_>>();
+        assert_eq!(vec!["10.0.0.1", "10.0.0.3"], addresses);
+        let (_, resources) = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({"subnet": true}),
+                                                   &AllocationOptions { dry_run: true, ..Default::default() })
+            .unwrap();
+        assert_eq!(json!({"address": "10.0.0.4"}), resources[0].value);
+
+        // shrinking the pool rebuilds the free list
+        let pool = db.update_pool_properties(pool, &mut wasmer_env, json!({"address": "10.0.0.0", "prefix": 29}))
+            .unwrap();
+        assert_eq!(vec![range("10.0.0.4", "10.0.0.7")], db.get_free_ranges(pool.id).unwrap());
+        let err = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 5}),
+                                        &AllocationOptions::default()).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(),
+                         Some(AllocationError::Strategy { code, .. }) if code == "POOL_EXHAUSTED"), "{}", err);
+    }
+}
@@ -0,0 +1,206 @@
+use std::net::{SocketAddr, TcpListener};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tracing::*;
+
+use crate::{DB, Resource};
+use crate::shutdown::Shutdown;
+
+include!(concat!(env!("OUT_DIR"), "/rm.v1.Resources.rs"));
+
+use resources_server::{Resources, ResourcesServer};
+
+const DEFAULT_BATCH_SIZE: i32 = 1000;
+// Resources read from the database but not sent yet, a batch waits until they are sent.
+const STREAM_BUFFER: usize = 1;
+// How often the server checks for shutdown.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Request of `ListResources`, see proto/resources.proto.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListResourcesRequest {
+    #[prost(int64, tag = "1")]
+    pub pool_id: i64,
+    // 0 for `DEFAULT_BATCH_SIZE`
+    #[prost(int32, tag = "2")]
+    pub batch_size: i32,
+}
+
+/// `Resource` of proto/resources.proto, values and metadata are JSON.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ResourceMessage {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(string, tag = "2")]
+    pub value: String,
+    #[prost(string, tag = "3")]
+    pub state: String,
+    #[prost(string, tag = "4")]
+    pub metadata: String,
+    #[prost(string, optional, tag = "5")]
+    pub owner: Option<String>,
+    #[prost(string, optional, tag = "6")]
+    pub description: Option<String>,
+}
+
+impl ResourceMessage {
+    fn of(resource: &Resource) -> ResourceMessage {
+        ResourceMessage {
+            id: resource.id.unwrap_or_default(),
+            value: resource.value.to_string(),
+            state: resource.state.as_str().to_owned(),
+            metadata: resource.metadata.to_string(),
+            owner: resource.owner.clone(),
+            description: resource.description.clone(),
+        }
+    }
+}
+
+struct ResourcesService;
+
+#[tonic::async_trait]
+impl Resources for ResourcesService {
+    type ListResourcesStream = ReceiverStream<Result<ResourceMessage, Status>>;
+
+    // Streams from `DB::stream_resources` on a blocking thread with a connection of its own. Sending waits while
+    // the buffer is full, so the cursor is read only as fast as the client receives.
+    async fn list_resources(&self, request: Request<ListResourcesRequest>)
+                            -> Result<Response<Self::ListResourcesStream>, Status> {
+        let ListResourcesRequest { pool_id, batch_size } = request.into_inner();
+        let batch_size = match batch_size {
+            0 => DEFAULT_BATCH_SIZE,
+            batch_size if batch_size < 0 => return Err(Status::invalid_argument("batch_size must be positive")),
+            batch_size => batch_size,
+        };
+        let (sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || {
+            let streamed = DB::new_from_env()
+                .map_err(|err| Status::unavailable(format!("{:#}", err)))
+                .and_then(|mut db| {
+                    db.find_resource_pool_by_id(pool_id)
+                        .map_err(|err| Status::internal(format!("{:#}", err)))?
+                        .ok_or_else(|| Status::not_found(format!("Resource pool {} not found", pool_id)))?;
+                    db.stream_resources(pool_id, batch_size, |resources| {
+                        for resource in &resources {
+                            sender.blocking_send(Ok(ResourceMessage::of(resource)))
+                                .map_err(|_| anyhow!("Client went away"))?;
+                        }
+                        Ok(())
+                    }).map_err(|err| Status::internal(format!("{:#}", err)))
+                });
+            match streamed {
+                Ok(streamed) => debug!("Streamed {} resources of pool {} over gRPC", streamed, pool_id),
+                Err(status) => {
+                    warn!("Streaming resources of pool {} over gRPC failed: {}", pool_id, status.message());
+                    let _ = sender.blocking_send(Err(status));
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// gRPC service `rm.v1.Resources` of proto/resources.proto, served next to the JSON API by `serve --grpc-listen`.
+pub struct GrpcServer {
+    listener: TcpListener,
+}
+
+impl GrpcServer {
+    pub fn bind(address: &str) -> Result<GrpcServer> {
+        let listener = TcpListener::bind(address).context(format!("Cannot listen on {}", address))?;
+        Ok(GrpcServer { listener })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Serves on a runtime of its own until shutdown is requested, streams being sent are finished first.
+    pub fn run(self, shutdown: Shutdown) -> Result<()> {
+        info!("Serving gRPC on {}", self.local_addr()?);
+        self.listener.set_nonblocking(true)?;
+        let runtime = tokio::runtime::Builder::new_multi_thread().thread_name("grpc").enable_all().build()?;
+        runtime.block_on(async move {
+            let listener = tokio::net::TcpListener::from_std(self.listener)?;
+            let requested = async move {
+                while !shutdown.is_requested() {
+                    tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+                }
+            };
+            tonic::transport::Server::builder()
+                .add_service(ResourcesServer::new(ResourcesService))
+                .serve_with_incoming_shutdown(TcpListenerStream::new(listener), requested)
+                .await
+                .context("gRPC server failed")
+        })?;
+        info!("Stopped serving gRPC");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use serde_json::{Value, json};
+    use tonic::Code;
+
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    // Calls `ListResources` the way the generated client would.
+    async fn list_resources(address: SocketAddr, request: ListResourcesRequest)
+                            -> Result<tonic::codec::Streaming<ResourceMessage>, Status> {
+        let channel = tonic::transport::Endpoint::new(format!("http://{}", address)).unwrap().connect().await.unwrap();
+        let mut client = tonic::client::Grpc::new(channel);
+        client.ready().await.unwrap();
+        let path = tonic::codegen::http::uri::PathAndQuery::from_static("/rm.v1.Resources/ListResources");
+        let codec = tonic_prost::ProstCodec::default();
+        Ok(client.server_streaming(Request::new(request), path, codec).await?.into_inner())
+    }
+
+    #[test]
+    fn grpc_list_resources() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = (1..=5)
+            .map(|i| Resource::new_from_value(pool.id, json!({"address": format!("10.0.0.{}", i)})))
+            .collect();
+        db.insert_resources(pool.clone(), resources).unwrap();
+        let server = GrpcServer::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let handle = thread::spawn(move || server.run(shutdown));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let (values, status) = runtime.block_on(async {
+            let request = ListResourcesRequest { pool_id: pool.id, batch_size: 2 };
+            let mut stream = list_resources(address, request).await.unwrap();
+            let mut values = vec![];
+            while let Some(resource) = stream.message().await.unwrap() {
+                assert_eq!("allocated", resource.state);
+                values.push(serde_json::from_str::<Value>(&resource.value).unwrap());
+            }
+            let request = ListResourcesRequest { pool_id: -1, batch_size: 0 };
+            let status = match list_resources(address, request).await {
+                Ok(mut stream) => stream.message().await.unwrap_err(),
+                Err(status) => status,
+            };
+            (values, status)
+        });
+        // closes the connections, the server waits for them when stopping
+        drop(runtime);
+        let expected = (1..=5).map(|i| json!({"address": format!("10.0.0.{}", i)})).collect::<Vec<_>>();
+        assert_eq!(expected, values);
+        assert_eq!(Code::NotFound, status.code());
+
+        shutdown.request();
+        handle.join().unwrap().unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use postgres::Row;
+
+use crate::{DB, ResourcePool};
+
+/// Pool of a subtree returned by `DB::get_pool_tree`, with utilization rolled up from its descendants.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PoolTreeNode {
+    pub pool: ResourcePool,
+    // 0 for the root of the queried subtree
+    pub depth: i32,
+    // resources of this pool that are not retired
+    pub resource_count: i64,
+    // resources of this pool and all of its descendants
+    pub subtree_resource_count: i64,
+}
+
+impl DB {
+    // Pool with all of its descendants, depth first, children ordered by id.
+    pub fn get_pool_tree(&mut self, root_id: i64) -> Result<Vec<PoolTreeNode>> {
+        let rows = self.reader().query(
+            format!("WITH RECURSIVE tree(id, depth, path) AS ( \
+                SELECT id, 0, ARRAY[id] FROM resource_pools WHERE id=$1 \
+                UNION ALL \
+                SELECT child.id, tree.depth + 1, tree.path || child.id \
+                FROM resource_pools child JOIN tree ON child.parent_pool = tree.id \
+                WHERE NOT child.id = ANY(tree.path) \
+            ), counts AS ( \
+                SELECT tree.id, tree.depth, tree.path, \
+                (SELECT count(*) FROM resources WHERE resource_pool = tree.id AND status <> 'retired') \
+                AS resource_count FROM tree \
+            ) \
+            SELECT {}, depth, resource_count, \
+            (SELECT sum(nested.resource_count) FROM counts nested WHERE counts.id = ANY(nested.path))::bigint \
+            FROM counts JOIN resource_pools USING (id) ORDER BY path",
+                    Self::RESOURCE_POOL_COLUMNS).as_str(),
+            &[&root_id])?;
+        rows.into_iter().map(Self::row_to_pool_tree_node).collect()
+    }
+
+    // Parent, grandparent, ... of the pool, nearest first.
+    pub fn get_ancestors(&mut self, pool_id: i64) -> Result<Vec<ResourcePool>> {
+        let rows = self.client.query(
+            format!("WITH RECURSIVE ancestors(id, path) AS ( \
+                SELECT parent_pool, ARRAY[id] FROM resource_pools WHERE id=$1 AND parent_pool IS NOT NULL \
+                UNION ALL \
+                SELECT parent.parent_pool, ancestors.path || parent.id \
+                FROM resource_pools parent JOIN ancestors ON parent.id = ancestors.id \
+                WHERE parent.parent_pool IS NOT NULL AND NOT parent.parent_pool = ANY(ancestors.path) \
+            ) \
+            SELECT {} FROM ancestors JOIN resource_pools USING (id) ORDER BY array_length(path, 1)",
+                    Self::RESOURCE_POOL_COLUMNS).as_str(),
+            &[&pool_id])?;
+        rows.into_iter().map(Self::row_to_resource_pool).collect()
+    }
+
+    fn row_to_pool_tree_node(row: Row) -> Result<PoolTreeNode> {
+        // rollup columns follow RESOURCE_POOL_COLUMNS
+        let first = row.len() - 3;
+        let depth = row.get(first);
+        let resource_count = row.get(first + 1);
+        let subtree_resource_count = row.get(first + 2);
+        let pool = Self::row_to_resource_pool(row)?;
+        Ok(PoolTreeNode { pool, depth, resource_count, subtree_resource_count })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::Resource;
+    use crate::tests::{create_random_pool, initialize_logging, IPV4_ALLOCATION_STRATEGY_ID};
+    use super::*;
+
+    #[test]
+    fn db_pool_tree_and_ancestors() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let root = create_random_pool(&mut db).unwrap();
+        let child_a = db.insert_nested_resource_pool(&format!("{}-a", root.name),
+                                                     IPV4_ALLOCATION_STRATEGY_ID, Some(root.id)).unwrap();
+        let child_b = db.insert_nested_resource_pool(&format!("{}-b", root.name),
+                                                     IPV4_ALLOCATION_STRATEGY_ID, Some(root.id)).unwrap();
+        let grandchild = db.insert_nested_resource_pool(&format!("{}-a-1", root.name),
+                                                        IPV4_ALLOCATION_STRATEGY_ID, Some(child_a.id)).unwrap();
+        let resources = |pool: &ResourcePool, count: usize| (0..count)
+            .map(|i| Resource::new_from_value(pool.id, json!({"address": format!("10.0.0.{}", i)})))
+            .collect::<Vec<_>>();
+        db.insert_resources(root.clone(), resources(&root, 1)).unwrap();
+        db.insert_resources(child_a.clone(), resources(&child_a, 2)).unwrap();
+        db.insert_resources(grandchild.clone(), resources(&grandchild, 3)).unwrap();
+
+        let tree = db.get_pool_tree(root.id).unwrap();
+        let summary = tree.iter()
+            .map(|node| (node.pool.id, node.depth, node.resource_count, node.subtree_resource_count))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![
+            (root.id, 0, 1, 6),
+            (child_a.id, 1, 2, 5),
+            (grandchild.id, 2, 3, 3),
+            (child_b.id, 1, 0, 0),
+        ], summary);
+        assert_eq!(Some(child_a.id), tree[2].pool.parent_id);
+
+        let ancestors = db.get_ancestors(grandchild.id).unwrap()
+            .into_iter().map(|pool| pool.id).collect::<Vec<_>>();
+        assert_eq!(vec![child_a.id, root.id], ancestors);
+        assert!(db.get_ancestors(root.id).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,273 @@
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, bail, ensure};
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::error::AllocationError;
+use crate::{DB, ResourcePool, WasmerEnv};
+
+/// When a hook runs. `pre_allocation` runs `preAllocate()`, which returns the user input the strategy gets,
+/// e.g. with fields added, or throws to reject it. `post_allocation` runs `postAllocate(values)`, which returns
+/// the values to insert in the same order, e.g. with a hostname computed from the address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    PreAllocation,
+    PostAllocation,
+}
+
+impl HookStage {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookStage::PreAllocation => "pre_allocation",
+            HookStage::PostAllocation => "post_allocation",
+        }
+    }
+}
+
+impl FromStr for HookStage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<HookStage> {
+        [HookStage::PreAllocation, HookStage::PostAllocation].iter()
+            .find(|stage| stage.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown hook stage '{}'", s))
+    }
+}
+
+/// What a failing or timed out hook does to the allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookErrorPolicy {
+    // with `AllocationError::HookFailed`, errors thrown as `{code, message, details}` stay strategy errors
+    Fail,
+    // logged, the allocation continues with the input or values the hook got
+    Skip,
+}
+
+impl HookErrorPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookErrorPolicy::Fail => "fail",
+            HookErrorPolicy::Skip => "skip",
+        }
+    }
+}
+
+impl FromStr for HookErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<HookErrorPolicy> {
+        [HookErrorPolicy::Fail, HookErrorPolicy::Skip].iter()
+            .find(|policy| policy.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown hook error policy '{}'", s))
+    }
+}
+
+/// Script of a pool run around its strategy by the quickJS subprocess, with `userInput`
+/// and `resourcePoolProperties` of the allocation. Hooks do not see current resources.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolHook {
+    pub stage: HookStage,
+    pub script: String,
+    pub timeout: Duration,
+    pub on_error: HookErrorPolicy,
+}
+
+impl PoolHook {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "stage": self.stage.as_str(),
+            "script": &self.script,
+            "timeoutMs": self.timeout.as_millis() as u64,
+            "onError": self.on_error.as_str(),
+        })
+    }
+
+    pub(crate) fn from_json(hook: &Value) -> Result<PoolHook> {
+        let field = |key: &str| hook[key].as_str().ok_or_else(|| anyhow!("Hook without '{}': {}", key, hook));
+        Ok(PoolHook {
+            stage: field("stage")?.parse()?,
+            script: field("script")?.to_owned(),
+            timeout: Duration::from_millis(hook["timeoutMs"].as_u64()
+                .ok_or_else(|| anyhow!("Hook without 'timeoutMs': {}", hook))?),
+            on_error: field("onError")?.parse()?,
+        })
+    }
+
+    // Runs `preAllocate()` and returns the user input to allocate with.
+    pub(crate) fn pre_allocate(&self, wasmer_env: &mut WasmerEnv, pool: &ResourcePool, user_input: Value)
+                               -> Result<Value> {
+        let result = self.invoke(wasmer_env, pool, user_input.clone(), "preAllocate()")
+            .and_then(|input| {
+                ensure!(input.is_object(), "preAllocate() did not return an object, got {}", input);
+                Ok(input)
+            });
+        self.outcome(pool, result, user_input)
+    }
+
+    // Runs `postAllocate(values)` and returns the values to insert.
+    pub(crate) fn post_allocate(&self, wasmer_env: &mut WasmerEnv, pool: &ResourcePool, user_input: &Value,
+                                values: Vec<Value>) -> Result<Vec<Value>> {
+        let function_call = format!("postAllocate({})", Value::Array(values.clone()));
+        let result = self.invoke(wasmer_env, pool, user_input.clone(), &function_call)
+            .and_then(|enriched| match enriched {
+                Value::Array(enriched) if enriched.len() == values.len() => Ok(enriched),
+                other => bail!("postAllocate() did not return an array of {} values, got {}", values.len(), other),
+            });
+        self.outcome(pool, result, values)
+    }
+
+    fn invoke(&self, wasmer_env: &mut WasmerEnv, pool: &ResourcePool, user_input: Value, function_call: &str)
+              -> Result<Value> {
+        let previous = wasmer_env.script_timeout.replace(self.timeout);
+        let result = wasmer_env.invoke_and_parse_value(&self.script, user_input, pool.get_pool_properties(),
+                                                       pool.as_json(), &mut vec![], function_call);
+        wasmer_env.script_timeout = previous;
+        result
+    }
+
+    // `skipped` is what the allocation continues with if the hook is skipped on errors.
+    fn outcome<T>(&self, pool: &ResourcePool, result: Result<T>, skipped: T) -> Result<T> {
+        let err = match result {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        if self.on_error == HookErrorPolicy::Skip {
+            warn!("Skipping {} hook of pool {}: {:#}", self.stage.as_str(), pool.id, err);
+            return Ok(skipped);
+        }
+        if let Some(AllocationError::Strategy { .. }) = err.downcast_ref::<AllocationError>() {
+            return Err(err);
+        }
+        Err(AllocationError::HookFailed {
+            resource_pool: pool.name.clone(),
+            hook: self.stage.as_str().to_owned(),
+            reason: format!("{:#}", err),
+        }.into())
+    }
+}
+
+// `pool_hooks` of `p` as a JSON array read by `PoolHook::from_json`, `pre_allocation` first.
+pub(crate) const POOL_HOOKS_JSON: &str =
+    "(SELECT coalesce(jsonb_agg(jsonb_build_object('stage', h.stage, 'script', h.script, \
+    'timeoutMs', h.timeout_ms, 'onError', h.on_error) ORDER BY h.stage DESC), '[]') FROM pool_hooks h \
+    WHERE h.resource_pool = p.id)";
+
+impl DB {
+    // Replaces the hook of the pool at the same stage.
+    pub fn set_pool_hook(&mut self, resource_pool_id: i64, hook: &PoolHook) -> Result<()> {
+        ensure!(!hook.script.trim().is_empty(), "Script of the {} hook is empty", hook.stage.as_str());
+        let timeout_ms = i32::try_from(hook.timeout.as_millis()).ok().filter(|timeout_ms| *timeout_ms > 0)
+            .ok_or_else(|| anyhow!("Timeout of the {} hook must be positive, got {:?}", hook.stage.as_str(),
+                                   hook.timeout))?;
+        Self::check_not_archived(&mut self.client, resource_pool_id)?;
+        self.client.execute(
+            "INSERT INTO pool_hooks (resource_pool, stage, script, timeout_ms, on_error) VALUES ($1, $2, $3, $4, $5) \
+            ON CONFLICT (resource_pool, stage) DO UPDATE SET script = EXCLUDED.script, \
+            timeout_ms = EXCLUDED.timeout_ms, on_error = EXCLUDED.on_error",
+            &[&resource_pool_id, &hook.stage.as_str(), &hook.script, &timeout_ms, &hook.on_error.as_str()])?;
+        Ok(())
+    }
+
+    pub fn remove_pool_hook(&mut self, resource_pool_id: i64, stage: HookStage) -> Result<()> {
+        let deleted = self.client.execute("DELETE FROM pool_hooks WHERE resource_pool=$1 AND stage=$2",
+                                          &[&resource_pool_id, &stage.as_str()])?;
+        ensure!(deleted > 0, "Pool {} has no {} hook", resource_pool_id, stage.as_str());
+        Ok(())
+    }
+
+    // `pre_allocation` first.
+    pub fn get_pool_hooks(&mut self, resource_pool_id: i64) -> Result<Vec<PoolHook>> {
+        let hooks: Value = self.client.query_one(
+            format!("SELECT {} FROM resource_pools p WHERE p.id=$1", POOL_HOOKS_JSON).as_str(),
+            &[&resource_pool_id])?.get(0);
+        hooks.as_array().into_iter().flatten().map(PoolHook::from_json).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::AllocationOptions;
+    use crate::jobs::JobStatus;
+    use crate::strategy::StrategyFiles;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_pool_hooks() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(
+            &name, "function invoke() { return [{address: `10.0.0.${userInput.host}`}] }", None,
+            &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let hook = |stage, script: &str| PoolHook {
+            stage,
+            script: script.to_owned(),
+            timeout: Duration::from_secs(5),
+            on_error: HookErrorPolicy::Fail,
+        };
+        db.set_pool_hook(pool.id, &hook(HookStage::PreAllocation, "function preAllocate() {
+            if (userInput.host > 254) throw {code: 'HOST_OUT_OF_RANGE', message: 'too large'};
+            return {...userInput, host: userInput.host + 1};
+        }")).unwrap();
+        db.set_pool_hook(pool.id, &hook(HookStage::PostAllocation, "function postAllocate(values) {
+            return values.map(value => ({...value, hostname: 'host-' + value.address.split('.')[3]}));
+        }")).unwrap();
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"host": 1}), &options).unwrap();
+        assert_eq!(json!({"address": "10.0.0.2", "hostname": "host-2"}), resources[0].value);
+        let rejected = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({"host": 300}), &options)
+            .unwrap_err();
+        assert!(matches!(rejected.downcast_ref::<AllocationError>(),
+                         Some(AllocationError::Strategy { code, .. }) if code == "HOST_OUT_OF_RANGE"), "{}", rejected);
+        assert_eq!(vec![HookStage::PreAllocation, HookStage::PostAllocation],
+                   db.get_pool_hooks(pool.id).unwrap().iter().map(|hook| hook.stage).collect::<Vec<_>>());
+
+        // a hook running past its timeout is killed
+        let looping = PoolHook {
+            timeout: Duration::from_millis(300),
+            ..hook(HookStage::PostAllocation, "function postAllocate(values) { while (true) {} }")
+        };
+        db.set_pool_hook(pool.id, &looping).unwrap();
+        let started = Instant::now();
+        let failed = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({"host": 5}), &options)
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(matches!(failed.downcast_ref::<AllocationError>(),
+                         Some(AllocationError::HookFailed { hook, .. }) if hook == "post_allocation"), "{}", failed);
+        db.set_pool_hook(pool.id, &PoolHook { on_error: HookErrorPolicy::Skip, ..looping }).unwrap();
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"host": 5}), &options).unwrap();
+        assert_eq!(json!({"address": "10.0.0.6"}), resources[0].value);
+
+        db.remove_pool_hook(pool.id, HookStage::PreAllocation).unwrap();
+        assert!(db.remove_pool_hook(pool.id, HookStage::PreAllocation).is_err());
+        // jobs of pools with hooks are not batched
+        db.set_pool_hook(pool.id, &hook(HookStage::PostAllocation, "function postAllocate(values) {
+            return values.map(value => ({...value, job: true}));
+        }")).unwrap();
+        let job_id = db.enqueue_allocation(pool.id, json!({"host": 7}), &options).unwrap();
+        let mut batches = 0;
+        while db.get_job_status(job_id).unwrap().status != JobStatus::Done {
+            batches += 1;
+            assert!(batches < 50, "Job was not finished");
+            db.run_allocation_batch(&mut wasmer_env, 10).unwrap();
+        }
+        let result = db.get_job_status(job_id).unwrap().result.unwrap();
+        assert_eq!(json!({"address": "10.0.0.7", "job": true}), result[0]["value"]);
+        let invalid = PoolHook { timeout: Duration::from_millis(0), ..hook(HookStage::PreAllocation, "x") };
+        assert!(db.set_pool_hook(pool.id, &invalid).is_err());
+    }
+}
@@ -0,0 +1,412 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::PathBuf;
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::sync::mpsc;
+use std::{env, fs};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
+use postgres::Client;
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{DB, ResourceFilter, WasmerEnv};
+use crate::error::AllocationError;
+
+// Starts a line the script writes to stdout to call the host, the response is written to its stdin.
+const HOST_CALL_PREFIX: char = '\u{1}';
+// Line written by the footer before the result, so that logging to stdout does not corrupt it.
+const RESULT_PREFIX: char = '\u{2}';
+
+/// Functions defined for scripts before the strategy, backed by `CurrentResources`.
+/// `hostRequest` is the index of the request being evaluated by a batch, see `WasmerEnv::invoke_batch`.
+pub(crate) const HOST_FUNCTIONS: &str = "
+let hostRequest = 0;
+function hostCall(method, ...args) {
+    std.out.puts('\\u0001' + JSON.stringify({method, args, request: hostRequest}) + '\\n');
+    std.out.flush();
+    const response = JSON.parse(std.in.getline());
+    if (response.error != null) {
+        throw new Error(response.error);
+    }
+    return response.result;
+}
+const getCurrentResources = (offset, limit) => hostCall('getCurrentResources', offset, limit);
+const isAllocated = (value) => hostCall('isAllocated', value);
+const now = () => hostCall('now');
+const uuid = () => hostCall('uuid');
+const logStructured = (fields) => hostCall('logStructured', fields);
+";
+
+/// Resources of the pool as seen by a script, in the format of `Resource::as_json`.
+pub trait CurrentResources {
+    // embedded as `currentResources`, only for scripts referencing it
+    fn all(&mut self) -> Result<Vec<Value>>;
+    // `getCurrentResources(offset, limit)`
+    fn page(&mut self, offset: i64, limit: i64) -> Result<Vec<Value>>;
+    // `isAllocated(value)`
+    fn is_allocated(&mut self, value: &Value) -> Result<bool>;
+    // embedded as `resourceSummary`, only for scripts referencing it, see `ContextQueries`
+    fn summary(&mut self) -> Result<Value> {
+        Ok(Value::Object(Default::default()))
+    }
+}
+
+impl CurrentResources for Vec<Value> {
+    fn all(&mut self) -> Result<Vec<Value>> {
+        Ok(self.clone())
+    }
+
+    fn page(&mut self, offset: i64, limit: i64) -> Result<Vec<Value>> {
+        Ok(self.iter().skip(offset.max(0) as usize).take(limit.max(0) as usize).cloned().collect())
+    }
+
+    fn is_allocated(&mut self, value: &Value) -> Result<bool> {
+        Ok(self.iter().any(|it| it["Properties"] == *value))
+    }
+}
+
+/// Resources in use of a pool, queried only when the script asks for them.
+pub struct PoolResources<'a> {
+    client: &'a mut Client,
+    resource_pool_id: i64,
+}
+
+impl<'a> PoolResources<'a> {
+    pub fn new(client: &'a mut Client, resource_pool_id: i64) -> PoolResources<'a> {
+        PoolResources { client, resource_pool_id }
+    }
+}
+
+impl CurrentResources for PoolResources<'_> {
+    fn all(&mut self) -> Result<Vec<Value>> {
+        Ok(DB::query_resources(self.client, self.resource_pool_id, &ResourceFilter::default())?
+            .iter().map(|it| it.as_json()).collect())
+    }
+
+    fn page(&mut self, offset: i64, limit: i64) -> Result<Vec<Value>> {
+        let rows = self.client.query(
+            "SELECT value FROM resources WHERE resource_pool=$1 AND status <> 'retired' \
+            ORDER BY id OFFSET $2 LIMIT $3",
+            &[&self.resource_pool_id, &offset.max(0), &limit.max(0)])?;
+        Ok(rows.into_iter().map(|row| json!({"Properties": row.get::<_, Value>(0)})).collect())
+    }
+
+    fn is_allocated(&mut self, value: &Value) -> Result<bool> {
+        let row = self.client.query_one(
+            "SELECT EXISTS (SELECT 1 FROM resources WHERE resource_pool=$1 AND value=$2 AND status <> 'retired')",
+            &[&self.resource_pool_id, value])?;
+        Ok(row.get(0))
+    }
+
+    fn summary(&mut self) -> Result<Value> {
+        DB::resource_summary(self.client, self.resource_pool_id)
+    }
+}
+
+// Random UUID version 4, e.g. for naming resources.
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+pub(crate) fn answer(resources: &mut dyn CurrentResources, request: &Value) -> Result<Value> {
+    let args = &request["args"];
+    let int_arg = |idx: usize| args[idx].as_i64()
+        .ok_or_else(|| anyhow!("Argument {} of {} must be an integer", idx, request["method"]));
+    match request["method"].as_str() {
+        Some("getCurrentResources") => Ok(Value::Array(resources.page(int_arg(0)?, int_arg(1)?)?)),
+        Some("isAllocated") => Ok(Value::Bool(resources.is_allocated(&args[0])?)),
+        Some("now") => Ok(Value::String(Utc::now().to_rfc3339())),
+        Some("uuid") => Ok(Value::String(random_uuid())),
+        Some("logStructured") => {
+            info!(target: "strategy", fields = %args[0], "Strategy log");
+            Ok(Value::Null)
+        }
+        _ => bail!("Unknown host function {}", request["method"]),
+    }
+}
+
+/// Output of a script, what it logged is kept apart from its result.
+#[derive(Debug)]
+pub struct ScriptResult {
+    pub status: ExitStatus,
+    // JSON written after the result sentinel, None if the script did not get to the footer
+    pub result: Option<Value>,
+    // lines written by `console.log` and the like, followed by stderr
+    pub logs: Vec<String>,
+}
+
+// Needed to find and run wasmer, everything else such as `DB_PARAMS` is hidden from scripts.
+const INHERITED_ENV_VARS: [&str; 4] = ["PATH", "HOME", "WASMER_DIR", "WASMER_CACHE_DIR"];
+
+// Empty working directory of a script, the only one it can access. Removed when dropped.
+pub(crate) struct ScriptDir(pub PathBuf);
+
+impl ScriptDir {
+    pub fn create() -> Result<ScriptDir> {
+        let dir = env::temp_dir().join(format!("rm-script-{}-{:x}", process::id(), rand::random::<u64>()));
+        fs::create_dir(&dir).context(format!("Cannot create working directory {:?}", dir))?;
+        Ok(ScriptDir(dir))
+    }
+}
+
+impl Drop for ScriptDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.0) {
+            warn!("Cannot remove working directory {:?}: {}", self.0, err);
+        }
+    }
+}
+
+// Kills the script once the timeout passes, unless it is stopped before.
+struct Watchdog {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(pid: u32, timeout: Duration) -> Watchdog {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let expired = stopped.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout);
+            if expired {
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+            }
+            expired
+        });
+        Watchdog { stop, handle }
+    }
+
+    // Whether the script was killed.
+    fn stop(self) -> Result<bool> {
+        drop(self.stop);
+        self.handle.join().map_err(|_| anyhow!("Watchdog of the script panicked"))
+    }
+}
+
+// Fails reading once the limit is exceeded, so that a runaway script cannot exhaust memory.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read as u64 > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::other("Script output is too large"));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+impl WasmerEnv {
+    // Run the script, answering its host calls. Host calls and the result are read from stdout,
+    // all other output becomes `ScriptResult::logs`. Fails with `AllocationError::OutputTooLarge`
+    // if stdout exceeds `max_output_bytes`, stderr is truncated to the same size. The script is killed
+    // if it runs past `script_timeout`.
+    pub(crate) fn invoke_js(&mut self, script: &str, resources: &mut dyn CurrentResources) -> Result<ScriptResult> {
+        self.invoke_js_with(script, &mut |_, request| answer(resources, request))
+    }
+
+    // Like `invoke_js`, host calls are answered by `host` with the index of the batched request making them.
+    pub(crate) fn invoke_js_with(&mut self, script: &str, host: &mut dyn FnMut(usize, &Value) -> Result<Value>)
+                                 -> Result<ScriptResult> {
+        let workdir = ScriptDir::create()?;
+        // std opens all descriptors with CLOEXEC, the script only gets the three pipes
+        let mut child = Command::new(&self.wasmer_bin)
+            .arg(&self.wasmer_js)
+            .arg("--dir")
+            .arg(&workdir.0)
+            .arg("--")
+            .arg("--std")
+            .arg("-e")
+            .arg(script)
+            .env_clear()
+            .envs(INHERITED_ENV_VARS.iter().filter_map(|var| env::var_os(var).map(|val| (var, val))))
+            .current_dir(&workdir.0)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Cannot execute quickJS")?;
+        // drained concurrently, so that a chatty script cannot block on a full pipe
+        let mut stderr_pipe = child.stderr.take().ok_or_else(|| anyhow!("Missing stderr of quickJS"))?;
+        let max_output_bytes = self.max_output_bytes;
+        let stderr = thread::spawn(move || -> io::Result<Vec<String>> {
+            let lines = BufReader::new((&mut stderr_pipe).take(max_output_bytes)).lines().collect();
+            io::copy(&mut stderr_pipe, &mut io::sink())?;
+            lines
+        });
+        let mut stdin = child.stdin.take().ok_or_else(|| anyhow!("Missing stdin of quickJS"))?;
+        let stdout = child.stdout.take().ok_or_else(|| anyhow!("Missing stdout of quickJS"))?;
+        let mut stdout = BufReader::new(LimitedReader { inner: stdout, remaining: max_output_bytes, exceeded: false });
+        let watchdog = self.script_timeout.map(|timeout| Watchdog::start(child.id(), timeout));
+        let read = Self::read_stdout(&mut stdout, &mut stdin, host);
+        drop(stdin);
+        if stdout.get_ref().exceeded {
+            let _ = child.kill();
+        }
+        // stopped before the child is reaped, so that its pid cannot be reused meanwhile
+        let timed_out = watchdog.map(Watchdog::stop).transpose()?.unwrap_or(false);
+        let status = child.wait()?;
+        if timed_out {
+            bail!("Script did not finish within {}ms", self.script_timeout.unwrap_or_default().as_millis());
+        }
+        let stderr = stderr.join().map_err(|_| anyhow!("Cannot read stderr of quickJS"))??;
+        if stdout.get_ref().exceeded {
+            return Err(AllocationError::OutputTooLarge { limit: max_output_bytes }.into());
+        }
+        let (mut logs, result) = read?;
+        logs.extend(stderr);
+        Ok(ScriptResult { status, result, logs })
+    }
+
+    fn read_stdout(stdout: &mut impl BufRead, stdin: &mut impl Write,
+                   host: &mut dyn FnMut(usize, &Value) -> Result<Value>) -> Result<(Vec<String>, Option<Value>)> {
+        let mut logs = Vec::new();
+        let mut line = Vec::new();
+        while stdout.read_until(b'\n', &mut line)? > 0 {
+            let text = String::from_utf8_lossy(&line);
+            let text = text.trim_end_matches(&['\r', '\n'][..]);
+            if let Some(request) = text.strip_prefix(HOST_CALL_PREFIX) {
+                trace!("Host call {}", request);
+                let response = match serde_json::from_str::<Value>(request)
+                    .context(format!("Invalid host call '{}'", request))
+                    .and_then(|request| host(request["request"].as_u64().unwrap_or_default() as usize, &request)) {
+                    Ok(result) => json!({"result": result}),
+                    Err(err) => json!({"error": format!("{:#}", err)}),
+                };
+                // the script may have failed meanwhile, its exit status is reported by the caller
+                let _ = writeln!(stdin, "{}", response).and_then(|_| stdin.flush());
+            } else if text.starts_with(RESULT_PREFIX) {
+                // the footer writes the result last, parsed without buffering its text
+                let result = serde_json::from_reader(stdout).context("Cannot deserialize result of the script")?;
+                return Ok((logs, Some(result)));
+            } else {
+                logs.push(text.to_owned());
+            }
+            line.clear();
+        }
+        Ok((logs, None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use crate::Resource;
+    use crate::engine::StrategyEngine;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    const PAGING_SCRIPT: &str = "
+    function invoke() {
+        const second = getCurrentResources(1, 1);
+        return [{ second, allocated: isAllocated(userInput.value), free: isAllocated({ n: -1 }) }];
+    }";
+
+    // resources that can only be read through the host functions
+    struct HostOnly(Vec<Value>);
+
+    impl CurrentResources for HostOnly {
+        fn all(&mut self) -> Result<Vec<Value>> {
+            bail!("Resources should not be embedded")
+        }
+
+        fn page(&mut self, offset: i64, limit: i64) -> Result<Vec<Value>> {
+            self.0.page(offset, limit)
+        }
+
+        fn is_allocated(&mut self, value: &Value) -> Result<bool> {
+            self.0.is_allocated(value)
+        }
+    }
+
+    #[test]
+    fn wasmer_host_functions() {
+        initialize_logging();
+
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let mut resources = HostOnly(vec![json!({"Properties": {"n": 1}}), json!({"Properties": {"n": 2}})]);
+        let actual = wasmer_env.invoke_and_parse(PAGING_SCRIPT, json!({"value": {"n": 1}}), json!({}), json!({}),
+                                                 &mut resources, "invoke()").unwrap();
+        assert_eq!(vec![json!({"second": [{"Properties": {"n": 2}}], "allocated": true, "free": false})], actual);
+
+        // errors of the host are thrown in the script
+        let actual = wasmer_env.invoke_and_parse("", json!({}), json!({}), json!({}), &mut resources,
+                                                 "(() => { try { getCurrentResources('a', 1) } catch (e) { return [e.message] } })()")
+            .unwrap();
+        assert_eq!(vec![json!("Argument 0 of \"getCurrentResources\" must be an integer")], actual);
+    }
+
+    #[test]
+    fn wasmer_utility_functions() {
+        initialize_logging();
+
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let actual = wasmer_env.invoke_and_parse("", json!({}), json!({}), json!({}), &mut vec![],
+                                                 "[now(), uuid(), uuid(), logStructured({ step: 1 })]").unwrap();
+        assert!(DateTime::parse_from_rfc3339(actual[0].as_str().unwrap()).is_ok());
+        let uuid = actual[1].as_str().unwrap();
+        assert_eq!((36, Some('4')), (uuid.len(), uuid.chars().nth(14)));
+        assert_ne!(actual[1], actual[2]);
+        assert_eq!(Value::Null, actual[3]);
+    }
+
+    #[test]
+    fn wasmer_output_too_large() {
+        initialize_logging();
+
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        wasmer_env.max_output_bytes = 1000;
+        let too_large = Some(&AllocationError::OutputTooLarge { limit: 1000 });
+        let err = wasmer_env.invoke_and_parse("", json!({}), json!({}), json!({}), &mut vec![],
+                                              "Array.from({ length: 1000 }, (_, n) => ({ n }))").unwrap_err();
+        assert_eq!(too_large, err.downcast_ref::<AllocationError>());
+        let err = wasmer_env.invoke_js("for (let i = 0; i < 1000; i++) console.log('line', i)", &mut vec![])
+            .err().unwrap();
+        assert_eq!(too_large, err.downcast_ref::<AllocationError>());
+
+        let actual = wasmer_env.invoke_and_parse("", json!({}), json!({}), json!({}), &mut vec![],
+                                                 "Array.from({ length: 10 }, (_, n) => ({ n }))").unwrap();
+        assert_eq!(10, actual.len());
+    }
+
+    #[test]
+    fn wasmer_isolated_environment() {
+        initialize_logging();
+
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let actual = wasmer_env.invoke_and_parse("", json!({}), json!({}), json!({}), &mut vec![],
+                                                 "[std.getenv('DB_PARAMS') || null, std.getenv('PATH') != null]")
+            .unwrap();
+        assert_eq!(vec![Value::Null, Value::Bool(true)], actual);
+    }
+
+    #[test]
+    fn db_pool_resources() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        db.insert_resources(pool.clone(), (1..=3)
+            .map(|n| Resource::new_from_value(pool.id, json!({"n": n}))).collect()).unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let mut resources = PoolResources::new(&mut db.client, pool.id);
+        let actual = wasmer_env.invoke_and_parse(PAGING_SCRIPT, json!({"value": {"n": 3}}), json!({}), json!({}),
+                                                 &mut resources, "invoke()").unwrap();
+        assert_eq!(vec![json!({"second": [{"Properties": {"n": 2}}], "allocated": true, "free": false})], actual);
+        assert_eq!(3, resources.all().unwrap().len());
+    }
+}
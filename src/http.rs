@@ -0,0 +1,1381 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use postgres::error::SqlState;
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{AllocationOptions, DB, Resource, ResourcePool, WasmerEnv, metrics};
+use crate::config::Config;
+use crate::cursor::CursorKey;
+use crate::error::AllocationError;
+use crate::events::PoolEvents;
+use crate::metadata::MetadataQuery;
+use crate::pools::{DEFAULT_PAGE_SIZE, PoolFilter, PoolPosition};
+use crate::reload::ScriptCache;
+use crate::shutdown::Shutdown;
+use crate::state::ResourceState;
+use crate::supervisor::Health;
+use crate::stats::parse_range;
+use crate::{sse, websocket};
+
+// Requests with larger bodies are refused.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+// Prometheus text format of `GET /metrics`
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+// Resources of `GET /pools/<id>/resources`, one JSON per line
+const JSON_LINES_CONTENT_TYPE: &str = "application/x-ndjson";
+const DEFAULT_STREAM_BATCH_SIZE: i32 = 1000;
+// How often idle HTTP threads check for shutdown.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// `Retry-After` of writes refused during maintenance
+const MAINTENANCE_RETRY_AFTER_SECS: u64 = 30;
+
+/// Parsed HTTP/1.1 request, the connection is closed after the response.
+#[derive(Debug, Clone, Default)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    // percent-decoded parameters of the query string
+    pub query: HashMap<String, String>,
+    // lowercase names
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    // besides Content-Type and Content-Length
+    pub headers: Vec<(&'static str, String)>,
+    // not sent with 204, a string with a Content-Type header is sent as is
+    pub body: Value,
+}
+
+impl HttpResponse {
+    pub fn ok(body: Value) -> HttpResponse {
+        HttpResponse { status: 200, headers: vec![], body }
+    }
+
+    fn text(content_type: &str, body: String) -> HttpResponse {
+        HttpResponse { status: 200, headers: vec![("Content-Type", content_type.to_owned())], body: Value::String(body) }
+    }
+
+    fn error(status: u16, err: &anyhow::Error) -> HttpResponse {
+        HttpResponse { status, headers: vec![], body: json!({"error": format!("{:#}", err)}) }
+    }
+
+    // Version of the pool as a strong entity tag, sent back by clients in `If-Match`, and the time of its last
+    // change as an IMF-fixdate for `If-Unmodified-Since`.
+    fn with_etag(self, pool: &ResourcePool) -> HttpResponse {
+        let last_modified = DateTime::<Utc>::from(pool.updated_at).format("%a, %d %b %Y %H:%M:%S GMT");
+        self.with_header("ETag", format!("\"{}\"", pool.version))
+            .with_header("Last-Modified", last_modified.to_string())
+    }
+
+    fn with_header(mut self, name: &'static str, value: String) -> HttpResponse {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/// Error with the HTTP status it is reported with, e.g. 400 for an invalid query parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+pub fn bad_request(message: String) -> anyhow::Error {
+    HttpError { status: 400, message }.into()
+}
+
+fn status_of(err: &anyhow::Error) -> u16 {
+    if let Some(err) = err.downcast_ref::<HttpError>() {
+        return err.status;
+    }
+    match err.downcast_ref::<AllocationError>() {
+        Some(AllocationError::ResourceNotFound { .. }) => 404,
+        Some(AllocationError::IllegalTransition { .. }) | Some(AllocationError::ResourceClaimed { .. })
+        | Some(AllocationError::VersionConflict { .. })
+        | Some(AllocationError::PoolArchived { .. }) | Some(AllocationError::UniquenessConflict { .. })
+        | Some(AllocationError::ValueUnavailable { .. }) | Some(AllocationError::LeaseExpired { .. })
+        | Some(AllocationError::LeaseLifetimeExceeded { .. }) => 409,
+        Some(AllocationError::InvalidPoolProperties { .. }) | Some(AllocationError::InvalidUserInput { .. })
+        | Some(AllocationError::InvalidStrategy { .. }) => 400,
+        Some(AllocationError::Strategy { .. }) | Some(AllocationError::HookFailed { .. }) => 422,
+        Some(AllocationError::DatabaseUnavailable { .. }) | Some(AllocationError::Timeout { .. })
+        | Some(AllocationError::ServiceReadOnly) => 503,
+        Some(AllocationError::OutputTooLarge { .. }) | None => 500,
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        422 => "Unprocessable Entity",
+        428 => "Precondition Required",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'+' => decoded.push(b' '),
+            b'%' if idx + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[idx + 1..idx + 3]).ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match hex {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        idx += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<HttpRequest> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => return Err(bad_request(format!("Invalid request line '{}'", line.trim()))),
+    };
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_owned());
+        }
+    }
+    let length = headers.get("content-length")
+        .map(|length| length.parse::<usize>().map_err(|_| bad_request(format!("Invalid Content-Length '{}'", length))))
+        .transpose()?
+        .unwrap_or_default();
+    if length > MAX_BODY_BYTES {
+        return Err(HttpError { status: 413, message: format!("Body exceeds {} bytes", MAX_BODY_BYTES) }.into());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    Ok(HttpRequest { method, path: percent_decode(path), query: parse_query(query), headers, body })
+}
+
+fn write_response(stream: &mut TcpStream, response: &HttpResponse) -> Result<()> {
+    let typed = response.headers.iter().any(|(name, _)| *name == "Content-Type");
+    let body = match &response.body {
+        _ if response.status == 204 => String::new(),
+        Value::String(text) if typed => text.clone(),
+        body => body.to_string(),
+    };
+    let mut headers = response.headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect::<String>();
+    if !typed {
+        headers += "Content-Type: application/json\r\n";
+    }
+    write!(stream, "HTTP/1.1 {} {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+           response.status, reason_phrase(response.status), headers, body.len(), body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+impl HttpRequest {
+    // Query parameter parsed with `FromStr`, None if it is missing.
+    pub fn param<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>> where T::Err: fmt::Display {
+        self.query.get(name)
+            .map(|value| value.parse::<T>()
+                .map_err(|err| bad_request(format!("Invalid parameter {}='{}': {}", name, value, err))))
+            .transpose()
+    }
+
+    pub fn json_body(&self) -> Result<Value> {
+        serde_json::from_slice(&self.body).map_err(|err| bad_request(format!("Body is not a valid JSON: {}", err)))
+    }
+}
+
+fn not_found(message: String) -> anyhow::Error {
+    HttpError { status: 404, message }.into()
+}
+
+fn parse_id<T: FromStr>(id: &str) -> Result<T> {
+    id.parse().map_err(|_| bad_request(format!("Invalid id '{}'", id)))
+}
+
+fn limit_param(request: &HttpRequest) -> Result<i64> {
+    let limit = request.param("limit")?.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit <= 0 {
+        return Err(bad_request("limit must be positive".to_owned()));
+    }
+    Ok(limit)
+}
+
+// Position signed into the `cursor` parameter by `next_cursor`, errors are reported as 400.
+fn cursor_param(request: &HttpRequest, cursor_key: &CursorKey, filter: &str) -> Result<Option<Value>> {
+    request.query.get("cursor")
+        .map(|cursor| cursor_key.decode(filter, cursor).map_err(|err| bad_request(err.to_string())))
+        .transpose()
+}
+
+// Cursor of the page following a full one, null after the last page.
+fn next_cursor(cursor_key: &CursorKey, filter: &str, limit: i64, page_len: usize, last: Option<Value>) -> Value {
+    match last {
+        Some(last) if page_len as i64 == limit => Value::String(cursor_key.encode(filter, last)),
+        _ => Value::Null,
+    }
+}
+
+// Pages are continued by passing `nextCursor` of the response as `cursor`, with the same filter.
+// Cursors keep the position of the last pool, so pools created or deleted meanwhile do not shift pages.
+fn list_pools(db: &mut DB, cursor_key: &CursorKey, request: &HttpRequest) -> Result<HttpResponse> {
+    let mut filter = PoolFilter {
+        name_prefix: request.param("name_prefix")?,
+        allocation_strategy_id: request.param("strategy_id")?,
+        tag: request.param("tag")?,
+        tenant: request.param("tenant")?,
+        sort: request.param("sort")?.unwrap_or_default(),
+        after: None,
+        limit: limit_param(request)?,
+        offset: request.param("offset")?.unwrap_or_default(),
+        include_archived: request.param("include_archived")?.unwrap_or_default(),
+    };
+    if filter.offset < 0 {
+        return Err(bad_request("offset cannot be negative".to_owned()));
+    }
+    let filter_key = format!("pools?name_prefix={:?}&strategy_id={:?}&tag={:?}&tenant={:?}&sort={}\
+        &include_archived={}", filter.name_prefix, filter.allocation_strategy_id, filter.tag, filter.tenant,
+                             filter.sort, filter.include_archived);
+    if let Some(after) = cursor_param(request, cursor_key, &filter_key)? {
+        if filter.offset != 0 {
+            return Err(bad_request("cursor cannot be combined with offset".to_owned()));
+        }
+        filter.after = Some(PoolPosition::from_json(after).ok_or_else(|| bad_request("Invalid cursor".to_owned()))?);
+    }
+    let pools = db.list_pools(&filter)?;
+    let last = pools.last().map(|pool| PoolPosition::of(pool).as_json());
+    Ok(HttpResponse::ok(json!({
+        "pools": pools.iter().map(|pool| pool.as_export_json()).collect::<Vec<_>>(),
+        "nextCursor": next_cursor(cursor_key, &filter_key, filter.limit, pools.len(), last),
+    })))
+}
+
+fn list_strategies(db: &mut DB, cursor_key: &CursorKey, request: &HttpRequest) -> Result<HttpResponse> {
+    let limit = limit_param(request)?;
+    let after_id = match cursor_param(request, cursor_key, "strategies")? {
+        Some(after) => Some(serde_json::from_value(after).map_err(|_| bad_request("Invalid cursor".to_owned()))?),
+        None => None,
+    };
+    let strategies = db.list_strategies(after_id, Some(limit))?;
+    let last = strategies.last().map(|strategy| json!(strategy.id));
+    Ok(HttpResponse::ok(json!({
+        "strategies": strategies.iter().map(|strategy| strategy.as_json()).collect::<Vec<_>>(),
+        "nextCursor": next_cursor(cursor_key, "strategies", limit, strategies.len(), last),
+    })))
+}
+
+fn get_strategy(db: &mut DB, id: &str) -> Result<HttpResponse> {
+    let id = parse_id(id)?;
+    let strategy = db.find_strategy(id)?.ok_or_else(|| not_found(format!("Allocation strategy {} not found", id)))?;
+    Ok(HttpResponse::ok(strategy.as_json()))
+}
+
+fn find_pool(db: &mut DB, id: &str) -> Result<ResourcePool> {
+    let id = parse_id(id)?;
+    db.find_resource_pool_by_id(id)?.ok_or_else(|| not_found(format!("Resource pool {} not found", id)))
+}
+
+// Mutations of a pool require `If-Match` with its current ETag or `*`, or `If-Unmodified-Since` with its
+// `updatedAt` as RFC 3339 or its `Last-Modified`, otherwise they fail with 428 or 412. `If-Unmodified-Since`
+// is ignored along with `If-Match` (RFC 7232 section 3.4), an IMF-fixdate is compared at second precision.
+// The version still guards the mutation itself.
+fn check_if_match(request: &HttpRequest, pool: &ResourcePool) -> Result<()> {
+    let if_match = request.headers.get("if-match");
+    let if_unmodified_since = request.headers.get("if-unmodified-since");
+    if if_match.is_none() && if_unmodified_since.is_none() {
+        return Err(HttpError {
+            status: 428,
+            message: format!("If-Match with the ETag or If-Unmodified-Since with updatedAt of pool '{}' is required",
+                             pool.name),
+        }.into());
+    }
+    if let Some(if_match) = if_match {
+        let version = pool.version.to_string();
+        let matches = if_match.split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == version);
+        if !matches {
+            return Err(precondition_failed(pool));
+        }
+    }
+    if let (None, Some(since)) = (if_match, if_unmodified_since) {
+        let modified = match DateTime::parse_from_rfc3339(since.trim()) {
+            Ok(since) => SystemTime::from(since) < pool.updated_at,
+            Err(_) => {
+                let since = DateTime::parse_from_rfc2822(since.trim()).map_err(|_| bad_request(format!(
+                    "If-Unmodified-Since '{}' is neither an RFC 3339 timestamp nor an IMF-fixdate", since)))?;
+                since.timestamp() < DateTime::<Utc>::from(pool.updated_at).timestamp()
+            }
+        };
+        if modified {
+            return Err(precondition_failed(pool));
+        }
+    }
+    Ok(())
+}
+
+fn precondition_failed(pool: &ResourcePool) -> anyhow::Error {
+    HttpError { status: 412, message: format!("Pool '{}' does not match If-Match", pool.name) }.into()
+}
+
+// Concurrent changes after `check_if_match` are reported the same way as a stale ETag.
+fn or_precondition_failed<T>(result: Result<T>, pool: &ResourcePool) -> Result<T> {
+    result.map_err(|err| match err.downcast_ref::<AllocationError>() {
+        Some(AllocationError::VersionConflict { .. }) => precondition_failed(pool),
+        _ => err,
+    })
+}
+
+fn get_pool(db: &mut DB, id: &str) -> Result<HttpResponse> {
+    let pool = find_pool(db, id)?;
+    Ok(HttpResponse::ok(pool.as_export_json()).with_etag(&pool))
+}
+
+// The body replaces all properties, they are validated the same way as by `pool configure --properties`.
+fn update_pool_properties(db: &mut DB, wasmer_env: &mut WasmerEnv, id: &str, request: &HttpRequest)
+                          -> Result<HttpResponse> {
+    let pool = find_pool(db, id)?;
+    check_if_match(request, &pool)?;
+    let properties = request.json_body()?;
+    let pool = or_precondition_failed(db.update_pool_properties(pool.clone(), wasmer_env, properties), &pool)?;
+    Ok(HttpResponse::ok(pool.as_export_json()).with_etag(&pool))
+}
+
+fn delete_pool(db: &mut DB, id: &str, request: &HttpRequest) -> Result<HttpResponse> {
+    let pool = find_pool(db, id)?;
+    check_if_match(request, &pool)?;
+    or_precondition_failed(db.delete_resource_pool(pool.clone()), &pool)?;
+    Ok(HttpResponse { status: 204, headers: vec![], body: Value::Null })
+}
+
+fn get_resource(db: &mut DB, id: &str) -> Result<HttpResponse> {
+    let id = parse_id(id)?;
+    let resource = db.find_resource(id)?.ok_or_else(|| not_found(format!("Resource {} not found", id)))?;
+    Ok(HttpResponse::ok(resource.as_detail_json()))
+}
+
+fn update_resource_metadata(db: &mut DB, id: &str, request: &HttpRequest) -> Result<HttpResponse> {
+    let id = parse_id(id)?;
+    let patch = request.json_body()?;
+    if !patch.is_object() {
+        return Err(bad_request("Metadata patch must be a JSON object".to_owned()));
+    }
+    db.find_resource(id)?.ok_or_else(|| not_found(format!("Resource {} not found", id)))?;
+    let resource = db.update_resource_metadata(id, &patch)?;
+    Ok(HttpResponse::ok(resource.as_detail_json()))
+}
+
+// Body `{"owner": "..."}`, a null owner releases the resource.
+fn transfer_resource(db: &mut DB, id: &str, request: &HttpRequest) -> Result<HttpResponse> {
+    let id = parse_id(id)?;
+    let owner = match request.json_body()?.get("owner") {
+        Some(Value::String(owner)) => Some(owner.clone()),
+        Some(Value::Null) => None,
+        _ => return Err(bad_request("Body must contain owner as a string or null".to_owned())),
+    };
+    if owner.as_deref() == Some("") {
+        return Err(bad_request("Owner cannot be empty".to_owned()));
+    }
+    let resource = db.find_resource(id)?.ok_or_else(|| not_found(format!("Resource {} not found", id)))?;
+    if resource.state == ResourceState::Retired {
+        return Err(HttpError { status: 409, message: format!("Resource {} is retired", id) }.into());
+    }
+    let resource = db.transfer_resource(id, owner.as_deref())?;
+    Ok(HttpResponse::ok(resource.as_detail_json()))
+}
+
+// Body `{"extendBy": 3600}` in seconds, the renewed lease is capped by `RM_MAX_LEASE_LIFETIME_SECS`.
+fn renew_lease(db: &mut DB, id: &str, request: &HttpRequest) -> Result<HttpResponse> {
+    let id = parse_id(id)?;
+    let extend_by = request.json_body()?["extendBy"].as_u64().filter(|secs| *secs > 0)
+        .ok_or_else(|| bad_request("Body must contain extendBy as a positive number of seconds".to_owned()))?;
+    let resource = db.find_resource(id)?.ok_or_else(|| not_found(format!("Resource {} not found", id)))?;
+    if resource.state == ResourceState::Retired || resource.lease_expires_at.is_none() {
+        return Err(HttpError { status: 409, message: format!("Resource {} has no lease", id) }.into());
+    }
+    let resource = db.renew_lease(id, Duration::from_secs(extend_by))?;
+    Ok(HttpResponse::ok(resource.as_detail_json()))
+}
+
+// Resources in use of several pools by pool id, e.g. `?pools=1,2,3`, otherwise resources in use of the `owner`
+// parameter across all pools.
+fn list_resources(db: &mut DB, cursor_key: &CursorKey, request: &HttpRequest) -> Result<HttpResponse> {
+    let pool_ids = match request.query.get("pools") {
+        Some(pool_ids) => pool_ids.split(',').map(parse_id).collect::<Result<Vec<_>>>()?,
+        None => return owned_resources(db, cursor_key, request),
+    };
+    let mut resources = db.get_resources_for_pools(&pool_ids)?;
+    let pools = pool_ids.iter()
+        .map(|id| {
+            let resources = resources.remove(id).unwrap_or_default();
+            (id.to_string(), json!(resources.iter().map(Resource::as_detail_json).collect::<Vec<_>>()))
+        })
+        .collect::<serde_json::Map<_, _>>();
+    Ok(HttpResponse::ok(json!({"pools": pools})))
+}
+
+// Pages are continued by passing `nextCursor` of the response as `cursor`, with the same owner.
+fn owned_resources(db: &mut DB, cursor_key: &CursorKey, request: &HttpRequest) -> Result<HttpResponse> {
+    let owner = request.query.get("owner")
+        .ok_or_else(|| bad_request("Missing parameter owner or pools".to_owned()))?;
+    let limit = limit_param(request)?;
+    let filter_key = format!("resources?owner={:?}", owner);
+    let after_id = match cursor_param(request, cursor_key, &filter_key)? {
+        Some(after) => Some(serde_json::from_value(after).map_err(|_| bad_request("Invalid cursor".to_owned()))?),
+        None => None,
+    };
+    let resources = db.find_owned_resources(owner, after_id, limit)?;
+    let last = resources.last().map(|resource| json!(resource.id));
+    Ok(HttpResponse::ok(json!({
+        "resources": resources.iter().map(Resource::as_detail_json).collect::<Vec<_>>(),
+        "nextCursor": next_cursor(cursor_key, &filter_key, limit, resources.len(), last),
+    })))
+}
+
+fn count_resources(db: &mut DB, pool_id: &str) -> Result<HttpResponse> {
+    let count = db.count_resources(parse_id(pool_id)?)?;
+    Ok(HttpResponse::ok(json!({"count": count})))
+}
+
+// The value is passed as JSON in the `value` parameter, e.g. `?value={"address":"10.0.0.1"}`.
+fn resource_exists(db: &mut DB, wasmer_env: &mut WasmerEnv, pool_id: &str, request: &HttpRequest)
+                   -> Result<HttpResponse> {
+    let value = request.query.get("value").ok_or_else(|| bad_request("Missing parameter value".to_owned()))?;
+    let value: Value = serde_json::from_str(value)
+        .map_err(|err| bad_request(format!("Parameter value is not a valid JSON: {}", err)))?;
+    let exists = db.resource_exists(wasmer_env, parse_id(pool_id)?, &value)?;
+    Ok(HttpResponse::ok(json!({"exists": exists})))
+}
+
+// Metadata are matched by JSON in `contains`, e.g. `?contains={"service":"voip"}`, or by a jsonpath predicate
+// in `path`, e.g. `?path=$.service == "voip"`. Pages are continued by passing `nextCursor` of the response
+// as `cursor`, with the same query.
+fn search_resources(db: &mut DB, cursor_key: &CursorKey, pool_id: &str, request: &HttpRequest)
+                    -> Result<HttpResponse> {
+    let pool = find_pool(db, pool_id)?;
+    let (contains, path) = (request.query.get("contains"), request.query.get("path"));
+    let filter_key = format!("pools/{}/resources/search?contains={:?}&path={:?}", pool.id, contains, path);
+    let query = match (contains, path) {
+        (Some(contains), None) => match serde_json::from_str(contains) {
+            Ok(contains @ Value::Object(_)) => MetadataQuery::Contains(contains),
+            _ => return Err(bad_request(format!("Parameter contains '{}' is not a JSON object", contains))),
+        },
+        (None, Some(path)) => MetadataQuery::Path(path.clone()),
+        _ => return Err(bad_request("Exactly one of parameters contains and path is required".to_owned())),
+    };
+    let limit = limit_param(request)?;
+    let after_id = match cursor_param(request, cursor_key, &filter_key)? {
+        Some(after) => Some(serde_json::from_value(after).map_err(|_| bad_request("Invalid cursor".to_owned()))?),
+        None => None,
+    };
+    let resources = db.search_resources(pool.id, &query, after_id, limit).map_err(|err| {
+        // jsonpath is parsed by Postgres
+        match err.downcast_ref::<postgres::Error>().and_then(|err| err.code()) {
+            Some(code) if *code == SqlState::SYNTAX_ERROR => bad_request(format!("{:#}", err)),
+            _ => err,
+        }
+    })?;
+    let last = resources.last().map(|resource| json!(resource.id));
+    Ok(HttpResponse::ok(json!({
+        "resources": resources.iter().map(Resource::as_detail_json).collect::<Vec<_>>(),
+        "nextCursor": next_cursor(cursor_key, &filter_key, limit, resources.len(), last),
+    })))
+}
+
+// Utilization samples recorded by the worker within `range`, 7 days by default.
+fn pool_stats(db: &mut DB, pool_id: &str, request: &HttpRequest) -> Result<HttpResponse> {
+    let pool_id = parse_id(pool_id)?;
+    let range = parse_range(request.query.get("range").map_or("7d", String::as_str))
+        .map_err(|err| bad_request(err.to_string()))?;
+    db.find_resource_pool_by_id(pool_id)?.ok_or_else(|| not_found(format!("Resource pool {} not found", pool_id)))?;
+    let samples = db.get_pool_stats(pool_id, range)?;
+    Ok(HttpResponse::ok(json!({"samples": samples.iter().map(|sample| sample.as_json()).collect::<Vec<_>>()})))
+}
+
+// Runs the strategy against current resources of the pool without inserting anything. The body is the user input,
+// an empty body is the same as `{}`.
+fn preview_allocation(db: &mut DB, wasmer_env: &mut WasmerEnv, pool_id: &str, request: &HttpRequest)
+                      -> Result<HttpResponse> {
+    let pool_id = parse_id(pool_id)?;
+    let user_input = if request.body.is_empty() { json!({}) } else { request.json_body()? };
+    let pool = db.find_resource_pool_by_id(pool_id)?
+        .ok_or_else(|| not_found(format!("Resource pool {} not found", pool_id)))?;
+    let options = AllocationOptions { dry_run: true, ..AllocationOptions::default() };
+    let (_pool, resources, timings) = db.allocate_resources_with_timings(pool, wasmer_env, user_input, &options)?;
+    Ok(HttpResponse::ok(json!({
+        "count": resources.len(),
+        "resources": resources.iter().map(|resource| resource.value.clone()).collect::<Vec<_>>(),
+        "timings": timings.as_json(),
+    })))
+}
+
+// Pool of `GET /pools/<id>/resources`, which is streamed by `stream_resources` instead of being handled by `handle`.
+fn streamed_pool(request: &HttpRequest) -> Option<&str> {
+    match (request.method.as_str(), request.path.trim_matches('/').split('/').collect::<Vec<_>>().as_slice()) {
+        ("GET", ["pools", id, "resources"]) => Some(id),
+        _ => None,
+    }
+}
+
+// Pool of `GET /pools/<id>/events`, which is handed over to `subscribe_events`.
+fn subscribed_pool(request: &HttpRequest) -> Option<&str> {
+    match (request.method.as_str(), request.path.trim_matches('/').split('/').collect::<Vec<_>>().as_slice()) {
+        ("GET", ["pools", id, "events"]) => Some(id),
+        _ => None,
+    }
+}
+
+// Writes resources in use of the pool as chunks of JSON lines in the format of `resources export`, one chunk per
+// `batch_size` resources read from a portal. The next batch is read only after the previous one was written, so
+// slow clients hold back the database instead of the response being buffered. Returns the response to send if
+// the request failed before streaming started. Later failures close the connection without the last chunk.
+fn stream_resources(db: &mut DB, stream: &mut TcpStream, pool_id: &str, request: &HttpRequest, request_id: &str)
+                    -> Option<HttpResponse> {
+    let prepared = parse_id(pool_id).and_then(|pool_id| {
+        let batch_size = request.param("batch_size")?.unwrap_or(DEFAULT_STREAM_BATCH_SIZE);
+        if batch_size <= 0 {
+            return Err(bad_request("batch_size must be positive".to_owned()));
+        }
+        db.find_resource_pool_by_id(pool_id)?
+            .ok_or_else(|| not_found(format!("Resource pool {} not found", pool_id)))
+            .map(|pool| (pool, batch_size))
+    });
+    let (pool, batch_size) = match prepared {
+        Ok(prepared) => prepared,
+        Err(err) => return Some(HttpResponse::error(status_of(&err), &err)),
+    };
+    let streamed = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nTransfer-Encoding: chunked\r\n\
+        X-Request-Id: {}\r\nConnection: close\r\n\r\n", JSON_LINES_CONTENT_TYPE, request_id)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| db.stream_resources(pool.id, batch_size, |resources| {
+            let chunk = resources.iter().map(|resource| resource.as_export_json().to_string() + "\n")
+                .collect::<String>();
+            write!(stream, "{:x}\r\n{}\r\n", chunk.len(), chunk)?;
+            Ok(())
+        }))
+        .and_then(|streamed| {
+            write!(stream, "0\r\n\r\n")?;
+            stream.flush()?;
+            Ok(streamed)
+        });
+    match streamed {
+        Ok(streamed) => debug!("Streamed {} resources of pool '{}'", streamed, pool.name),
+        Err(err) => warn!("Streaming resources of pool '{}' failed: {:#}", pool.name, err),
+    }
+    None
+}
+
+// Upgrades `GET /pools/<id>/events` to a WebSocket pushing events of the pool, see `DB::notify_pool_event`.
+// Clients accepting `text/event-stream` get Server-Sent Events instead, resumed after the audit log entry
+// of `Last-Event-ID` or of the `last_event_id` parameter. The connection is served by threads of its own
+// instead of the HTTP thread. Returns the response to send if the request cannot be served.
+fn subscribe_events(db: &mut DB, events: &PoolEvents, shutdown: Shutdown, stream: &TcpStream, pool_id: &str,
+                    request: &HttpRequest) -> Option<HttpResponse> {
+    let subscribed = parse_id(pool_id).and_then(|pool_id| {
+        let upgrade = request.headers.get("upgrade").map(|upgrade| upgrade.to_ascii_lowercase());
+        let event_stream = request.headers.get("accept").is_some_and(|accept| accept.contains(sse::CONTENT_TYPE));
+        let key = match (upgrade.as_deref(), request.headers.get("sec-websocket-key")) {
+            (Some("websocket"), Some(key)) => Some(key),
+            (None, _) if event_stream => None,
+            _ => return Err(bad_request(format!(
+                "Expected a WebSocket upgrade with Sec-WebSocket-Key or Accept: {}", sse::CONTENT_TYPE))),
+        };
+        let last_event_id = match request.headers.get("last-event-id") {
+            Some(id) => Some(id.trim().parse::<i64>()
+                .map_err(|_| bad_request(format!("Invalid Last-Event-ID '{}'", id)))?),
+            None => request.param("last_event_id")?,
+        };
+        db.find_resource_pool_by_id(pool_id)?
+            .ok_or_else(|| not_found(format!("Resource pool {} not found", pool_id)))?;
+        match key {
+            Some(key) => websocket::serve_events(stream.try_clone()?, key, events.subscribe(pool_id), shutdown),
+            None => sse::serve_events(db, stream.try_clone()?, pool_id, last_event_id, events.subscribe(pool_id),
+                                      shutdown),
+        }
+    });
+    subscribed.err().map(|err| HttpResponse::error(status_of(&err), &err))
+}
+
+// 200 when all subsystems are running, 503 while any is restarting or stopped.
+fn readiness(health: &Health) -> HttpResponse {
+    let status = if health.is_ready() { 200 } else { 503 };
+    HttpResponse { status, headers: vec![], body: health.as_json() }
+}
+
+// Turns maintenance mode of all processes on or off, `GET` reports it.
+fn set_maintenance(db: &mut DB, enabled: Option<bool>) -> Result<HttpResponse> {
+    if let Some(enabled) = enabled {
+        db.set_maintenance(enabled)?;
+        info!("Maintenance mode {}", if enabled { "enabled" } else { "disabled" });
+    }
+    Ok(HttpResponse::ok(json!({"maintenance": db.is_maintenance()?})))
+}
+
+// Executes the request, errors are reported as `{"error": message}` with the status given by `status_of`.
+// Writes refused during maintenance are reported with `Retry-After`.
+pub fn handle(db: &mut DB, wasmer_env: &mut WasmerEnv, cursor_key: &CursorKey, health: &Health,
+              request: &HttpRequest) -> HttpResponse {
+    let segments = request.path.trim_matches('/').split('/').collect::<Vec<_>>();
+    let result = db.sync_maintenance().and_then(|_| match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["metrics"]) => Ok(HttpResponse::text(METRICS_CONTENT_TYPE, metrics::render())),
+        ("GET", ["readyz"]) => Ok(readiness(health)),
+        ("GET", ["maintenance"]) => set_maintenance(db, None),
+        ("PUT", ["maintenance"]) => set_maintenance(db, Some(true)),
+        ("DELETE", ["maintenance"]) => set_maintenance(db, Some(false)),
+        ("GET", ["pools"]) => list_pools(db, cursor_key, request),
+        ("GET", ["strategies"]) => list_strategies(db, cursor_key, request),
+        ("GET", ["strategies", id]) => get_strategy(db, id),
+        ("GET", ["pools", id]) => get_pool(db, id),
+        ("DELETE", ["pools", id]) => delete_pool(db, id, request),
+        ("PUT", ["pools", id, "properties"]) => update_pool_properties(db, wasmer_env, id, request),
+        ("POST", ["pools", id, "allocate:preview"]) => preview_allocation(db, wasmer_env, id, request),
+        ("GET", ["pools", id, "stats"]) => pool_stats(db, id, request),
+        ("GET", ["pools", id, "resources", "count"]) => count_resources(db, id),
+        ("GET", ["pools", id, "resources", "exists"]) => resource_exists(db, wasmer_env, id, request),
+        ("GET", ["pools", id, "resources", "search"]) => search_resources(db, cursor_key, id, request),
+        ("GET", ["resources"]) => list_resources(db, cursor_key, request),
+        ("GET", ["resources", id]) => get_resource(db, id),
+        ("PUT", ["resources", id, "owner"]) => transfer_resource(db, id, request),
+        ("POST", ["resources", id, "lease"]) => renew_lease(db, id, request),
+        ("PATCH", ["resources", id]) => update_resource_metadata(db, id, request),
+        (_, ["pools"]) | (_, ["pools", _]) | (_, ["strategies"]) | (_, ["strategies", _]) | (_, ["resources", _])
+        | (_, ["maintenance"]) =>
+            Err(HttpError { status: 405, message: format!("{} is not allowed", request.method) }.into()),
+        _ => Err(HttpError { status: 404, message: format!("No route for {}", request.path) }.into()),
+    });
+    result.map_err(DB::read_only_error).unwrap_or_else(|err| {
+        let status = status_of(&err);
+        if let Some(AllocationError::ServiceReadOnly) = err.downcast_ref::<AllocationError>() {
+            return HttpResponse::error(status, &err)
+                .with_header("Retry-After", MAINTENANCE_RETRY_AFTER_SECS.to_string());
+        }
+        if status >= 500 {
+            warn!("{} {} failed: {:#}", request.method, request.path, err);
+        }
+        HttpResponse::error(status, &err)
+    })
+}
+
+/// JSON API served by a fixed number of threads, each with its own database connection and `WasmerEnv`.
+pub struct Server {
+    listener: TcpListener,
+    cursor_key: CursorKey,
+    health: Health,
+}
+
+impl Server {
+    pub fn bind(address: &str) -> Result<Server> {
+        let listener = TcpListener::bind(address).context(format!("Cannot listen on {}", address))?;
+        let cursor_key = CursorKey::from_config(&Config::from_env()?);
+        Ok(Server { listener, cursor_key, health: Health::default() })
+    }
+
+    // Reports the subsystems in `GET /readyz`, e.g. of the daemon running this server.
+    pub fn with_health(mut self, health: Health) -> Server {
+        self.health = health;
+        self
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    // Serves until shutdown is requested, requests being handled are finished first.
+    pub fn run(self, threads: usize, shutdown: Shutdown) -> Result<()> {
+        info!("Listening on {}", self.local_addr()?);
+        // accept polls the shutdown flag instead of blocking
+        self.listener.set_nonblocking(true)?;
+        let events = PoolEvents::start(shutdown)?;
+        let scripts = ScriptCache::start(shutdown)?;
+        let handles = (0..threads.max(1))
+            .map(|idx| {
+                let listener = self.listener.try_clone()?;
+                let mut db = DB::new_from_env()?.with_script_cache(scripts.clone());
+                metrics::connection_opened();
+                let mut wasmer_env = WasmerEnv::new()?;
+                let cursor_key = self.cursor_key.clone();
+                let health = self.health.clone();
+                let events = events.clone();
+                Ok(thread::Builder::new().name(format!("http-{}", idx)).spawn(move || {
+                    while !shutdown.is_requested() {
+                        match listener.accept() {
+                            Ok((mut stream, _)) => {
+                                if let Err(err) = stream.set_nonblocking(false) {
+                                    warn!("Cannot accept connection: {}", err);
+                                    continue;
+                                }
+                                let response = match read_request(&mut stream) {
+                                    Ok(request) => {
+                                        // logged by every event of the request and sent back to the client
+                                        let request_id = request.headers.get("x-request-id").cloned()
+                                            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+                                        let _span = info_span!("request", request_id = %request_id).entered();
+                                        let _in_use = metrics::ConnectionInUse::acquire();
+                                        let started = Instant::now();
+                                        let response = match (streamed_pool(&request), subscribed_pool(&request)) {
+                                            (Some(pool_id), _) => stream_resources(&mut db, &mut stream, pool_id,
+                                                                                   &request, &request_id),
+                                            (_, Some(pool_id)) => subscribe_events(&mut db, &events, shutdown,
+                                                                                   &stream, pool_id, &request),
+                                            _ => Some(handle(&mut db, &mut wasmer_env, &cursor_key, &health,
+                                                             &request)),
+                                        };
+                                        let status = response.as_ref().map_or(200, |response| response.status);
+                                        debug!(duration_ms = started.elapsed().as_millis() as u64, "{} {} {}",
+                                               request.method, request.path, status);
+                                        response.map(|mut response| {
+                                            response.headers.push(("X-Request-Id", request_id));
+                                            response
+                                        })
+                                    }
+                                    Err(err) => Some(HttpResponse::error(status_of(&err), &err)),
+                                };
+                                // streamed responses were written already
+                                if let Some(response) = response {
+                                    if let Err(err) = write_response(&mut stream, &response) {
+                                        debug!("Cannot write response: {:#}", err);
+                                    }
+                                }
+                            }
+                            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+                            Err(err) => warn!("Cannot accept connection: {}", err),
+                        }
+                    }
+                })?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for handle in handles {
+            handle.join().map_err(|_| anyhow!("HTTP thread panicked"))?;
+        }
+        info!("Stopped listening on {}", self.local_addr()?);
+        Ok(())
+    }
+}
+
+// Serves only `GET /metrics` of this process on a background thread, e.g. for workers. Returns the bound address.
+pub fn serve_metrics(address: &str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(address).context(format!("Cannot listen on {}", address))?;
+    let local_addr = listener.local_addr()?;
+    thread::Builder::new().name("metrics".to_owned()).spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("Cannot accept connection: {}", err);
+                    continue;
+                }
+            };
+            let response = match read_request(&mut stream) {
+                Ok(request) if request.method == "GET" && request.path == "/metrics" =>
+                    HttpResponse::text(METRICS_CONTENT_TYPE, metrics::render()),
+                Ok(request) => HttpResponse::error(404, &anyhow!("No route for {}", request.path)),
+                Err(err) => HttpResponse::error(status_of(&err), &err),
+            };
+            if let Err(err) = write_response(&mut stream, &response) {
+                debug!("Cannot write response: {:#}", err);
+            }
+        }
+    })?;
+    info!("Serving metrics on {}", local_addr);
+    Ok(local_addr)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{BufRead, BufReader, Read};
+    use std::time::Duration;
+
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::Resource;
+    use crate::supervisor::Supervisor;
+    use crate::tests::{create_random_pool, initialize_logging, IPV4_ALLOCATION_STRATEGY_ID};
+    use super::*;
+
+    // Request to a server started on a random port, returns the status and the JSON body.
+    pub(crate) fn send(address: SocketAddr, method: &str, target: &str, body: Option<&Value>) -> (u16, Value) {
+        let (status, _, body) = send_with_headers(address, method, target, &[], body);
+        (status, body)
+    }
+
+    // Like `send`, also returns headers of the response with lowercase names. An empty body is returned as null.
+    pub(crate) fn send_with_headers(address: SocketAddr, method: &str, target: &str, headers: &[(&str, &str)],
+                                    body: Option<&Value>) -> (u16, HashMap<String, String>, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let headers = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect::<String>();
+        write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+               method, target, headers, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let mut lines = head.lines();
+        let status = lines.next().unwrap().split_whitespace().nth(1).unwrap().parse().unwrap();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+            .collect();
+        let body = if body.is_empty() { Value::Null } else { serde_json::from_str(body).unwrap() };
+        (status, headers, body)
+    }
+
+    pub(crate) fn start_server() -> SocketAddr {
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run(2, Shutdown::new()).unwrap());
+        address
+    }
+
+    #[test]
+    fn query_decoding() {
+        let query = parse_query("name_prefix=a%2Fb+c&tag=&sort=-name&broken=%zz");
+        assert_eq!("a/b c", query["name_prefix"]);
+        assert_eq!("", query["tag"]);
+        assert_eq!("-name", query["sort"]);
+        assert_eq!("%zz", query["broken"]);
+        assert_eq!("100%", percent_decode("100%"));
+    }
+
+    #[test]
+    fn http_list_pools() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let prefix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        for suffix in ["a", "b"] {
+            db.insert_resource_pool(&format!("{}-{}", prefix, suffix), IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        }
+        let address = start_server();
+        let (status, body) = send(address, "GET", &format!("/pools?name_prefix={}&sort=-name&limit=1", prefix), None);
+        assert_eq!(200, status);
+        assert_eq!(vec![json!(format!("{}-b", prefix))],
+                   body["pools"].as_array().unwrap().iter().map(|pool| pool["name"].clone()).collect::<Vec<_>>());
+
+        let next = body["nextCursor"].as_str().unwrap().to_owned();
+        // pools created meanwhile do not shift the next page
+        db.insert_resource_pool(&format!("{}-c", prefix), IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        let page = format!("/pools?name_prefix={}&sort=-name&limit=1&cursor={}", prefix, next);
+        let (status, body) = send(address, "GET", &page, None);
+        assert_eq!(200, status);
+        assert_eq!(json!(format!("{}-a", prefix)), body["pools"][0]["name"]);
+        let last = send(address, "GET", &format!("/pools?name_prefix={}&sort=-name&limit=1&cursor={}",
+                                                 prefix, body["nextCursor"].as_str().unwrap()), None).1;
+        assert_eq!((json!([]), Value::Null), (last["pools"].clone(), last["nextCursor"].clone()));
+        // the cursor is bound to the filter
+        assert_eq!(400, send(address, "GET", &format!("/pools?sort=-name&limit=1&cursor={}", next), None).0);
+        assert_eq!(400, send(address, "GET", &format!("/pools?name_prefix={}&sort=-name&cursor={}x", prefix, next),
+                             None).0);
+
+        assert_eq!(400, send(address, "GET", "/pools?limit=x", None).0);
+        assert_eq!(400, send(address, "GET", "/pools?sort=size", None).0);
+        assert_eq!(405, send(address, "DELETE", "/pools", None).0);
+        assert_eq!(404, send(address, "GET", "/unknown", None).0);
+    }
+
+    #[test]
+    fn http_stream_resources() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = (1..=5)
+            .map(|i| Resource::new_from_value(pool.id, json!({"address": format!("10.0.0.{}", i)})))
+            .collect();
+        db.insert_resources(pool.clone(), resources).unwrap();
+        let address = start_server();
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /pools/{}/resources?batch_size=2 HTTP/1.1\r\nHost: localhost\r\n\r\n", pool.id).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, mut chunked) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK") && head.contains("Transfer-Encoding: chunked"), "{}", head);
+        let mut chunks = vec![];
+        loop {
+            let (size, rest) = chunked.split_once("\r\n").unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            if size == 0 {
+                break;
+            }
+            chunks.push(&rest[..size]);
+            chunked = &rest[size + 2..];
+        }
+        assert_eq!(3, chunks.len());
+        let lines = chunks.concat().lines().map(|line| serde_json::from_str::<Value>(line).unwrap()["value"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!((1..=5).map(|i| json!({"address": format!("10.0.0.{}", i)})).collect::<Vec<_>>(), lines);
+
+        assert_eq!(400, send(address, "GET", &format!("/pools/{}/resources?batch_size=0", pool.id), None).0);
+        assert_eq!(404, send(address, "GET", "/pools/-1/resources", None).0);
+    }
+
+    #[test]
+    fn http_pool_events() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let address = start_server();
+        assert_eq!(400, send(address, "GET", &format!("/pools/{}/events", pool.id), None).0);
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /pools/{}/events HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\n\
+            Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+               pool.id).unwrap();
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("HTTP/1.1 101") && head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="),
+                "{}", head);
+
+        let (_, allocated) = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}),
+                                                   &AllocationOptions::default()).unwrap();
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).unwrap();
+        assert_eq!(0x81, header[0]);
+        let len = match header[1] {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).unwrap();
+        let event: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!((json!("allocated"), &allocated[0].value),
+                   (event["event"].clone(), &event["resources"][0]["value"]));
+
+        // masked close frame without payload, answered by a close frame with status 1000
+        stream.write_all(&[0x88, 0x80, 1, 2, 3, 4]).unwrap();
+        let mut close = vec![];
+        stream.read_to_end(&mut close).unwrap();
+        assert_eq!(vec![0x88, 2, 0x03, 0xe8], close);
+    }
+
+    #[test]
+    fn http_pool_event_stream() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .unwrap();
+        let (pool, second) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .unwrap();
+        let first_id = db.get_pool_events(pool.id, 0, 1).unwrap()[0]["id"].clone();
+        let address = start_server();
+        assert_eq!(400, send(address, "GET", &format!("/pools/{}/events?last_event_id=x", pool.id), None).0);
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /pools/{}/events HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\
+            Last-Event-ID: {}\r\n\r\n", pool.id, first_id).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut next_event = || {
+            let mut fields = vec![];
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end() {
+                    "" if !fields.is_empty() => return fields,
+                    line if line.starts_with("id:") || line.starts_with("event:") || line.starts_with("data:") =>
+                        fields.push(line.to_owned()),
+                    _ => {}
+                }
+            }
+        };
+
+        // the missed event is replayed, the first one was seen already
+        let replayed = next_event();
+        assert_eq!("event: allocated", replayed[1]);
+        let data: Value = serde_json::from_str(replayed[2].trim_start_matches("data: ")).unwrap();
+        assert_eq!((format!("id: {}", data["id"]), &second[0].value),
+                   (replayed[0].clone(), &data["resources"][0]["value"]));
+        assert!(data["id"].as_i64() > first_id.as_i64());
+
+        let (_, third) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .unwrap();
+        let live = next_event();
+        let data: Value = serde_json::from_str(live[2].trim_start_matches("data: ")).unwrap();
+        assert_eq!(&third[0].value, &data["resources"][0]["value"]);
+    }
+
+    #[test]
+    fn http_shutdown() {
+        initialize_logging();
+
+        let server = Server::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let shutdown = Shutdown::new();
+        let handle = thread::spawn(move || server.run(2, shutdown));
+        assert_eq!(404, send(address, "GET", "/unknown", None).0);
+        shutdown.request();
+        handle.join().unwrap().unwrap();
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn http_resources_for_pools() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let empty = create_random_pool(&mut db).unwrap();
+        db.insert_resources(pool.clone(), vec![Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"}))])
+            .unwrap();
+        let address = start_server();
+
+        let (status, body) = send(address, "GET", &format!("/resources?pools={},{}", pool.id, empty.id), None);
+        assert_eq!(200, status, "{}", body);
+        assert_eq!(json!({"address": "10.0.0.1"}), body["pools"][pool.id.to_string()][0]["value"]);
+        assert_eq!(json!([]), body["pools"][empty.id.to_string()]);
+        assert_eq!(400, send(address, "GET", "/resources?pools=1,x", None).0);
+    }
+
+    #[test]
+    fn http_resource_owner() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.insert_resources(
+            pool.clone(), vec![Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"}))]).unwrap();
+        let id = db.get_resources(pool.id).unwrap()[0].id.unwrap();
+        let owner: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let address = start_server();
+
+        let target = format!("/resources/{}/owner", id);
+        let (status, body) = send(address, "PUT", &target, Some(&json!({"owner": owner})));
+        assert_eq!(200, status, "{}", body);
+        assert_eq!(json!(owner), body["owner"]);
+        let (status, body) = send(address, "GET", &format!("/resources?owner={}", owner), None);
+        assert_eq!(200, status);
+        assert_eq!(json!([id]), json!(body["resources"].as_array().unwrap().iter()
+            .map(|it| it["id"].clone()).collect::<Vec<_>>()));
+        assert_eq!(Value::Null, body["nextCursor"]);
+
+        // pages of one resource, the second one is owned by the same owner
+        let (pool, _) = db.insert_resources(
+            pool.clone(), vec![Resource::new_from_value(pool.id, json!({"address": "10.0.0.2"}))]).unwrap();
+        let second_id = db.get_resources(pool.id).unwrap()[1].id.unwrap();
+        db.transfer_resource(second_id, Some(&owner)).unwrap();
+        let (_, first_page) = send(address, "GET", &format!("/resources?owner={}&limit=1", owner), None);
+        assert_eq!(json!(id), first_page["resources"][0]["id"]);
+        let cursor = first_page["nextCursor"].as_str().unwrap();
+        let (status, second_page) = send(address, "GET", &format!("/resources?owner={}&limit=1&cursor={}", owner,
+                                                                  cursor), None);
+        assert_eq!(200, status, "{}", second_page);
+        assert_eq!(json!([second_id]), json!(second_page["resources"].as_array().unwrap().iter()
+            .map(|it| it["id"].clone()).collect::<Vec<_>>()));
+        let (_, last_page) = send(address, "GET", &format!("/resources?owner={}&limit=1&cursor={}", owner,
+                                                           second_page["nextCursor"].as_str().unwrap()), None);
+        assert_eq!((json!([]), Value::Null), (last_page["resources"].clone(), last_page["nextCursor"].clone()));
+        // the cursor belongs to another owner
+        assert_eq!(400, send(address, "GET", &format!("/resources?owner=x&cursor={}", cursor), None).0);
+
+        assert_eq!(400, send(address, "PUT", &target, Some(&json!({"owner": 1}))).0);
+        assert_eq!(400, send(address, "GET", "/resources", None).0);
+        assert_eq!(404, send(address, "PUT", "/resources/-1/owner", Some(&json!({"owner": null}))).0);
+        let (status, body) = send(address, "PUT", &target, Some(&json!({"owner": null})));
+        assert_eq!((200, None), (status, body.get("owner")));
+        // the resource was inserted without a lease
+        assert_eq!(409, send(address, "POST", &format!("/resources/{}/lease", id),
+                             Some(&json!({"extendBy": 60}))).0);
+        assert_eq!(400, send(address, "POST", &format!("/resources/{}/lease", id), Some(&json!({}))).0);
+        db.client.execute("UPDATE resources SET lease_expires_at = now() - interval '1 minute' WHERE id=$1", &[&id])
+            .unwrap();
+        let (status, body) = send(address, "POST", &format!("/resources/{}/lease", id), Some(&json!({"extendBy": 60})));
+        assert_eq!(409, status);
+        assert_eq!(json!(format!("Resource {} has no unexpired lease", id)), body["error"]);
+    }
+
+    #[test]
+    fn http_readiness() {
+        initialize_logging();
+
+        assert_eq!((200, json!({"ready": true, "subsystems": {}})), send(start_server(), "GET", "/readyz", None));
+
+        let shutdown = Shutdown::new();
+        let mut supervisor = Supervisor::new(shutdown);
+        supervisor.spawn("crashing", || Err(anyhow!("Cannot connect"))).unwrap();
+        let server = Server::bind("127.0.0.1:0").unwrap().with_health(supervisor.health());
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run(1, shutdown));
+        let (status, body) = send(address, "GET", "/readyz", None);
+        assert_eq!(503, status);
+        assert_eq!(json!(false), body["ready"]);
+        assert_eq!(json!("restarting"), body["subsystems"]["crashing"]["state"]);
+        shutdown.request();
+        supervisor.join().unwrap();
+    }
+
+    #[test]
+    fn http_resource_metadata() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.insert_resources(
+            pool.clone(), vec![Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"}))]).unwrap();
+        let id = db.get_resources(pool.id).unwrap()[0].id.unwrap();
+        let address = start_server();
+        let (status, body) = send(address, "PATCH", &format!("/resources/{}", id), Some(&json!({"owner": "a"})));
+        assert_eq!(200, status);
+        assert_eq!(json!({"owner": "a"}), body["metadata"]);
+        let (status, body) = send(address, "GET", &format!("/resources/{}", id), None);
+        assert_eq!((200, json!(pool.id)), (status, body["resourcePool"].clone()));
+
+        assert_eq!(404, send(address, "GET", "/resources/-1", None).0);
+        assert_eq!(400, send(address, "GET", "/resources/x", None).0);
+        assert_eq!(400, send(address, "PATCH", &format!("/resources/{}", id), Some(&json!([]))).0);
+    }
+
+    #[test]
+    fn http_count_and_exists() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        db.insert_resources(pool.clone(), vec![Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"}))])
+            .unwrap();
+        let address = start_server();
+        let count = send(address, "GET", &format!("/pools/{}/resources/count", pool.id), None);
+        assert_eq!((200, json!({"count": 1})), count);
+        let exists = |value: &str| send(address, "GET", &format!("/pools/{}/resources/exists?value={}", pool.id,
+                                                                 value), None);
+        assert_eq!((200, json!({"exists": true})), exists("%7B%22address%22%3A%2210.0.0.1%22%7D"));
+        assert_eq!((200, json!({"exists": false})), exists("%7B%22address%22%3A%2210.0.0.2%22%7D"));
+        assert_eq!(400, exists("not-json").0);
+    }
+
+    #[test]
+    fn http_search_resources() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = (1..=2)
+            .map(|idx| Resource::new_from_value(pool.id, json!({"address": format!("10.0.0.{}", idx)})))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let ids = db.get_resources(pool.id).unwrap().iter().map(|it| it.id.unwrap()).collect::<Vec<_>>();
+        for id in &ids {
+            db.update_resource_metadata(*id, &json!({"service": "voip"})).unwrap();
+        }
+        let id = ids[0];
+        let address = start_server();
+        let search = |query: &str| send(address, "GET", &format!("/pools/{}/resources/search?{}", pool.id, query), None);
+        let contains = "contains=%7B%22service%22%3A%22voip%22%7D";
+        let (status, body) = search(contains);
+        assert_eq!(200, status);
+        assert_eq!(json!(id), body["resources"][0]["id"]);
+        assert_eq!(Value::Null, body["nextCursor"]);
+
+        let (_, first_page) = search(&format!("{}&limit=1", contains));
+        assert_eq!(json!(ids[0]), first_page["resources"][0]["id"]);
+        let cursor = first_page["nextCursor"].as_str().unwrap();
+        let (status, second_page) = search(&format!("{}&limit=1&cursor={}", contains, cursor));
+        assert_eq!(200, status, "{}", second_page);
+        assert_eq!(json!(ids[1]), second_page["resources"][0]["id"]);
+        // the cursor belongs to another query
+        let path = "path=%24.service%20%3D%3D%20%22voip%22";
+        assert_eq!(400, search(&format!("{}&limit=1&cursor={}", path, cursor)).0);
+        let (_, body) = search("path=%24.service+%3D%3D+%22iptv%22");
+        assert_eq!(json!([]), body["resources"]);
+        assert_eq!(400, search("path=%24.%5B").0);
+        assert_eq!(400, search("contains=%22voip%22").0);
+        assert_eq!(400, search("limit=1").0);
+    }
+
+    #[test]
+    fn http_preview_allocation() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let address = start_server();
+        let preview = format!("/pools/{}/allocate:preview", pool.id);
+        let (status, mut body) = send(address, "POST", &preview, Some(&json!({"resourceCount": 2})));
+        assert_eq!(200, status);
+        // a preview writes nothing
+        let timings = body.as_object_mut().unwrap().remove("timings").unwrap();
+        assert_eq!(json!(0.0), timings["db_write_ms"]);
+        assert!(timings["total_ms"].as_f64().unwrap() >= timings["db_read_ms"].as_f64().unwrap());
+        assert_eq!(json!({"count": 2, "resources": [{"address": "10.0.0.0"}, {"address": "10.0.0.1"}]}), body);
+        assert_eq!(0, db.count_resources(pool.id).unwrap());
+        assert_eq!(pool.version, db.get_resource_pool_by_id(pool.id).unwrap().version);
+
+        assert_eq!(400, send(address, "POST", &preview, Some(&json!({"resourceCount": 0}))).0);
+        assert_eq!(404, send(address, "POST", "/pools/-1/allocate:preview", None).0);
+    }
+
+    #[test]
+    fn http_pool_stats() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        db.record_pool_stats(Duration::from_secs(3600)).unwrap();
+        let address = start_server();
+        let (status, body) = send(address, "GET", &format!("/pools/{}/stats?range=1h", pool.id), None);
+        assert_eq!(200, status);
+        assert_eq!(json!(0), body["samples"][0]["inUse"]);
+        assert_eq!(json!({}), body["samples"][0]["byState"]);
+        assert_eq!(400, send(address, "GET", &format!("/pools/{}/stats?range=week", pool.id), None).0);
+        assert_eq!(404, send(address, "GET", "/pools/-1/stats", None).0);
+    }
+
+    #[test]
+    fn http_pool_if_match() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let address = start_server();
+        let target = format!("/pools/{}", pool.id);
+        let (status, headers, body) = send_with_headers(address, "GET", &target, &[], None);
+        assert_eq!(200, status);
+        assert_eq!(json!(pool.name), body["name"]);
+        let etag = headers["etag"].clone();
+        assert_eq!(format!("\"{}\"", pool.version), etag);
+
+        let properties = format!("{}/properties", target);
+        let new_properties = json!({"address": "10.0.0.0", "prefix": 16});
+        assert_eq!(428, send(address, "PUT", &properties, Some(&new_properties)).0);
+        let (status, headers, body) = send_with_headers(address, "PUT", &properties, &[("If-Match", &etag)],
+                                                        Some(&new_properties));
+        assert_eq!(200, status);
+        assert_eq!(new_properties, body["properties"]);
+        assert_eq!(format!("\"{}\"", pool.version + 1), headers["etag"]);
+
+        // the ETag is stale after the update
+        let (status, _, body) = send_with_headers(address, "DELETE", &target, &[("If-Match", &etag)], None);
+        assert_eq!(412, status, "{}", body);
+        assert!(db.find_resource_pool_by_id(pool.id).unwrap().is_some());
+        let (status, headers, body) = send_with_headers(address, "GET", &target, &[], None);
+        assert_eq!(200, status);
+        assert_eq!(format!("\"{}\"", body["version"]), headers["etag"]);
+
+        // or by the time of the last change, as RFC 3339 or an IMF-fixdate
+        let updated_at = body["updatedAt"].as_str().unwrap().to_owned();
+        let stale = pool.as_export_json()["updatedAt"].as_str().unwrap().to_owned();
+        let (status, _, body) = send_with_headers(address, "PUT", &properties, &[("If-Unmodified-Since", &stale)],
+                                                  Some(&new_properties));
+        assert_eq!(412, status, "{}", body);
+        let imf_stale = [("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")];
+        assert_eq!(412, send_with_headers(address, "PUT", &properties, &imf_stale, Some(&new_properties)).0);
+        assert_eq!(400, send_with_headers(address, "DELETE", &target, &[("If-Unmodified-Since", "yesterday")],
+                                          None).0);
+        let last_modified = [("If-Unmodified-Since", headers["last-modified"].as_str())];
+        let (status, headers, body) = send_with_headers(address, "PUT", &properties, &last_modified,
+                                                        Some(&new_properties));
+        assert_eq!(200, status, "{}", body);
+        // the stale time is ignored along with If-Match
+        let if_match = format!("\"0\", W/{}", headers["etag"]);
+        let since = [("If-Unmodified-Since", updated_at.as_str()), ("If-Match", if_match.as_str())];
+        assert_eq!(204, send_with_headers(address, "DELETE", &target, &since, None).0);
+        assert!(db.find_resource_pool_by_id(pool.id).unwrap().is_none());
+        assert_eq!(404, send(address, "GET", &target, None).0);
+    }
+
+    #[test]
+    fn http_list_strategies() {
+        initialize_logging();
+
+        let address = start_server();
+        let (status, body) = send(address, "GET", "/strategies", None);
+        assert_eq!(200, status);
+        let ipv4 = &body["strategies"].as_array().unwrap()[0];
+        assert_eq!(json!(IPV4_ALLOCATION_STRATEGY_ID), ipv4["id"]);
+        assert_eq!("javascript", ipv4["language"]);
+
+        let (_, first) = send(address, "GET", "/strategies?limit=1", None);
+        assert_eq!(vec![ipv4.clone()], first["strategies"].as_array().unwrap().clone());
+        let (status, second) = send(address, "GET", &format!("/strategies?limit=1&cursor={}",
+                                                             first["nextCursor"].as_str().unwrap()), None);
+        assert_eq!(200, status);
+        assert!(second["strategies"][0]["id"].as_i64().unwrap() > IPV4_ALLOCATION_STRATEGY_ID);
+
+        let (status, strategy) = send(address, "GET", &format!("/strategies/{}", IPV4_ALLOCATION_STRATEGY_ID), None);
+        assert_eq!(200, status);
+        assert_eq!(ipv4["description"], strategy["description"]);
+        assert!(strategy["expectedUserInputDoc"].as_str().unwrap().contains("resourceCount"));
+        assert_eq!(404, send(address, "GET", &format!("/strategies/{}", i64::MAX), None).0);
+    }
+
+    #[test]
+    fn http_metrics() {
+        initialize_logging();
+
+        let address = serve_metrics("127.0.0.1:0").unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(stream, "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert!(response.contains(&format!("Content-Type: {}\r\n", METRICS_CONTENT_TYPE)), "{}", response);
+        assert!(response.contains("\r\n\r\n# HELP allocations_total "), "{}", response);
+        assert_eq!(404, send(address, "GET", "/pools", None).0);
+    }
+}
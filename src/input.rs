@@ -0,0 +1,312 @@
+use anyhow::{Result, anyhow, bail, ensure};
+use postgres::Client;
+use serde_json::Value;
+
+use crate::{DB, ResourcePool};
+use crate::error::{AllocationError, FieldError};
+
+// Subset of JSON Schema supported by `InputSchema`, unsupported keywords are refused when the schema is declared.
+const KEYWORDS: [&str; 18] = ["$schema", "title", "description", "type", "enum", "minimum", "maximum",
+    "exclusiveMinimum", "exclusiveMaximum", "minLength", "maxLength", "properties", "required",
+    "additionalProperties", "items", "minItems", "maxItems", "default"];
+
+const TYPES: [&str; 7] = ["null", "boolean", "integer", "number", "string", "array", "object"];
+
+/// JSON Schema a strategy declares for `userInput`, e.g.
+/// `{"type": "object", "properties": {"resourceCount": {"type": "integer", "minimum": 1}}}`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputSchema(Value);
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn check_schema(schema: &Value, path: &str) -> Result<()> {
+    let schema = schema.as_object().ok_or_else(|| anyhow!("Schema of '{}' must be a JSON object", path))?;
+    for (keyword, value) in schema {
+        ensure!(KEYWORDS.contains(&keyword.as_str()), "Unsupported keyword '{}' in schema of '{}'", keyword, path);
+        match keyword.as_str() {
+            "type" => {
+                let types = match value {
+                    Value::Array(types) => types.iter().collect(),
+                    single => vec![single],
+                };
+                for declared in types {
+                    ensure!(declared.as_str().is_some_and(|it| TYPES.contains(&it)),
+                            "Unknown type {} in schema of '{}'", declared, path);
+                }
+            }
+            "enum" => ensure!(value.is_array(), "'enum' of '{}' must be an array", path),
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" =>
+                ensure!(value.is_number(), "'{}' of '{}' must be a number", keyword, path),
+            "minLength" | "maxLength" | "minItems" | "maxItems" =>
+                ensure!(value.is_u64(), "'{}' of '{}' must be a non-negative integer", keyword, path),
+            "required" => ensure!(value.as_array().is_some_and(|it| it.iter().all(Value::is_string)),
+                                  "'required' of '{}' must be an array of strings", path),
+            "additionalProperties" => match value {
+                Value::Bool(_) => {}
+                schema => check_schema(schema, &format!("{}.*", path))?,
+            },
+            "properties" => {
+                let properties = value.as_object()
+                    .ok_or_else(|| anyhow!("'properties' of '{}' must be a JSON object", path))?;
+                for (name, schema) in properties {
+                    check_schema(schema, &field_path(path, name))?;
+                }
+            }
+            "items" => check_schema(value, &format!("{}[]", path))?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn field_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() { name.to_owned() } else { format!("{}.{}", parent, name) }
+}
+
+fn validate(schema: &Value, value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+    let mut error = |message: String| errors.push(FieldError { field: path.to_owned(), message });
+    if let Some(declared) = schema.get("type") {
+        let actual = type_of(value);
+        let matches = |declared: &Value| declared == actual || (declared == "number" && actual == "integer");
+        let valid = match declared {
+            Value::Array(types) => types.iter().any(matches),
+            single => matches(single),
+        };
+        if !valid {
+            error(format!("must be {}, got {}", declared.as_str().map_or_else(|| declared.to_string(), str::to_owned),
+                          value));
+            return;
+        }
+    }
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            error(format!("must be one of {}", Value::Array(allowed.clone())));
+        }
+    }
+    if let Some(number) = value.as_f64() {
+        let limit = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+        if let Some(minimum) = limit("minimum").filter(|minimum| number < *minimum) {
+            error(format!("must be >= {}", minimum));
+        }
+        if let Some(maximum) = limit("maximum").filter(|maximum| number > *maximum) {
+            error(format!("must be <= {}", maximum));
+        }
+        if let Some(minimum) = limit("exclusiveMinimum").filter(|minimum| number <= *minimum) {
+            error(format!("must be > {}", minimum));
+        }
+        if let Some(maximum) = limit("exclusiveMaximum").filter(|maximum| number >= *maximum) {
+            error(format!("must be < {}", maximum));
+        }
+    }
+    let length = |keyword: &str| schema.get(keyword).and_then(Value::as_u64).map(|it| it as usize);
+    if let Some(string) = value.as_str() {
+        let chars = string.chars().count();
+        if let Some(min_length) = length("minLength").filter(|min_length| chars < *min_length) {
+            error(format!("must have at least {} characters", min_length));
+        }
+        if let Some(max_length) = length("maxLength").filter(|max_length| chars > *max_length) {
+            error(format!("must have at most {} characters", max_length));
+        }
+    }
+    if let Some(items) = value.as_array() {
+        if let Some(min_items) = length("minItems").filter(|min_items| items.len() < *min_items) {
+            error(format!("must have at least {} items", min_items));
+        }
+        if let Some(max_items) = length("maxItems").filter(|max_items| items.len() > *max_items) {
+            error(format!("must have at most {} items", max_items));
+        }
+        if let Some(item_schema) = schema.get("items") {
+            for (idx, item) in items.iter().enumerate() {
+                validate(item_schema, item, &format!("{}[{}]", path, idx), errors);
+            }
+        }
+    }
+    if let Some(object) = value.as_object() {
+        for required in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+            let required = required.as_str().unwrap_or_default();
+            if !object.contains_key(required) {
+                errors.push(FieldError { field: field_path(path, required), message: "is required".to_owned() });
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, property) in object {
+            let field = field_path(path, name);
+            match (properties.and_then(|it| it.get(name)), schema.get("additionalProperties")) {
+                (Some(property_schema), _) => validate(property_schema, property, &field, errors),
+                (None, Some(Value::Bool(false))) =>
+                    errors.push(FieldError { field, message: "is not allowed".to_owned() }),
+                (None, Some(additional)) if additional.is_object() => validate(additional, property, &field, errors),
+                (None, _) => {}
+            }
+        }
+    }
+}
+
+impl InputSchema {
+    pub fn from_json(schema: Value) -> Result<InputSchema> {
+        check_schema(&schema, "userInput")?;
+        Ok(InputSchema(schema))
+    }
+
+    pub fn as_json(&self) -> &Value {
+        &self.0
+    }
+
+    // Every field of the input failing the schema, empty if the input is valid.
+    pub fn errors(&self, user_input: &Value) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        validate(&self.0, user_input, "", &mut errors);
+        errors
+    }
+}
+
+impl DB {
+    pub(crate) fn find_input_schema(client: &mut Client, allocation_strategy_id: i64)
+                                    -> Result<Option<InputSchema>> {
+        let row = client.query_opt(
+            "SELECT input_schema FROM allocation_strategies WHERE id=$1", &[&allocation_strategy_id])?
+            .ok_or_else(|| anyhow!("Allocation strategy {} not found", allocation_strategy_id))?;
+        row.get::<_, Option<Value>>(0).map(InputSchema::from_json).transpose()
+    }
+
+    // Declare the input of the strategy, None accepts any input.
+    pub fn set_input_schema(&mut self, allocation_strategy_id: i64, schema: Option<&InputSchema>) -> Result<()> {
+        let schema = schema.map(InputSchema::as_json);
+        let updated = self.client.execute(
+            "UPDATE allocation_strategies SET input_schema=$2 WHERE id=$1", &[&allocation_strategy_id, &schema])?;
+        if updated == 0 {
+            bail!("Allocation strategy {} not found", allocation_strategy_id);
+        }
+        Ok(())
+    }
+
+    // Fails with `AllocationError::InvalidUserInput` listing all fields failing the schema of the pool's strategy.
+    // Default input of the pool is merged under the input first.
+    pub(crate) fn check_user_input(client: &mut Client, resource_pool_id: i64, user_input: &Value)
+                                   -> Result<()> {
+        let row = client.query_opt(
+            "SELECT name, resource_pool_allocation_strategy, default_user_input FROM resource_pools WHERE id=$1",
+            &[&resource_pool_id])?
+            .ok_or_else(|| anyhow!("Resource pool {} not found", resource_pool_id))?;
+        let schema = Self::find_input_schema(client, row.get(1))?;
+        let user_input = ResourcePool::merge_user_input(&row.get(2), user_input.clone());
+        Self::check_input_schema(row.get(0), schema.as_ref(), &user_input)
+    }
+
+    // Fields merged under `userInput` of every allocation from the pool, e.g. `{"subnet": true}` of a prefix pool,
+    // so that clients do not have to repeat them. Must be an object.
+    pub fn set_default_user_input(&mut self, mut pool: ResourcePool, default_user_input: Value)
+                                  -> Result<ResourcePool> {
+        ensure!(default_user_input.is_object(), "Default user input must be a JSON object, got '{}'",
+                default_user_input);
+        Self::check_not_archived(&mut self.client, pool.id)?;
+        let updated = self.client.query_opt(
+            "UPDATE resource_pools SET default_user_input=$1 WHERE id=$2 RETURNING updated_at",
+            &[&default_user_input, &pool.id])?
+            .ok_or_else(|| anyhow!("Update of resource_pools returned wrong number of rows"))?;
+        pool.default_user_input = default_user_input;
+        pool.updated_at = updated.get(0);
+        Ok(pool)
+    }
+
+    pub(crate) fn check_input_schema(resource_pool: &str, schema: Option<&InputSchema>, user_input: &Value)
+                                     -> Result<()> {
+        if let Some(schema) = schema {
+            let errors = schema.errors(user_input);
+            if !errors.is_empty() {
+                return Err(AllocationError::InvalidUserInput { resource_pool: resource_pool.to_owned(), errors }.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::AllocationOptions;
+    use crate::WasmerEnv;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn input_schema_errors() {
+        let schema = InputSchema::from_json(json!({
+            "type": "object",
+            "required": ["name"],
+            "additionalProperties": false,
+            "properties": {
+                "resourceCount": {"type": "integer", "minimum": 1},
+                "name": {"type": "string", "maxLength": 3},
+                "ports": {"type": "array", "items": {"type": "integer", "exclusiveMaximum": 65536}},
+                "mode": {"enum": ["a", "b"]}
+            }
+        })).unwrap();
+        assert!(schema.errors(&json!({"name": "abc", "resourceCount": 2, "ports": [80], "mode": "a"})).is_empty());
+        let errors = schema.errors(&json!({"resourceCount": 0, "ports": [80, 70000], "mode": "c", "other": 1}))
+            .iter().map(FieldError::to_string).collect::<Vec<_>>();
+        assert_eq!(vec!["name is required", "mode must be one of [\"a\",\"b\"]", "other is not allowed",
+                        "ports[1] must be < 65536", "resourceCount must be >= 1"], errors);
+        assert_eq!(vec!["must be object, got []"],
+                   schema.errors(&json!([])).iter().map(FieldError::to_string).collect::<Vec<_>>());
+
+        assert!(InputSchema::from_json(json!({"properties": {"name": {"pattern": "^a"}}})).is_err());
+        assert!(InputSchema::from_json(json!({"type": "int"})).is_err());
+    }
+
+    #[test]
+    fn db_allocate_invalid_user_input() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({"resourceCount": 0}),
+                                        &AllocationOptions::default()).unwrap_err();
+        assert_eq!(Some(&AllocationError::InvalidUserInput {
+            resource_pool: pool.name.clone(),
+            errors: vec![FieldError { field: "resourceCount".to_owned(), message: "must be >= 1".to_owned() }],
+        }), err.downcast_ref::<AllocationError>());
+        let err = db.enqueue_allocation(pool.id, json!({"subnet": "yes"}), &AllocationOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("subnet must be boolean"), "{}", err);
+        let (_, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 2}),
+                                                   &AllocationOptions::default()).unwrap();
+        assert_eq!(2, resources.len());
+    }
+
+    #[test]
+    fn db_default_user_input() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        assert!(db.set_default_user_input(pool.clone(), json!([])).is_err());
+        let pool = db.set_default_user_input(pool, json!({"resourceCount": 3})).unwrap();
+        assert_eq!(pool, db.get_resource_pool_by_id(pool.id).unwrap());
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({}),
+                                                      &AllocationOptions::default()).unwrap();
+        assert_eq!(3, resources.len());
+        // input of the caller wins
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 1}),
+                                                      &AllocationOptions::default()).unwrap();
+        assert_eq!(1, resources.len());
+
+        let pool = db.set_default_user_input(pool, json!({"subnet": "yes"})).unwrap();
+        let err = db.enqueue_allocation(pool.id, json!({}), &AllocationOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("subnet must be boolean"), "{}", err);
+        db.enqueue_allocation(pool.id, json!({"subnet": false}), &AllocationOptions::default()).unwrap();
+    }
+}
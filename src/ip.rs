@@ -0,0 +1,78 @@
+use anyhow::{Context, Result};
+
+use crate::{DB, Resource};
+
+impl DB {
+    // Resources of an IP pool whose `address` lies within the CIDR, e.g. `10.0.1.0/24`.
+    // Uses the `ip` column generated from `address`, resources without a valid address are skipped.
+    pub fn find_resources_in_cidr(&mut self, resource_pool_id: i64, cidr: &str) -> Result<Vec<Resource>> {
+        self.find_resources_by_cidr(resource_pool_id, cidr, "ip <<= $2::text::inet")
+    }
+
+    // Resources of an IP pool with an address that does not fit into the CIDR.
+    pub fn find_resources_outside_cidr(&mut self, resource_pool_id: i64, cidr: &str) -> Result<Vec<Resource>> {
+        self.find_resources_by_cidr(resource_pool_id, cidr, "NOT ip <<= $2::text::inet")
+    }
+
+    fn find_resources_by_cidr(&mut self, resource_pool_id: i64, cidr: &str, condition: &str)
+                              -> Result<Vec<Resource>> {
+        let rows = self.client.query(
+            format!("SELECT {} FROM resources WHERE resource_pool=$1 AND status <> 'retired' \
+                AND ip IS NOT NULL AND {} ORDER BY ip", Self::RESOURCE_COLUMNS, condition).as_str(),
+            &[&resource_pool_id, &cidr])
+            .context(format!("Cannot find resources by '{}'", cidr))?;
+        rows.into_iter()
+            .map(|row| Self::row_to_resource(resource_pool_id, row))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_find_resources_in_cidr() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = ["10.0.1.7", "10.0.0.1", "10.0.1.3", "10.0.2.1"].iter()
+            .map(|address| Resource::new_from_value(pool.id, json!({"address": address})))
+            .collect();
+        db.insert_resources(pool.clone(), resources).unwrap();
+
+        let found = db.find_resources_in_cidr(pool.id, "10.0.1.0/24").unwrap().into_iter()
+            .map(|it| it.value)
+            .collect::<Vec<Value>>();
+        assert_eq!(vec![json!({"address": "10.0.1.3"}), json!({"address": "10.0.1.7"})], found);
+        assert_eq!(4, db.find_resources_in_cidr(pool.id, "10.0.0.0/8").unwrap().len());
+        assert!(db.find_resources_in_cidr(pool.id, "192.168.0.0/16").unwrap().is_empty());
+        assert!(db.find_resources_in_cidr(pool.id, "not a cidr").is_err());
+        let outside = db.find_resources_outside_cidr(pool.id, "10.0.1.0/24").unwrap().into_iter()
+            .map(|it| it.value)
+            .collect::<Vec<Value>>();
+        assert_eq!(vec![json!({"address": "10.0.0.1"}), json!({"address": "10.0.2.1"})], outside);
+    }
+
+    #[test]
+    fn db_ip_duplicates_should_fail() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"})),
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.1", "description": "gateway"})),
+        ];
+        db.insert_resources(pool.clone(), resources).expect_err("Should not accept the same address twice");
+        let resources = vec![
+            Resource::new_from_value(pool.id, json!({"vlan": 1})),
+            Resource::new_from_value(pool.id, json!({"vlan": 2})),
+        ];
+        db.insert_resources(pool, resources).unwrap();
+    }
+}
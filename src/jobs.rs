@@ -0,0 +1,277 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Result, anyhow, ensure};
+use chrono::{DateTime, Utc};
+use postgres::{Client, Row};
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{AllocationOptions, DB, Resource, WasmerEnv};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub const ALL: [JobStatus; 4] = [JobStatus::Pending, JobStatus::Running, JobStatus::Done, JobStatus::Failed];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<JobStatus> {
+        JobStatus::ALL.iter()
+            .find(|status| status.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown job status '{}'", s))
+    }
+}
+
+/// Allocation requested by `DB::enqueue_allocation`, executed later by `DB::run_next_allocation_job`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocationJob {
+    pub id: i32,
+    pub resource_pool_id: i64,
+    pub user_input: Value,
+    pub lease: Option<Duration>,
+    pub reserve: bool,
+    pub owner: Option<String>,
+    pub description: Option<String>,
+    pub status: JobStatus,
+    // exported allocated resources once the job is done
+    pub result: Option<Value>,
+    pub error: Option<String>,
+    pub created_at: SystemTime,
+    pub finished_at: Option<SystemTime>,
+}
+
+impl AllocationJob {
+    pub fn options(&self) -> AllocationOptions {
+        AllocationOptions {
+            lease: self.lease,
+            reserve: self.reserve,
+            owner: self.owner.clone(),
+            description: self.description.clone(),
+            ..AllocationOptions::default()
+        }
+    }
+
+    pub fn as_json(&self) -> Value {
+        let mut json = json!({
+            "id": self.id,
+            "resourcePool": self.resource_pool_id,
+            "status": self.status.as_str(),
+            "createdAt": DateTime::<Utc>::from(self.created_at).to_rfc3339(),
+        });
+        if let Some(finished_at) = self.finished_at {
+            json["finishedAt"] = Value::String(DateTime::<Utc>::from(finished_at).to_rfc3339());
+        }
+        if let Some(result) = &self.result {
+            json["resources"] = result.clone();
+        }
+        if let Some(error) = &self.error {
+            json["error"] = Value::String(error.clone());
+        }
+        json
+    }
+}
+
+impl DB {
+    pub(crate) const ALLOCATION_JOB_COLUMNS: &'static str =
+        "id, resource_pool, user_input, lease_seconds, reserve, status, result, error, created_at, finished_at, \
+        owner, description";
+
+    // Store an allocation request, returns id of the job to poll with `get_job_status`.
+    pub fn enqueue_allocation(&mut self, resource_pool_id: i64, user_input: Value, options: &AllocationOptions)
+                              -> Result<i32> {
+        Self::insert_allocation_job(&mut self.client, resource_pool_id, user_input, options)
+    }
+
+    pub(crate) fn insert_allocation_job(client: &mut Client, resource_pool_id: i64, user_input: Value,
+                                        options: &AllocationOptions) -> Result<i32> {
+        ensure!(!options.dry_run, "Dry run cannot be enqueued");
+        Self::check_user_input(client, resource_pool_id, &user_input)?;
+        let lease_seconds = options.lease.map(|lease| lease.as_secs() as i64);
+        let row = client.query_one(
+            "INSERT INTO allocation_jobs (resource_pool, user_input, lease_seconds, reserve, owner, description) \
+            VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            &[&resource_pool_id, &user_input, &lease_seconds, &options.reserve, &options.owner, &options.description])?;
+        let id = row.get(0);
+        debug!("Enqueued allocation job {} of pool {}", id, resource_pool_id);
+        Ok(id)
+    }
+
+    pub fn get_job_status(&mut self, job_id: i32) -> Result<AllocationJob> {
+        let row = self.client.query_opt(
+            format!("SELECT {} FROM allocation_jobs WHERE id=$1", Self::ALLOCATION_JOB_COLUMNS).as_str(),
+            &[&job_id])?
+            .ok_or_else(|| anyhow!("Allocation job {} not found", job_id))?;
+        Self::row_to_allocation_job(row)
+    }
+
+    // Claim the oldest pending job and run the allocation. Jobs are claimed with SKIP LOCKED,
+    // so that concurrent runners never pick the same job. Returns None if there is nothing to do.
+    pub fn run_next_allocation_job(&mut self, wasmer_env: &mut WasmerEnv) -> Result<Option<AllocationJob>> {
+        let mut transaction = self.client.transaction()?;
+        let row = transaction.query_opt(
+            format!("SELECT {} FROM allocation_jobs WHERE status = 'pending' ORDER BY id LIMIT 1 \
+                FOR UPDATE SKIP LOCKED", Self::ALLOCATION_JOB_COLUMNS).as_str(), &[])?;
+        let job = match row {
+            Some(row) => Self::row_to_allocation_job(row)?,
+            None => return Ok(None),
+        };
+        transaction.execute(
+            "UPDATE allocation_jobs SET status = 'running', started_at = now() WHERE id=$1", &[&job.id])?;
+        transaction.commit()?;
+
+        let allocated = self.get_resource_pool_by_id(job.resource_pool_id)
+            .and_then(|pool| self.allocate_resources(pool, wasmer_env, job.user_input.clone(), &job.options()))
+            .map(|(_pool, resources)| resources);
+        self.finish_allocation_job(job.id, allocated).map(Some)
+    }
+
+    // Jobs left running by a worker that crashed or lost its connection are failed once they have run for longer
+    // than `timeout`. They are not requeued, the crashed allocation may have inserted its resources.
+    // Returns number of failed jobs.
+    pub fn fail_stale_allocation_jobs(&mut self, timeout: Duration) -> Result<u64> {
+        let reason = format!("Job was running for more than {} seconds, its worker probably stopped. \
+            Resources of the job may have been allocated", timeout.as_secs());
+        let rows = self.client.query(
+            "UPDATE allocation_jobs SET status = 'failed', error = $1, finished_at = now() \
+            WHERE status = 'running' AND started_at < now() - make_interval(secs => $2) RETURNING id",
+            &[&reason, &(timeout.as_secs() as f64)])?;
+        for row in &rows {
+            warn!("Allocation job {} failed: {}", row.get::<_, i32>(0), reason);
+        }
+        Ok(rows.len() as u64)
+    }
+
+    // Store the outcome of a running job.
+    pub(crate) fn finish_allocation_job(&mut self, job_id: i32, allocated: Result<Vec<Resource>>)
+                                        -> Result<AllocationJob> {
+        match allocated {
+            Ok(resources) => {
+                let result = Value::Array(resources.iter().map(|it| it.as_export_json()).collect());
+                self.client.execute(
+                    "UPDATE allocation_jobs SET status = 'done', result = $1, finished_at = now() WHERE id=$2",
+                    &[&result, &job_id])?;
+            }
+            Err(err) => {
+                warn!("Allocation job {} failed: {:#}", job_id, err);
+                self.client.execute(
+                    "UPDATE allocation_jobs SET status = 'failed', error = $1, finished_at = now() WHERE id=$2",
+                    &[&format!("{:#}", err), &job_id])?;
+            }
+        }
+        self.get_job_status(job_id)
+    }
+
+    pub(crate) fn row_to_allocation_job(row: Row) -> Result<AllocationJob> {
+        let lease_seconds: Option<i64> = row.get(3);
+        let status: &str = row.get(5);
+        Ok(AllocationJob {
+            id: row.get(0),
+            resource_pool_id: row.get(1),
+            user_input: row.get(2),
+            lease: lease_seconds.map(|secs| Duration::from_secs(secs as u64)),
+            reserve: row.get(4),
+            owner: row.get(10),
+            description: row.get(11),
+            status: status.parse()?,
+            result: row.get(6),
+            error: row.get(7),
+            created_at: row.get(8),
+            finished_at: row.get(9),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_allocation_jobs() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let options = AllocationOptions { reserve: true, ..AllocationOptions::default() };
+        let job_id = db.enqueue_allocation(pool.id, json!({"resourceCount": 2}), &options).unwrap();
+        let tiny_pool = create_random_pool(&mut db).unwrap();
+        let tiny_pool = db.update_pool_properties(tiny_pool, &mut wasmer_env,
+                                                  json!({"address": "10.0.0.0", "prefix": 32})).unwrap();
+        let failing_id = db.enqueue_allocation(tiny_pool.id, json!({"resourceCount": 2}), &options).unwrap();
+        let job = db.get_job_status(job_id).unwrap();
+        assert_eq!(JobStatus::Pending, job.status);
+        assert_eq!(None, job.result);
+
+        // other tests may enqueue or run jobs concurrently, wait until both of ours are finished
+        let is_finished = |job: AllocationJob| matches!(job.status, JobStatus::Done | JobStatus::Failed);
+        while ![job_id, failing_id].iter().all(|id| is_finished(db.get_job_status(*id).unwrap())) {
+            if db.run_next_allocation_job(&mut wasmer_env).unwrap().is_none() {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        let job = db.get_job_status(job_id).unwrap();
+        assert_eq!(JobStatus::Done, job.status);
+        assert!(job.finished_at.is_some());
+        let states = job.result.unwrap().as_array().unwrap().iter()
+            .map(|it| it["state"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![json!("reserved"), json!("reserved")], states);
+        assert_eq!(2, db.count_resources(pool.id).unwrap());
+        let failed = db.get_job_status(failing_id).unwrap();
+        assert_eq!(JobStatus::Failed, failed.status);
+        assert!(failed.error.is_some());
+        assert_eq!(0, db.count_resources(tiny_pool.id).unwrap());
+        assert!(db.enqueue_allocation(pool.id, json!({}),
+                                      &AllocationOptions { dry_run: true, ..AllocationOptions::default() }).is_err());
+    }
+
+    #[test]
+    fn db_fail_stale_allocation_jobs() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let stale_id = db.enqueue_allocation(pool.id, json!({}), &AllocationOptions::default()).unwrap();
+        let running_id = db.enqueue_allocation(pool.id, json!({}), &AllocationOptions::default()).unwrap();
+        // a worker crashed an hour after claiming the first job, another one is running the second job
+        db.client.execute("UPDATE allocation_jobs SET status = 'running', started_at = now() - interval '1 hour' \
+            WHERE id=$1", &[&stale_id]).unwrap();
+        db.client.execute("UPDATE allocation_jobs SET status = 'running', started_at = now() WHERE id=$1",
+                          &[&running_id]).unwrap();
+
+        assert!(db.fail_stale_allocation_jobs(Duration::from_secs(600)).unwrap() >= 1);
+        let stale = db.get_job_status(stale_id).unwrap();
+        assert_eq!(JobStatus::Failed, stale.status);
+        assert!(stale.error.unwrap().contains("running for more than 600 seconds"));
+        assert!(stale.finished_at.is_some());
+        assert_eq!(JobStatus::Running, db.get_job_status(running_id).unwrap().status);
+        db.finish_allocation_job(running_id, Ok(vec![])).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::{Result, anyhow, ensure};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+
+use crate::{DB, Resource};
+use crate::error::AllocationError;
+use crate::transaction::Transaction;
+
+// Extends an unexpired lease, capped at the maximum lifetime counted from `created_at` of the resource.
+// `least` ignores the cap when there is none.
+const RENEWED_LEASE: &str =
+    "least(lease_expires_at + make_interval(secs => $2), created_at + make_interval(secs => $3))";
+
+impl DB {
+    // Extends the lease of a resource in use by `extend_by`, but not past `max_lease_lifetime` of its
+    // creation. Fails if the resource has no lease, it has expired or it cannot be extended any further.
+    // Leases are not seen by strategies, so the pool version stays the same.
+    pub fn renew_lease(&mut self, id: i64, extend_by: Duration) -> Result<Resource> {
+        ensure!(extend_by > Duration::from_secs(0), "Lease must be extended by a positive duration");
+        let max_lifetime = self.max_lease_lifetime.map(|lifetime| lifetime.as_secs_f64());
+        let mut transaction = self.client.transaction()?;
+        let row = transaction.query_opt(
+            "SELECT resource_pool, status, lease_expires_at > now() FROM resources WHERE id=$1 FOR UPDATE", &[&id])?
+            .ok_or_else(|| anyhow!("Resource {} not found", id))?;
+        let resource_pool_id: i64 = row.get(0);
+        Self::check_not_archived(&mut transaction, resource_pool_id)?;
+        ensure!(row.get::<_, &str>(1) != "retired", "Resource {} is retired and its lease cannot be renewed", id);
+        if row.get::<_, Option<bool>>(2) != Some(true) {
+            return Err(AllocationError::LeaseExpired { resource: id.to_string() }.into());
+        }
+        let updated = transaction.query_opt(
+            format!("UPDATE resources SET lease_expires_at={} WHERE id=$1 AND {} > lease_expires_at RETURNING {}",
+                    RENEWED_LEASE, RENEWED_LEASE, Self::RESOURCE_COLUMNS).as_str(),
+            &[&id, &extend_by.as_secs_f64(), &max_lifetime])?
+            .ok_or_else(|| AllocationError::LeaseLifetimeExceeded { resource: id.to_string() })?;
+        let resource = Self::row_to_resource(resource_pool_id, updated)?;
+        Self::record_lease_renewals(&mut transaction, resource_pool_id, &[&resource])?;
+        transaction.commit()?;
+        Ok(resource)
+    }
+
+    // Renews unexpired leases of all resources in use of the owner as `DB::renew_lease` does, skipping those
+    // at the maximum lifetime and in archived pools. Returns the renewed resources ordered by id.
+    pub fn renew_leases_by_owner(&mut self, owner: &str, extend_by: Duration) -> Result<Vec<Resource>> {
+        ensure!(extend_by > Duration::from_secs(0), "Lease must be extended by a positive duration");
+        let max_lifetime = self.max_lease_lifetime.map(|lifetime| lifetime.as_secs_f64());
+        let mut transaction = self.client.transaction()?;
+        // the pool follows columns of the resource
+        let rows = transaction.query(
+            format!("UPDATE resources SET lease_expires_at={} WHERE owner=$1 \
+                AND status IN ('reserved', 'allocated', 'claimed') AND lease_expires_at > now() \
+                AND {} > lease_expires_at \
+                AND resource_pool IN (SELECT id FROM resource_pools WHERE archived_at IS NULL) \
+                RETURNING {}, resource_pool", RENEWED_LEASE, RENEWED_LEASE, Self::RESOURCE_COLUMNS).as_str(),
+            &[&owner, &extend_by.as_secs_f64(), &max_lifetime])?;
+        let mut resources = rows.into_iter()
+            .map(|row| {
+                let resource_pool_id = row.get(row.len() - 1);
+                Self::row_to_resource(resource_pool_id, row)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        resources.sort_by_key(|resource| resource.id);
+        let mut by_pool = BTreeMap::<i64, Vec<&Resource>>::new();
+        for resource in &resources {
+            by_pool.entry(resource.resource_pool_id).or_default().push(resource);
+        }
+        for (resource_pool_id, renewed) in by_pool {
+            Self::record_lease_renewals(&mut transaction, resource_pool_id, &renewed)?;
+        }
+        transaction.commit()?;
+        Ok(resources)
+    }
+
+    fn record_lease_renewals(transaction: &mut Transaction, resource_pool_id: i64, renewed: &[&Resource])
+                             -> Result<()> {
+        let leases = renewed.iter()
+            .map(|resource| json!({
+                "id": resource.id,
+                "leaseExpiresAt": resource.lease_expires_at.map(|lease| DateTime::<Utc>::from(lease).to_rfc3339()),
+            }))
+            .collect::<Vec<_>>();
+        Self::record_audit(transaction, Some(resource_pool_id), "lease_renewed", json!({"resources": leases}))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::{AllocationOptions, ResourceSelector, WasmerEnv};
+    use crate::state::ResourceState;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_lease_renewal() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let owner: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let options = AllocationOptions {
+            lease: Some(Duration::from_secs(60)),
+            owner: Some(owner.clone()),
+            ..AllocationOptions::default()
+        };
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 2}), &options).unwrap();
+        let resources = db.find_owned_resources(&owner, None, 10).unwrap();
+        let lease = resources[0].lease_expires_at.unwrap();
+        let renewed = db.renew_lease(resources[0].id.unwrap(), Duration::from_secs(60)).unwrap();
+        assert!(renewed.lease_expires_at.unwrap() >= lease + Duration::from_secs(59));
+        assert_eq!("lease_renewed", db.get_audit_log(pool.id, 1).unwrap()[0].action);
+
+        // renewals stop at the maximum lifetime
+        db.max_lease_lifetime = Some(Duration::from_secs(300));
+        let renewed = db.renew_leases_by_owner(&owner, Duration::from_secs(3600)).unwrap();
+        assert_eq!(2, renewed.len());
+        let cap = SystemTime::now() + Duration::from_secs(300);
+        assert!(renewed.iter().all(|resource| resource.lease_expires_at.unwrap() <= cap));
+        let err = db.renew_lease(resources[0].id.unwrap(), Duration::from_secs(60)).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(), Some(AllocationError::LeaseLifetimeExceeded { .. })));
+        assert!(db.renew_leases_by_owner(&owner, Duration::from_secs(60)).unwrap().is_empty());
+        assert!(db.renew_lease(resources[0].id.unwrap(), Duration::from_secs(0)).is_err());
+
+        // resources without a lease or retired ones cannot be renewed
+        let options = AllocationOptions { owner: Some(format!("{}-unleased", owner)), ..AllocationOptions::default() };
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        let unleased = db.find_owned_resources(options.owner.as_ref().unwrap(), None, 10).unwrap();
+        let err = db.renew_lease(unleased[0].id.unwrap(), Duration::from_secs(60)).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(), Some(AllocationError::LeaseExpired { .. })));
+        db.transition_resource(pool, &ResourceSelector::Id(unleased[0].id.unwrap()), ResourceState::Retired)
+            .unwrap();
+        assert!(db.renew_lease(unleased[0].id.unwrap(), Duration::from_secs(60)).is_err());
+    }
+}
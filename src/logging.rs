@@ -0,0 +1,72 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+use tracing_subscriber::EnvFilter;
+
+/// Format of logs written to stderr, read from `RM_LOG_FORMAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    // one JSON object per event with fields of the current span, e.g. `pool_id` of an allocation
+    Json,
+}
+
+impl LogFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<LogFormat> {
+        [LogFormat::Text, LogFormat::Json].iter()
+            .find(|format| format.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown log format '{}', expected text or json", s))
+    }
+}
+
+// Logs to stderr filtered by `RUST_LOG`, `info` by default.
+pub fn init(format: LogFormat) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt()
+            .event_format(tracing_subscriber::fmt::format::Format::default().with_target(false))
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_env_filter(filter)
+            .with_writer(std::io::stderr)
+            .init(),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_format_from_str() {
+        assert_eq!(LogFormat::Json, "json".parse().unwrap());
+        assert_eq!("text", LogFormat::Text.to_string());
+        assert!("yaml".parse::<LogFormat>().is_err());
+    }
+}
@@ -1,22 +1,121 @@
+mod alerts;
+mod archive;
+mod audit;
+mod backpressure;
+mod backup;
+mod batch;
+mod blocks;
+mod bundle;
+mod canonical;
+mod chaos;
+mod cli;
+mod config;
+mod connect;
+mod context;
+mod cursor;
+mod desired;
+mod diff;
+mod discover;
+mod engine;
+mod error;
+mod events;
+mod fairness;
+mod fixtures;
+mod freelist;
+mod grpc;
+mod hooks;
+mod hierarchy;
+mod host;
+mod http;
+mod input;
+mod ip;
+mod jobs;
+mod leases;
+mod logging;
+mod maintenance;
+mod metadata;
+mod metrics;
+mod overrides;
+mod outbox;
+mod ownership;
+mod partition;
+mod pools;
+mod progress;
+mod properties;
+mod prune;
+mod reload;
+mod replay;
+mod routing;
+mod schedule;
+mod schema;
+mod shutdown;
+mod snapshot;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod sse;
+mod state;
+mod stats;
+mod sticky;
+mod storage;
+mod strategy;
+mod subranges;
+mod summary;
+mod supervisor;
+mod timeout;
+mod timings;
+mod transaction;
+mod typescript;
+mod uniqueness;
+mod upstream;
+mod websocket;
+mod worker;
+
 use std::{
-    env,
-    process::{Command, Output},
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
 };
 
-use anyhow::{Context, Result, ensure, anyhow};
-use postgres::{Client, NoTls, Row};
+use anyhow::{Context, Result, bail, ensure, anyhow};
+use postgres::{Client, Row};
 use serde_json::Value;
 use tracing::*;
-use tracing_subscriber::*;
-use stopwatch::{Stopwatch};
 use serde_json::json;
-
-#[derive(Debug, PartialEq)]
+use clap::Parser;
+use chrono::{DateTime, Utc};
+
+use config::Config;
+use connect::ConnectRetry;
+use host::{CurrentResources, PoolResources};
+use error::AllocationError;
+use timeout::TransactionTimeouts;
+use timings::{Timer, Timings};
+use transaction::{Connection, Transaction};
+use state::ResourceState;
+use strategy::{ScriptKind, StrategyFiles};
+use subranges::SubRange;
+
+#[derive(Debug, PartialEq, Clone)]
 struct ResourcePool {
-    id: i32,
+    id: i64,
     name: String,
     version: i32,
-    allocation_strategy_id: i32,
+    allocation_strategy_id: i64,
+    // seconds a deallocated resource stays in quarantine before it can be reallocated
+    deallocation_safety_period: i32,
+    parent_id: Option<i64>,
+    // passed to the strategy as `resourcePoolProperties`, e.g. range of an IPv4 pool
+    properties: Value,
+    tenant: Option<String>,
+    tags: Vec<String>,
+    // set while the pool is archived and read-only, see `DB::archive_pool`
+    archived_at: Option<SystemTime>,
+    // fields of `userInput` that callers may omit, see `DB::set_default_user_input`
+    default_user_input: Value,
+    created_at: SystemTime,
+    // any change of the pool row, including its version, is maintained by the `touch_updated_at` trigger
+    updated_at: SystemTime,
+    // allocated by its own script instead of the strategy's, see `DB::set_script_override`
+    script_overridden: bool,
 }
 
 impl ResourcePool {
@@ -25,102 +124,342 @@ impl ResourcePool {
         json!({})
     }
 
-    pub fn get_pool_properties(&self) -> Value {
-        // currently hardcoded
+    // Metadata of the pool as stored in snapshots
+    pub fn as_export_json(&self) -> Value {
         json!({
-            "address": "10.0.0.0",
-            "prefix": 8,
+            "id": self.id,
+            "name": &self.name,
+            "version": self.version,
+            "allocationStrategyId": self.allocation_strategy_id,
+            "deallocationSafetyPeriod": self.deallocation_safety_period,
+            "parentId": self.parent_id,
+            "properties": &self.properties,
+            "tenant": &self.tenant,
+            "tags": &self.tags,
+            "archivedAt": self.archived_at.map(|archived_at| DateTime::<Utc>::from(archived_at).to_rfc3339()),
+            "defaultUserInput": &self.default_user_input,
+            "createdAt": DateTime::<Utc>::from(self.created_at).to_rfc3339(),
+            "updatedAt": DateTime::<Utc>::from(self.updated_at).to_rfc3339(),
+            "scriptOverridden": self.script_overridden,
         })
     }
+
+    pub fn get_pool_properties(&self) -> Value {
+        self.properties.clone()
+    }
+
+    // Fields of the default input missing in `user_input` are added, fields given by the caller win.
+    pub fn user_input_with_defaults(&self, user_input: Value) -> Value {
+        Self::merge_user_input(&self.default_user_input, user_input)
+    }
+
+    fn merge_user_input(defaults: &Value, user_input: Value) -> Value {
+        match (defaults.as_object(), user_input) {
+            (Some(defaults), Value::Object(mut user_input)) => {
+                for (key, value) in defaults {
+                    user_input.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+                Value::Object(user_input)
+            }
+            (Some(_), Value::Null) => defaults.clone(),
+            (_, user_input) => user_input,
+        }
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 struct Resource {
-    id: Option<i32>,
-    resource_pool_id: i32,
+    id: Option<i64>,
+    resource_pool_id: i64,
     value: Value,
+    state: ResourceState,
+    lease_expires_at: Option<SystemTime>,
+    quarantined_until: Option<SystemTime>,
+    deleted_at: Option<SystemTime>,
+    // labels such as the owner, unlike the value they can be changed, see `DB::update_resource_metadata`
+    metadata: Value,
+    // principal or foreign reference, see `DB::transfer_resource`
+    owner: Option<String>,
+    // human-readable note given at allocation, e.g. `loopback for PE-router-3`
+    description: Option<String>,
+    // None until inserted, the allocation time of the resource
+    created_at: Option<SystemTime>,
+    updated_at: Option<SystemTime>,
+    // compared for duplicates instead of the value, see `canonical::canonicalize_resources`
+    canonical_value: Option<Value>,
 }
 
 impl Resource {
-    fn new_from_str(resource_pool_id: i32, value_str: &str) -> Result<Resource> {
+    fn new_from_str(resource_pool_id: i64, value_str: &str) -> Result<Resource> {
         let value = serde_json::from_str(value_str)?;
-        Ok(Resource {
+        Ok(Resource::new_from_value(resource_pool_id, value))
+    }
+
+    fn new_from_value(resource_pool_id: i64, value: Value) -> Resource {
+        Resource {
             id: None,
             resource_pool_id,
             value,
+            state: ResourceState::Allocated,
+            lease_expires_at: None,
+            quarantined_until: None,
+            deleted_at: None,
+            metadata: json!({}),
+            owner: None,
+            description: None,
+            created_at: None,
+            updated_at: None,
+            canonical_value: None,
+        }
+    }
+
+    fn as_json(&self) -> Value {
+        json!({"Properties": &self.value})
+    }
+
+    // format used by `resources export` and `pool import`, one object per line
+    fn as_export_json(&self) -> Value {
+        let mut exported = json!({"id": self.id, "value": &self.value, "state": self.state.as_str()});
+        if self.metadata.as_object().is_some_and(|metadata| !metadata.is_empty()) {
+            exported["metadata"] = self.metadata.clone();
+        }
+        if let Some(owner) = &self.owner {
+            exported["owner"] = Value::String(owner.clone());
+        }
+        if let Some(description) = &self.description {
+            exported["description"] = Value::String(description.clone());
+        }
+        if let Some(canonical_value) = &self.canonical_value {
+            exported["canonicalValue"] = canonical_value.clone();
+        }
+        let timestamps = [
+            ("leaseExpiresAt", self.lease_expires_at),
+            ("quarantinedUntil", self.quarantined_until),
+            ("deletedAt", self.deleted_at),
+            ("createdAt", self.created_at),
+            ("updatedAt", self.updated_at),
+        ];
+        for (key, timestamp) in timestamps.iter() {
+            if let Some(timestamp) = timestamp {
+                exported[*key] = Value::String(DateTime::<Utc>::from(*timestamp).to_rfc3339());
+            }
+        }
+        exported
+    }
+
+    fn new_from_export_json(resource_pool_id: i64, exported: Value) -> Result<Resource> {
+        let value = exported.get("value")
+            .ok_or_else(|| anyhow!("Exported resource does not contain 'value': {}", exported))?;
+        Ok(Resource::new_from_value(resource_pool_id, value.to_owned()))
+    }
+
+    // Like `new_from_export_json`, but keeps the state and timestamps.
+    fn restore_from_export_json(resource_pool_id: i64, exported: Value) -> Result<Resource> {
+        let state = exported["state"].as_str()
+            .ok_or_else(|| anyhow!("Exported resource does not contain 'state': {}", exported))?
+            .parse()?;
+        let timestamp = |key: &str| exported[key].as_str()
+            .map(|timestamp| DateTime::parse_from_rfc3339(timestamp)
+                .map(SystemTime::from)
+                .context(format!("Invalid '{}' of exported resource {}", key, exported)))
+            .transpose();
+        Ok(Resource {
+            state,
+            lease_expires_at: timestamp("leaseExpiresAt")?,
+            quarantined_until: timestamp("quarantinedUntil")?,
+            deleted_at: timestamp("deletedAt")?,
+            metadata: exported.get("metadata").cloned().unwrap_or_else(|| json!({})),
+            owner: exported["owner"].as_str().map(str::to_owned),
+            description: exported["description"].as_str().map(str::to_owned),
+            created_at: timestamp("createdAt")?,
+            updated_at: timestamp("updatedAt")?,
+            canonical_value: exported.get("canonicalValue").cloned(),
+            ..Resource::new_from_export_json(resource_pool_id, exported.clone())?
         })
     }
+}
 
-    fn new_from_value(resource_pool_id: i32, value: Value) -> Resource {
-        Resource { id: None, resource_pool_id, value }
+/// Identifies a single resource of a pool.
+#[derive(Debug, Clone, PartialEq)]
+enum ResourceSelector {
+    Id(i64),
+    Value(Value),
+}
+
+impl std::fmt::Display for ResourceSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceSelector::Id(id) => write!(f, "with id {}", id),
+            ResourceSelector::Value(value) => write!(f, "{}", value),
+        }
     }
+}
 
-    fn as_json(&self) -> Value {
-        json!({"Properties": &self.value})
+/// Resources deallocated together by `DB::deallocate_resources`.
+#[derive(Debug, Clone, PartialEq)]
+enum BulkSelector {
+    Ids(Vec<i64>),
+    // all resources of the pool in this state
+    State(ResourceState),
+}
+
+#[derive(Debug, Clone, Default)]
+struct AllocationOptions {
+    // allocated resources are reclaimed by `DB::gc_pool` after this duration
+    lease: Option<Duration>,
+    // run the script against current resources, but do not insert its result
+    dry_run: bool,
+    // insert resources as reserved instead of allocated
+    reserve: bool,
+    // owner of inserted resources
+    owner: Option<String>,
+    // description of inserted resources
+    description: Option<String>,
+}
+
+/// Number of resources reclaimed by `DB::gc_pool`.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct GcReport {
+    expired_leases: u64,
+    released_from_quarantine: u64,
+    // deleted resources older than the retention window
+    purged: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ResourceFilter {
+    // also return retired resources that were not purged yet
+    include_deleted: bool,
+    // only resources allocated within the period, each bound is exclusive
+    allocated_after: Option<SystemTime>,
+    allocated_before: Option<SystemTime>,
+}
+
+impl ResourceFilter {
+    // Takes the pool as `$1` and the bounds of the period as `$2` and `$3`.
+    fn where_clause(&self) -> &'static str {
+        if self.include_deleted {
+            "resource_pool=$1 AND ($2::timestamptz IS NULL OR created_at > $2) \
+            AND ($3::timestamptz IS NULL OR created_at < $3)"
+        } else {
+            "resource_pool=$1 AND status <> 'retired' AND ($2::timestamptz IS NULL OR created_at > $2) \
+            AND ($3::timestamptz IS NULL OR created_at < $3)"
+        }
     }
 }
 
 struct WasmerEnv {
     wasmer_bin: String,
     wasmer_js: String,
+    // stdout of a script, including its logs, host calls and result
+    max_output_bytes: u64,
+    // scripts are killed once it passes, set while a `PoolHook` runs
+    script_timeout: Option<Duration>,
 }
 
 impl WasmerEnv {
+    // Finds wasmer and quickJS, see `WasmerEnv::discover`, and checks that they work.
     fn new() -> Result<WasmerEnv> {
-        let wasmer_bin = env::var("WASMER_BIN").context("Cannot read env var WASMER_BIN")?;
-        let wasmer_js = env::var("WASMER_JS").context("Cannot read env var WASMER_JS")?;
-        Ok(WasmerEnv {
+        Self::new_from_config(&Config::from_env()?)
+    }
+
+    fn new_from_config(config: &Config) -> Result<WasmerEnv> {
+        let (wasmer_bin, wasmer_js) = Self::discover(config)?;
+        let mut wasmer_env = WasmerEnv {
             wasmer_bin,
             wasmer_js,
-        })
+            max_output_bytes: config.wasmer_max_output_bytes,
+            script_timeout: None,
+        };
+        wasmer_env.validate()?;
+        Ok(wasmer_env)
     }
 
-    fn invoke_js(&mut self, script: &str) -> Result<Output> {
-        Command::new(&self.wasmer_bin)
-            .arg(&self.wasmer_js)
-            .arg("--")
-            .arg("--std")
-            .arg("-e")
-            .arg(script)
-            .output()
-            .context("Cannot execute quickJS")
+    // Run a trivial script so that wasmer compiles and caches the quickJS module, then load every strategy
+    // referenced by a pool, so that the first allocation does not pay for it and broken strategies are
+    // reported right away. Returns the number of strategies that loaded.
+    fn warm_up(&mut self, db: &mut DB) -> Result<usize> {
+        let timer = Timer::start("warm_up");
+        self.invoke_js("", &mut vec![])?;
+        let strategy_ids = db.client.query(
+            "SELECT DISTINCT resource_pool_allocation_strategy FROM resource_pools ORDER BY 1", &[])?;
+        let mut loaded = 0;
+        for row in strategy_ids {
+            let strategy_id: i64 = row.get(0);
+            match db.get_allocation_script(strategy_id).and_then(|script| self.load_strategy(&script)) {
+                Ok(()) => loaded += 1,
+                Err(err) => warn!("Cannot load strategy {}: {:#}", strategy_id, err),
+            }
+        }
+        info!("Warmed up {} strategies in {}ms", loaded, timer.finish().as_millis());
+        Ok(loaded)
     }
 
-    fn invoke_and_parse(&mut self, script: &str, user_input: Value, resource_pool_properties: Value,
-                        resource_pool: Value, current_resources: Vec<Value>, function_call: &str)
-                        -> Result<Vec<Value>> {
-        let sw = Stopwatch::start_new();
-        let mut header = "
-        console.error = function(...args) {
-            std.err.puts(args.join(' '));
-            std.err.puts('\\n');
-        }
-        const log = console.error;
-        ".to_owned();
+    // Fails if the linked script does not run or does not define invoke().
+    fn load_strategy(&mut self, script: &str) -> Result<()> {
+        let invoke = self.invoke_and_parse_value(script, json!({}), json!({}), json!({}), &mut vec![],
+                                                 "JSON.stringify(typeof invoke)")?;
+        ensure!(invoke == json!("function"), "Strategy does not define invoke()");
+        Ok(())
+    }
 
+    fn invoke_and_parse_value(&mut self, script: &str, user_input: Value, resource_pool_properties: Value,
+                              resource_pool: Value, current_resources: &mut dyn CurrentResources, function_call: &str)
+                              -> Result<Value> {
+        let mut header = Self::header();
         header += &Self::add_js_var("userInput", user_input)?;
         header += &Self::add_js_var("resourcePoolProperties", resource_pool_properties)?;
         header += &Self::add_js_var("resourcePool", resource_pool)?;
-        header += &Self::add_js_var("currentResources", Value::Array(current_resources))?;
+        // large pools are only embedded for strategies reading them all, others use the host functions
+        if script.contains("currentResources") {
+            header += &Self::add_js_var("currentResources", Value::Array(current_resources.all()?))?;
+        }
+        if script.contains("resourceSummary") {
+            header += &Self::add_js_var("resourceSummary", current_resources.summary()?)?;
+        }
 
-        let footer = format!("\nlet result = {};\n", function_call) + "
+        let footer = format!("\nlet result;\ntry {{ result = {}; }} catch (e) {{ result = strategyError(e); }}\n",
+                             function_call) + "
         if (result != null) {
             if (typeof result === 'object') {
                 result = JSON.stringify(result);
             }
+            std.out.puts('\\u0002\\n');
             std.out.puts(result);
         }
         ";
         let script = header + script + &footer;
         trace!("Executing script:\n{}", script);
-        let output = self.invoke_js(&script)?;
+        let output = self.invoke_js(&script, current_resources)?;
         debug!("Output {:?}", output);
-        let val: Value = serde_json::from_slice(&output.stdout)
-            .context(format!("Cannot deserialize '{:?}'", output))?;
-        info!("Wasmer finished in {}ms", sw.elapsed_ms());
-        val.as_array()
-            .ok_or(anyhow!("Script did not return an array"))
-            .map(|vec| vec.to_owned())
+        for line in &output.logs {
+            debug!(target: "strategy", "{}", line);
+        }
+        let (status, logs) = (output.status, output.logs);
+        let val = output.result
+            .ok_or_else(|| anyhow!("Script did not return a result, {}: {}", status, logs.join("\n")))?;
+        match AllocationError::from_envelope(&val) {
+            Some(err) => Err(err.into()),
+            None => Ok(val),
+        }
+    }
+
+    // Logging and host functions, defined before the globals of a strategy.
+    fn header() -> String {
+        "
+        console.error = function(...args) {
+            std.err.puts(args.join(' '));
+            std.err.puts('\\n');
+        }
+        const log = console.error;
+        // `throw {code, message, details}` becomes an error envelope, see `AllocationError::Strategy`
+        function strategyError(e) {
+            if (e != null && typeof e === 'object' && typeof e.code === 'string') {
+                return { error: { code: e.code, message: String(e.message || ''), details: e.details === undefined ? null : e.details } };
+            }
+            throw e;
+        }
+        ".to_owned() + host::HOST_FUNCTIONS
     }
 
     fn add_js_var(name: &str, val: Value) -> Result<String> {
@@ -130,78 +469,381 @@ impl WasmerEnv {
 }
 
 struct DB {
-    client: Client,
+    // primary, used for all transactions
+    client: Connection,
+    // read-only replica for listing, counting and exporting resources
+    replica: Option<Connection>,
+    // None to use the search_path of the connection
+    schema: Option<String>,
+    connect_retry: ConnectRetry,
+    timeouts: TransactionTimeouts,
+    // transactions of `client` are read-only, see `DB::set_read_only`
+    read_only: bool,
+    // leases cannot be renewed past this age of the resource, see `DB::renew_lease`
+    max_lease_lifetime: Option<Duration>,
+    // failures injected by builds with the `chaos` feature
+    chaos: chaos::Chaos,
+    // linked scripts of strategies shared by the threads of a server, see `DB::get_allocation_context`
+    script_cache: Option<reload::ScriptCache>,
+    // pool events are written to the outbox, see `DB::drain_outbox`
+    outbox: bool,
+    // None to never enqueue allocations of contended pools, see `DB::allocate_or_enqueue`
+    backpressure: Option<backpressure::Backpressure>,
+    // allocations of a pool are served in the order they arrived, see `DB::allocate_in_turn`
+    fair_allocation: bool,
 }
 
 impl DB {
     pub fn new_from_env() -> Result<DB> {
-        let params = std::env::var("DB_PARAMS")
-            .context("Cannot read env var DB_PARAMS")?;
-        Self::new(&params)
+        Self::new_from_config(&Config::from_env()?)
+    }
+
+    pub fn new_from_config(config: &Config) -> Result<DB> {
+        let mut db = Self::new(&config.db_params, config.db_schema.as_deref(), config.connect_retry.clone())?;
+        db.timeouts = config.timeouts.clone();
+        db.max_lease_lifetime = config.max_lease_lifetime;
+        db.outbox = config.outbox;
+        db.backpressure = config.backpressure.clone();
+        db.fair_allocation = config.fair_allocation;
+        if config.chaos.is_enabled() {
+            warn!("Chaos mode: DB latency up to {:?}, abort probability {}, script failure probability {}",
+                  config.chaos.db_latency, config.chaos.abort_probability, config.chaos.script_failure_probability);
+            db.chaos = config.chaos.clone();
+        }
+        if config.maintenance {
+            db.set_maintenance(true)?;
+        } else {
+            db.sync_maintenance()?;
+        }
+        match &config.db_replica_params {
+            Some(replica_params) => db.with_replica(replica_params),
+            None => Ok(db),
+        }
+    }
+
+    // Queries are not schema qualified, they resolve in the schema set as search_path.
+    pub fn new(params: &str, schema: Option<&str>, connect_retry: ConnectRetry) -> Result<DB> {
+        let client = Self::connect(params, schema, &connect_retry)?;
+        Ok(DB {
+            client: Connection::new(client),
+            replica: None,
+            schema: schema.map(str::to_owned),
+            connect_retry,
+            timeouts: TransactionTimeouts::default(),
+            read_only: false,
+            max_lease_lifetime: None,
+            chaos: chaos::Chaos::default(),
+            script_cache: None,
+            outbox: false,
+            backpressure: None,
+            fair_allocation: false,
+        })
+    }
+
+    // Reads that tolerate replication lag go to the replica, everything else stays on the primary.
+    pub fn with_replica(mut self, params: &str) -> Result<DB> {
+        let replica = Self::connect(params, self.schema.as_deref(), &self.connect_retry)
+            .context("Cannot connect to the replica")?;
+        self.replica = Some(Connection::new(replica));
+        Ok(self)
     }
 
-    pub fn new(params: &str) -> Result<DB> {
-        let client = Client::connect(params, NoTls)?;
-        Ok(DB { client })
+    pub fn with_script_cache(mut self, script_cache: reload::ScriptCache) -> DB {
+        self.script_cache = Some(script_cache);
+        self
+    }
+
+    fn connect(params: &str, schema: Option<&str>, connect_retry: &ConnectRetry) -> Result<Client> {
+        let mut client = connect_retry.connect(params)?;
+        if let Some(schema) = schema {
+            client.execute("SELECT set_config('search_path', quote_ident($1), false)", &[&schema])?;
+        }
+        Ok(client)
+    }
+
+    // Replica if configured, the primary otherwise and during dry runs, which the replica does not see.
+    fn reader(&mut self) -> &mut Connection {
+        match &mut self.replica {
+            Some(replica) if !self.client.is_dry_run() => replica,
+            _ => &mut self.client,
+        }
     }
 
     // allocation strategies
-    pub fn get_allocation_script(&mut self, id: i32) -> Result<String> {
+    // Script ready to be wrapped by `WasmerEnv`, modules are converted by `module_to_script`.
+    pub fn get_allocation_script(&mut self, id: i64) -> Result<String> {
         let found = self.client.query_one(
-            "SELECT script FROM allocation_strategies WHERE id=$1", &[&id])?;
-        let script: &str = found.get(0);
-        Ok(script.to_owned())
+            "SELECT name, script, script_kind FROM allocation_strategies WHERE id=$1", &[&id])?;
+        let files = Self::get_strategy_files(&mut self.client, id)?;
+        self.strategy_script(found.get(0), found.get(1), found.get(2), &files)
+    }
+
+    fn strategy_script(&mut self, name: &str, script: &str, script_kind: Option<&str>, files: &StrategyFiles)
+                       -> Result<String> {
+        match ScriptKind::of_strategy(script_kind, script)? {
+            ScriptKind::Script => Ok(Self::bundle_script(script, files)),
+            ScriptKind::Module => self.module_to_script(name, script, files),
+        }
     }
 
     // resource pools
-    pub fn insert_resource_pool(&mut self, name: &str, allocation_strategy_id: i32) -> Result<ResourcePool> {
+    const RESOURCE_POOL_COLUMNS: &'static str =
+        "id, name, version, resource_pool_allocation_strategy, deallocation_safety_period, parent_pool, properties, \
+        tenant, tags, archived_at, default_user_input, created_at, updated_at, script_override IS NOT NULL";
+
+    pub fn insert_resource_pool(&mut self, name: &str, allocation_strategy_id: i64) -> Result<ResourcePool> {
+        self.insert_nested_resource_pool(name, allocation_strategy_id, None)
+    }
+
+    pub fn insert_nested_resource_pool(&mut self, name: &str, allocation_strategy_id: i64, parent_id: Option<i64>)
+                                       -> Result<ResourcePool> {
+        self.insert_resource_pool_with_properties(name, allocation_strategy_id, parent_id, None)
+    }
+
+    // Pools without properties get the default of the `properties` column. Properties must match
+    // the schema declared by the strategy, see `PropertiesSchema`.
+    pub fn insert_resource_pool_with_properties(&mut self, name: &str, allocation_strategy_id: i64,
+                                                parent_id: Option<i64>, properties: Option<Value>)
+                                                -> Result<ResourcePool> {
+        let mut transaction = self.client.transaction()?;
+        let pool = Self::insert_pool_row(&mut transaction, name, allocation_strategy_id, parent_id,
+                                         properties.as_ref())?;
+        transaction.commit()?;
+        Ok(pool)
+    }
+
+    fn insert_pool_row(transaction: &mut Client, name: &str, allocation_strategy_id: i64,
+                       parent_id: Option<i64>, properties: Option<&Value>)
+                       -> Result<ResourcePool> {
         let version: i32 = 0;
-        let row = self.client.query_one(
-            "INSERT INTO resource_pools (name, version, resource_pool_allocation_strategy) \
-            VALUES ($1, $2, $3) RETURNING id as id",
-            &[&name, &version, &allocation_strategy_id],
-        )?;
-        let id: i32 = row.get(0);
-        Ok(ResourcePool { id, name: name.to_owned(), version, allocation_strategy_id })
+        let id: i64 = transaction.query_one("SELECT nextval('resource_pools_id_seq')", &[])?.get(0);
+        // before inserting the pool, otherwise concurrent inserts deadlock on partition creation
+        Self::create_resources_partition(transaction, id)?;
+        let row = match properties {
+            Some(properties) => transaction.query_one(
+                "INSERT INTO resource_pools (id, name, version, resource_pool_allocation_strategy, parent_pool, \
+                properties) VALUES ($1, $2, $3, $4, $5, $6) RETURNING properties, created_at, updated_at",
+                &[&id, &name, &version, &allocation_strategy_id, &parent_id, properties],
+            )?,
+            None => transaction.query_one(
+                "INSERT INTO resource_pools (id, name, version, resource_pool_allocation_strategy, parent_pool) \
+                VALUES ($1, $2, $3, $4, $5) RETURNING properties, created_at, updated_at",
+                &[&id, &name, &version, &allocation_strategy_id, &parent_id],
+            )?,
+        };
+        let properties: Value = row.get(0);
+        Self::check_properties_schema(transaction, name, allocation_strategy_id, &properties)?;
+        Self::rebuild_free_ranges(transaction, id)?;
+        Ok(ResourcePool {
+            id,
+            name: name.to_owned(),
+            version,
+            allocation_strategy_id,
+            deallocation_safety_period: 0,
+            parent_id,
+            properties,
+            tenant: None,
+            tags: vec![],
+            archived_at: None,
+            default_user_input: json!({}),
+            created_at: row.get(1),
+            updated_at: row.get(2),
+            script_overridden: false,
+        })
+    }
+
+    // Delete a pool without resources in use, together with its deallocated resources.
+    pub fn delete_resource_pool(&mut self, mut pool: ResourcePool) -> Result<()> {
+        let mut transaction = self.client.transaction()?;
+        // fails if resources were allocated concurrently
+        Self::bump_version(&mut transaction, &mut pool)?;
+        let in_use: i64 = transaction.query_one(
+            "SELECT count(*) FROM resources WHERE resource_pool=$1 AND status <> 'retired'", &[&pool.id])?.get(0);
+        ensure!(in_use == 0, "Resource pool '{}' still has {} resources in use", pool.name, in_use);
+        if !Self::drop_resources_partition(&mut transaction, pool.id)? {
+            transaction.execute("DELETE FROM resources WHERE resource_pool=$1", &[&pool.id])?;
+        }
+        transaction.execute("DELETE FROM resource_pools WHERE id=$1", &[&pool.id])?;
+        Self::record_audit(&mut transaction, None, "pool_deleted", json!({"id": pool.id, "name": &pool.name}))?;
+        transaction.commit()?;
+        Ok(())
     }
 
-    pub fn get_resource_pool_by_id(&mut self, id: i32) -> Result<ResourcePool> {
+    pub fn get_resource_pool_by_id(&mut self, id: i64) -> Result<ResourcePool> {
         let found = self.client.query_one(
-            "SELECT id, name, version, resource_pool_allocation_strategy FROM resource_pools WHERE id=$1", &[&id])?;
+            format!("SELECT {} FROM resource_pools WHERE id=$1", Self::RESOURCE_POOL_COLUMNS).as_str(), &[&id])?;
         Self::row_to_resource_pool(found)
     }
 
+    pub fn find_resource_pool_by_id(&mut self, id: i64) -> Result<Option<ResourcePool>> {
+        let found = self.client.query_opt(
+            format!("SELECT {} FROM resource_pools WHERE id=$1", Self::RESOURCE_POOL_COLUMNS).as_str(), &[&id])?;
+        found.map(Self::row_to_resource_pool).transpose()
+    }
+
     pub fn get_resource_pool_by_name(&mut self, name: &str) -> Result<ResourcePool> {
-        let found = self.client.query_one(
-            "SELECT id, name, version, resource_pool_allocation_strategy FROM resource_pools WHERE name=$1", &[&name])?;
-        Self::row_to_resource_pool(found)
+        self.find_resource_pool_by_name(name)?
+            .ok_or_else(|| anyhow!("Resource pool '{}' not found", name))
+    }
+
+    pub fn find_resource_pool_by_name(&mut self, name: &str) -> Result<Option<ResourcePool>> {
+        let found = self.client.query_opt(
+            format!("SELECT {} FROM resource_pools WHERE name=$1", Self::RESOURCE_POOL_COLUMNS).as_str(), &[&name])?;
+        found.map(Self::row_to_resource_pool).transpose()
     }
 
     fn row_to_resource_pool(row: Row) -> Result<ResourcePool> {
-        let id: i32 = row.get(0);
+        let id: i64 = row.get(0);
         let name: String = row.get(1);
         let version: i32 = row.get(2);
         let allocation_strategy_id = row.get(3);
-        Ok(ResourcePool { id, name, version, allocation_strategy_id })
+        let deallocation_safety_period = row.get(4);
+        let parent_id = row.get(5);
+        let properties = row.get(6);
+        let tenant = row.get(7);
+        let tags = row.get(8);
+        let archived_at = row.get(9);
+        let default_user_input = row.get(10);
+        let created_at = row.get(11);
+        let updated_at = row.get(12);
+        let script_overridden = row.get(13);
+        Ok(ResourcePool {
+            id, name, version, allocation_strategy_id, deallocation_safety_period, parent_id, properties, tenant, tags,
+            archived_at, default_user_input, created_at, updated_at, script_overridden,
+        })
+    }
+
+    pub fn set_deallocation_safety_period(&mut self, mut pool: ResourcePool, seconds: i32) -> Result<ResourcePool> {
+        ensure!(seconds >= 0, "Deallocation safety period cannot be negative");
+        Self::check_not_archived(&mut self.client, pool.id)?;
+        let updated = self.client.query_opt(
+            "UPDATE resource_pools SET deallocation_safety_period=$1 WHERE id=$2 RETURNING updated_at",
+            &[&seconds, &pool.id])?
+            .ok_or_else(|| anyhow!("Update of resource_pools returned wrong number of rows"))?;
+        pool.deallocation_safety_period = seconds;
+        pool.updated_at = updated.get(0);
+        Ok(pool)
+    }
+
+    // Change properties of a pool after the strategy confirmed that current resources still fit.
+    // Strategies can define `capacity()`, free capacity must not be negative, and `outOfRange()`,
+    // returning resources that would be orphaned by the change. Addresses of pools with
+    // `address` and `prefix` properties are also checked in the DB.
+    pub fn update_pool_properties(&mut self, mut pool: ResourcePool, wasmer_env: &mut WasmerEnv,
+                                  new_properties: Value) -> Result<ResourcePool> {
+        Self::check_properties_schema(&mut self.client, &pool.name, pool.allocation_strategy_id, &new_properties)?;
+        let context = self.get_allocation_context(pool.id)?;
+        let engine = wasmer_env.engine(context.engine)?;
+        let mut current_resources = PoolResources::new(&mut self.client, pool.id);
+        let validation = engine.invoke(
+            &context.script, json!({}), new_properties.clone(), pool.as_json(), &mut current_resources,
+            "{ capacity: typeof capacity === 'function' ? capacity() : null, \
+            outOfRange: typeof outOfRange === 'function' ? outOfRange() : [] }")?;
+        let invalid = |reason: String| AllocationError::InvalidPoolProperties {
+            resource_pool: pool.name.clone(),
+            reason,
+        };
+        if let Some(free_capacity) = validation["capacity"]["freeCapacity"].as_i64() {
+            if free_capacity < 0 {
+                return Err(invalid(format!("{} resources do not fit into the new capacity", -free_capacity)).into());
+            }
+        }
+        // prefix pools are checked using the typed `ip` column, which does not depend on the script
+        let orphaned_ips = match (new_properties["address"].as_str(), new_properties["prefix"].as_i64()) {
+            (Some(address), Some(prefix)) => self.find_resources_outside_cidr(
+                pool.id, &format!("{}/{}", address, prefix))?,
+            _ => vec![],
+        };
+        if !orphaned_ips.is_empty() {
+            let orphaned = orphaned_ips.iter().map(|it| it.value.to_string()).collect::<Vec<_>>();
+            return Err(invalid(format!("resources {} would be orphaned", orphaned.join(", "))).into());
+        }
+        match validation["outOfRange"].as_array() {
+            Some(orphaned) if !orphaned.is_empty() => {
+                let orphaned = orphaned.iter().map(|it| it.to_string()).collect::<Vec<_>>();
+                return Err(invalid(format!("resources {} would be orphaned", orphaned.join(", "))).into());
+            }
+            Some(_) => {}
+            None => bail!("Script returned invalid outOfRange() result '{}'", validation["outOfRange"]),
+        }
+
+        let mut transaction = self.client.transaction()?;
+        // fails if resources were allocated since the validation
+        Self::bump_version(&mut transaction, &mut pool)?;
+        transaction.execute("UPDATE resource_pools SET properties=$1 WHERE id=$2", &[&new_properties, &pool.id])?;
+        Self::rebuild_free_ranges(&mut transaction, pool.id)?;
+        transaction.commit()?;
+        pool.properties = new_properties;
+        Ok(pool)
     }
 
     // resources
-    pub fn insert_resources(&mut self, mut pool: ResourcePool, items: Vec<Resource>)
+    // Optimistic locking: every change of pool's resources increments pool version.
+    // Fails with `AllocationError::VersionConflict` if the pool was modified concurrently
+    // and with `AllocationError::PoolArchived` if it was archived.
+    fn bump_version(transaction: &mut Transaction, pool: &mut ResourcePool) -> Result<()> {
+        let expected_current_version = pool.version;
+        pool.version += 1;
+        let updated = transaction.query_opt(
+            "UPDATE resource_pools SET version=$1 WHERE id=$2 AND version=$3 AND archived_at IS NULL \
+            RETURNING updated_at",
+            &[&pool.version, &pool.id, &expected_current_version])?;
+        match updated {
+            Some(updated) => pool.updated_at = updated.get(0),
+            None => {
+                Self::check_not_archived(transaction, pool.id)?;
+                return Err(AllocationError::VersionConflict {
+                    resource_pool: pool.name.clone(),
+                    expected: expected_current_version,
+                }.into());
+            }
+        }
+        Ok(())
+    }
+
+    // Fails with `AllocationError::Timeout` if the configured `TransactionTimeouts` are exceeded
+    // and with `AllocationError::UniquenessConflict` if a value is in use by another pool of a uniqueness group.
+    pub fn insert_resources(&mut self, pool: ResourcePool, items: Vec<Resource>)
                             -> Result<(ResourcePool, Vec<Resource>)> {
-        let mut transaction = self.client.transaction()?;
-        ensure!(items.len() > 0, "Cannot insert zero resources");
-        const PARAMS_PER_ROW: usize = 2;
+        self.insert_allocated_resources(pool, items, None)
+    }
+
+    // As `DB::insert_resources`, recording the `request` the strategy allocated the resources for.
+    pub(crate) fn insert_allocated_resources(&mut self, pool: ResourcePool, items: Vec<Resource>,
+                                             request: Option<&Value>) -> Result<(ResourcePool, Vec<Resource>)> {
+        let resource_pool = pool.name.clone();
+        let started = Instant::now();
+        let inserted = self.try_insert_resources(pool, items, request)
+            .map_err(|err| Self::uniqueness_error(Self::timeout_error(&resource_pool, err)));
+        metrics::record_transaction("allocation", started.elapsed());
+        inserted
+    }
+
+    fn try_insert_resources(&mut self, mut pool: ResourcePool, items: Vec<Resource>, request: Option<&Value>)
+                            -> Result<(ResourcePool, Vec<Resource>)> {
+        let (chaos, outbox) = (self.chaos.clone(), self.outbox);
+        let mut transaction = self.allocation_transaction()?;
+        ensure!(!items.is_empty(), "Cannot insert zero resources");
+        const PARAMS_PER_ROW: usize = 8;
         let mut params: Vec<&(dyn postgres::types::ToSql + Sync)> =
             Vec::with_capacity(PARAMS_PER_ROW * items.len());
-        let mut query =
-            "INSERT INTO resources (resource_pool, value) VALUES ".to_owned();
-        let mut idx = 0;
-        for resource in &items {
+        let states = items.iter().map(|it| it.state.as_str()).collect::<Vec<&str>>();
+        let mut query = "INSERT INTO resources (resource_pool, value, status, lease_expires_at, owner, description, \
+            metadata, canonical_value) VALUES ".to_owned();
+        for (idx, resource) in items.iter().enumerate() {
             ensure!(resource.resource_pool_id == pool.id, "Wrong resource id");
+            ensure!(resource.state.is_initial(), "Cannot insert resource in state {}", resource.state);
             params.push(&resource.resource_pool_id);
             params.push(&resource.value);
-            query += &format!("(${},${}),", PARAMS_PER_ROW * idx + 1, PARAMS_PER_ROW * idx + 2);
-            idx += 1;
+            params.push(&states[idx]);
+            params.push(&resource.lease_expires_at);
+            params.push(&resource.owner);
+            params.push(&resource.description);
+            params.push(&resource.metadata);
+            params.push(&resource.canonical_value);
+            let first = PARAMS_PER_ROW * idx;
+            query += &format!("(${},${},${},${},${},${},${},${}),",
+                              first + 1, first + 2, first + 3, first + 4, first + 5, first + 6, first + 7, first + 8);
         }
         ensure!(query.remove(query.len() - 1) == ',', "Expected to remove a coma");
 
@@ -210,64 +852,459 @@ impl DB {
         let inserted_count = transaction.execute(query.as_str(), &params)?;
         trace!("Inserted {} resources", inserted_count);
         ensure!(inserted_count == items.len() as u64, "Insertion of resources returned wrong number of rows");
-        // update pool version
-        let expected_current_version = pool.version;
-        pool.version += 1;
-        let updated_count = transaction.execute(
-            "UPDATE resource_pools SET version=$1 WHERE id=$2 AND version=$3",
-            &[&pool.version, &pool.id, &expected_current_version])?;
-        ensure!(updated_count == 1, "Update of resource_pools returned wrong number of rows");
+        Self::bump_version(&mut transaction, &mut pool)?;
+        Self::notify_pool_event(&mut transaction, outbox, &pool, "allocated", &items, request)?;
+        chaos.before_commit(&mut transaction)?;
         transaction.commit()?;
-        Ok((ResourcePool {
-            id: pool.id,
-            name: pool.name,
-            version: pool.version,
-            allocation_strategy_id: pool.allocation_strategy_id,
-        }, items))
+        Ok((pool, items))
     }
 
-    pub fn get_resources(&mut self, resource_pool_id: i32) -> Result<Vec<Resource>> {
-        let rows = self.client.query(
-            "SELECT id, value FROM resources WHERE resource_pool=$1", &[&resource_pool_id])?;
-        let mut result = Vec::with_capacity(rows.len());
+    // Fails with `AllocationError::ResourceClaimed` for claimed resources, see `force_deallocate_resource`.
+    pub fn deallocate_resource(&mut self, pool: ResourcePool, selector: &ResourceSelector)
+                               -> Result<(ResourcePool, Resource)> {
+        let to = Self::deallocated_state(&pool);
+        self.move_resource(pool, selector, to, true)
+    }
+
+    pub fn force_deallocate_resource(&mut self, pool: ResourcePool, selector: &ResourceSelector)
+                                     -> Result<(ResourcePool, Resource)> {
+        let to = Self::deallocated_state(&pool);
+        self.transition_resource(pool, selector, to)
+    }
+
+    fn deallocated_state(pool: &ResourcePool) -> ResourceState {
+        if pool.deallocation_safety_period > 0 {
+            ResourceState::Bench
+        } else {
+            ResourceState::Retired
+        }
+    }
+
+    // Deallocate many resources in one transaction with a single version bump.
+    // Selected ids must all exist and be deallocatable, otherwise nothing is changed.
+    pub fn deallocate_resources(&mut self, mut pool: ResourcePool, selector: &BulkSelector)
+                                -> Result<(ResourcePool, Vec<Resource>)> {
+        let (to, outbox) = (Self::deallocated_state(&pool), self.outbox);
+        let mut transaction = self.client.transaction()?;
+        let found = match selector {
+            BulkSelector::Ids(ids) => transaction.query(format!(
+                "SELECT {} FROM resources WHERE resource_pool=$1 AND id = ANY($2) ORDER BY id FOR UPDATE",
+                Self::RESOURCE_COLUMNS).as_str(), &[&pool.id, ids])?,
+            BulkSelector::State(state) => transaction.query(format!(
+                "SELECT {} FROM resources WHERE resource_pool=$1 AND status=$2 ORDER BY id FOR UPDATE",
+                Self::RESOURCE_COLUMNS).as_str(), &[&pool.id, &state.as_str()])?,
+        }.into_iter()
+            .map(|row| Self::row_to_resource(pool.id, row))
+            .collect::<Result<Vec<Resource>>>()?;
+        if let BulkSelector::Ids(ids) = selector {
+            if let Some(missing) = ids.iter().find(|id| !found.iter().any(|it| it.id == Some(**id))) {
+                return Err(AllocationError::ResourceNotFound {
+                    resource_pool: pool.name.clone(),
+                    resource: ResourceSelector::Id(*missing).to_string(),
+                }.into());
+            }
+        }
+        if let Some(illegal) = found.iter().find(|it| !it.state.can_transition_to(to)) {
+            return Err(AllocationError::IllegalTransition {
+                resource: ResourceSelector::Id(illegal.id.unwrap_or_default()).to_string(),
+                from: illegal.state,
+                to,
+            }.into());
+        }
+        if found.is_empty() {
+            return Ok((pool, found));
+        }
+        let ids = found.iter().filter_map(|it| it.id).collect::<Vec<i64>>();
+        let updated = transaction.query(
+            Self::update_state_sql("id = ANY($1)").as_str(),
+            &[&ids, &to.as_str(), &(pool.deallocation_safety_period as f64)])?
+            .into_iter()
+            .map(|row| Self::row_to_resource(pool.id, row))
+            .collect::<Result<Vec<Resource>>>()?;
+        Self::bump_version(&mut transaction, &mut pool)?;
+        Self::notify_pool_event(&mut transaction, outbox, &pool, Self::transition_event(to), &updated, None)?;
+        transaction.commit()?;
+        debug!("Deallocated {} resources of pool {}", updated.len(), pool.id);
+        Ok((pool, updated))
+    }
+
+    // Undo deallocation of a benched or retired resource.
+    pub fn restore_resource(&mut self, pool: ResourcePool, id: i64) -> Result<(ResourcePool, Resource)> {
+        self.transition_resource(pool, &ResourceSelector::Id(id), ResourceState::Allocated)
+            .map_err(Self::uniqueness_error)
+            .context("Cannot restore resource")
+    }
+
+    // Move a resource to another state. Fails with `AllocationError::IllegalTransition` if
+    // the state machine does not allow it. Bumps the pool version.
+    pub fn transition_resource(&mut self, pool: ResourcePool, selector: &ResourceSelector,
+                               to: ResourceState) -> Result<(ResourcePool, Resource)> {
+        self.move_resource(pool, selector, to, false)
+    }
+
+    fn move_resource(&mut self, mut pool: ResourcePool, selector: &ResourceSelector, to: ResourceState,
+                     keep_claimed: bool) -> Result<(ResourcePool, Resource)> {
+        let outbox = self.outbox;
+        let mut transaction = self.client.transaction()?;
+        let (condition, param): (&str, &(dyn postgres::types::ToSql + Sync)) = match selector {
+            ResourceSelector::Id(id) => ("id=$2", id),
+            ResourceSelector::Value(value) => ("value=$2", value),
+        };
+        // a value can also belong to retired resources, prefer the one in use
+        let found = transaction.query_opt(format!(
+            "SELECT {} FROM resources WHERE resource_pool=$1 AND {} \
+            ORDER BY status = 'retired', id DESC LIMIT 1 FOR UPDATE",
+            Self::RESOURCE_COLUMNS, condition).as_str(), &[&pool.id, param])?
+            .ok_or_else(|| AllocationError::ResourceNotFound {
+                resource_pool: pool.name.clone(),
+                resource: selector.to_string(),
+            })?;
+        let found = Self::row_to_resource(pool.id, found)?;
+        if keep_claimed && found.state == ResourceState::Claimed {
+            return Err(AllocationError::ResourceClaimed {
+                resource_pool: pool.name.clone(),
+                resource: selector.to_string(),
+            }.into());
+        }
+        if !found.state.can_transition_to(to) {
+            return Err(AllocationError::IllegalTransition {
+                resource: selector.to_string(),
+                from: found.state,
+                to,
+            }.into());
+        }
+        let updated = transaction.query_one(
+            Self::update_state_sql("id=$1").as_str(),
+            &[&found.id, &to.as_str(), &(pool.deallocation_safety_period as f64)])
+            .context("Cannot update resource state, its value might have been allocated again")?;
+        let resource = Self::row_to_resource(pool.id, updated)?;
+        Self::bump_version(&mut transaction, &mut pool)?;
+        Self::notify_pool_event(&mut transaction, outbox, &pool, Self::transition_event(to),
+                                std::slice::from_ref(&resource), None)?;
+        transaction.commit()?;
+        debug!("Resource {:?} of pool {} moved from {} to {}", resource.id, pool.id, found.state, to);
+        Ok((pool, resource))
+    }
+
+    // Event of `notify_pool_event` for resources moved to the state.
+    fn transition_event(to: ResourceState) -> &'static str {
+        match to {
+            ResourceState::Bench | ResourceState::Retired => "deallocated",
+            _ => "state_changed",
+        }
+    }
+
+    // Parameters: $1 selected by the condition, $2 target state, $3 deallocation safety period
+    fn update_state_sql(condition: &str) -> String {
+        format!("UPDATE resources SET status=$2::text, \
+            quarantined_until = CASE \
+                WHEN $2::text = 'bench' THEN now() + make_interval(secs => $3) \
+                WHEN $2::text = 'retired' THEN quarantined_until END, \
+            deleted_at = CASE WHEN $2::text = 'retired' THEN now() END \
+            WHERE {} RETURNING {}", condition, Self::RESOURCE_COLUMNS)
+    }
+
+    pub fn get_resources(&mut self, resource_pool_id: i64) -> Result<Vec<Resource>> {
+        self.get_resources_filtered(resource_pool_id, &ResourceFilter::default())
+    }
+
+    pub fn get_resources_filtered(&mut self, resource_pool_id: i64, filter: &ResourceFilter)
+                                  -> Result<Vec<Resource>> {
+        Self::query_resources(self.reader(), resource_pool_id, filter)
+    }
+
+    // Resources in use of every pool, read in one query. Pools without resources, including nonexistent ones,
+    // map to an empty list.
+    pub fn get_resources_for_pools(&mut self, resource_pool_ids: &[i64]) -> Result<HashMap<i64, Vec<Resource>>> {
+        let mut result = resource_pool_ids.iter().map(|id| (*id, vec![])).collect::<HashMap<_, _>>();
+        let rows = self.reader().query(
+            format!("SELECT {}, resource_pool FROM resources WHERE resource_pool = ANY($1) AND status <> 'retired' \
+                ORDER BY id", Self::RESOURCE_COLUMNS).as_str(),
+            &[&resource_pool_ids])?;
         for row in rows {
-            let id: i32 = row.get(0);
-            let value: Value = row.get(1);
-            result.push(Resource { id: Some(id), resource_pool_id, value });
+            let resource_pool_id: i64 = row.get(row.len() - 1);
+            result.entry(resource_pool_id).or_default().push(Self::row_to_resource(resource_pool_id, row)?);
         }
+        Ok(result)
+    }
+
+    fn query_resources(client: &mut Client, resource_pool_id: i64, filter: &ResourceFilter)
+                       -> Result<Vec<Resource>> {
+        let rows = client.query(
+            format!("SELECT {} FROM resources WHERE {} ORDER BY id",
+                    Self::RESOURCE_COLUMNS, filter.where_clause()).as_str(),
+            &[&resource_pool_id, &filter.allocated_after, &filter.allocated_before])?;
+        let result = rows.into_iter()
+            .map(|row| Self::row_to_resource(resource_pool_id, row))
+            .collect::<Result<Vec<Resource>>>()?;
         debug!("Found {} resources of pool {}", result.len(), resource_pool_id);
         Ok(result)
     }
 
+    // Resources in use, counted using the `resources_in_use` index.
+    pub fn count_resources(&mut self, resource_pool_id: i64) -> Result<i64> {
+        let row = self.reader().query_one(
+            "SELECT count(*) FROM resources WHERE resource_pool=$1 AND status <> 'retired'", &[&resource_pool_id])?;
+        Ok(row.get(0))
+    }
+
+    // Whether the value is in use, checked using the unique index of values.
+    // Read resources in batches using a cursor, so that huge pools do not need to fit into memory.
+    // Returns number of streamed resources.
+    pub fn stream_resources<F>(&mut self, resource_pool_id: i64, batch_size: i32, mut consumer: F) -> Result<u64>
+        where F: FnMut(Vec<Resource>) -> Result<()> {
+        ensure!(batch_size > 0, "Batch size must be positive");
+        let mut transaction = self.reader().transaction()?;
+        transaction.execute(
+            format!("DECLARE streamed_resources NO SCROLL CURSOR FOR SELECT {} FROM resources \
+                    WHERE resource_pool=$1 AND status <> 'retired' ORDER BY id", Self::RESOURCE_COLUMNS).as_str(),
+            &[&resource_pool_id])?;
+        let mut streamed = 0;
+        loop {
+            let rows = transaction.query(format!("FETCH {} FROM streamed_resources", batch_size).as_str(), &[])?;
+            if rows.is_empty() {
+                break;
+            }
+            streamed += rows.len() as u64;
+            consumer(rows.into_iter()
+                .map(|row| Self::row_to_resource(resource_pool_id, row))
+                .collect::<Result<Vec<Resource>>>()?)?;
+        }
+        // a dry run may stream again before its transaction ends
+        transaction.batch_execute("CLOSE streamed_resources")?;
+        transaction.commit()?;
+        Ok(streamed)
+    }
+
+    const RESOURCE_COLUMNS: &'static str =
+        "id, value, status, lease_expires_at, quarantined_until, deleted_at, metadata, owner, description, \
+        created_at, updated_at, canonical_value";
+
+    fn row_to_resource(resource_pool_id: i64, row: Row) -> Result<Resource> {
+        let id: i64 = row.get(0);
+        let value: Value = row.get(1);
+        let state: &str = row.get(2);
+        let state = state.parse()?;
+        let lease_expires_at = row.get(3);
+        let quarantined_until = row.get(4);
+        let deleted_at = row.get(5);
+        let metadata = row.get(6);
+        let owner = row.get(7);
+        let description = row.get(8);
+        let created_at = row.get(9);
+        let updated_at = row.get(10);
+        let canonical_value = row.get(11);
+        Ok(Resource {
+            id: Some(id), resource_pool_id, value, state, lease_expires_at, quarantined_until, deleted_at, metadata,
+            owner, description, created_at, updated_at, canonical_value,
+        })
+    }
+
+    // Ids of pools that contain expired leases, benched resources past their quarantine
+    // or retired resources older than retention.
+    pub fn find_pools_to_gc(&mut self, retention: Duration) -> Result<Vec<i64>> {
+        let rows = self.client.query(
+            "SELECT DISTINCT resource_pool FROM resources WHERE \
+            resource_pool NOT IN (SELECT id FROM resource_pools WHERE archived_at IS NOT NULL) AND \
+            ((status IN ('reserved', 'allocated', 'claimed') AND lease_expires_at < now()) \
+            OR (status = 'bench' AND quarantined_until < now()) \
+            OR (status = 'retired' AND deleted_at < now() - make_interval(secs => $1))) \
+            ORDER BY resource_pool",
+            &[&retention.as_secs_f64()])?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    // Retire expired leases and benched resources past their quarantine, each batch in its own
+    // transaction that bumps the pool version. Then purge retired resources older than retention.
+    pub fn gc_pool(&mut self, mut pool: ResourcePool, batch_size: i64, retention: Duration)
+                   -> Result<(ResourcePool, GcReport)> {
+        ensure!(batch_size > 0, "Batch size must be positive");
+        let mut report = GcReport::default();
+        loop {
+            let mut transaction = self.client.transaction()?;
+            let rows = transaction.query(
+                "UPDATE resources SET status = 'retired', deleted_at = now() WHERE id IN (\
+                    SELECT id FROM resources WHERE resource_pool=$1 AND (\
+                    (status IN ('reserved', 'allocated', 'claimed') AND lease_expires_at < now()) \
+                    OR (status = 'bench' AND quarantined_until < now())) LIMIT $2) \
+                RETURNING quarantined_until IS NOT NULL", &[&pool.id, &batch_size])?;
+            if rows.is_empty() {
+                break;
+            }
+            for row in &rows {
+                if row.get(0) {
+                    report.released_from_quarantine += 1;
+                } else {
+                    report.expired_leases += 1;
+                }
+            }
+            Self::bump_version(&mut transaction, &mut pool)?;
+            transaction.commit()?;
+            debug!("Reclaimed {} resources of pool {}", rows.len(), pool.id);
+            if (rows.len() as i64) < batch_size {
+                break;
+            }
+        }
+        // purged resources are not visible to strategies, no need to bump the version
+        loop {
+            let purged = self.client.execute(
+                "DELETE FROM resources WHERE id IN (\
+                    SELECT id FROM resources WHERE resource_pool=$1 AND status = 'retired' \
+                    AND deleted_at < now() - make_interval(secs => $2) LIMIT $3)",
+                &[&pool.id, &retention.as_secs_f64(), &batch_size])?;
+            report.purged += purged;
+            if purged < batch_size as u64 {
+                break;
+            }
+        }
+        Ok((pool, report))
+    }
+
+    // Resources for values returned by the strategy, not inserted yet.
+    fn new_resources(pool: &ResourcePool, values: Vec<Value>, options: &AllocationOptions) -> Vec<Resource> {
+        let lease_expires_at = options.lease.map(|lease| SystemTime::now() + lease);
+        let state = if options.reserve { ResourceState::Reserved } else { ResourceState::Allocated };
+        values.into_iter()
+            .map(|value| Resource {
+                state,
+                lease_expires_at,
+                owner: options.owner.clone(),
+                description: options.description.clone(),
+                ..Resource::new_from_value(pool.id, value)
+            })
+            .collect()
+    }
+
     pub fn allocate_resources(&mut self, pool: ResourcePool, wasmer_env: &mut WasmerEnv,
-                              user_input: Value) -> Result<(ResourcePool, Vec<Resource>)> {
-        // get script
-        let script = self.get_allocation_script(pool.allocation_strategy_id)?;
-
-        let current_resources = self.get_resources(pool.id)?.iter()
-            .map(|it| it.as_json())
-            .collect::<Vec<Value>>();
-        let resource_pool = pool.as_json();
-        let resource_pool_properties = pool.get_pool_properties();
-        let execution_result = wasmer_env.invoke_and_parse(
-            &script, user_input, resource_pool_properties,
-            resource_pool, current_resources, "invoke()")?;
-
-        // save to DB
-        let resources = execution_result.into_iter()
-            .map(|value| Resource::new_from_value(pool.id, value))
-            .collect::<Vec<Resource>>();
-        let (pool, resources) = self.insert_resources(pool, resources)?;
+                              user_input: Value, options: &AllocationOptions)
+                              -> Result<(ResourcePool, Vec<Resource>)> {
+        let (pool, resources, _) = self.allocate_resources_with_timings(pool, wasmer_env, user_input, options)?;
         Ok((pool, resources))
     }
+
+    // Allocations other than dry runs are counted by outcome, see `metrics::render` and `DB::record_contention`.
+    pub fn allocate_resources_with_timings(&mut self, pool: ResourcePool, wasmer_env: &mut WasmerEnv,
+                                           user_input: Value, options: &AllocationOptions)
+                                           -> Result<(ResourcePool, Vec<Resource>, Timings)> {
+        let (resource_pool_id, allocation_strategy_id) = (pool.id, pool.allocation_strategy_id);
+        let _span = info_span!("allocation", pool_id = resource_pool_id, strategy_id = allocation_strategy_id)
+            .entered();
+        let started = Instant::now();
+        let mut timings = Timings::default();
+        let result = self.allocate_in_turn(pool, wasmer_env, user_input, options, &mut timings);
+        timings.total = started.elapsed();
+        if !options.dry_run && !self.client.is_dry_run() {
+            metrics::record_allocation(resource_pool_id, allocation_strategy_id, &result);
+            if let Err(err) = self.record_contention(resource_pool_id, metrics::AllocationOutcome::of(&result)) {
+                warn!("Cannot record contention of pool {}: {:#}", resource_pool_id, err);
+            }
+        }
+        debug!(duration_ms = timings.total.as_millis() as u64, script_ms = timings.script.as_millis() as u64,
+               db_read_ms = timings.db_read.as_millis() as u64, db_write_ms = timings.db_write.as_millis() as u64,
+               outcome = %metrics::AllocationOutcome::of(&result), "Allocation finished");
+        result.map(|(pool, resources)| (pool, resources, timings))
+    }
+
+    fn try_allocate_resources(&mut self, pool: ResourcePool, wasmer_env: &mut WasmerEnv,
+                              user_input: Value, options: &AllocationOptions, timings: &mut Timings)
+                              -> Result<(ResourcePool, Vec<Resource>)> {
+        let context = timings.db_read("allocation_context", || self.get_allocation_context(pool.id))?;
+        let user_input = pool.user_input_with_defaults(user_input);
+        let user_input = match &context.pre_allocation_hook {
+            Some(hook) => timings.script("pre_allocation_hook", || hook.pre_allocate(wasmer_env, &pool, user_input))?,
+            None => user_input,
+        };
+        // before spawning the engine
+        Self::check_input_schema(&pool.name, context.input_schema.as_ref(), &user_input)?;
+        // requesters with a sticky key get back what they were allocated before
+        let sticky_key = sticky::sticky_key(&user_input)?.map(str::to_owned);
+        if let Some(key) = &sticky_key {
+            let existing = timings.db_read("sticky_resources", || self.find_sticky_resources(pool.id, key))?;
+            if !existing.is_empty() {
+                debug!("Pool {} returns {} resources of sticky key '{}'", pool.id, existing.len(), key);
+                return Ok((pool, existing));
+            }
+        }
+        let desired = desired::desired_value(&user_input)?.cloned();
+        let block = blocks::BlockRequest::from_user_input(&user_input)?;
+        // sequential strategies pop from the free list instead of passing all resources to the script
+        let free_addresses = timings.db_read(
+            "free_addresses", || self.find_free_addresses(&pool, context.free_range_bounds, &user_input))?;
+        let execution_result = match free_addresses {
+            Some(addresses) => addresses,
+            None => {
+                let engine = wasmer_env.engine(context.engine)?;
+
+                let mut current_resources = PoolResources::new(&mut self.client, pool.id);
+                let resource_pool = pool.as_json();
+                let resource_pool_properties = pool.get_pool_properties();
+                let chaos = &self.chaos;
+                let result = timings.script("strategy", || chaos.before_script().and_then(|_| engine.invoke_and_parse(
+                    &context.script, user_input.clone(), resource_pool_properties,
+                    resource_pool, &mut current_resources, "invoke()")));
+                let values = match &desired {
+                    Some(desired) => Self::check_desired_result(&pool, desired, result)?,
+                    None => result?,
+                };
+                let sub_ranges = SubRange::of_pool(&pool)?;
+                SubRange::check_values(&sub_ranges, SubRange::selected(&pool, &sub_ranges, &user_input)?, &values)?;
+                values
+            }
+        };
+        let execution_result = match &context.post_allocation_hook {
+            Some(hook) => timings.script(
+                "post_allocation_hook", || hook.post_allocate(wasmer_env, &pool, &user_input, execution_result))?,
+            None => execution_result,
+        };
+
+        let resources = Self::new_resources(&pool, execution_result, options);
+        let resources = match &sticky_key {
+            Some(key) => sticky::with_sticky_key(resources, key),
+            None => resources,
+        };
+        let resources = match &block {
+            Some(block) => block.resources(resources)?,
+            None => resources,
+        };
+        if options.dry_run {
+            debug!("Dry run of pool {} would allocate {} resources", pool.id, resources.len());
+            return Ok((pool, resources));
+        }
+        let resources = if block.is_none() && canonical::may_canonicalize(&context.script) {
+            timings.script("canonicalize", || canonical::canonicalize_resources(
+                wasmer_env, context.engine, &context.script, &pool, resources))?
+        } else {
+            resources
+        };
+        // save to DB, with the request kept for `DB::replay_pool`
+        let request = json!({"userInput": &user_input, "strategyVersion": context.strategy_version});
+        timings.db_write("insert_resources", || {
+            if block.is_some() {
+                return self.insert_block(pool, resources, &request);
+            }
+            match &desired {
+                Some(desired) => {
+                    let resource_pool = pool.clone();
+                    self.insert_allocated_resources(pool, resources, Some(&request))
+                        .map_err(|err| Self::desired_insert_error(&resource_pool, desired, err))
+                }
+                None => self.insert_allocated_resources(pool, resources, Some(&request)),
+            }
+        })
+    }
 }
 
 fn main() -> Result<()> {
-    Ok(())
+    let cli = cli::Cli::parse();
+    // every missing or invalid setting is reported before anything runs
+    let config = Config::from_env()?;
+    logging::init(config.log_format)?;
+    cli.run().map_err(DB::read_only_error)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::env;
     use std::sync::Once;
 
     use rand::Rng;
@@ -275,13 +1312,15 @@ mod tests {
     use serde_json::json;
     use std::str::FromStr;
     use std::thread;
+    use tracing_subscriber::EnvFilter;
 
+    use crate::engine::StrategyEngine;
     use super::*;
 
     static START: Once = Once::new();
-    const IPV4_ALLOCATION_STRATEGY_ID: i32 = 1;
+    pub(crate) const IPV4_ALLOCATION_STRATEGY_ID: i64 = 1;
 
-    fn initialize_logging() {
+    pub(crate) fn initialize_logging() {
         START.call_once(|| {
             let fmt_event = tracing_subscriber::fmt::format::Format::default()
                 .with_target(false)
@@ -316,9 +1355,19 @@ mod tests {
         initialize_logging();
 
         let mut wasmer_env = WasmerEnv::new().unwrap();
-        let output = wasmer_env.invoke_js("console.log(2+2)").unwrap();
+        let output = wasmer_env.invoke_js("console.log(2+2)", &mut vec![]).unwrap();
         trace!("{:?}", output);
-        assert_eq!("4\n", String::from_utf8(output.stdout).unwrap());
+        assert_eq!((vec!["4".to_owned()], None), (output.logs, output.result));
+    }
+
+    #[test]
+    fn wasmer_warm_up() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        create_random_pool(&mut db).unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        assert!(wasmer_env.warm_up(&mut db).unwrap() >= 1);
     }
 
     #[test]
@@ -327,6 +1376,7 @@ mod tests {
 
         let mut wasmer_env = WasmerEnv::new().unwrap();
         let script = "function invoke() {\
+        console.log('logged', 'to stdout');\
         return [{mykey:1, userInput, resourcePoolProperties, resourcePool, currentResources}]\
         }";
         let user_input = json!({
@@ -338,12 +1388,12 @@ mod tests {
         let resource_pool = json!({
             "rp":"rp"
         });
-        let current_resources = json!([
+        let mut current_resources = json!([
             "res1", "res2"
         ]).as_array().ok_or(anyhow!("Unexpected")).unwrap().to_owned();
 
         let actual = wasmer_env.invoke_and_parse(script, user_input, resource_pool_properties,
-                                                 resource_pool, current_resources, "invoke()").unwrap();
+                                                 resource_pool, &mut current_resources, "invoke()").unwrap();
         let expected = json!([{
             "mykey": 1,
             "userInput": {"input":"input"},
@@ -359,9 +1409,7 @@ mod tests {
         initialize_logging();
 
         let mut db = DB::new_from_env().unwrap();
-        let sw = Stopwatch::start_new();
         let script = db.get_allocation_script(IPV4_ALLOCATION_STRATEGY_ID).unwrap();
-        debug!("Found row in {}ms", sw.elapsed_ms());
         trace!("found script: {}", script);
     }
 
@@ -382,20 +1430,20 @@ mod tests {
             "prefix": 24,
         });
         let resource_pool = json!({});
-        let current_resources = create_some_ips(1, 2, true); // 10.0.0.1, 10.0.0.2
+        let mut current_resources = create_some_ips(1, 2, true); // 10.0.0.1, 10.0.0.2
 
         let actual = wasmer_env.invoke_and_parse(&script, user_input.clone(), resource_pool_properties.clone(),
-                                                 resource_pool.clone(), current_resources, "invoke()").unwrap();
+                                                 resource_pool.clone(), &mut current_resources, "invoke()").unwrap();
         let expected = json!([
             {"address":"10.0.0.0"},
             {"address":"10.0.0.3"}
         ]).as_array().ok_or(anyhow!("Unreachable")).unwrap().to_owned();
         assert_eq!(expected, actual);
 
-        let current_resources = create_some_ips(0, 4, true); // 10.0.0.0 - 10.0.0.3
+        let mut current_resources = create_some_ips(0, 4, true); // 10.0.0.0 - 10.0.0.3
 
         let actual = wasmer_env.invoke_and_parse(&script, user_input, resource_pool_properties,
-                                                 resource_pool, current_resources, "invoke()").unwrap();
+                                                 resource_pool, &mut current_resources, "invoke()").unwrap();
         let expected = json!([
             {"address":"10.0.0.4"},
             {"address":"10.0.0.5"}
@@ -403,7 +1451,7 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
-    fn create_random_pool(db: &mut DB) -> Result<ResourcePool> {
+    pub(crate) fn create_random_pool(db: &mut DB) -> Result<ResourcePool> {
         let random_string: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
         // check that it does not exist
         assert!(db.get_resource_pool_by_name(&random_string).is_err());
@@ -415,19 +1463,32 @@ mod tests {
         initialize_logging();
 
         let mut db = DB::new_from_env().unwrap();
-        let sw = Stopwatch::start_new();
         let inserted = create_random_pool(&mut db).unwrap();
-        debug!("Inserted row in {}ms", sw.elapsed_ms());
-        let sw = Stopwatch::start_new();
         let by_name = db.get_resource_pool_by_name(&inserted.name).unwrap();
-        debug!("Found row in {}ms", sw.elapsed_ms());
-        let sw = Stopwatch::start_new();
         assert_eq!(inserted, by_name);
         let by_id = db.get_resource_pool_by_id(inserted.id).unwrap();
-        debug!("Found row in {}ms", sw.elapsed_ms());
         assert_eq!(inserted, by_id);
     }
 
+    #[test]
+    fn db_delete_resource_pool() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = create_some_ips(1, 2, false).into_iter()
+            .map(|value| Resource::new_from_value(pool.id, value))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        db.delete_resource_pool(pool.clone()).expect_err("Pool has resources in use");
+        let (pool, _) = db.deallocate_resources(pool, &BulkSelector::State(ResourceState::Allocated)).unwrap();
+        db.delete_resource_pool(pool.clone()).unwrap();
+        assert!(db.find_resource_pool_by_name(&pool.name).unwrap().is_none());
+        let remaining: i64 = db.client.query_one("SELECT count(*) FROM resources WHERE resource_pool=$1",
+                                                 &[&pool.id]).unwrap().get(0);
+        assert_eq!(0, remaining);
+    }
+
     #[test]
     fn db_insert_resources_duplicates_should_fail() {
         initialize_logging();
@@ -454,14 +1515,12 @@ mod tests {
 
         let resource_pool_id = pool.id;
         let old_version = pool.version;
-        let sw = Stopwatch::start_new();
         let mut resources = Vec::with_capacity(ROW_COUNT);
         for idx in 0..ROW_COUNT {
             resources.push(Resource::new_from_str(resource_pool_id,
                                                   &format!("{{\"address\":\"1.1.1.{}\"}}", idx)).unwrap());
         }
         let (pool, resources) = db.insert_resources(pool, resources).unwrap();
-        debug!("inserted {} rows in {}ms", resources.len(), sw.elapsed_ms());
         // check that version is incremented
         assert_eq!(pool.version, old_version + 1);
         assert_eq!(db.get_resource_pool_by_id(resource_pool_id).unwrap().version, old_version + 1);
@@ -473,6 +1532,363 @@ mod tests {
                    found_resources.iter().map(|it| &it.value).collect::<Vec<&Value>>());
     }
 
+    #[test]
+    fn db_resources_for_pools() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let first = create_random_pool(&mut db).unwrap();
+        let second = create_random_pool(&mut db).unwrap();
+        let empty = create_random_pool(&mut db).unwrap();
+        let resources = ["10.0.0.1", "10.0.0.2"].iter()
+            .map(|address| Resource::new_from_value(first.id, json!({"address": address})))
+            .collect();
+        db.insert_resources(first.clone(), resources).unwrap();
+        db.insert_resources(second.clone(), vec![Resource::new_from_value(second.id, json!({"address": "10.0.0.1"}))])
+            .unwrap();
+
+        let found = db.get_resources_for_pools(&[first.id, second.id, empty.id]).unwrap();
+        assert_eq!(3, found.len());
+        assert_eq!(db.get_resources(first.id).unwrap(), found[&first.id]);
+        assert_eq!(db.get_resources(second.id).unwrap(), found[&second.id]);
+        assert!(found[&empty.id].is_empty());
+    }
+
+    #[test]
+    fn db_allocation_description() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let options = AllocationOptions {
+            description: Some("loopback for PE-router-3".to_owned()), ..AllocationOptions::default()
+        };
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        let job_id = db.enqueue_allocation(pool.id, json!({}), &options).unwrap();
+        // other tests may enqueue or run jobs concurrently
+        while db.get_job_status(job_id).unwrap().status == crate::jobs::JobStatus::Pending {
+            if db.run_next_allocation_job(&mut wasmer_env).unwrap().is_none() {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        assert_eq!(options.description, db.get_job_status(job_id).unwrap().description);
+
+        let exported = db.get_resources(pool.id).unwrap().iter().map(Resource::as_export_json).collect::<Vec<_>>();
+        assert_eq!(vec![json!("loopback for PE-router-3"); 2],
+                   exported.iter().map(|it| it["description"].clone()).collect::<Vec<_>>());
+        let restored = Resource::restore_from_export_json(pool.id, exported[0].clone()).unwrap();
+        assert_eq!(options.description, restored.description);
+    }
+
+    #[test]
+    fn db_deallocate_resource() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = create_some_ips(0, 3, false).into_iter()
+            .map(|value| Resource::new_from_value(pool.id, value))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let by_id = db.get_resources(pool.id).unwrap().remove(0);
+
+        let (pool, deallocated) = db.deallocate_resource(
+            pool, &ResourceSelector::Id(by_id.id.unwrap())).unwrap();
+        assert_eq!((by_id.id, &by_id.value), (deallocated.id, &deallocated.value));
+        assert!(deallocated.deleted_at.is_some());
+        let (pool, deallocated) = db.deallocate_resource(
+            pool, &ResourceSelector::Value(json!({"address": "10.0.0.2"}))).unwrap();
+        assert_eq!(json!({"address": "10.0.0.2"}), deallocated.value);
+        assert_eq!(3, pool.version);
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+
+        let err = db.deallocate_resource(pool.clone(), &ResourceSelector::Id(by_id.id.unwrap()))
+            .expect_err("Resource was already deallocated");
+        assert_eq!(Some(&AllocationError::IllegalTransition {
+            resource: format!("with id {}", by_id.id.unwrap()),
+            from: ResourceState::Retired,
+            to: ResourceState::Retired,
+        }), err.downcast_ref::<AllocationError>());
+        let err = db.deallocate_resource(pool.clone(), &ResourceSelector::Id(-1))
+            .expect_err("Resource does not exist");
+        assert_eq!(Some(&AllocationError::ResourceNotFound {
+            resource_pool: pool.name.clone(),
+            resource: "with id -1".to_owned(),
+        }), err.downcast_ref::<AllocationError>());
+        // failed deallocation must not change the version
+        assert_eq!(pool.version, db.get_resource_pool_by_id(pool.id).unwrap().version);
+
+        let claimed = ResourceSelector::Value(json!({"address": "10.0.0.1"}));
+        let (pool, _) = db.transition_resource(pool, &claimed, ResourceState::Claimed).unwrap();
+        let err = db.deallocate_resource(pool.clone(), &claimed).expect_err("Resource is claimed");
+        assert_eq!(Some(&AllocationError::ResourceClaimed {
+            resource_pool: pool.name.clone(),
+            resource: claimed.to_string(),
+        }), err.downcast_ref::<AllocationError>());
+        let (_, deallocated) = db.force_deallocate_resource(pool, &claimed).unwrap();
+        assert_eq!(ResourceState::Retired, deallocated.state);
+    }
+
+    #[test]
+    fn db_deallocate_resources() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let mut resources = create_some_ips(0, 5, false).into_iter()
+            .map(|value| Resource::new_from_value(pool.id, value))
+            .collect::<Vec<_>>();
+        resources[4].state = ResourceState::Reserved;
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let ids = db.get_resources(pool.id).unwrap().iter().map(|it| it.id.unwrap()).collect::<Vec<_>>();
+
+        let err = db.deallocate_resources(pool.clone(), &BulkSelector::Ids(vec![ids[0], -1]))
+            .expect_err("Resource -1 does not exist");
+        assert!(matches!(err.downcast_ref::<AllocationError>(),
+            Some(AllocationError::ResourceNotFound { .. })), "Unexpected error {:?}", err);
+        assert_eq!(5, db.count_resources(pool.id).unwrap());
+
+        let (pool, deallocated) = db.deallocate_resources(pool, &BulkSelector::Ids(ids[..3].to_vec())).unwrap();
+        assert_eq!(ids[..3].to_vec(), deallocated.iter().map(|it| it.id.unwrap()).collect::<Vec<_>>());
+        assert!(deallocated.iter().all(|it| it.state == ResourceState::Retired));
+        assert_eq!(2, pool.version);
+        let err = db.deallocate_resources(pool.clone(), &BulkSelector::Ids(ids[2..4].to_vec()))
+            .expect_err("Resource was already deallocated");
+        assert!(matches!(err.downcast_ref::<AllocationError>(),
+            Some(AllocationError::IllegalTransition { .. })), "Unexpected error {:?}", err);
+
+        let (pool, deallocated) = db.deallocate_resources(pool, &BulkSelector::State(ResourceState::Reserved))
+            .unwrap();
+        assert_eq!(vec![ids[4]], deallocated.iter().map(|it| it.id.unwrap()).collect::<Vec<_>>());
+        assert_eq!(3, pool.version);
+        let (pool, deallocated) = db.deallocate_resources(pool, &BulkSelector::State(ResourceState::Reserved))
+            .unwrap();
+        assert!(deallocated.is_empty());
+        assert_eq!(pool.version, db.get_resource_pool_by_id(pool.id).unwrap().version);
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+    }
+
+    #[test]
+    fn allocate_resources_dry_run() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let user_input = json!({"resourceCount": 2});
+        let dry_run = AllocationOptions { dry_run: true, ..AllocationOptions::default() };
+
+        let (pool, previewed) = db.allocate_resources(
+            pool, &mut wasmer_env, user_input.clone(), &dry_run).unwrap();
+        assert_eq!(0, pool.version);
+        assert_eq!(0, db.get_resource_pool_by_id(pool.id).unwrap().version);
+        assert_eq!(0, db.count_resources(pool.id).unwrap());
+
+        let (_pool, allocated) = db.allocate_resources(
+            pool, &mut wasmer_env, user_input, &AllocationOptions::default()).unwrap();
+        assert_eq!(previewed, allocated);
+    }
+
+    #[test]
+    fn db_read_replica() {
+        initialize_logging();
+
+        // a read-only session of the same database stands in for a hot standby
+        let params = std::env::var("DB_PARAMS").unwrap();
+        let replica_params = format!("{} options='-c default_transaction_read_only=on'", params);
+        let mut db = DB::new_from_env().unwrap().with_replica(&replica_params).unwrap();
+        let read_only: String = db.reader().query_one("SHOW transaction_read_only", &[]).unwrap().get(0);
+        assert_eq!("on", read_only);
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 2}),
+                                              &AllocationOptions::default()).unwrap();
+        assert_eq!(2, db.get_resources(pool.id).unwrap().len());
+        assert_eq!(2, db.count_resources(pool.id).unwrap());
+        assert_eq!(2, db.stream_resources(pool.id, 1, |_| Ok(())).unwrap());
+        assert!(db.reader().execute("DELETE FROM resources WHERE resource_pool=$1", &[&pool.id]).is_err());
+
+        let mut primary_only = DB::new_from_env().unwrap();
+        let read_only: String = primary_only.reader().query_one("SHOW transaction_read_only", &[]).unwrap().get(0);
+        assert_eq!("off", read_only);
+    }
+
+    #[test]
+    fn db_gc_pool() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let pool = db.set_deallocation_safety_period(pool, 3600).unwrap();
+        let expired = SystemTime::now() - Duration::from_secs(1);
+        let valid = SystemTime::now() + Duration::from_secs(3600);
+        let resources = create_some_ips(0, 4, false).into_iter()
+            .map(|value| Resource::new_from_value(pool.id, value))
+            .enumerate()
+            .map(|(idx, resource)| Resource {
+                lease_expires_at: match idx { 0 => Some(expired), 1 => Some(valid), _ => None },
+                ..resource
+            })
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        // quarantined resources stay in the pool until the safety period passes
+        let (pool, quarantined) = db.deallocate_resource(
+            pool, &ResourceSelector::Value(json!({"address": "10.0.0.2"}))).unwrap();
+        assert!(quarantined.quarantined_until.unwrap() > SystemTime::now());
+        assert_eq!(ResourceState::Bench, quarantined.state);
+        assert_eq!(4, db.count_resources(pool.id).unwrap());
+        db.deallocate_resource(pool.clone(), &ResourceSelector::Value(json!({"address": "10.0.0.2"})))
+            .expect_err("Quarantined resource cannot be deallocated again");
+        db.client.execute("UPDATE resources SET quarantined_until = now() - interval '1 second' \
+            WHERE id=$1", &[&quarantined.id]).unwrap();
+        let retention = Duration::from_secs(3600);
+        assert!(db.find_pools_to_gc(retention).unwrap().contains(&pool.id));
+
+        let (pool, report) = db.gc_pool(pool, 1, retention).unwrap();
+        assert_eq!(GcReport { expired_leases: 1, released_from_quarantine: 1, purged: 0 }, report);
+        assert_eq!(4, pool.version);
+        let remaining = db.get_resources(pool.id).unwrap().into_iter()
+            .map(|it| it.value).collect::<Vec<Value>>();
+        assert_eq!(vec![json!({"address": "10.0.0.1"}), json!({"address": "10.0.0.3"})], remaining);
+        assert!(!db.find_pools_to_gc(retention).unwrap().contains(&pool.id));
+        // reclaimed resources are purged after retention
+        let (pool, report) = db.gc_pool(pool, 1, Duration::from_secs(0)).unwrap();
+        assert_eq!(GcReport { expired_leases: 0, released_from_quarantine: 0, purged: 2 }, report);
+        assert_eq!(4, pool.version);
+        let filter = ResourceFilter { include_deleted: true, ..ResourceFilter::default() };
+        assert_eq!(2, db.get_resources_filtered(pool.id, &filter).unwrap().len());
+    }
+
+    #[test]
+    fn db_update_pool_properties() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        assert_eq!(json!({"address": "10.0.0.0", "prefix": 8}), pool.get_pool_properties());
+        let resources = create_some_ips(1, 3, false).into_iter()
+            .chain(vec![json!({"address": "10.1.0.1"})])
+            .map(|value| Resource::new_from_value(pool.id, value))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+
+        let err = db.update_pool_properties(pool.clone(), &mut wasmer_env,
+                                            json!({"address": "10.0.0.0", "prefix": 16}))
+            .expect_err("10.1.0.1 would be orphaned");
+        assert!(matches!(err.downcast_ref::<AllocationError>(),
+            Some(AllocationError::InvalidPoolProperties { .. })), "Unexpected error {:?}", err);
+        let err = db.update_pool_properties(pool.clone(), &mut wasmer_env,
+                                            json!({"address": "10.0.0.0", "prefix": 30}))
+            .expect_err("4 resources do not fit into /30");
+        assert!(err.to_string().contains("capacity"), "Unexpected error {:?}", err);
+        assert_eq!(pool, db.get_resource_pool_by_id(pool.id).unwrap());
+
+        let new_properties = json!({"address": "10.0.0.0", "prefix": 15});
+        let pool = db.update_pool_properties(pool, &mut wasmer_env, new_properties.clone()).unwrap();
+        assert_eq!(new_properties, pool.get_pool_properties());
+        assert_eq!(pool, db.get_resource_pool_by_id(pool.id).unwrap());
+    }
+
+    #[test]
+    fn db_resource_state_transitions() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let reserved = Resource {
+            state: ResourceState::Reserved,
+            ..Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"}))
+        };
+        let (pool, _) = db.insert_resources(pool, vec![reserved]).unwrap();
+        let selector = ResourceSelector::Value(json!({"address": "10.0.0.1"}));
+        let (pool, _) = db.transition_resource(pool, &selector, ResourceState::Allocated).unwrap();
+        let (pool, claimed) = db.transition_resource(pool, &selector, ResourceState::Claimed).unwrap();
+        assert_eq!(ResourceState::Claimed, claimed.state);
+        assert_eq!(3, pool.version);
+
+        let err = db.transition_resource(pool.clone(), &selector, ResourceState::Reserved)
+            .expect_err("Claimed resource cannot be reserved");
+        assert_eq!(Some(&AllocationError::IllegalTransition {
+            resource: selector.to_string(),
+            from: ResourceState::Claimed,
+            to: ResourceState::Reserved,
+        }), err.downcast_ref::<AllocationError>());
+        assert_eq!(pool.version, db.get_resource_pool_by_id(pool.id).unwrap().version);
+
+        let bench = Resource {
+            state: ResourceState::Bench,
+            ..Resource::new_from_value(pool.id, json!({"address": "10.0.0.2"}))
+        };
+        db.insert_resources(pool, vec![bench]).expect_err("Bench is not an initial state");
+    }
+
+    #[test]
+    fn db_timestamps() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        assert_eq!(pool.created_at, pool.updated_at);
+        let ip = |pool: &ResourcePool, idx: i32| create_some_ips(idx, 1, false).into_iter()
+            .map(|value| Resource::new_from_value(pool.id, value)).collect::<Vec<_>>();
+        let (pool, _) = db.insert_resources(pool.clone(), ip(&pool, 0)).unwrap();
+        // the version changed in a later transaction
+        assert!(pool.updated_at > pool.created_at);
+        assert_eq!(pool, db.get_resource_pool_by_id(pool.id).unwrap());
+        let first = db.get_resources(pool.id).unwrap().remove(0);
+        assert_eq!(first.created_at, first.updated_at);
+        let between = SystemTime::now();
+        let (pool, _) = db.insert_resources(pool.clone(), ip(&pool, 1)).unwrap();
+        let (pool, updated) = db.transition_resource(pool, &ResourceSelector::Id(first.id.unwrap()),
+                                                     ResourceState::Claimed).unwrap();
+        assert!(updated.updated_at > first.updated_at);
+        assert_eq!(first.created_at, updated.created_at);
+
+        let allocated = |db: &mut DB, allocated_after, allocated_before| db.get_resources_filtered(
+            pool.id, &ResourceFilter { allocated_after, allocated_before, ..ResourceFilter::default() }).unwrap()
+            .into_iter().map(|it| it.value).collect::<Vec<_>>();
+        assert_eq!(vec![json!({"address": "10.0.0.1"})], allocated(&mut db, Some(between), None));
+        assert_eq!(vec![json!({"address": "10.0.0.0"})], allocated(&mut db, None, Some(between)));
+        assert_eq!(json!(DateTime::<Utc>::from(updated.created_at.unwrap()).to_rfc3339()),
+                   updated.as_detail_json()["createdAt"]);
+    }
+
+    #[test]
+    fn db_soft_delete_and_restore() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = create_some_ips(0, 2, false).into_iter()
+            .map(|value| Resource::new_from_value(pool.id, value))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let selector = ResourceSelector::Value(json!({"address": "10.0.0.0"}));
+        let (pool, deleted) = db.deallocate_resource(pool, &selector).unwrap();
+        assert!(deleted.deleted_at.is_some());
+        assert_eq!(ResourceState::Retired, deleted.state);
+        assert_eq!(1, db.get_resources(pool.id).unwrap().len());
+        let filter = ResourceFilter { include_deleted: true, ..ResourceFilter::default() };
+        let with_deleted = db.get_resources_filtered(pool.id, &filter).unwrap();
+        assert_eq!(vec![deleted.clone()], with_deleted.into_iter()
+            .filter(|it| it.deleted_at.is_some()).collect::<Vec<Resource>>());
+
+        let (pool, restored) = db.restore_resource(pool, deleted.id.unwrap()).unwrap();
+        assert!(restored.deleted_at.is_none());
+        assert_eq!(ResourceState::Allocated, restored.state);
+        assert_eq!(2, db.get_resources(pool.id).unwrap().len());
+        assert!(db.restore_resource(pool.clone(), deleted.id.unwrap()).is_err());
+
+        // value of a deleted resource can be allocated again, but then it cannot be restored
+        let (pool, deleted) = db.deallocate_resource(pool, &selector).unwrap();
+        let (pool, _) = db.insert_resources(pool, vec![
+            Resource::new_from_value(deleted.resource_pool_id, deleted.value.clone())]).unwrap();
+        assert!(db.restore_resource(pool.clone(), deleted.id.unwrap()).is_err());
+        assert_eq!(pool.version, db.get_resource_pool_by_id(pool.id).unwrap().version);
+    }
+
     // Get env.var value. If present, panic on parsing error.
     fn get_env_value<F: FromStr>(key: &str, default_value: F) -> F
         where <F as FromStr>::Err: std::fmt::Debug {
@@ -485,7 +1901,7 @@ mod tests {
     fn execute_ipv4_script_with_db() {
         initialize_logging();
 
-        let sw = Stopwatch::start_new();
+        let timer = Timer::start("create_pool");
         let row_count = get_env_value("ROW_COUNT", 100);
         let iterations = get_env_value("ITERATIONS", 2);
 
@@ -496,12 +1912,12 @@ mod tests {
         let user_input = json!({
             "resourceCount": row_count
         });
-        info!("Created pool in {}ms", sw.elapsed_ms());
+        info!("Created pool in {}ms", timer.finish().as_millis());
         for iteration in 1..iterations + 1 {
             info!("Starting iteration {}", iteration);
-            let sw = Stopwatch::start_new();
+            let timer = Timer::start("iteration");
             let (pool2, _resources) = db.allocate_resources(
-                pool, &mut wasmer_env, user_input.clone()).unwrap();
+                pool, &mut wasmer_env, user_input.clone(), &AllocationOptions::default()).unwrap();
             pool = pool2;
             // check that version is incremented
             let expected_version = old_version + iteration;
@@ -528,7 +1944,7 @@ mod tests {
                 expected.sort();
                 assert_eq!(expected, actual);
             }
-            info!("Inserted {} resources in {}ms", row_count, sw.elapsed_ms());
+            info!("Inserted {} resources in {}ms", row_count, timer.finish().as_millis());
         }
     }
 
@@ -536,7 +1952,7 @@ mod tests {
     fn parallel_allocation() {
         initialize_logging();
 
-        let sw = Stopwatch::start_new();
+        let timer = Timer::start("parallel_allocation");
         let number_of_threads = get_env_value("NUMBER_OF_THREADS", 2);
         let mut join_handles = vec![];
         for _ in 0..number_of_threads {
@@ -546,6 +1962,6 @@ mod tests {
         }
         // join all
         join_handles.into_iter().for_each(|handle| handle.join().unwrap());
-        info!("Finished executing {} threads in {}ms", number_of_threads, sw.elapsed_ms());
+        info!("Finished executing {} threads in {}ms", number_of_threads, timer.finish().as_millis());
     }
 }
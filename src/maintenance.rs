@@ -0,0 +1,107 @@
+use anyhow::Result;
+use postgres::error::SqlState;
+
+use crate::DB;
+use crate::error::AllocationError;
+
+impl DB {
+    // Maintenance mode is shared by all processes of the instance, set from `RM_MAINTENANCE` and by
+    // `PUT /maintenance`. It is off until the schema is created.
+    pub fn is_maintenance(&mut self) -> Result<bool> {
+        match self.client.query_opt("SELECT maintenance FROM service_settings", &[]) {
+            Ok(row) => Ok(row.is_some_and(|row| row.get(0))),
+            Err(err) if err.code() == Some(&SqlState::UNDEFINED_TABLE) => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Other processes follow within their next request or tick.
+    pub fn set_maintenance(&mut self, enabled: bool) -> Result<()> {
+        // the write that turns maintenance off is accepted by a read-only connection
+        self.set_read_only(false)?;
+        self.client.execute("UPDATE service_settings SET maintenance = $1", &[&enabled])?;
+        self.sync_maintenance().map(|_| ())
+    }
+
+    // Follows the maintenance mode of the instance, checked before each request and tick. Returns whether it is on.
+    pub(crate) fn sync_maintenance(&mut self) -> Result<bool> {
+        let enabled = self.is_maintenance()?;
+        self.set_read_only(enabled)?;
+        Ok(enabled)
+    }
+
+    // Transactions of the primary connection become read-only, so that every write fails in Postgres
+    // while reads keep working, without each operation checking the mode.
+    pub(crate) fn set_read_only(&mut self, read_only: bool) -> Result<()> {
+        if self.read_only != read_only {
+            let value = if read_only { "on" } else { "off" };
+            self.client.execute("SELECT set_config('default_transaction_read_only', $1, false)", &[&value])?;
+            self.read_only = read_only;
+        }
+        Ok(())
+    }
+
+    // Converts a write refused by a read-only connection into `AllocationError::ServiceReadOnly`.
+    pub(crate) fn read_only_error(err: anyhow::Error) -> anyhow::Error {
+        match err.downcast_ref::<postgres::Error>().and_then(|err| err.code()) {
+            Some(code) if *code == SqlState::READ_ONLY_SQL_TRANSACTION => AllocationError::ServiceReadOnly.into(),
+            _ => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use serde_json::json;
+
+    use crate::{AllocationOptions, WasmerEnv};
+    use crate::connect::ConnectRetry;
+    use crate::tests::{IPV4_ALLOCATION_STRATEGY_ID, create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_read_only() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        db.set_read_only(true).unwrap();
+        let pool = db.get_resource_pool_by_id(pool.id).unwrap();
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .map_err(DB::read_only_error)
+            .unwrap_err();
+        assert_eq!(Some(&AllocationError::ServiceReadOnly), err.downcast_ref::<AllocationError>(), "{:#}", err);
+        assert_eq!(0, db.count_resources(pool.id).unwrap());
+
+        db.set_read_only(false).unwrap();
+        db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &AllocationOptions::default()).unwrap();
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+    }
+    #[test]
+    fn db_maintenance_is_shared() {
+        initialize_logging();
+
+        // maintenance of a separate schema does not affect other tests
+        let schema = format!("rm_maintenance_{}", rand::thread_rng().gen::<u32>());
+        let params = std::env::var("DB_PARAMS").unwrap();
+        let connect = || DB::new(&params, Some(&schema), ConnectRetry::default()).unwrap();
+        let mut db = connect();
+        assert!(!db.is_maintenance().unwrap());
+        db.init_schema().unwrap();
+        let mut other = connect();
+        db.set_maintenance(true).unwrap();
+        assert!(other.sync_maintenance().unwrap());
+        let err = other.insert_resource_pool(&schema, IPV4_ALLOCATION_STRATEGY_ID)
+            .map_err(DB::read_only_error)
+            .unwrap_err();
+        assert_eq!(Some(&AllocationError::ServiceReadOnly), err.downcast_ref::<AllocationError>(), "{:#}", err);
+
+        // turned off by a process in maintenance
+        other.set_maintenance(false).unwrap();
+        assert!(!db.sync_maintenance().unwrap());
+        db.insert_resource_pool(&schema, IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        db.client.execute(format!("DROP SCHEMA {} CASCADE", schema).as_str(), &[]).unwrap();
+    }
+}
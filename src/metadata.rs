@@ -0,0 +1,164 @@
+use anyhow::{Context, Result, anyhow, ensure};
+use serde_json::{Value, json};
+
+use crate::{DB, Resource};
+
+/// Predicate of `DB::search_resources` on metadata of resources in use, backed by the `resources_metadata` index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetadataQuery {
+    // metadata contains the JSON, e.g. `{"labels": {"service": "voip"}}`
+    Contains(Value),
+    // jsonpath predicate, e.g. `$.labels.service == "voip"`
+    Path(String),
+}
+
+// JSON merge patch (RFC 7396): objects are merged recursively, null removes the key, anything else replaces it.
+fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = json!({});
+            }
+            let target = target.as_object_mut().expect("target is an object");
+            for (key, value) in patch {
+                if value.is_null() {
+                    target.remove(key);
+                } else {
+                    merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+impl Resource {
+    // `as_export_json` together with the pool and the metadata, even if empty.
+    pub fn as_detail_json(&self) -> Value {
+        let mut json = self.as_export_json();
+        json["resourcePool"] = json!(self.resource_pool_id);
+        json["metadata"] = self.metadata.clone();
+        json
+    }
+}
+
+impl DB {
+    pub fn find_resource(&mut self, id: i64) -> Result<Option<Resource>> {
+        // the pool follows columns of the resource
+        let row = self.client.query_opt(
+            format!("SELECT {}, resource_pool FROM resources WHERE id=$1", Self::RESOURCE_COLUMNS).as_str(), &[&id])?;
+        row.map(|row| {
+            let resource_pool_id = row.get(row.len() - 1);
+            Self::row_to_resource(resource_pool_id, row)
+        }).transpose()
+    }
+
+    pub fn get_resource(&mut self, id: i64) -> Result<Resource> {
+        self.find_resource(id)?.ok_or_else(|| anyhow!("Resource {} not found", id))
+    }
+
+    // Applies a JSON merge patch to the metadata of the resource, its value cannot be changed.
+    // Does not bump the pool version, metadata is not seen by strategies.
+    pub fn update_resource_metadata(&mut self, id: i64, patch: &Value) -> Result<Resource> {
+        ensure!(patch.is_object(), "Metadata patch must be a JSON object");
+        let mut transaction = self.client.transaction()?;
+        let row = transaction.query_opt("SELECT resource_pool, metadata FROM resources WHERE id=$1 FOR UPDATE", &[&id])?
+            .ok_or_else(|| anyhow!("Resource {} not found", id))?;
+        let resource_pool_id: i64 = row.get(0);
+        Self::check_not_archived(&mut transaction, resource_pool_id)?;
+        let mut metadata: Value = row.get(1);
+        merge_patch(&mut metadata, patch);
+        let updated = transaction.query_one(
+            format!("UPDATE resources SET metadata=$2 WHERE id=$1 RETURNING {}", Self::RESOURCE_COLUMNS).as_str(),
+            &[&id, &metadata])?;
+        Self::record_audit(&mut transaction, Some(resource_pool_id), "resource_metadata_updated",
+                           json!({"id": id, "patch": patch}))?;
+        transaction.commit()?;
+        Self::row_to_resource(resource_pool_id, updated)
+    }
+
+    // Resources in use whose metadata match the query, ordered by id starting after `after_id`.
+    // From the replica if configured.
+    pub fn search_resources(&mut self, resource_pool_id: i64, query: &MetadataQuery, after_id: Option<i64>,
+                            limit: i64) -> Result<Vec<Resource>> {
+        ensure!(limit > 0, "Limit must be positive");
+        let (predicate, argument) = match query {
+            MetadataQuery::Contains(json) => {
+                ensure!(json.is_object(), "Searched metadata must be a JSON object");
+                ("metadata @> $2::text::jsonb", json.to_string())
+            }
+            MetadataQuery::Path(path) => ("metadata @@ $2::text::jsonpath", path.clone()),
+        };
+        let rows = self.reader().query(
+            format!("SELECT {} FROM resources WHERE resource_pool=$1 AND status <> 'retired' AND {} \
+                AND ($3::bigint IS NULL OR id > $3) ORDER BY id LIMIT $4", Self::RESOURCE_COLUMNS, predicate).as_str(),
+            &[&resource_pool_id, &argument, &after_id, &limit])
+            .context(format!("Cannot search resources of pool {} by {:?}", resource_pool_id, query))?;
+        rows.into_iter().map(|row| Self::row_to_resource(resource_pool_id, row)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn metadata_merge_patch() {
+        let mut metadata = json!({"owner": "a", "labels": {"env": "dev", "team": "x"}});
+        merge_patch(&mut metadata, &json!({"owner": null, "labels": {"env": "prod", "team": null}, "note": "n"}));
+        assert_eq!(json!({"labels": {"env": "prod"}, "note": "n"}), metadata);
+    }
+
+    #[test]
+    fn db_update_resource_metadata() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let value = json!({"address": "10.0.0.1"});
+        let (pool, _) = db.insert_resources(pool.clone(), vec![Resource::new_from_value(pool.id, value.clone())])
+            .unwrap();
+        let id = db.get_resources(pool.id).unwrap()[0].id.unwrap();
+        assert_eq!(json!({}), db.get_resource(id).unwrap().metadata);
+
+        db.update_resource_metadata(id, &json!({"owner": "team-a", "labels": {"env": "dev"}})).unwrap();
+        let updated = db.update_resource_metadata(id, &json!({"labels": {"env": "prod"}})).unwrap();
+        assert_eq!(json!({"owner": "team-a", "labels": {"env": "prod"}}), updated.metadata);
+        assert_eq!(value, updated.value);
+        assert_eq!(updated, db.get_resource(id).unwrap());
+        assert_eq!(pool.version, db.get_resource_pool_by_id(pool.id).unwrap().version);
+
+        assert!(db.update_resource_metadata(id, &json!(["owner"])).is_err());
+        assert!(db.find_resource(-1).unwrap().is_none());
+    }
+
+    #[test]
+    fn db_search_resources() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = (1..=3)
+            .map(|idx| Resource::new_from_value(pool.id, json!({"address": format!("10.0.0.{}", idx)})))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let ids = db.get_resources(pool.id).unwrap().iter().map(|it| it.id.unwrap()).collect::<Vec<_>>();
+        for (id, service) in ids.iter().zip(["voip", "iptv", "voip"]) {
+            db.update_resource_metadata(*id, &json!({"labels": {"service": service}, "rank": id})).unwrap();
+        }
+        let found_ids = |db: &mut DB, query: &MetadataQuery| db.search_resources(pool.id, query, None, 10)
+            .unwrap().iter().map(|it| it.id.unwrap()).collect::<Vec<_>>();
+
+        let voip = MetadataQuery::Contains(json!({"labels": {"service": "voip"}}));
+        assert_eq!(vec![ids[0], ids[2]], found_ids(&mut db, &voip));
+        let path = MetadataQuery::Path(format!("$.labels.service == \"voip\" && $.rank > {}", ids[0]));
+        assert_eq!(vec![ids[2]], found_ids(&mut db, &path));
+        assert_eq!(1, db.search_resources(pool.id, &voip, None, 1).unwrap().len());
+        assert_eq!(vec![ids[2]], db.search_resources(pool.id, &voip, Some(ids[0]), 10).unwrap().iter()
+            .map(|it| it.id.unwrap()).collect::<Vec<_>>());
+
+        assert!(db.search_resources(pool.id, &MetadataQuery::Path("$.[".to_owned()), None, 10).is_err());
+        assert!(db.search_resources(pool.id, &MetadataQuery::Contains(json!("voip")), None, 10).is_err());
+    }
+}
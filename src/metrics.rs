@@ -0,0 +1,255 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use postgres::error::SqlState;
+
+use crate::error::AllocationError;
+
+/// Outcome of an allocation, the label of `allocations_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AllocationOutcome {
+    Ok,
+    // the pool was changed concurrently
+    VersionConflict,
+    // a value returned by the strategy is already in use
+    Duplicate,
+    // the strategy failed or its input or output was refused
+    ScriptError,
+    Timeout,
+    DbError,
+}
+
+impl AllocationOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AllocationOutcome::Ok => "ok",
+            AllocationOutcome::VersionConflict => "version_conflict",
+            AllocationOutcome::Duplicate => "duplicate",
+            AllocationOutcome::ScriptError => "script_error",
+            AllocationOutcome::Timeout => "timeout",
+            AllocationOutcome::DbError => "db_error",
+        }
+    }
+
+    pub fn of<T>(result: &anyhow::Result<T>) -> AllocationOutcome {
+        let err = match result {
+            Ok(_) => return AllocationOutcome::Ok,
+            Err(err) => err,
+        };
+        match err.downcast_ref::<AllocationError>() {
+            Some(AllocationError::VersionConflict { .. }) => return AllocationOutcome::VersionConflict,
+            Some(AllocationError::Timeout { .. }) => return AllocationOutcome::Timeout,
+            Some(AllocationError::DatabaseUnavailable { .. }) => return AllocationOutcome::DbError,
+            _ => {}
+        }
+        match err.chain().find_map(|cause| cause.downcast_ref::<postgres::Error>()) {
+            Some(db_err) if db_err.code() == Some(&SqlState::UNIQUE_VIOLATION) => AllocationOutcome::Duplicate,
+            Some(_) => AllocationOutcome::DbError,
+            None => AllocationOutcome::ScriptError,
+        }
+    }
+}
+
+impl fmt::Display for AllocationOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Allocations of this process by pool, strategy and outcome.
+static ALLOCATIONS: Mutex<BTreeMap<(i64, i64, AllocationOutcome), u64>> = Mutex::new(BTreeMap::new());
+
+// Upper bounds of `db_transaction_duration_seconds` and `operation_duration_seconds` buckets.
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    // observations up to the bucket bound, not cumulative
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(idx) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+// Durations of transactions of this process by kind.
+static TRANSACTIONS: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+
+// Durations of operations timed by `timings::Timer` of this process by operation.
+static OPERATIONS: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+
+// Fired alert rules of this process by pool and threshold, kept as text to be usable as a key.
+static ALERTS: Mutex<BTreeMap<(i64, String), u64>> = Mutex::new(BTreeMap::new());
+
+// Predicted exhaustion of growing pools by pool, replaced by the leading worker after sampling.
+static EXHAUSTION: Mutex<BTreeMap<i64, SystemTime>> = Mutex::new(BTreeMap::new());
+
+// Connections of HTTP threads, each owns one and uses it while handling a request.
+static CONNECTIONS_IN_USE: AtomicI64 = AtomicI64::new(0);
+static CONNECTIONS_IDLE: AtomicI64 = AtomicI64::new(0);
+
+// Counts an allocation request of the pool, dry runs are not counted.
+pub fn record_allocation<T>(resource_pool_id: i64, allocation_strategy_id: i64, result: &anyhow::Result<T>) {
+    let outcome = AllocationOutcome::of(result);
+    let mut allocations = ALLOCATIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *allocations.entry((resource_pool_id, allocation_strategy_id, outcome)).or_insert(0) += 1;
+}
+
+// Observes a committed or failed transaction, e.g. `allocation` for inserting allocated resources.
+pub fn record_transaction(kind: &'static str, duration: Duration) {
+    let mut transactions = TRANSACTIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    transactions.entry(kind).or_default().observe(duration.as_secs_f64());
+}
+
+// Observes a finished operation of the engine or the database, e.g. `strategy` for running an allocation script.
+pub fn record_operation(operation: &'static str, duration: Duration) {
+    let mut operations = OPERATIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    operations.entry(operation).or_default().observe(duration.as_secs_f64());
+}
+
+// Counts an alert rule of the pool crossing its utilization threshold.
+pub fn record_alert(resource_pool_id: i64, threshold: f64) {
+    let mut alerts = ALERTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    *alerts.entry((resource_pool_id, threshold.to_string())).or_insert(0) += 1;
+}
+
+// Replaces predicted exhaustion of pools, pools not predicted to run out are dropped.
+pub fn set_exhaustion_predictions(predictions: BTreeMap<i64, SystemTime>) {
+    *EXHAUSTION.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = predictions;
+}
+
+/// Connection counted as in use until dropped, see `connection_opened`.
+pub struct ConnectionInUse(());
+
+impl ConnectionInUse {
+    pub fn acquire() -> ConnectionInUse {
+        CONNECTIONS_IDLE.fetch_sub(1, Ordering::Relaxed);
+        CONNECTIONS_IN_USE.fetch_add(1, Ordering::Relaxed);
+        ConnectionInUse(())
+    }
+}
+
+impl Drop for ConnectionInUse {
+    fn drop(&mut self) {
+        CONNECTIONS_IN_USE.fetch_sub(1, Ordering::Relaxed);
+        CONNECTIONS_IDLE.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Counts a connection of an HTTP thread as idle.
+pub fn connection_opened() {
+    CONNECTIONS_IDLE.fetch_add(1, Ordering::Relaxed);
+}
+
+// Counters of this process in the Prometheus text format.
+pub fn render() -> String {
+    let mut text = "# HELP allocations_total Allocation requests by pool, strategy and outcome.\n\
+        # TYPE allocations_total counter\n".to_owned();
+    for ((pool, strategy, outcome), count) in ALLOCATIONS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+        text += &format!("allocations_total{{pool=\"{}\",strategy=\"{}\",outcome=\"{}\"}} {}\n",
+                         pool, strategy, outcome, count);
+    }
+    text += "# HELP db_transaction_duration_seconds Duration of transactions by kind.\n\
+        # TYPE db_transaction_duration_seconds histogram\n";
+    render_histograms(&mut text, "db_transaction_duration_seconds", "kind", &TRANSACTIONS);
+    text += "# HELP operation_duration_seconds Duration of engine and database operations by operation.\n\
+        # TYPE operation_duration_seconds histogram\n";
+    render_histograms(&mut text, "operation_duration_seconds", "operation", &OPERATIONS);
+    text += "# HELP alerts_fired_total Alert rules crossing their utilization threshold by pool and threshold.\n\
+        # TYPE alerts_fired_total counter\n";
+    for ((pool, threshold), count) in ALERTS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+        text += &format!("alerts_fired_total{{pool=\"{}\",threshold=\"{}\"}} {}\n", pool, threshold, count);
+    }
+    text += "# HELP pool_exhaustion_timestamp_seconds Predicted time the free capacity of a pool runs out by pool.\n\
+        # TYPE pool_exhaustion_timestamp_seconds gauge\n";
+    for (pool, exhaustion) in EXHAUSTION.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+        let timestamp = exhaustion.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        text += &format!("pool_exhaustion_timestamp_seconds{{pool=\"{}\"}} {}\n", pool, timestamp);
+    }
+    text += &format!("# HELP db_connections Connections of HTTP threads by state.\n\
+        # TYPE db_connections gauge\n\
+        db_connections{{state=\"in_use\"}} {}\n\
+        db_connections{{state=\"idle\"}} {}\n",
+                     CONNECTIONS_IN_USE.load(Ordering::Relaxed), CONNECTIONS_IDLE.load(Ordering::Relaxed));
+    text
+}
+
+fn render_histograms(text: &mut String, name: &str, label: &str,
+                     histograms: &Mutex<BTreeMap<&'static str, Histogram>>) {
+    for (value, histogram) in histograms.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            cumulative += count;
+            *text += &format!("{}_bucket{{{}=\"{}\",le=\"{}\"}} {}\n", name, label, value, bound, cumulative);
+        }
+        *text += &format!("{}_bucket{{{}=\"{}\",le=\"+Inf\"}} {}\n{}_sum{{{}=\"{}\"}} {}\n{}_count{{{}=\"{}\"}} {}\n",
+                          name, label, value, histogram.count, name, label, value, histogram.sum,
+                          name, label, value, histogram.count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Result, anyhow};
+    use serde_json::json;
+
+    use crate::{AllocationOptions, DB, WasmerEnv};
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn allocation_outcome_of() {
+        let conflict: Result<()> = Err(AllocationError::VersionConflict {
+            resource_pool: "pool".to_owned(), expected: 1,
+        }.into());
+        assert_eq!(AllocationOutcome::VersionConflict, AllocationOutcome::of(&conflict));
+        let failed: Result<()> = Err(anyhow!("Script did not return an array"));
+        assert_eq!(AllocationOutcome::ScriptError, AllocationOutcome::of(&failed));
+        assert_eq!(AllocationOutcome::Ok, AllocationOutcome::of(&Ok(())));
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        let mut histogram = Histogram::default();
+        for seconds in [0.001, 0.02, 0.02, 60.0] {
+            histogram.observe(seconds);
+        }
+        assert_eq!([1, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0], histogram.buckets);
+        assert_eq!(4, histogram.count);
+    }
+
+    #[test]
+    fn db_allocation_metrics() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let stale = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.allocate_resources(stale.clone(), &mut wasmer_env, json!({}),
+                                              &AllocationOptions::default()).unwrap();
+        let dry_run = AllocationOptions { dry_run: true, ..AllocationOptions::default() };
+        db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &dry_run).unwrap();
+        db.allocate_resources(stale, &mut wasmer_env, json!({}), &AllocationOptions::default()).unwrap_err();
+
+        let rendered = render();
+        for outcome in [AllocationOutcome::Ok, AllocationOutcome::VersionConflict] {
+            let line = format!("allocations_total{{pool=\"{}\",strategy=\"{}\",outcome=\"{}\"}} 1\n",
+                               pool.id, pool.allocation_strategy_id, outcome);
+            assert!(rendered.contains(&line), "{}", rendered);
+        }
+        assert!(rendered.contains("db_transaction_duration_seconds_count{kind=\"allocation\"} "), "{}", rendered);
+        assert!(rendered.contains("operation_duration_seconds_count{operation=\"allocation_context\"} "),
+                "{}", rendered);
+    }
+}
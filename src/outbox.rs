@@ -0,0 +1,165 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, ensure};
+use serde_json::{Value, json};
+
+use crate::DB;
+
+// Connecting to a webhook, sending the events and reading its response each time out after it.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Plain HTTP endpoint receiving pool events of the outbox, e.g. `http://bridge:8080/events` forwarding them to Kafka.
+/// Events are sent in batches as `POST` requests with the body `{"events": [...]}`, any `2xx` response
+/// acknowledges the batch. TLS is left to a proxy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Webhook {
+    // `host:port`
+    authority: String,
+    path: String,
+}
+
+impl FromStr for Webhook {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Webhook> {
+        let rest = s.strip_prefix("http://").ok_or_else(|| anyhow!("webhook must be an http:// URL"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        ensure!(!authority.is_empty(), "webhook URL has no host");
+        let authority = if authority.contains(':') { authority.to_owned() } else { format!("{}:80", authority) };
+        Ok(Webhook { authority, path: path.to_owned() })
+    }
+}
+
+impl Webhook {
+    pub fn deliver(&self, events: &[Value]) -> Result<()> {
+        let address = self.authority.to_socket_addrs()?.next()
+            .ok_or_else(|| anyhow!("Cannot resolve {}", self.authority))?;
+        let mut stream = TcpStream::connect_timeout(&address, WEBHOOK_TIMEOUT)?;
+        stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+        stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+        let body = json!({"events": events}).to_string();
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n{}", self.path, self.authority, body.len(), body)?;
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line)?;
+        let status_line = status_line.trim_end();
+        let status = status_line.split(' ').nth(1).and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("Invalid response '{}'", status_line))?;
+        ensure!((200..300).contains(&status), "Webhook responded with '{}'", status_line);
+        Ok(())
+    }
+}
+
+impl DB {
+    // Delivers up to `limit` events of the outbox, oldest first, and deletes them once `deliver` succeeds.
+    // A failed delivery is counted in `attempts` of the events and returned, they are sent again by the next
+    // drain before any newer ones. Delivery is at least once: events of a batch delivered right before
+    // the connection is lost are sent again. Returns the number of delivered events.
+    pub fn drain_outbox(&mut self, limit: i64, deliver: impl FnOnce(&[Value]) -> Result<()>) -> Result<u64> {
+        let mut transaction = self.client.transaction()?;
+        // concurrent drains wait for each other instead of skipping events, which would reorder them
+        let rows = transaction.query("SELECT id, payload FROM outbox ORDER BY id LIMIT $1 FOR UPDATE", &[&limit])?;
+        if rows.is_empty() {
+            return Ok(0);
+        }
+        let ids = rows.iter().map(|row| row.get(0)).collect::<Vec<i64>>();
+        let events = rows.into_iter().map(|row| row.get(1)).collect::<Vec<Value>>();
+        match deliver(&events) {
+            Ok(()) => {
+                transaction.execute("DELETE FROM outbox WHERE id = ANY($1)", &[&ids])?;
+                transaction.commit()?;
+                Ok(ids.len() as u64)
+            }
+            Err(err) => {
+                transaction.execute("UPDATE outbox SET attempts = attempts + 1, last_error = $2 WHERE id = ANY($1)",
+                                    &[&ids, &format!("{:#}", err)])?;
+                transaction.commit()?;
+                Err(err).context(format!("Cannot deliver {} outbox events", ids.len()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use crate::{AllocationOptions, ResourcePool, ResourceSelector, WasmerEnv};
+    use crate::state::ResourceState;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    // Events of the pool in the outbox, other tests may write events of their pools.
+    fn pending_events(db: &mut DB, pool: &ResourcePool) -> Vec<(String, i32)> {
+        db.client.query("SELECT payload->>'event', attempts FROM outbox WHERE resource_pool=$1 ORDER BY id",
+                        &[&pool.id]).unwrap()
+            .into_iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect()
+    }
+
+    #[test]
+    fn db_outbox() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (stale, _) = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &options).unwrap();
+        assert!(pending_events(&mut db, &pool).is_empty());
+
+        db.outbox = true;
+        let (pool, resources) = db.allocate_resources(stale.clone(), &mut wasmer_env, json!({}), &options).unwrap();
+        let (pool, _) = db.transition_resource(pool, &ResourceSelector::Value(resources[0].value.clone()),
+                                               ResourceState::Claimed).unwrap();
+        // a rolled back allocation leaves no event
+        assert!(db.allocate_resources(stale, &mut wasmer_env, json!({}), &options).is_err());
+        assert_eq!(vec![("allocated".to_owned(), 0), ("state_changed".to_owned(), 0)], pending_events(&mut db, &pool));
+
+        // failed deliveries are retried
+        let err = db.drain_outbox(1000, |_| Err(anyhow!("unavailable"))).unwrap_err();
+        assert!(format!("{:#}", err).contains("unavailable"), "{:#}", err);
+        assert_eq!(vec![1, 1], pending_events(&mut db, &pool).into_iter().map(|(_, attempts)| attempts)
+            .collect::<Vec<_>>());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let webhook: Webhook = format!("http://{}/events", listener.local_addr().unwrap()).parse().unwrap();
+        let received = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 1024 * 1024];
+            let mut len = 0;
+            // the body ends with the closing brace of the object
+            while len == 0 || request[len - 1] != b'}' {
+                len += stream.read(&mut request[len..]).unwrap();
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            String::from_utf8(request[..len].to_vec()).unwrap()
+        });
+        assert!(db.drain_outbox(1000, |events| webhook.deliver(events)).unwrap() >= 2);
+        assert!(pending_events(&mut db, &pool).is_empty());
+        let request = received.join().unwrap();
+        assert!(request.starts_with("POST /events HTTP/1.1\r\n"), "{}", request);
+        let body: Value = serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let events = body["events"].as_array().unwrap().iter()
+            .filter(|event| event["pool"] == json!(pool.id))
+            .map(|event| (event["event"].clone(), event["version"].clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![(json!("allocated"), json!(pool.version - 1)), (json!("state_changed"), json!(pool.version))],
+                   events);
+        // nothing is sent once the outbox is empty
+        assert_eq!(0, db.drain_outbox(1000, |_| Err(anyhow!("unexpected delivery"))).unwrap());
+
+        assert!("https://example.com".parse::<Webhook>().is_err());
+        assert_eq!(Webhook { authority: "bridge:80".to_owned(), path: "/".to_owned() },
+                   "http://bridge".parse().unwrap());
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{Result, anyhow, ensure};
+
+use crate::{DB, ResourcePool};
+
+impl DB {
+    // Script allocating from the pool instead of the one of its strategy, for one-off customizations that should
+    // not fork a strategy shared by other pools. It is linked with the files of the strategy and its kind is
+    // detected, engine and schemas stay those of the strategy. Pools are listed with `scriptOverridden`,
+    // None removes the override.
+    pub fn set_script_override(&mut self, mut pool: ResourcePool, script: Option<&str>) -> Result<ResourcePool> {
+        Self::check_not_archived(&mut self.client, pool.id)?;
+        if let Some(script) = script {
+            ensure!(!script.trim().is_empty(), "Script override of pool '{}' is empty", pool.name);
+            let strategy: String = self.client.query_one("SELECT name FROM allocation_strategies WHERE id=$1",
+                                                         &[&pool.allocation_strategy_id])?.get(0);
+            let files = Self::get_strategy_files(&mut self.client, pool.allocation_strategy_id)?;
+            // fail early on invalid imports and exports
+            self.strategy_script(&strategy, script, None, &files)?;
+        }
+        let updated = self.client.query_opt(
+            "UPDATE resource_pools SET script_override=$1 WHERE id=$2 RETURNING updated_at", &[&script, &pool.id])?
+            .ok_or_else(|| anyhow!("Update of resource_pools returned wrong number of rows"))?;
+        pool.script_overridden = script.is_some();
+        pool.updated_at = updated.get(0);
+        Ok(pool)
+    }
+
+    pub fn get_script_override(&mut self, resource_pool_id: i64) -> Result<Option<String>> {
+        let row = self.client.query_opt("SELECT script_override FROM resource_pools WHERE id=$1",
+                                        &[&resource_pool_id])?
+            .ok_or_else(|| anyhow!("Resource pool {} not found", resource_pool_id))?;
+        Ok(row.get(0))
+    }
+
+    // Linked script allocating from the pool, the override or the script of its strategy.
+    pub(crate) fn get_pool_allocation_script(&mut self, resource_pool_id: i64) -> Result<String> {
+        let row = self.client.query_opt(
+            "SELECT p.resource_pool_allocation_strategy, s.name, p.script_override FROM resource_pools p \
+            JOIN allocation_strategies s ON s.id = p.resource_pool_allocation_strategy WHERE p.id=$1",
+            &[&resource_pool_id])?
+            .ok_or_else(|| anyhow!("Resource pool {} not found", resource_pool_id))?;
+        match row.get::<_, Option<&str>>(2) {
+            Some(script) => {
+                let files = Self::get_strategy_files(&mut self.client, row.get(0))?;
+                self.strategy_script(row.get(1), script, None, &files)
+            }
+            None => self.get_allocation_script(row.get(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::{AllocationOptions, WasmerEnv};
+    use crate::jobs::JobStatus;
+    use crate::pools::PoolFilter;
+    use crate::strategy::StrategyFiles;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_script_override() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let mut files = StrategyFiles::new();
+        files.insert("base.js".to_owned(), "const base = 10;".to_owned());
+        let script = |n| format!("function invoke() {{ return [{{n: base + {}, i: currentResources.length}}] }}", n);
+        let id = db.insert_allocation_strategy(&name, &script(1), None, &files).unwrap();
+        let shared = db.insert_resource_pool(&format!("{}-shared", name), id).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let pool = db.set_script_override(pool, Some(&script(2))).unwrap();
+        assert!(pool.script_overridden);
+        assert_eq!(json!(true), pool.as_export_json()["scriptOverridden"]);
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        assert_eq!(json!(12), resources[0].value["n"]);
+        // other pools of the strategy are not affected
+        let (shared, resources) = db.allocate_resources(shared, &mut wasmer_env, json!({}), &options).unwrap();
+        assert_eq!(json!(11), resources[0].value["n"]);
+        let filter = PoolFilter { name_prefix: Some(name.clone()), limit: 10, ..PoolFilter::default() };
+        let listed = db.list_pools(&filter).unwrap();
+        assert_eq!(vec![true, false], listed.iter().map(|pool| pool.script_overridden).collect::<Vec<_>>());
+
+        // jobs of the overridden pool are not batched with jobs of other pools of the strategy
+        let job_ids = [pool.id, shared.id].iter()
+            .map(|pool_id| db.enqueue_allocation(*pool_id, json!({}), &options).unwrap())
+            .collect::<Vec<_>>();
+        let mut batches = 0;
+        while job_ids.iter().any(|id| db.get_job_status(*id).unwrap().status != JobStatus::Done) {
+            batches += 1;
+            assert!(batches < 50, "Jobs were not finished");
+            db.run_allocation_batch(&mut wasmer_env, 10).unwrap();
+        }
+        let results = job_ids.iter().map(|id| db.get_job_status(*id).unwrap().result.unwrap()).collect::<Vec<_>>();
+        assert_eq!((&json!(12), &json!(11)), (&results[0][0]["value"]["n"], &results[1][0]["value"]["n"]));
+
+        assert!(db.set_script_override(pool.clone(), Some(" ")).is_err());
+        assert!(db.set_script_override(pool.clone(), Some("import { x } from 'missing'; export { x }")).is_err());
+        let pool = db.get_resource_pool_by_id(pool.id).unwrap();
+        let pool = db.set_script_override(pool, None).unwrap();
+        assert_eq!(None, db.get_script_override(pool.id).unwrap());
+        let (_, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        assert_eq!(json!(11), resources[0].value["n"]);
+    }
+
+    #[test]
+    fn db_script_override_of_sequential_strategy() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let pool = create_random_pool(&mut db).unwrap();
+        let pool = db.update_pool_properties(pool, &mut wasmer_env, json!({"address": "10.0.0.0", "prefix": 29}))
+            .unwrap();
+        // the override is run instead of popping from the free list of the pool
+        let pool = db.set_script_override(pool, Some("function invoke() { return [{address: '10.0.0.7'}] }"))
+            .unwrap();
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        assert_eq!(json!({"address": "10.0.0.7"}), resources[0].value);
+        let pool = db.set_script_override(pool, None).unwrap();
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        assert_ne!(json!({"address": "10.0.0.7"}), resources[0].value);
+        assert_eq!(2, db.count_resources(pool.id).unwrap());
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::{Result, anyhow, ensure};
+use serde_json::json;
+
+use crate::{DB, Resource};
+
+impl DB {
+    // Changes the owner of a resource in use, None releases it. Owners are not seen by strategies,
+    // so the pool version stays the same.
+    pub fn transfer_resource(&mut self, id: i64, owner: Option<&str>) -> Result<Resource> {
+        ensure!(owner.is_none_or(|owner| !owner.is_empty()), "Owner cannot be empty");
+        let mut transaction = self.client.transaction()?;
+        let row = transaction.query_opt(
+            "SELECT resource_pool, owner, status FROM resources WHERE id=$1 FOR UPDATE", &[&id])?
+            .ok_or_else(|| anyhow!("Resource {} not found", id))?;
+        let resource_pool_id: i64 = row.get(0);
+        Self::check_not_archived(&mut transaction, resource_pool_id)?;
+        let previous: Option<String> = row.get(1);
+        ensure!(row.get::<_, &str>(2) != "retired", "Resource {} is retired and cannot be transferred", id);
+        let updated = transaction.query_one(
+            format!("UPDATE resources SET owner=$2 WHERE id=$1 RETURNING {}", Self::RESOURCE_COLUMNS).as_str(),
+            &[&id, &owner])?;
+        Self::record_audit(&mut transaction, Some(resource_pool_id), "resource_transferred",
+                           json!({"id": id, "from": previous, "to": owner}))?;
+        transaction.commit()?;
+        Self::row_to_resource(resource_pool_id, updated)
+    }
+
+    // Resources in use of the owner across all pools, ordered by id starting after `after_id`.
+    // From the replica if configured.
+    pub fn find_owned_resources(&mut self, owner: &str, after_id: Option<i64>, limit: i64) -> Result<Vec<Resource>> {
+        ensure!(limit > 0, "Limit must be positive");
+        // the pool follows columns of the resource
+        let rows = self.reader().query(
+            format!("SELECT {}, resource_pool FROM resources WHERE owner=$1 AND status <> 'retired' \
+                AND ($2::bigint IS NULL OR id > $2) ORDER BY id LIMIT $3", Self::RESOURCE_COLUMNS).as_str(),
+            &[&owner, &after_id, &limit])?;
+        rows.into_iter()
+            .map(|row| {
+                let resource_pool_id = row.get(row.len() - 1);
+                Self::row_to_resource(resource_pool_id, row)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::{AllocationOptions, ResourceSelector, WasmerEnv};
+    use crate::state::ResourceState;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_resource_ownership() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let owner: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let options = AllocationOptions { owner: Some(owner.clone()), ..AllocationOptions::default() };
+        let first = create_random_pool(&mut db).unwrap();
+        let (first, allocated) = db.allocate_resources(first, &mut wasmer_env, json!({"resourceCount": 2}), &options)
+            .unwrap();
+        assert_eq!(Some(owner.clone()), allocated[0].owner);
+        let second = create_random_pool(&mut db).unwrap();
+        let job = db.enqueue_allocation(second.id, json!({}), &options).unwrap();
+        // other tests may enqueue or run jobs concurrently
+        while db.get_job_status(job).unwrap().status == crate::jobs::JobStatus::Pending {
+            if db.run_next_allocation_job(&mut wasmer_env).unwrap().is_none() {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        assert_eq!(Some(owner.clone()), db.get_job_status(job).unwrap().options().owner);
+
+        let owned = db.find_owned_resources(&owner, None, 10).unwrap();
+        assert_eq!(vec![first.id, first.id, second.id], owned.iter().map(|it| it.resource_pool_id).collect::<Vec<_>>());
+        assert_eq!(json!(owner), owned[2].as_detail_json()["owner"]);
+
+        let version = db.get_resource_pool_by_id(first.id).unwrap().version;
+        let id = owned[0].id.unwrap();
+        let transferred = db.transfer_resource(id, Some("other-service")).unwrap();
+        assert_eq!(Some("other-service".to_owned()), transferred.owner);
+        assert_eq!(version, db.get_resource_pool_by_id(first.id).unwrap().version);
+        assert_eq!(2, db.find_owned_resources(&owner, None, 10).unwrap().len());
+        assert_eq!(None, db.transfer_resource(id, None).unwrap().owner);
+        assert!(db.transfer_resource(id, Some("")).is_err());
+
+        // retired resources are no longer owned
+        let address = owned[1].value.clone();
+        db.transition_resource(first, &ResourceSelector::Value(address), ResourceState::Retired).unwrap();
+        assert_eq!(vec![second.id], db.find_owned_resources(&owner, None, 10).unwrap().iter()
+            .map(|it| it.resource_pool_id).collect::<Vec<_>>());
+        assert!(db.transfer_resource(owned[1].id.unwrap(), Some("x")).is_err());
+    }
+}
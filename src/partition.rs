@@ -0,0 +1,38 @@
+use anyhow::Result;
+use postgres::Client;
+use tracing::*;
+
+use crate::DB;
+
+// Partitions of `resources` once migrations/optional/partition_resources.sql was applied.
+impl DB {
+    pub fn is_resources_partitioned(client: &mut Client) -> Result<bool> {
+        let row = client.query_one("SELECT relkind = 'p' FROM pg_class WHERE oid = 'resources'::regclass", &[])?;
+        Ok(row.get(0))
+    }
+
+    // Returns false if resources are not partitioned.
+    pub fn create_resources_partition(client: &mut Client, resource_pool_id: i64) -> Result<bool> {
+        if !Self::is_resources_partitioned(client)? {
+            return Ok(false);
+        }
+        client.batch_execute(&format!("CREATE TABLE {} PARTITION OF resources FOR VALUES IN ({})",
+                                      Self::partition_name(resource_pool_id), resource_pool_id))?;
+        debug!("Created resources partition of pool {}", resource_pool_id);
+        Ok(true)
+    }
+
+    // Drops the partition together with all its rows. Returns false if resources are not partitioned.
+    pub fn drop_resources_partition(client: &mut Client, resource_pool_id: i64) -> Result<bool> {
+        if !Self::is_resources_partitioned(client)? {
+            return Ok(false);
+        }
+        client.batch_execute(&format!("DROP TABLE IF EXISTS {}", Self::partition_name(resource_pool_id)))?;
+        debug!("Dropped resources partition of pool {}", resource_pool_id);
+        Ok(true)
+    }
+
+    fn partition_name(resource_pool_id: i64) -> String {
+        format!("resources_p{}", resource_pool_id)
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, ensure};
+use serde_json::{Value, json};
+
+use crate::{DB, ResourcePool};
+
+/// Order of `DB::list_pools`, descending if prefixed with `-`, e.g. `-name`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolSort {
+    pub column: PoolSortColumn,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolSortColumn {
+    Id,
+    Name,
+}
+
+impl PoolSortColumn {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PoolSortColumn::Id => "id",
+            PoolSortColumn::Name => "name",
+        }
+    }
+}
+
+impl Default for PoolSort {
+    fn default() -> Self {
+        PoolSort { column: PoolSortColumn::Name, descending: false }
+    }
+}
+
+impl fmt::Display for PoolSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", if self.descending { "-" } else { "" }, self.column.as_str())
+    }
+}
+
+impl FromStr for PoolSort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<PoolSort> {
+        let (descending, column) = match s.strip_prefix('-') {
+            Some(column) => (true, column),
+            None => (false, s),
+        };
+        let column = [PoolSortColumn::Id, PoolSortColumn::Name].iter()
+            .find(|it| it.as_str() == column)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown sort '{}', expected id, name, -id or -name", s))?;
+        Ok(PoolSort { column, descending })
+    }
+}
+
+/// Last pool of the previous page, `DB::list_pools` continues after it in the order of the filter.
+/// Unlike offsets, positions are not shifted by pools created or deleted in the meantime.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolPosition {
+    pub id: i64,
+    pub name: String,
+}
+
+impl PoolPosition {
+    pub fn of(pool: &ResourcePool) -> PoolPosition {
+        PoolPosition { id: pool.id, name: pool.name.clone() }
+    }
+
+    pub fn as_json(&self) -> Value {
+        json!({"id": self.id, "name": &self.name})
+    }
+
+    pub fn from_json(mut json: Value) -> Option<PoolPosition> {
+        let id = serde_json::from_value(json["id"].take()).ok()?;
+        Some(PoolPosition { id, name: json["name"].as_str()?.to_owned() })
+    }
+}
+
+/// Conditions of `DB::list_pools`, all set conditions must match.
+#[derive(Debug, Clone, Default)]
+pub struct PoolFilter {
+    pub name_prefix: Option<String>,
+    pub allocation_strategy_id: Option<i64>,
+    pub tag: Option<String>,
+    pub tenant: Option<String>,
+    pub sort: PoolSort,
+    pub after: Option<PoolPosition>,
+    pub limit: i64,
+    pub offset: i64,
+    // archived pools are skipped otherwise
+    pub include_archived: bool,
+}
+
+pub const DEFAULT_PAGE_SIZE: i64 = 100;
+
+impl DB {
+    // One page of pools matching the filter, from the replica if configured.
+    pub fn list_pools(&mut self, filter: &PoolFilter) -> Result<Vec<ResourcePool>> {
+        ensure!(filter.limit > 0, "Limit must be positive");
+        ensure!(filter.offset >= 0, "Offset cannot be negative");
+        let order = format!("{} {}", filter.sort.column.as_str(), if filter.sort.descending { "DESC" } else { "ASC" });
+        // both columns are unique, so the sort column alone identifies the position
+        let after = match (filter.sort.column, filter.sort.descending) {
+            (PoolSortColumn::Id, false) => "id > $7",
+            (PoolSortColumn::Id, true) => "id < $7",
+            (PoolSortColumn::Name, false) => "name > $8",
+            (PoolSortColumn::Name, true) => "name < $8",
+        };
+        let after_id = filter.after.as_ref().map(|after| after.id);
+        let after_name = filter.after.as_ref().map(|after| after.name.as_str());
+        let rows = self.reader().query(
+            format!("SELECT {} FROM resource_pools \
+                WHERE ($1::text IS NULL OR starts_with(name, $1)) \
+                AND ($2::bigint IS NULL OR resource_pool_allocation_strategy = $2) \
+                AND ($3::text IS NULL OR tags @> ARRAY[$3]) \
+                AND ($4::text IS NULL OR tenant = $4) \
+                AND ($7::bigint IS NULL OR $8::text IS NULL OR ({})) \
+                AND ($9 OR archived_at IS NULL) \
+                ORDER BY {} LIMIT $5 OFFSET $6", Self::RESOURCE_POOL_COLUMNS, after, order).as_str(),
+            &[&filter.name_prefix, &filter.allocation_strategy_id, &filter.tag, &filter.tenant,
+                &filter.limit, &filter.offset, &after_id, &after_name, &filter.include_archived])?;
+        rows.into_iter().map(Self::row_to_resource_pool).collect()
+    }
+
+    // Tenant owning the pool and tags used to find it, tags replace the current ones.
+    pub fn set_pool_labels(&mut self, mut pool: ResourcePool, tenant: Option<String>, tags: Vec<String>)
+                           -> Result<ResourcePool> {
+        Self::check_not_archived(&mut self.client, pool.id)?;
+        let updated = self.client.query_opt(
+            "UPDATE resource_pools SET tenant=$1, tags=$2 WHERE id=$3 RETURNING updated_at",
+            &[&tenant, &tags, &pool.id])?
+            .ok_or_else(|| anyhow!("Update of resource_pools returned wrong number of rows"))?;
+        pool.tenant = tenant;
+        pool.tags = tags;
+        pool.updated_at = updated.get(0);
+        Ok(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::tests::{initialize_logging, IPV4_ALLOCATION_STRATEGY_ID};
+    use super::*;
+
+    #[test]
+    fn db_list_pools() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let prefix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let mut pools = vec![];
+        for (suffix, tenant, tags) in [("a", "acme", vec!["edge"]), ("b", "acme", vec![]), ("c", "other", vec!["edge"])] {
+            let pool = db.insert_resource_pool(&format!("{}-{}", prefix, suffix), IPV4_ALLOCATION_STRATEGY_ID)
+                .unwrap();
+            pools.push(db.set_pool_labels(pool, Some(tenant.to_owned()),
+                                          tags.into_iter().map(str::to_owned).collect()).unwrap());
+        }
+        let filter = PoolFilter { name_prefix: Some(prefix.clone()), limit: DEFAULT_PAGE_SIZE, ..Default::default() };
+        assert_eq!(pools, db.list_pools(&filter).unwrap());
+
+        let edge = PoolFilter { tag: Some("edge".to_owned()), sort: "-name".parse().unwrap(), ..filter.clone() };
+        assert_eq!(vec![pools[2].clone(), pools[0].clone()], db.list_pools(&edge).unwrap());
+        let acme = PoolFilter { tenant: Some("acme".to_owned()), limit: 1, offset: 1, ..filter.clone() };
+        assert_eq!(vec![pools[1].clone()], db.list_pools(&acme).unwrap());
+        let position = PoolPosition::of(&pools[0]);
+        assert_eq!(Some(position.clone()), PoolPosition::from_json(position.as_json()));
+        let after_a = PoolFilter { after: Some(position), ..filter.clone() };
+        assert_eq!(pools[1..].to_vec(), db.list_pools(&after_a).unwrap());
+        let before_c = PoolFilter {
+            after: Some(PoolPosition::of(&pools[2])),
+            sort: "-id".parse().unwrap(),
+            ..filter.clone()
+        };
+        assert_eq!(vec![pools[1].clone(), pools[0].clone()], db.list_pools(&before_c).unwrap());
+        let other_strategy = PoolFilter { allocation_strategy_id: Some(-1), ..filter };
+        assert!(db.list_pools(&other_strategy).unwrap().is_empty());
+        assert!("size".parse::<PoolSort>().is_err());
+    }
+}
@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+
+use tracing::*;
+
+const DEFAULT_LOG_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Periodically logs how many rows a long running operation has processed,
+/// together with the current rate and, when the total is known, an ETA.
+pub struct Progress {
+    label: String,
+    total: Option<u64>,
+    processed: u64,
+    started: Instant,
+    last_logged: Instant,
+    log_interval: Duration,
+}
+
+impl Progress {
+    pub fn new(label: &str, total: Option<u64>) -> Progress {
+        let now = Instant::now();
+        Progress {
+            label: label.to_owned(),
+            total,
+            processed: 0,
+            started: now,
+            last_logged: now,
+            log_interval: DEFAULT_LOG_INTERVAL,
+        }
+    }
+
+    pub fn inc(&mut self, rows: u64) {
+        self.processed += rows;
+        if self.last_logged.elapsed() >= self.log_interval {
+            self.last_logged = Instant::now();
+            info!("{}: {}", self.label, self.status());
+        }
+    }
+
+    pub fn finish(&self) {
+        info!("{}: finished, {} rows in {}ms", self.label, self.processed,
+              self.started.elapsed().as_millis());
+    }
+
+    fn rate(&self) -> f64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            self.processed as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn eta(&self) -> Option<Duration> {
+        let remaining = self.total?.saturating_sub(self.processed);
+        let rate = self.rate();
+        if rate > 0.0 {
+            Some(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            None
+        }
+    }
+
+    fn status(&self) -> String {
+        let mut status = match self.total {
+            Some(total) => format!("{}/{} rows", self.processed, total),
+            None => format!("{} rows", self.processed),
+        };
+        status += &format!(", {:.0} rows/s", self.rate());
+        if let Some(eta) = self.eta() {
+            status += &format!(", ETA {}s", eta.as_secs());
+        }
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_status() {
+        let mut progress = Progress::new("test", Some(10));
+        progress.log_interval = Duration::from_secs(3600);
+        progress.inc(4);
+        assert_eq!(4, progress.processed);
+        let status = progress.status();
+        assert!(status.starts_with("4/10 rows, "), "Unexpected status {}", status);
+
+        let mut progress = Progress::new("test", None);
+        progress.inc(1);
+        assert!(progress.status().starts_with("1 rows, "));
+        assert!(progress.eta().is_none());
+    }
+}
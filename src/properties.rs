@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow, bail};
+use postgres::Client;
+use serde_json::Value;
+
+use crate::DB;
+use crate::error::AllocationError;
+use crate::subranges::SubRange;
+
+/// JSON type of a pool property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PropertyType {
+    String,
+    Number,
+    // number without a fractional part, e.g. `prefix`
+    Integer,
+    Boolean,
+    Object,
+    Array,
+}
+
+const PROPERTY_TYPES: [PropertyType; 6] = [PropertyType::String, PropertyType::Number, PropertyType::Integer,
+    PropertyType::Boolean, PropertyType::Object, PropertyType::Array];
+
+impl PropertyType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PropertyType::String => "string",
+            PropertyType::Number => "number",
+            PropertyType::Integer => "integer",
+            PropertyType::Boolean => "boolean",
+            PropertyType::Object => "object",
+            PropertyType::Array => "array",
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        match self {
+            PropertyType::String => value.is_string(),
+            PropertyType::Number => value.is_number(),
+            PropertyType::Integer => value.is_i64() || value.is_u64(),
+            PropertyType::Boolean => value.is_boolean(),
+            PropertyType::Object => value.is_object(),
+            PropertyType::Array => value.is_array(),
+        }
+    }
+}
+
+impl fmt::Display for PropertyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for PropertyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<PropertyType> {
+        PROPERTY_TYPES.iter()
+            .find(|it| it.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown property type '{}'", s))
+    }
+}
+
+/// Properties a strategy expects in `resourcePoolProperties`, declared as JSON object of types by key,
+/// e.g. `{"address": "string", "prefix": "integer", "note": "string?"}`. Keys with `?` are optional,
+/// keys that are not declared are allowed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PropertiesSchema {
+    // type and whether the property is required
+    properties: BTreeMap<String, (PropertyType, bool)>,
+}
+
+impl PropertiesSchema {
+    pub fn from_json(schema: &Value) -> Result<PropertiesSchema> {
+        let schema = schema.as_object()
+            .ok_or_else(|| anyhow!("Properties schema must be a JSON object, got '{}'", schema))?;
+        let mut properties = BTreeMap::new();
+        for (key, declared) in schema {
+            let declared = declared.as_str()
+                .ok_or_else(|| anyhow!("Type of property '{}' must be a string, got '{}'", key, declared))?;
+            let property = match declared.strip_suffix('?') {
+                Some(optional) => (optional.parse()?, false),
+                None => (declared.parse()?, true),
+            };
+            properties.insert(key.clone(), property);
+        }
+        Ok(PropertiesSchema { properties })
+    }
+
+    pub fn as_json(&self) -> Value {
+        self.properties.iter()
+            .map(|(key, (property_type, required))| {
+                let optional = if *required { "" } else { "?" };
+                (key.clone(), Value::String(format!("{}{}", property_type, optional)))
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    // Every missing or mistyped property, empty if the properties are valid.
+    pub fn violations(&self, properties: &Value) -> Vec<String> {
+        let mut violations = Vec::new();
+        for (key, (property_type, required)) in &self.properties {
+            match properties.get(key) {
+                None | Some(Value::Null) if *required => violations.push(format!("'{}' is required", key)),
+                None | Some(Value::Null) => {}
+                Some(value) if !property_type.matches(value) =>
+                    violations.push(format!("'{}' must be {}, got {}", key, property_type, value)),
+                Some(_) => {}
+            }
+        }
+        violations
+    }
+}
+
+impl DB {
+    pub(crate) fn find_properties_schema(client: &mut Client, allocation_strategy_id: i64)
+                                         -> Result<Option<PropertiesSchema>> {
+        let row = client.query_opt(
+            "SELECT properties_schema FROM allocation_strategies WHERE id=$1", &[&allocation_strategy_id])?
+            .ok_or_else(|| anyhow!("Allocation strategy {} not found", allocation_strategy_id))?;
+        row.get::<_, Option<Value>>(0).as_ref().map(PropertiesSchema::from_json).transpose()
+    }
+
+    // Declare properties of pools using the strategy, None accepts any properties.
+    // Existing pools are not checked, they are validated when their properties change.
+    pub fn set_properties_schema(&mut self, allocation_strategy_id: i64, schema: Option<&PropertiesSchema>)
+                                 -> Result<()> {
+        let schema = schema.map(PropertiesSchema::as_json);
+        let updated = self.client.execute(
+            "UPDATE allocation_strategies SET properties_schema=$2 WHERE id=$1", &[&allocation_strategy_id, &schema])?;
+        if updated == 0 {
+            bail!("Allocation strategy {} not found", allocation_strategy_id);
+        }
+        Ok(())
+    }
+
+    // Fails with `AllocationError::InvalidPoolProperties` listing all violations of the strategy's schema.
+    pub(crate) fn check_properties_schema(client: &mut Client, resource_pool: &str,
+                                          allocation_strategy_id: i64, properties: &Value)
+                                          -> Result<()> {
+        if !properties.is_object() {
+            return Err(AllocationError::InvalidPoolProperties {
+                resource_pool: resource_pool.to_owned(),
+                reason: "properties must be a JSON object".to_owned(),
+            }.into());
+        }
+        if let Err(reason) = SubRange::from_properties(properties) {
+            return Err(AllocationError::InvalidPoolProperties {
+                resource_pool: resource_pool.to_owned(),
+                reason,
+            }.into());
+        }
+        if let Some(schema) = Self::find_properties_schema(client, allocation_strategy_id)? {
+            let violations = schema.violations(properties);
+            if !violations.is_empty() {
+                return Err(AllocationError::InvalidPoolProperties {
+                    resource_pool: resource_pool.to_owned(),
+                    reason: violations.join(", "),
+                }.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::strategy::StrategyFiles;
+    use crate::tests::{initialize_logging, IPV4_ALLOCATION_STRATEGY_ID};
+    use super::*;
+
+    #[test]
+    fn properties_schema_violations() {
+        let schema = PropertiesSchema::from_json(
+            &json!({"address": "string", "prefix": "integer", "note": "string?"})).unwrap();
+        assert_eq!(json!({"address": "string", "note": "string?", "prefix": "integer"}), schema.as_json());
+        assert!(schema.violations(&json!({"address": "10.0.0.0", "prefix": 8, "other": 1})).is_empty());
+        assert_eq!(vec!["'address' is required", "'note' must be string, got 1", "'prefix' must be integer, got 8.5"],
+                   schema.violations(&json!({"prefix": 8.5, "note": 1})));
+        assert!(PropertiesSchema::from_json(&json!({"prefix": "int"})).is_err());
+        assert!(PropertiesSchema::from_json(&json!(["prefix"])).is_err());
+    }
+
+    #[test]
+    fn db_pool_properties_schema() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let random: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let err = db.insert_resource_pool_with_properties(&format!("pool-{}", random), IPV4_ALLOCATION_STRATEGY_ID,
+                                                          None, Some(json!({"address": "10.0.0.0"})))
+            .unwrap_err();
+        assert_eq!(Some(&AllocationError::InvalidPoolProperties {
+            resource_pool: format!("pool-{}", random),
+            reason: "'prefix' is required".to_owned(),
+        }), err.downcast_ref::<AllocationError>());
+        assert!(db.find_resource_pool_by_name(&format!("pool-{}", random)).unwrap().is_none());
+        let pool = db.insert_resource_pool_with_properties(&format!("pool-{}", random), IPV4_ALLOCATION_STRATEGY_ID,
+                                                           None, Some(json!({"address": "10.0.0.0", "prefix": 24})))
+            .unwrap();
+        assert_eq!(json!({"address": "10.0.0.0", "prefix": 24}), pool.properties);
+
+        let strategy_id = db.insert_allocation_strategy(&format!("strategy-{}", random), "function invoke() {}",
+                                                        None, &StrategyFiles::new()).unwrap();
+        assert_eq!(None, DB::find_properties_schema(&mut db.client, strategy_id).unwrap());
+        let schema = PropertiesSchema::from_json(&json!({"size": "integer"})).unwrap();
+        db.set_properties_schema(strategy_id, Some(&schema)).unwrap();
+        assert_eq!(Some(schema), DB::find_properties_schema(&mut db.client, strategy_id).unwrap());
+        let err = db.insert_resource_pool(&format!("pool-{}-default", random), strategy_id).unwrap_err();
+        assert!(err.to_string().contains("'size' is required"), "{}", err);
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::{Duration, SystemTime};
+
+use anyhow::Result;
+
+use crate::{DB, ResourcePool};
+
+impl DB {
+    // Pools created before `no_allocations_since` without resources in use and without any activity since then:
+    // no resources deallocated, no audit log entries and no allocation jobs. Archived pools, pools with schedules
+    // and pools with nested pools are never idle.
+    pub fn find_idle_pools(&mut self, no_allocations_since: SystemTime) -> Result<Vec<ResourcePool>> {
+        let rows = self.client.query(
+            format!("SELECT {} FROM resource_pools p WHERE created_at < $1 AND archived_at IS NULL \
+                AND NOT EXISTS (SELECT 1 FROM resources r WHERE r.resource_pool = p.id \
+                    AND (r.status <> 'retired' OR r.deleted_at >= $1)) \
+                AND NOT EXISTS (SELECT 1 FROM audit_log a WHERE a.resource_pool = p.id AND a.created_at >= $1) \
+                AND NOT EXISTS (SELECT 1 FROM allocation_jobs j WHERE j.resource_pool = p.id AND j.created_at >= $1) \
+                AND NOT EXISTS (SELECT 1 FROM allocation_schedules s WHERE s.resource_pool = p.id) \
+                AND NOT EXISTS (SELECT 1 FROM resource_pools c WHERE c.parent_pool = p.id) \
+                ORDER BY id", Self::RESOURCE_POOL_COLUMNS).as_str(), &[&no_allocations_since])?;
+        rows.into_iter().map(Self::row_to_resource_pool).collect()
+    }
+
+    // Moves retired resources of an idle pool into `resources_archive` and deletes the pool.
+    // Fails if resources were allocated since the pool was found. Returns number of archived resources.
+    pub fn prune_pool(&mut self, pool: ResourcePool, batch_size: i64) -> Result<u64> {
+        let archived = self.archive_pool_resources(pool.id, Duration::ZERO, batch_size)?;
+        self.delete_resource_pool(pool)?;
+        Ok(archived)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{AllocationOptions, BulkSelector, ResourceState, WasmerEnv};
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_prune_idle_pools() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let idle = create_random_pool(&mut db).unwrap();
+        let (idle, _) = db.allocate_resources(idle, &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .unwrap();
+        let (idle, _) = db.deallocate_resources(idle, &BulkSelector::State(ResourceState::Allocated))
+            .unwrap();
+        let active = create_random_pool(&mut db).unwrap();
+        db.allocate_resources(active.clone(), &mut wasmer_env, json!({}), &AllocationOptions::default()).unwrap();
+        let since = SystemTime::now();
+        let created = create_random_pool(&mut db).unwrap();
+
+        let idle_ids = db.find_idle_pools(since).unwrap().into_iter().map(|pool| pool.id).collect::<Vec<_>>();
+        assert!(idle_ids.contains(&idle.id));
+        assert!(!idle_ids.contains(&active.id));
+        assert!(!idle_ids.contains(&created.id), "Pools created later are not idle yet");
+        let idle_ids = db.find_idle_pools(SystemTime::now()).unwrap().into_iter().map(|pool| pool.id)
+            .collect::<Vec<_>>();
+        assert!(idle_ids.contains(&created.id));
+
+        assert_eq!(1, db.prune_pool(idle.clone(), 10).unwrap());
+        assert!(db.find_resource_pool_by_id(idle.id).unwrap().is_none());
+        assert!(db.prune_pool(active, 10).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use postgres::fallible_iterator::FallibleIterator;
+use serde_json::Value;
+use tracing::*;
+
+use crate::{DB, WasmerEnv};
+use crate::shutdown::Shutdown;
+
+// Channel of `notify_strategy_change`, suffixed with the schema. Payloads are `{"id": <strategy id>}`.
+const CHANNEL_PREFIX: &str = "strategy_changes.";
+// How often the listening thread checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+struct Scripts {
+    // bumped by every change, scripts linked before it are not stored
+    generation: u64,
+    // strategy id to its version and linked script
+    linked: HashMap<i64, (i32, String)>,
+    // changes are missed once the listener stops, nothing is cached from then on
+    stopped: bool,
+}
+
+/// Linked scripts of strategies shared by the threads of a server, so that allocations do not bundle files
+/// or transpile modules and their imports each time. Changes of strategies and their files are received
+/// by `NOTIFY "strategy_changes.<schema>"` on one listening connection: the cache is dropped, as modules link
+/// the strategies they import, and the changed strategy is linked and loaded by the engine right away.
+#[derive(Clone, Default)]
+pub struct ScriptCache {
+    scripts: Arc<Mutex<Scripts>>,
+}
+
+impl ScriptCache {
+    // Listens until shutdown is requested. Changes committed after this returns are seen.
+    pub fn start(shutdown: Shutdown) -> Result<ScriptCache> {
+        let mut db = DB::new_from_env()?;
+        let mut wasmer_env = WasmerEnv::new()?;
+        let schema: String = db.client.query_one("SELECT current_schema()", &[])?.get(0);
+        db.client.batch_execute(&format!("LISTEN \"{}{}\"", CHANNEL_PREFIX, schema.replace('"', "\"\"")))?;
+        let cache = ScriptCache::default();
+        let reloaded = cache.clone();
+        thread::Builder::new().name("strategies".to_owned()).spawn(move || {
+            while !shutdown.is_requested() {
+                // collected first, reloading queries the listening connection
+                let changed = db.client.notifications().timeout_iter(POLL_INTERVAL)
+                    .map(|notification| Ok(notification.payload().to_owned()))
+                    .collect::<Vec<_>>();
+                let changed = match changed {
+                    Ok(changed) => changed,
+                    Err(err) => {
+                        error!("Cannot receive strategy changes, scripts are no longer cached: {}", err);
+                        break;
+                    }
+                };
+                for payload in changed {
+                    reloaded.reload(&mut db, &mut wasmer_env, &payload);
+                }
+            }
+            reloaded.stop();
+        })?;
+        Ok(cache)
+    }
+
+    pub(crate) fn get(&self, strategy_id: i64, version: i32) -> Option<String> {
+        let scripts = self.scripts.lock().unwrap();
+        match scripts.linked.get(&strategy_id) {
+            Some((linked_version, script)) if *linked_version == version && !scripts.stopped => Some(script.clone()),
+            _ => None,
+        }
+    }
+
+    // Taken before the strategy is read, see `ScriptCache::insert`.
+    pub(crate) fn generation(&self) -> u64 {
+        self.scripts.lock().unwrap().generation
+    }
+
+    // Skipped if a strategy changed since `generation`, the script might have been linked from the old one.
+    pub(crate) fn insert(&self, generation: u64, strategy_id: i64, version: i32, script: String) {
+        let mut scripts = self.scripts.lock().unwrap();
+        if scripts.generation == generation && !scripts.stopped {
+            scripts.linked.insert(strategy_id, (version, script));
+        }
+    }
+
+    fn stop(&self) {
+        let mut scripts = self.scripts.lock().unwrap();
+        scripts.stopped = true;
+        scripts.linked.clear();
+    }
+
+    fn reload(&self, db: &mut DB, wasmer_env: &mut WasmerEnv, payload: &str) {
+        let generation = {
+            let mut scripts = self.scripts.lock().unwrap();
+            scripts.generation += 1;
+            scripts.linked.clear();
+            scripts.generation
+        };
+        let strategy_id = match serde_json::from_str::<Value>(payload).ok().and_then(|change| change["id"].as_i64()) {
+            Some(id) => id,
+            None => {
+                warn!("Invalid strategy change '{}'", payload);
+                return;
+            }
+        };
+        let version = match db.client.query_opt("SELECT version FROM allocation_strategies WHERE id=$1",
+                                                &[&strategy_id]) {
+            Ok(Some(row)) => row.get(0),
+            // deleted with its files
+            Ok(None) => return,
+            Err(err) => {
+                warn!("Cannot reload strategy {}: {}", strategy_id, err);
+                return;
+            }
+        };
+        match db.get_allocation_script(strategy_id)
+            .and_then(|script| wasmer_env.load_strategy(&script).map(|_| script)) {
+            Ok(script) => {
+                info!("Reloaded strategy {} version {}", strategy_id, version);
+                self.insert(generation, strategy_id, version, script);
+            }
+            Err(err) => warn!("Cannot reload strategy {}: {:#}", strategy_id, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::AllocationOptions;
+    use crate::strategy::StrategyFiles;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_script_cache_reload() {
+        initialize_logging();
+
+        let shutdown = Shutdown::new();
+        let cache = ScriptCache::start(shutdown).unwrap();
+        let mut db = DB::new_from_env().unwrap().with_script_cache(cache.clone());
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() { return [{n: 1}] }", None,
+                                               &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let dry_run = AllocationOptions { dry_run: true, ..AllocationOptions::default() };
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &dry_run).unwrap();
+        assert_eq!(json!({"n": 1}), resources[0].value);
+        assert!(cache.get(id, 1).is_some());
+
+        // the version stays the same, only the notification replaces the cached script
+        db.client.execute("UPDATE allocation_strategies SET script=$2 WHERE id=$1",
+                          &[&id, &"function invoke() { return [{n: 2}] }"]).unwrap();
+        let started = Instant::now();
+        loop {
+            let (_, resources) = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &dry_run).unwrap();
+            if resources[0].value == json!({"n": 2}) {
+                break;
+            }
+            assert!(started.elapsed() < Duration::from_secs(10), "strategy was not reloaded");
+            thread::sleep(Duration::from_millis(50));
+        }
+        shutdown.request();
+        thread::sleep(POLL_INTERVAL * 2);
+        assert!(cache.get(id, 1).is_none());
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+
+use crate::{AllocationOptions, DB, Resource, ResourcePool, ResourceSelector, WasmerEnv};
+use crate::state::ResourceState;
+
+// Audit entries of resource events read per query.
+const PAGE_SIZE: i64 = 1000;
+
+/// Event of the replayed pool whose outcome differs from the one recorded in the audit log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayMismatch {
+    pub audit_id: i64,
+    // `userInput` and `strategyVersion` of an allocation, value and target state of a transition
+    pub request: Value,
+    pub recorded: Value,
+    // values allocated or the resource transitioned by the replay, the error if it failed
+    pub replayed: std::result::Result<Value, String>,
+}
+
+/// Outcome of `DB::replay_pool`, the replay is deterministic if there are no mismatches.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    pub resource_pool_id: i64,
+    pub strategy_version: i32,
+    pub allocations: u64,
+    pub transitions: u64,
+    // allocations recorded without a request, e.g. imports, inserted as they were
+    pub copied: u64,
+    pub mismatches: Vec<ReplayMismatch>,
+}
+
+impl ReplayReport {
+    pub fn as_json(&self) -> Value {
+        let mismatches = self.mismatches.iter()
+            .map(|mismatch| {
+                let mut json = json!({
+                    "auditId": mismatch.audit_id,
+                    "request": &mismatch.request,
+                    "recorded": &mismatch.recorded,
+                });
+                match &mismatch.replayed {
+                    Ok(replayed) => json["replayed"] = replayed.clone(),
+                    Err(err) => json["error"] = json!(err),
+                }
+                json
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "pool": self.resource_pool_id,
+            "strategyVersion": self.strategy_version,
+            "allocations": self.allocations,
+            "transitions": self.transitions,
+            "copied": self.copied,
+            "mismatches": mismatches,
+        })
+    }
+}
+
+fn recorded_resources(details: &Value) -> Vec<Value> {
+    details["resources"].as_array().cloned().unwrap_or_default()
+}
+
+impl DB {
+    // Creates the pool `into` with the strategy, script override, properties and deallocation safety period of `pool`
+    // and re-executes its allocations and state transitions recorded in the audit log, in order. Allocations are run
+    // with their recorded user input by the current version of the strategy, so that a changed strategy can be
+    // checked against the history of the pool. Transitions follow the values the replay allocated in place
+    // of the recorded ones.
+    pub fn replay_pool(&mut self, pool: &ResourcePool, into: &str, wasmer_env: &mut WasmerEnv)
+                       -> Result<ReplayReport> {
+        let mut replay = self.insert_resource_pool_with_properties(into, pool.allocation_strategy_id, None,
+                                                                   Some(pool.properties.clone()))?;
+        if pool.deallocation_safety_period > 0 {
+            replay = self.set_deallocation_safety_period(replay, pool.deallocation_safety_period)?;
+        }
+        if let Some(script_override) = self.get_script_override(pool.id)? {
+            replay = self.set_script_override(replay, Some(&script_override))?;
+        }
+        let mut report = ReplayReport {
+            resource_pool_id: replay.id,
+            strategy_version: self.get_strategy(pool.allocation_strategy_id)?.version,
+            allocations: 0,
+            transitions: 0,
+            copied: 0,
+            mismatches: Vec::new(),
+        };
+        // serialized recorded value to the value allocated in its place
+        let mut replayed_values = HashMap::<String, Value>::new();
+        let mut after_id = 0;
+        loop {
+            let rows = self.client.query(
+                "SELECT id, action, details FROM audit_log WHERE resource_pool=$1 AND id > $2 \
+                AND action IN ('resources_allocated', 'resources_deallocated', 'resources_state_changed') \
+                ORDER BY id LIMIT $3",
+                &[&pool.id, &after_id, &PAGE_SIZE])?;
+            let page_size = rows.len() as i64;
+            for row in rows {
+                after_id = row.get(0);
+                let (action, details): (&str, Value) = (row.get(1), row.get(2));
+                replay = if action == "resources_allocated" {
+                    self.replay_allocation(replay, wasmer_env, after_id, &details, &mut replayed_values, &mut report)?
+                } else {
+                    self.replay_transitions(replay, after_id, &details, &replayed_values, &mut report)?
+                };
+            }
+            if page_size < PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(report)
+    }
+
+    // `details` of a `resources_allocated` audit entry
+    fn replay_allocation(&mut self, replay: ResourcePool, wasmer_env: &mut WasmerEnv, audit_id: i64, details: &Value,
+                         replayed_values: &mut HashMap<String, Value>, report: &mut ReplayReport)
+                         -> Result<ResourcePool> {
+        let (request, recorded) = (&details["request"], recorded_resources(details));
+        let state = |resource: &Value| resource["state"].as_str().and_then(|state| state.parse().ok())
+            .filter(ResourceState::is_initial)
+            .unwrap_or(ResourceState::Allocated);
+        if request.is_null() {
+            let resources = recorded.iter()
+                .map(|resource| Resource {
+                    state: state(resource),
+                    ..Resource::new_from_value(replay.id, resource["value"].clone())
+                })
+                .collect();
+            let (replay, _) = self.insert_resources(replay, resources)
+                .context(format!("Cannot copy resources of audit entry {}", audit_id))?;
+            for resource in recorded {
+                replayed_values.insert(resource["value"].to_string(), resource["value"].clone());
+            }
+            report.copied += 1;
+            return Ok(replay);
+        }
+        report.allocations += 1;
+        let options = AllocationOptions {
+            reserve: recorded.first().map(state) == Some(ResourceState::Reserved),
+            ..AllocationOptions::default()
+        };
+        let recorded_values = recorded.iter().map(|resource| resource["value"].clone()).collect::<Vec<_>>();
+        let mismatch = |replayed| ReplayMismatch {
+            audit_id,
+            request: request.clone(),
+            recorded: json!(recorded_values),
+            replayed,
+        };
+        match self.allocate_resources(replay.clone(), wasmer_env, request["userInput"].clone(), &options) {
+            Ok((replay, resources)) => {
+                let values = resources.into_iter().map(|resource| resource.value).collect::<Vec<_>>();
+                for (recorded, replayed) in recorded_values.iter().zip(&values) {
+                    replayed_values.insert(recorded.to_string(), replayed.clone());
+                }
+                if values != recorded_values {
+                    report.mismatches.push(mismatch(Ok(json!(values))));
+                }
+                Ok(replay)
+            }
+            Err(err) => {
+                report.mismatches.push(mismatch(Err(format!("{:#}", err))));
+                Ok(replay)
+            }
+        }
+    }
+
+    // Values the replay did not allocate are skipped, their allocation is already reported as a mismatch.
+    fn replay_transitions(&mut self, mut replay: ResourcePool, audit_id: i64, details: &Value,
+                          replayed_values: &HashMap<String, Value>, report: &mut ReplayReport)
+                          -> Result<ResourcePool> {
+        for resource in recorded_resources(details) {
+            let (value, state) = match (replayed_values.get(&resource["value"].to_string()),
+                                        resource["state"].as_str().and_then(|state| state.parse().ok())) {
+                (Some(value), Some(state)) => (value, state),
+                _ => continue,
+            };
+            report.transitions += 1;
+            let selector = ResourceSelector::Value(value.clone());
+            match self.transition_resource(replay.clone(), &selector, state) {
+                Ok((transitioned, _)) => replay = transitioned,
+                Err(err) => report.mismatches.push(ReplayMismatch {
+                    audit_id,
+                    request: json!({"value": value, "state": state.as_str()}),
+                    recorded: resource,
+                    replayed: Err(format!("{:#}", err)),
+                }),
+            }
+        }
+        Ok(replay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::strategy::StrategyFiles;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    fn random_name() -> String {
+        rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect()
+    }
+
+    #[test]
+    fn db_replay_pool() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 3}), &options).unwrap();
+        let selector = ResourceSelector::Value(json!({"address": "10.0.0.1"}));
+        let (pool, _) = db.transition_resource(pool, &selector, ResourceState::Retired).unwrap();
+        let (pool, _) = db.insert_resources(pool.clone(), vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.9"})),
+        ]).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+
+        let report = db.replay_pool(&pool, &random_name(), &mut wasmer_env).unwrap();
+        assert_eq!((2, 1, 1), (report.allocations, report.transitions, report.copied));
+        assert!(report.mismatches.is_empty(), "{}", report.as_json());
+        let values = |db: &mut DB, resource_pool_id| {
+            let mut values = db.get_resources(resource_pool_id).unwrap().into_iter()
+                .map(|resource| resource.value.to_string())
+                .collect::<Vec<_>>();
+            values.sort();
+            values
+        };
+        assert_eq!(values(&mut db, pool.id), values(&mut db, report.resource_pool_id));
+
+        // a changed strategy allocates other values
+        let name = random_name();
+        let id = db.insert_allocation_strategy(&name, "function invoke() { return [{n: currentResources.length}] }",
+                                               None, &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        db.client.execute("UPDATE allocation_strategies SET script=$2, version=version + 1 WHERE id=$1",
+                          &[&id, &"function invoke() { return [{n: 2 * currentResources.length}] }"]).unwrap();
+        let report = db.replay_pool(&pool, &random_name(), &mut wasmer_env).unwrap();
+        assert_eq!(2, report.strategy_version);
+        assert_eq!(1, report.mismatches.len());
+        assert_eq!((json!([{"n": 1}]), Ok(json!([{"n": 2}]))),
+                   (report.mismatches[0].recorded.clone(), report.mismatches[0].replayed.clone()));
+        assert_eq!(json!(1), report.mismatches[0].request["strategyVersion"]);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow, ensure};
+use serde_json::Value;
+
+use crate::DB;
+use crate::config::Config;
+
+/// Shards holding pools of some tenants or pools of given names, read from `RM_DB_ROUTING`. Other pools are
+/// in the database of `RM_DB_PARAMS`. Routes only place new pools, pools are not moved when they change.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RoutingTable {
+    // connection parameters by shard name
+    pub shards: BTreeMap<String, String>,
+    // shard by tenant
+    pub tenants: BTreeMap<String, String>,
+    // shard by pool name, preferred over the tenant of the pool
+    pub pools: BTreeMap<String, String>,
+}
+
+impl FromStr for RoutingTable {
+    type Err = anyhow::Error;
+
+    // `{"shards": {"eu": "host=eu-db user=postgres"}, "tenants": {"acme": "eu"}, "pools": {"edge-1": "eu"}}`
+    fn from_str(s: &str) -> Result<RoutingTable> {
+        let table = serde_json::from_str::<Value>(s).context("Routing table is not a valid JSON")?;
+        let map = |key: &str| -> Result<BTreeMap<String, String>> {
+            match &table[key] {
+                Value::Null => Ok(BTreeMap::new()),
+                value => serde_json::from_value(value.clone())
+                    .map_err(|_| anyhow!("'{}' of the routing table must be an object of strings", key)),
+            }
+        };
+        let table = RoutingTable { shards: map("shards")?, tenants: map("tenants")?, pools: map("pools")? };
+        for shard in table.tenants.values().chain(table.pools.values()) {
+            ensure!(table.shards.contains_key(shard), "Shard '{}' of the routing table is not declared", shard);
+        }
+        Ok(table)
+    }
+}
+
+impl RoutingTable {
+    // None is the default database.
+    pub fn shard_of(&self, pool: &str, tenant: Option<&str>) -> Option<&str> {
+        self.pools.get(pool)
+            .or_else(|| tenant.and_then(|tenant| self.tenants.get(tenant)))
+            .map(String::as_str)
+    }
+}
+
+/// Picks the database of a pool among the default one and the shards of the routing table. Databases
+/// connect when first used, shards with the settings of the default one.
+pub struct DbRouter {
+    config: Config,
+    table: RoutingTable,
+    // connected shards by name
+    shards: BTreeMap<String, DB>,
+}
+
+impl DbRouter {
+    pub fn from_env() -> Result<DbRouter> {
+        Ok(Self::from_config(&Config::from_env()?))
+    }
+
+    pub fn from_config(config: &Config) -> DbRouter {
+        let table = config.db_routing.clone().unwrap_or_default();
+        DbRouter { config: config.clone(), table, shards: BTreeMap::new() }
+    }
+
+    fn shard(&mut self, shard: &str) -> Result<&mut DB> {
+        if !self.shards.contains_key(shard) {
+            let params = self.table.shards.get(shard).ok_or_else(|| anyhow!("Shard '{}' not found", shard))?;
+            // replicas are only configured for the default database
+            let config = Config { db_params: params.clone(), db_replica_params: None, ..self.config.clone() };
+            let db = DB::new_from_config(&config).context(format!("Cannot connect to shard '{}'", shard))?;
+            self.shards.insert(shard.to_owned(), db);
+        }
+        Ok(self.shards.get_mut(shard).unwrap())
+    }
+
+    // The shard the pool is routed to by name, otherwise the first shard holding a pool of the name,
+    // as its tenant is not known before it is found. None for the default database.
+    fn shard_of_pool(&mut self, pool: &str) -> Result<Option<String>> {
+        if let Some(shard) = self.table.pools.get(pool) {
+            return Ok(Some(shard.clone()));
+        }
+        let shards = self.table.shards.keys().cloned().collect::<Vec<_>>();
+        for shard in shards {
+            if self.shard(&shard)?.find_resource_pool_by_name(pool)?.is_some() {
+                return Ok(Some(shard));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn into_pool_db(mut self, pool: &str) -> Result<DB> {
+        let shard = self.shard_of_pool(pool)?;
+        self.into_db(shard)
+    }
+
+    pub fn into_new_pool_db(self, pool: &str, tenant: Option<&str>) -> Result<DB> {
+        let shard = self.table.shard_of(pool, tenant).map(str::to_owned);
+        self.into_db(shard)
+    }
+
+    pub fn into_tenant_db(self, tenant: &str) -> Result<DB> {
+        let shard = self.table.tenants.get(tenant).cloned();
+        self.into_db(shard)
+    }
+
+    fn into_db(mut self, shard: Option<String>) -> Result<DB> {
+        match shard {
+            Some(shard) => {
+                self.shard(&shard)?;
+                Ok(self.shards.remove(&shard).unwrap())
+            }
+            None => DB::new_from_config(&self.config),
+        }
+    }
+}
+
+impl DB {
+    // Database holding the pool of the name, see `DbRouter`.
+    pub fn new_for_pool(pool: &str) -> Result<DB> {
+        DbRouter::from_env()?.into_pool_db(pool)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::connect::ConnectRetry;
+    use crate::tests::{initialize_logging, IPV4_ALLOCATION_STRATEGY_ID};
+    use super::*;
+
+    #[test]
+    fn routing_table() {
+        let table: RoutingTable = r#"{"shards": {"eu": "host=eu"}, "tenants": {"acme": "eu"}, "pools": {"edge": "eu"}}"#
+            .parse().unwrap();
+        assert_eq!(Some("eu"), table.shard_of("edge", None));
+        assert_eq!(Some("eu"), table.shard_of("core", Some("acme")));
+        assert_eq!(None, table.shard_of("core", Some("other")));
+        assert!(r#"{"tenants": {"acme": "us"}}"#.parse::<RoutingTable>().is_err());
+        assert!(r#"{"shards": {"eu": 1}}"#.parse::<RoutingTable>().is_err());
+    }
+
+    #[test]
+    fn db_routing() {
+        initialize_logging();
+
+        let schema = format!("rm_shard_{}", rand::thread_rng().gen::<u32>());
+        let params = std::env::var("DB_PARAMS").unwrap();
+        DB::new(&params, Some(&schema), ConnectRetry::default()).unwrap().init_schema().unwrap();
+        let mut config = Config::from_env().unwrap();
+        config.db_routing = Some(RoutingTable {
+            shards: vec![("eu".to_owned(), format!("{} options='-c search_path={}'", params, schema))]
+                .into_iter().collect(),
+            tenants: vec![("acme".to_owned(), "eu".to_owned())].into_iter().collect(),
+            pools: BTreeMap::new(),
+        });
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let pool = DbRouter::from_config(&config).into_new_pool_db(&name, Some("acme")).unwrap()
+            .insert_resource_pool(&name, IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        // found in the shard by name
+        let mut db = DbRouter::from_config(&config).into_pool_db(&name).unwrap();
+        assert_eq!(pool, db.get_resource_pool_by_name(&name).unwrap());
+        let mut db = DbRouter::from_config(&config).into_new_pool_db(&name, Some("other")).unwrap();
+        assert!(db.find_resource_pool_by_name(&name).unwrap().is_none());
+        assert!(DbRouter::from_config(&config).into_tenant_db("acme").unwrap()
+            .find_resource_pool_by_name(&name).unwrap().is_some());
+
+        db.client.execute(format!("DROP SCHEMA {} CASCADE", schema).as_str(), &[]).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use chrono::{DateTime, Utc};
+use postgres::Row;
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{AllocationOptions, DB, ResourcePool};
+
+/// Allocation enqueued by the worker whenever the cron expression fires.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllocationSchedule {
+    pub id: i32,
+    pub resource_pool_id: i64,
+    pub name: String,
+    // `cron` crate syntax including seconds, e.g. `0 0 2 * * *` for every night at 2:00 UTC
+    pub cron_expression: String,
+    pub user_input: Value,
+    pub lease: Option<Duration>,
+    pub next_run_at: SystemTime,
+    pub last_run_at: Option<SystemTime>,
+}
+
+impl AllocationSchedule {
+    pub fn as_json(&self) -> Value {
+        let mut json = json!({
+            "id": self.id,
+            "resourcePool": self.resource_pool_id,
+            "name": &self.name,
+            "cron": &self.cron_expression,
+            "userInput": &self.user_input,
+            "nextRunAt": DateTime::<Utc>::from(self.next_run_at).to_rfc3339(),
+        });
+        if let Some(lease) = self.lease {
+            json["leaseSeconds"] = lease.as_secs().into();
+        }
+        if let Some(last_run_at) = self.last_run_at {
+            json["lastRunAt"] = Value::String(DateTime::<Utc>::from(last_run_at).to_rfc3339());
+        }
+        json
+    }
+}
+
+fn next_run(cron_expression: &str, after: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    let schedule = cron::Schedule::from_str(cron_expression)
+        .context(format!("Invalid cron expression '{}'", cron_expression))?;
+    schedule.after(&after).next()
+        .ok_or_else(|| anyhow!("Cron expression '{}' never fires", cron_expression))
+}
+
+impl DB {
+    const ALLOCATION_SCHEDULE_COLUMNS: &'static str =
+        "id, resource_pool, name, cron_expression, user_input, lease_seconds, next_run_at, last_run_at";
+
+    pub fn create_schedule(&mut self, pool: &ResourcePool, name: &str, cron_expression: &str, user_input: Value,
+                           lease: Option<Duration>) -> Result<AllocationSchedule> {
+        let next_run_at = SystemTime::from(next_run(cron_expression, Utc::now())?);
+        let lease_seconds = lease.map(|lease| lease.as_secs() as i64);
+        let mut transaction = self.client.transaction()?;
+        let row = transaction.query_one(
+            format!("INSERT INTO allocation_schedules \
+                (resource_pool, name, cron_expression, user_input, lease_seconds, next_run_at) \
+                VALUES ($1, $2, $3, $4, $5, $6) RETURNING {}", Self::ALLOCATION_SCHEDULE_COLUMNS).as_str(),
+            &[&pool.id, &name, &cron_expression, &user_input, &lease_seconds, &next_run_at])?;
+        let schedule = Self::row_to_allocation_schedule(row);
+        Self::record_audit(&mut transaction, Some(pool.id), "schedule_created", schedule.as_json())?;
+        transaction.commit()?;
+        Ok(schedule)
+    }
+
+    pub fn get_schedules(&mut self, resource_pool_id: i64) -> Result<Vec<AllocationSchedule>> {
+        let rows = self.client.query(
+            format!("SELECT {} FROM allocation_schedules WHERE resource_pool=$1 ORDER BY name",
+                    Self::ALLOCATION_SCHEDULE_COLUMNS).as_str(),
+            &[&resource_pool_id])?;
+        Ok(rows.into_iter().map(Self::row_to_allocation_schedule).collect())
+    }
+
+    pub fn delete_schedule(&mut self, pool: &ResourcePool, name: &str) -> Result<()> {
+        let mut transaction = self.client.transaction()?;
+        let deleted_count = transaction.execute(
+            "DELETE FROM allocation_schedules WHERE resource_pool=$1 AND name=$2", &[&pool.id, &name])?;
+        ensure!(deleted_count == 1, "Schedule '{}' not found in pool '{}'", name, pool.name);
+        Self::record_audit(&mut transaction, Some(pool.id), "schedule_deleted", json!({"name": name}))?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // Enqueue an allocation job for every schedule that is due and move it to its next run.
+    // Runs missed while no worker was running are skipped. Returns ids of the enqueued jobs.
+    pub fn enqueue_due_schedules(&mut self) -> Result<Vec<i32>> {
+        let mut transaction = self.client.transaction()?;
+        let rows = transaction.query(
+            format!("SELECT {} FROM allocation_schedules WHERE next_run_at <= now() ORDER BY next_run_at \
+                FOR UPDATE SKIP LOCKED", Self::ALLOCATION_SCHEDULE_COLUMNS).as_str(), &[])?;
+        let mut job_ids = Vec::with_capacity(rows.len());
+        for schedule in rows.into_iter().map(Self::row_to_allocation_schedule) {
+            let options = AllocationOptions { lease: schedule.lease, ..AllocationOptions::default() };
+            let job_id = Self::insert_allocation_job(
+                &mut transaction, schedule.resource_pool_id, schedule.user_input.clone(), &options)?;
+            let next_run_at = SystemTime::from(next_run(&schedule.cron_expression, Utc::now())?);
+            transaction.execute(
+                "UPDATE allocation_schedules SET next_run_at=$1, last_run_at=now() WHERE id=$2",
+                &[&next_run_at, &schedule.id])?;
+            Self::record_audit(&mut transaction, Some(schedule.resource_pool_id), "scheduled_allocation",
+                               json!({"schedule": &schedule.name, "job": job_id}))?;
+            debug!("Schedule '{}' of pool {} enqueued job {}", schedule.name, schedule.resource_pool_id, job_id);
+            job_ids.push(job_id);
+        }
+        transaction.commit()?;
+        Ok(job_ids)
+    }
+
+    fn row_to_allocation_schedule(row: Row) -> AllocationSchedule {
+        let lease_seconds: Option<i64> = row.get(5);
+        AllocationSchedule {
+            id: row.get(0),
+            resource_pool_id: row.get(1),
+            name: row.get(2),
+            cron_expression: row.get(3),
+            user_input: row.get(4),
+            lease: lease_seconds.map(|secs| Duration::from_secs(secs as u64)),
+            next_run_at: row.get(6),
+            last_run_at: row.get(7),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_schedules() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        assert!(db.create_schedule(&pool, "broken", "every night", json!({}), None).is_err());
+        let nightly = db.create_schedule(&pool, "nightly", "0 0 2 * * *", json!({"resourceCount": 2}), None)
+            .unwrap();
+        assert!(nightly.next_run_at > SystemTime::now());
+        assert!(nightly.next_run_at <= SystemTime::now() + Duration::from_secs(24 * 3600));
+        assert_eq!(vec![nightly.clone()], db.get_schedules(pool.id).unwrap());
+
+        db.client.execute("UPDATE allocation_schedules SET next_run_at = now() - interval '1 hour' WHERE id=$1",
+                          &[&nightly.id]).unwrap();
+        // a worker of another test may enqueue it first
+        db.enqueue_due_schedules().unwrap();
+        let rescheduled = db.get_schedules(pool.id).unwrap().remove(0);
+        assert!(rescheduled.next_run_at > SystemTime::now());
+        assert!(rescheduled.last_run_at.is_some());
+        // resources allocated by the job are audited as well
+        let history = db.get_audit_log(pool.id, 10).unwrap().into_iter()
+            .filter(|entry| !entry.action.starts_with("resources_"))
+            .collect::<Vec<_>>();
+        let actions = history.iter().map(|entry| entry.action.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["schedule_created", "scheduled_allocation"], actions);
+        let job_id = history[1].details["job"].as_i64().unwrap() as i32;
+        assert_eq!(json!({"resourceCount": 2}), db.get_job_status(job_id).unwrap().user_input);
+
+        db.delete_schedule(&pool, "nightly").unwrap();
+        assert!(db.get_schedules(pool.id).unwrap().is_empty());
+        assert!(db.delete_schedule(&pool, "nightly").is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Result, bail};
+use postgres::Client;
+use tracing::*;
+
+use crate::DB;
+use crate::transaction::Transaction;
+
+/// Numbered migrations, applied in order by `DB::init_schema`.
+const MIGRATIONS: [(&str, &str); 47] = [
+    ("001_init", include_str!("../migrations/001_init.sql")),
+    ("002_resource_lifecycle", include_str!("../migrations/002_resource_lifecycle.sql")),
+    ("003_soft_delete", include_str!("../migrations/003_soft_delete.sql")),
+    ("004_resource_state", include_str!("../migrations/004_resource_state.sql")),
+    ("005_nested_pools", include_str!("../migrations/005_nested_pools.sql")),
+    ("006_pool_properties", include_str!("../migrations/006_pool_properties.sql")),
+    ("007_allocation_jobs", include_str!("../migrations/007_allocation_jobs.sql")),
+    ("008_schedules_and_audit_log", include_str!("../migrations/008_schedules_and_audit_log.sql")),
+    ("009_inet_column", include_str!("../migrations/009_inet_column.sql")),
+    ("010_resources_archive", include_str!("../migrations/010_resources_archive.sql")),
+    ("011_pool_snapshots", include_str!("../migrations/011_pool_snapshots.sql")),
+    ("012_seed_sequences", include_str!("../migrations/012_seed_sequences.sql")),
+    ("013_strategy_script_kind", include_str!("../migrations/013_strategy_script_kind.sql")),
+    ("014_strategy_files", include_str!("../migrations/014_strategy_files.sql")),
+    ("015_strategy_typescript", include_str!("../migrations/015_strategy_typescript.sql")),
+    ("016_strategy_engine", include_str!("../migrations/016_strategy_engine.sql")),
+    ("017_strategy_properties_schema", include_str!("../migrations/017_strategy_properties_schema.sql")),
+    ("018_strategy_input_schema", include_str!("../migrations/018_strategy_input_schema.sql")),
+    ("019_pool_tags_and_tenant", include_str!("../migrations/019_pool_tags_and_tenant.sql")),
+    ("020_strategy_version", include_str!("../migrations/020_strategy_version.sql")),
+    ("021_resource_metadata", include_str!("../migrations/021_resource_metadata.sql")),
+    ("022_resources_in_use_index", include_str!("../migrations/022_resources_in_use_index.sql")),
+    ("023_pool_stats_history", include_str!("../migrations/023_pool_stats_history.sql")),
+    ("024_resources_metadata_search", include_str!("../migrations/024_resources_metadata_search.sql")),
+    ("025_free_ranges", include_str!("../migrations/025_free_ranges.sql")),
+    ("026_strategy_context_queries", include_str!("../migrations/026_strategy_context_queries.sql")),
+    ("027_resource_owner", include_str!("../migrations/027_resource_owner.sql")),
+    ("028_resource_description", include_str!("../migrations/028_resource_description.sql")),
+    ("029_pool_alert_rules", include_str!("../migrations/029_pool_alert_rules.sql")),
+    ("030_pool_created_at", include_str!("../migrations/030_pool_created_at.sql")),
+    ("031_pool_archived", include_str!("../migrations/031_pool_archived.sql")),
+    ("032_uniqueness_groups", include_str!("../migrations/032_uniqueness_groups.sql")),
+    ("033_pool_default_user_input", include_str!("../migrations/033_pool_default_user_input.sql")),
+    ("034_strategy_docs", include_str!("../migrations/034_strategy_docs.sql")),
+    ("035_strategy_tests", include_str!("../migrations/035_strategy_tests.sql")),
+    ("036_resource_blocks", include_str!("../migrations/036_resource_blocks.sql")),
+    ("037_resource_created_at", include_str!("../migrations/037_resource_created_at.sql")),
+    ("038_resource_bigint_ids", include_str!("../migrations/038_resource_bigint_ids.sql")),
+    ("039_updated_at", include_str!("../migrations/039_updated_at.sql")),
+    ("040_strategy_changes", include_str!("../migrations/040_strategy_changes.sql")),
+    ("041_pool_script_override", include_str!("../migrations/041_pool_script_override.sql")),
+    ("042_pool_hooks", include_str!("../migrations/042_pool_hooks.sql")),
+    ("043_outbox", include_str!("../migrations/043_outbox.sql")),
+    ("044_pool_contention", include_str!("../migrations/044_pool_contention.sql")),
+    ("045_canonical_values", include_str!("../migrations/045_canonical_values.sql")),
+    ("046_service_settings", include_str!("../migrations/046_service_settings.sql")),
+    ("047_pool_bigint_ids", include_str!("../migrations/047_pool_bigint_ids.sql")),
+];
+
+const PARTITION_RESOURCES: &str = include_str!("../migrations/optional/partition_resources.sql");
+
+const CREATE_SCHEMA_MIGRATIONS: &str = "CREATE TABLE IF NOT EXISTS schema_migrations \
+    (name VARCHAR PRIMARY KEY, applied_at TIMESTAMPTZ NOT NULL DEFAULT now())";
+
+// Serializes concurrent `db init` runs.
+const INIT_LOCK_KEY: i64 = 0x726d_696e_6974;
+
+impl DB {
+    // Create the schema, or apply migrations missing since the last run. Returns names of applied migrations.
+    // A schema created by applying the scripts manually is adopted if `verify_schema` finds no drift.
+    pub fn init_schema(&mut self) -> Result<Vec<&'static str>> {
+        let mut transaction = self.client.transaction()?;
+        transaction.execute("SELECT pg_advisory_xact_lock($1)", &[&INIT_LOCK_KEY])?;
+        if let Some(schema) = &self.schema {
+            let create: String = transaction.query_one(
+                "SELECT format('CREATE SCHEMA IF NOT EXISTS %I', $1::text)", &[schema])?.get(0);
+            transaction.batch_execute(&create)?;
+        }
+        let has_history = Self::table_exists(&mut transaction, "schema_migrations")?;
+        let has_pools = Self::table_exists(&mut transaction, "resource_pools")?;
+        transaction.batch_execute(CREATE_SCHEMA_MIGRATIONS)?;
+        if !has_history && has_pools {
+            let drift = Self::schema_drift(&mut transaction)?;
+            if !drift.is_empty() {
+                bail!("Existing schema was not created by `db init` and differs from the migrations: {}",
+                      drift.join(", "));
+            }
+            for (name, _) in MIGRATIONS.iter() {
+                transaction.execute("INSERT INTO schema_migrations (name) VALUES ($1)", &[name])?;
+            }
+            info!("Adopted existing schema");
+            transaction.commit()?;
+            return Ok(Vec::new());
+        }
+        let applied = transaction.query("SELECT name FROM schema_migrations", &[])?
+            .into_iter().map(|row| row.get(0)).collect::<Vec<String>>();
+        let mut result = Vec::new();
+        for (name, script) in MIGRATIONS.iter().filter(|(name, _)| !applied.iter().any(|it| it == name)) {
+            debug!("Applying migration {}", name);
+            transaction.batch_execute(script)?;
+            // the partition key keeps its type until the table is copied
+            if *name == "047_pool_bigint_ids" && Self::is_resources_partitioned(&mut transaction)? {
+                transaction.batch_execute(PARTITION_RESOURCES)?;
+            }
+            transaction.execute("INSERT INTO schema_migrations (name) VALUES ($1)", &[name])?;
+            result.push(*name);
+        }
+        transaction.commit()?;
+        Ok(result)
+    }
+
+    // Differences between the live schema and the one created by the migrations, empty if there is no drift.
+    pub fn verify_schema(&mut self) -> Result<Vec<String>> {
+        let mut transaction = self.client.transaction()?;
+        let drift = Self::schema_drift(&mut transaction)?;
+        transaction.rollback()?;
+        Ok(drift)
+    }
+
+    // Applies the migrations to a temporary schema inside a savepoint and compares its catalog with the live one.
+    fn schema_drift(transaction: &mut Transaction) -> Result<Vec<String>> {
+        let live_schema: String = transaction.query_one("SELECT current_schema()", &[])?.get(0);
+        let partitioned = Self::is_resources_partitioned(transaction)?;
+        let actual = Self::describe_schema(transaction, &live_schema)?;
+
+        let mut scratch = transaction.transaction()?;
+        let expected_schema: String = scratch.query_one(
+            "SELECT 'rm_verify_' || pg_backend_pid()", &[])?.get(0);
+        scratch.batch_execute(&format!("CREATE SCHEMA {0}; SET LOCAL search_path TO {0}", expected_schema))?;
+        for (_, script) in MIGRATIONS.iter() {
+            scratch.batch_execute(script)?;
+        }
+        if partitioned {
+            scratch.batch_execute(PARTITION_RESOURCES)?;
+        }
+        let expected = Self::describe_schema(&mut scratch, &expected_schema)?;
+        scratch.rollback()?;
+
+        // columns, constraints and indexes of a missing or unexpected table are not listed separately
+        let table_key = |object: &str| match object.split_once(' ') {
+            Some((_, name)) => format!("table {}", name.split('.').next().unwrap_or(name)),
+            None => object.to_owned(),
+        };
+        let mut drift = Vec::new();
+        for (object, definition) in &expected {
+            match actual.get(object) {
+                None if actual.contains_key(&table_key(object)) || table_key(object) == *object =>
+                    drift.push(format!("missing {}", object)),
+                Some(found) if found != definition =>
+                    drift.push(format!("{} is {}, expected {}", object, found, definition)),
+                _ => {}
+            }
+        }
+        drift.extend(actual.keys()
+            .filter(|object| !expected.contains_key(*object))
+            .filter(|object| expected.contains_key(&table_key(object)) || table_key(object) == **object)
+            .map(|object| format!("unexpected {}", object)));
+        Ok(drift)
+    }
+
+    // Tables, columns, constraints and indexes of the schema. Partitions and `schema_migrations` are skipped.
+    fn describe_schema(client: &mut Client, schema: &str) -> Result<BTreeMap<String, String>> {
+        let rows = client.query(
+            "WITH tables AS ( \
+                SELECT c.oid, c.relname FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+                WHERE n.nspname = $1 AND c.relkind IN ('r', 'p') AND NOT c.relispartition \
+                AND c.relname <> 'schema_migrations' \
+            ) \
+            SELECT 'table ' || relname, '' FROM tables \
+            UNION ALL \
+            SELECT 'column ' || t.relname || '.' || a.attname, \
+                format_type(a.atttypid, a.atttypmod) || CASE WHEN a.attnotnull THEN ' NOT NULL' ELSE '' END \
+            FROM tables t JOIN pg_attribute a ON a.attrelid = t.oid WHERE a.attnum > 0 AND NOT a.attisdropped \
+            UNION ALL \
+            SELECT 'constraint ' || t.relname || '.' || con.conname, pg_get_constraintdef(con.oid) \
+            FROM tables t JOIN pg_constraint con ON con.conrelid = t.oid \
+            UNION ALL \
+            SELECT 'index ' || t.relname || '.' || i.relname, '' \
+            FROM tables t JOIN pg_index x ON x.indrelid = t.oid JOIN pg_class i ON i.oid = x.indexrelid",
+            &[&schema])?;
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    fn table_exists(client: &mut Client, table: &str) -> Result<bool> {
+        let row = client.query_one("SELECT to_regclass($1) IS NOT NULL", &[&table])?;
+        Ok(row.get(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use serde_json::json;
+
+    use crate::Resource;
+    use crate::connect::ConnectRetry;
+    use crate::tests::{initialize_logging, IPV4_ALLOCATION_STRATEGY_ID};
+    use super::*;
+
+    #[test]
+    fn db_verify_schema() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        assert_eq!(Vec::<String>::new(), db.verify_schema().unwrap());
+        // migrations of a schema at the latest version are not applied again
+        assert!(db.init_schema().unwrap().is_empty());
+
+        let mut transaction = db.client.transaction().unwrap();
+        // allocation strategies are only read by other tests, so the exclusive lock does not deadlock them
+        transaction.batch_execute("ALTER TABLE allocation_strategies ADD COLUMN note TEXT; \
+            ALTER TABLE allocation_strategies ALTER COLUMN script DROP NOT NULL; \
+            ALTER TABLE allocation_strategies DROP CONSTRAINT allocation_strategies_name_key; \
+            CREATE TABLE rm_verify_extra (id INT)").unwrap();
+        let drift = DB::schema_drift(&mut transaction).unwrap();
+        assert_eq!(vec![
+            "column allocation_strategies.script is text, expected text NOT NULL",
+            "missing constraint allocation_strategies.allocation_strategies_name_key",
+            "missing index allocation_strategies.allocation_strategies_name_key",
+            "unexpected column allocation_strategies.note",
+            "unexpected table rm_verify_extra",
+        ], drift);
+    }
+
+    #[test]
+    fn db_custom_schema() {
+        initialize_logging();
+
+        let schema = format!("rm_test_{}", rand::thread_rng().gen::<u32>());
+        let params = std::env::var("DB_PARAMS").unwrap();
+        let mut db = DB::new(&params, Some(&schema), ConnectRetry::default()).unwrap();
+        assert_eq!(MIGRATIONS.len(), db.init_schema().unwrap().len());
+        assert_eq!(Vec::<String>::new(), db.verify_schema().unwrap());
+        let pool = db.insert_resource_pool(&schema, IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        assert_eq!(pool, db.get_resource_pool_by_name(&schema).unwrap());
+        // ids of resources do not overflow 32 bits
+        db.client.execute("SELECT setval('resources_id_seq', 4294967296)", &[]).unwrap();
+        let (pool, _) = db.insert_resources(pool.clone(), vec![Resource::new_from_value(pool.id, json!({"n": 1}))])
+            .unwrap();
+        let id = db.get_resources(pool.id).unwrap()[0].id.unwrap();
+        assert_eq!(4294967297, id);
+        assert_eq!(json!({"n": 1}), db.get_resource(id).unwrap().value);
+        // neither do ids of pools
+        db.client.execute("SELECT setval('resource_pools_id_seq', 4294967296)", &[]).unwrap();
+        let pool = db.insert_resource_pool(&format!("{}-large", schema), IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        assert_eq!(4294967297, pool.id);
+        let (pool, _) = db.insert_resources(pool.clone(), vec![Resource::new_from_value(pool.id, json!({"n": 1}))])
+            .unwrap();
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+
+        let mut default = DB::new_from_env().unwrap();
+        assert!(default.get_resource_pool_by_name(&schema).is_err());
+        default.client.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema)).unwrap();
+    }
+}
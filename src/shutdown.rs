@@ -0,0 +1,79 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+use tracing::*;
+
+// Set by the signal handler, which must not allocate or lock.
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(signal: libc::c_int) {
+    if SIGNALLED.swap(true, Ordering::SeqCst) {
+        // the second signal does not wait for the drain
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+/// Request to stop, checked by the server and workers between requests and ticks, so that in-flight
+/// transactions and scripts finish.
+#[derive(Debug, Clone, Copy)]
+pub struct Shutdown(&'static AtomicBool);
+
+impl Shutdown {
+    // Only requested by `request`, e.g. in tests.
+    pub fn new() -> Shutdown {
+        Shutdown(Box::leak(Box::new(AtomicBool::new(false))))
+    }
+
+    // Requested by SIGTERM or SIGINT, a second signal exits immediately.
+    pub fn on_signals() -> Result<Shutdown> {
+        for signal in [libc::SIGTERM, libc::SIGINT] {
+            let handler = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                bail!("Cannot install handler of signal {}", signal);
+            }
+        }
+        Ok(Shutdown(&SIGNALLED))
+    }
+
+    #[cfg(test)]
+    pub fn request(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // Sleeps for the duration or until shutdown is requested.
+    pub fn sleep(&self, duration: Duration) {
+        const SLICE: Duration = Duration::from_millis(100);
+        let mut remaining = duration;
+        while !self.is_requested() && remaining > Duration::ZERO {
+            thread::sleep(remaining.min(SLICE));
+            remaining = remaining.saturating_sub(SLICE);
+        }
+    }
+
+    // Exits the process if it is still running `drain_timeout` after shutdown was requested.
+    pub fn exit_after(&self, drain_timeout: Duration) -> Result<()> {
+        let shutdown = *self;
+        thread::Builder::new().name("drain".to_owned()).spawn(move || {
+            while !shutdown.is_requested() {
+                thread::sleep(Duration::from_millis(100));
+            }
+            info!("Shutting down, waiting up to {:?} for in-flight work", drain_timeout);
+            thread::sleep(drain_timeout);
+            warn!("In-flight work did not finish within {:?}, exiting", drain_timeout);
+            std::process::exit(1);
+        })?;
+        Ok(())
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Shutdown {
+        Shutdown::new()
+    }
+}
@@ -0,0 +1,275 @@
+use std::time::SystemTime;
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Utc};
+use postgres::{Client, Row};
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{DB, Resource, ResourcePool, ResourceState};
+
+/// Copy of a pool and its resources in use, created by `DB::snapshot_pool`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolSnapshot {
+    pub id: i32,
+    // None if the pool was deleted since
+    pub resource_pool_id: Option<i64>,
+    pub label: String,
+    pub version: i32,
+    // `ResourcePool::as_export_json`
+    pub pool: Value,
+    pub created_at: SystemTime,
+    pub resource_count: i64,
+}
+
+impl PoolSnapshot {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "resourcePool": self.resource_pool_id,
+            "label": &self.label,
+            "version": self.version,
+            "pool": &self.pool,
+            "createdAt": DateTime::<Utc>::from(self.created_at).to_rfc3339(),
+            "resourceCount": self.resource_count,
+        })
+    }
+}
+
+/// How `DB::restore_snapshot` applies a snapshot.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreMode {
+    // retire resources of the snapshotted pool and insert the snapshotted ones instead
+    Replace,
+    // create a new top level pool with the given name
+    NewPool(String),
+}
+
+impl DB {
+    const POOL_SNAPSHOT_COLUMNS: &'static str = "id, resource_pool, label, version, pool, created_at, \
+        (SELECT count(*) FROM pool_snapshot_resources WHERE snapshot = pool_snapshots.id)";
+
+    // The pool row is locked for the duration of the snapshot, so that no allocation can change it meanwhile.
+    pub fn snapshot_pool(&mut self, resource_pool_id: i64, label: &str) -> Result<PoolSnapshot> {
+        let mut transaction = self.client.transaction()?;
+        let pool = transaction.query_one(
+            format!("SELECT {} FROM resource_pools WHERE id=$1 FOR SHARE", Self::RESOURCE_POOL_COLUMNS).as_str(),
+            &[&resource_pool_id])?;
+        let pool = Self::row_to_resource_pool(pool)?;
+        let resources = transaction.query(
+            format!("SELECT {} FROM resources WHERE resource_pool=$1 AND status <> 'retired' ORDER BY id",
+                    Self::RESOURCE_COLUMNS).as_str(),
+            &[&resource_pool_id])?
+            .into_iter()
+            .map(|row| Self::row_to_resource(resource_pool_id, row).map(|it| it.as_export_json()))
+            .collect::<Result<Vec<Value>>>()?;
+        let id: i32 = transaction.query_one(
+            "INSERT INTO pool_snapshots (resource_pool, label, version, pool) VALUES ($1, $2, $3, $4) RETURNING id",
+            &[&pool.id, &label, &pool.version, &pool.as_export_json()])?.get(0);
+        transaction.execute(
+            "INSERT INTO pool_snapshot_resources (snapshot, resource) SELECT $1, unnest($2::jsonb[])",
+            &[&id, &resources])?;
+        Self::record_audit(&mut transaction, Some(pool.id), "snapshot_created",
+                           json!({"snapshot": id, "label": label, "version": pool.version}))?;
+        transaction.commit()?;
+        debug!("Snapshot {} of pool {} contains {} resources", id, pool.id, resources.len());
+        self.get_snapshot(id)
+    }
+
+    pub fn get_snapshot(&mut self, snapshot_id: i32) -> Result<PoolSnapshot> {
+        let row = self.client.query_opt(
+            format!("SELECT {} FROM pool_snapshots WHERE id=$1", Self::POOL_SNAPSHOT_COLUMNS).as_str(),
+            &[&snapshot_id])?
+            .ok_or_else(|| anyhow!("Snapshot {} not found", snapshot_id))?;
+        Ok(Self::row_to_pool_snapshot(row))
+    }
+
+    pub fn list_snapshots(&mut self, resource_pool_id: i64) -> Result<Vec<PoolSnapshot>> {
+        let rows = self.client.query(
+            format!("SELECT {} FROM pool_snapshots WHERE resource_pool=$1 ORDER BY id",
+                    Self::POOL_SNAPSHOT_COLUMNS).as_str(),
+            &[&resource_pool_id])?;
+        Ok(rows.into_iter().map(Self::row_to_pool_snapshot).collect())
+    }
+
+    // Resources of the snapshot as `Resource::as_export_json`, ordered by id.
+    pub fn get_snapshot_resources(&mut self, snapshot_id: i32) -> Result<Vec<Value>> {
+        let rows = self.client.query(
+            "SELECT resource FROM pool_snapshot_resources WHERE snapshot=$1 ORDER BY (resource->>'id')::bigint",
+            &[&snapshot_id])?;
+        Ok(rows.into_iter().map(|row| row.get(0)).collect())
+    }
+
+    // Properties, safety period and resources of the pool are set to the snapshotted ones in a single transaction.
+    // Restored resources get new ids.
+    pub fn restore_snapshot(&mut self, snapshot_id: i32, mode: &RestoreMode) -> Result<ResourcePool> {
+        let snapshot = self.get_snapshot(snapshot_id)?;
+        let resources = self.get_snapshot_resources(snapshot_id)?;
+        let mut transaction = self.client.transaction()?;
+        let mut pool = match mode {
+            RestoreMode::Replace => {
+                let pool_id = snapshot.resource_pool_id
+                    .ok_or_else(|| anyhow!("Pool of snapshot {} was deleted", snapshot_id))?;
+                let pool = transaction.query_one(
+                    format!("SELECT {} FROM resource_pools WHERE id=$1 FOR UPDATE",
+                            Self::RESOURCE_POOL_COLUMNS).as_str(),
+                    &[&pool_id])?;
+                let pool = Self::row_to_resource_pool(pool)?;
+                let retired = transaction.query(
+                    Self::update_state_sql("resource_pool=$1 AND status <> 'retired'").as_str(),
+                    &[&pool.id, &ResourceState::Retired.as_str(), &(pool.deallocation_safety_period as f64)])?;
+                debug!("Retired {} resources of pool {} before restoring snapshot {}",
+                       retired.len(), pool.id, snapshot_id);
+                pool
+            }
+            RestoreMode::NewPool(name) => {
+                let allocation_strategy_id = snapshot.pool["allocationStrategyId"].as_i64()
+                    .ok_or_else(|| anyhow!("Snapshot {} does not contain allocation strategy", snapshot_id))?;
+                Self::insert_pool_row(&mut transaction, name, allocation_strategy_id, None,
+                                     Some(&snapshot.pool["properties"]))?
+            }
+        };
+        pool.properties = snapshot.pool["properties"].clone();
+        pool.deallocation_safety_period = snapshot.pool["deallocationSafetyPeriod"].as_i64()
+            .ok_or_else(|| anyhow!("Snapshot {} does not contain deallocation safety period", snapshot_id))? as i32;
+        transaction.execute(
+            "UPDATE resource_pools SET properties=$1, deallocation_safety_period=$2 WHERE id=$3",
+            &[&pool.properties, &pool.deallocation_safety_period, &pool.id])?;
+        Self::rebuild_free_ranges(&mut transaction, pool.id)?;
+
+        let resources = resources.into_iter()
+            .map(|exported| Resource::restore_from_export_json(pool.id, exported))
+            .collect::<Result<Vec<Resource>>>()
+            .context(format!("Cannot restore snapshot {}", snapshot_id))?;
+        Self::insert_exported_resources(&mut transaction, pool.id, &resources)?;
+        Self::bump_version(&mut transaction, &mut pool)?;
+        Self::record_audit(&mut transaction, Some(pool.id), "snapshot_restored",
+                           json!({"snapshot": snapshot_id, "label": &snapshot.label,
+                           "replace": *mode == RestoreMode::Replace}))?;
+        transaction.commit()?;
+        debug!("Restored {} resources of snapshot {} into pool {}", resources.len(), snapshot_id, pool.id);
+        Ok(pool)
+    }
+
+    // Inserts resources with the state, timestamps and metadata they were exported with, in a single statement.
+    // Resources without an id get a new one, those without timestamps count as created now.
+    pub(crate) fn insert_exported_resources(client: &mut Client, resource_pool_id: i64,
+                                            resources: &[Resource]) -> Result<()> {
+        let ids = resources.iter().map(|it| it.id).collect::<Vec<_>>();
+        let values = resources.iter().map(|it| it.value.clone()).collect::<Vec<Value>>();
+        let states = resources.iter().map(|it| it.state.as_str()).collect::<Vec<&str>>();
+        let lease_expires_at = resources.iter().map(|it| it.lease_expires_at).collect::<Vec<_>>();
+        let quarantined_until = resources.iter().map(|it| it.quarantined_until).collect::<Vec<_>>();
+        let deleted_at = resources.iter().map(|it| it.deleted_at).collect::<Vec<_>>();
+        let metadata = resources.iter().map(|it| it.metadata.clone()).collect::<Vec<Value>>();
+        let owners = resources.iter().map(|it| it.owner.as_deref()).collect::<Vec<_>>();
+        let descriptions = resources.iter().map(|it| it.description.as_deref()).collect::<Vec<_>>();
+        let created_at = resources.iter().map(|it| it.created_at).collect::<Vec<_>>();
+        let updated_at = resources.iter().map(|it| it.updated_at).collect::<Vec<_>>();
+        let canonical_values = resources.iter().map(|it| it.canonical_value.clone()).collect::<Vec<_>>();
+        client.execute(
+            "INSERT INTO resources (id, resource_pool, value, status, lease_expires_at, quarantined_until, deleted_at, \
+            metadata, owner, description, created_at, updated_at, canonical_value) \
+            SELECT coalesce(id, nextval('resources_id_seq')), \
+            $1, value, status, lease_expires_at, quarantined_until, deleted_at, metadata, owner, description, \
+            coalesce(created_at, now()), coalesce(updated_at, now()), canonical_value \
+            FROM unnest($2::bigint[], $3::jsonb[], $4::text[], $5::timestamptz[], $6::timestamptz[], \
+            $7::timestamptz[], $8::jsonb[], $9::text[], $10::text[], $11::timestamptz[], $12::timestamptz[], \
+            $13::jsonb[]) AS r(id, value, status, lease_expires_at, quarantined_until, deleted_at, metadata, owner, \
+            description, created_at, updated_at, canonical_value)",
+            &[&resource_pool_id, &ids, &values, &states, &lease_expires_at, &quarantined_until, &deleted_at,
+                &metadata, &owners, &descriptions, &created_at, &updated_at, &canonical_values])?;
+        Ok(())
+    }
+
+    fn row_to_pool_snapshot(row: Row) -> PoolSnapshot {
+        PoolSnapshot {
+            id: row.get(0),
+            resource_pool_id: row.get(1),
+            label: row.get(2),
+            version: row.get(3),
+            pool: row.get(4),
+            created_at: row.get(5),
+            resource_count: row.get(6),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{BulkSelector, ResourceSelector};
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_snapshot_pool() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let resources = vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"})),
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.2"})),
+        ];
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let snapshot = db.snapshot_pool(pool.id, "before cleanup").unwrap();
+        assert_eq!(Some(pool.id), snapshot.resource_pool_id);
+        assert_eq!(pool.version, snapshot.version);
+        assert_eq!(pool.as_export_json(), snapshot.pool);
+        assert_eq!(2, snapshot.resource_count);
+
+        db.insert_resources(pool.clone(), vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.3"}))]).unwrap();
+        let values = db.get_snapshot_resources(snapshot.id).unwrap().into_iter()
+            .map(|it| it["value"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(vec![json!({"address": "10.0.0.1"}), json!({"address": "10.0.0.2"})], values);
+        assert_eq!(vec![snapshot], db.list_snapshots(pool.id).unwrap());
+        assert!(db.get_snapshot(-1).is_err());
+    }
+
+    #[test]
+    fn db_restore_snapshot() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let pool = db.set_deallocation_safety_period(pool, 60).unwrap();
+        let resources = vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"})),
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.2"})),
+        ];
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        let (pool, _) = db.deallocate_resource(pool, &ResourceSelector::Value(json!({"address": "10.0.0.2"})))
+            .unwrap();
+        let snapshot = db.snapshot_pool(pool.id, "before cleanup").unwrap();
+        let summary = |resources: Vec<Resource>| resources.into_iter()
+            .filter(|it| it.state != ResourceState::Retired)
+            .map(|it| (it.value["address"].clone(), it.state, it.quarantined_until.is_some()))
+            .collect::<Vec<_>>();
+        let expected = vec![
+            (json!("10.0.0.1"), ResourceState::Allocated, false),
+            (json!("10.0.0.2"), ResourceState::Bench, true),
+        ];
+
+        let (pool, _) = db.deallocate_resources(pool, &BulkSelector::State(ResourceState::Allocated)).unwrap();
+        let pool = db.set_deallocation_safety_period(pool, 0).unwrap();
+        let restored = db.restore_snapshot(snapshot.id, &RestoreMode::Replace).unwrap();
+        assert_eq!(pool.id, restored.id);
+        assert_eq!(pool.version + 1, restored.version);
+        assert_eq!(60, restored.deallocation_safety_period);
+        assert_eq!(restored, db.get_resource_pool_by_id(pool.id).unwrap());
+        assert_eq!(expected, summary(db.get_resources(pool.id).unwrap()));
+        assert_eq!("snapshot_restored", db.get_audit_log(pool.id, 1).unwrap()[0].action);
+
+        let name = format!("{}-copy", pool.name);
+        let copy = db.restore_snapshot(snapshot.id, &RestoreMode::NewPool(name.clone())).unwrap();
+        assert_ne!(pool.id, copy.id);
+        assert_eq!(name, copy.name);
+        assert_eq!(pool.properties, copy.properties);
+        assert_eq!(expected, summary(db.get_resources(copy.id).unwrap()));
+        // original pool is untouched
+        assert_eq!(2, db.count_resources(pool.id).unwrap());
+        assert!(db.restore_snapshot(snapshot.id, &RestoreMode::NewPool(name)).is_err());
+    }
+}
@@ -0,0 +1,307 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow, ensure};
+use rusqlite::types::Type;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::error::AllocationError;
+use crate::state::ResourceState;
+use crate::storage::Storage;
+use crate::{DB, Resource, ResourcePool, ResourceSelector};
+
+// Values are stored as JSON text, compared by `json()` of JSON1. Times are seconds since the epoch.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS allocation_strategies (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        script TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS resource_pools (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        version INTEGER NOT NULL DEFAULT 0,
+        resource_pool_allocation_strategy INTEGER NOT NULL REFERENCES allocation_strategies (id),
+        deallocation_safety_period INTEGER NOT NULL DEFAULT 0,
+        properties TEXT NOT NULL DEFAULT '{\"address\": \"10.0.0.0\", \"prefix\": 8}' CHECK (json_valid(properties)),
+        created_at REAL NOT NULL,
+        updated_at REAL NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS resources (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        resource_pool INTEGER NOT NULL REFERENCES resource_pools (id),
+        value TEXT NOT NULL CHECK (json_valid(value)),
+        status TEXT NOT NULL,
+        lease_expires_at REAL,
+        quarantined_until REAL,
+        deleted_at REAL,
+        metadata TEXT NOT NULL DEFAULT '{}' CHECK (json_valid(metadata)),
+        owner TEXT,
+        description TEXT,
+        created_at REAL NOT NULL,
+        updated_at REAL NOT NULL
+    );
+    -- a value is in use at most once per pool, as in `resources_in_use` of Postgres
+    CREATE UNIQUE INDEX IF NOT EXISTS resources_in_use ON resources (resource_pool, json(value))
+        WHERE status <> 'retired';
+";
+
+const NOW: &str = "((julianday('now') - 2440587.5) * 86400.0)";
+
+const RESOURCE_POOL_COLUMNS: &str =
+    "id, name, version, resource_pool_allocation_strategy, deallocation_safety_period, properties, created_at, \
+    updated_at";
+
+const RESOURCE_COLUMNS: &str =
+    "id, value, status, lease_expires_at, quarantined_until, deleted_at, metadata, owner, description, created_at, \
+    updated_at";
+
+/// `Storage` in a SQLite file, for labs and demos without Postgres. Built with the `sqlite` feature.
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+impl SqliteStorage {
+    // Creates the schema if the file is new, `:memory:` opens a database that lives as long as the storage.
+    pub fn open(path: &str) -> Result<SqliteStorage> {
+        let connection = Connection::open(path).context(format!("Cannot open SQLite database '{}'", path))?;
+        connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+        connection.execute_batch(SCHEMA).context("Cannot create the SQLite schema")?;
+        debug!("Opened SQLite database '{}'", path);
+        Ok(SqliteStorage { connection })
+    }
+
+    fn find_resource_pool(connection: &Connection, condition: &str, param: &dyn rusqlite::ToSql)
+                          -> Result<Option<ResourcePool>> {
+        let found = connection.query_row(
+            &format!("SELECT {} FROM resource_pools WHERE {}", RESOURCE_POOL_COLUMNS, condition), [param],
+            Self::row_to_resource_pool).optional()?;
+        Ok(found)
+    }
+
+    fn row_to_resource_pool(row: &Row) -> rusqlite::Result<ResourcePool> {
+        Ok(ResourcePool {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            version: row.get(2)?,
+            allocation_strategy_id: row.get(3)?,
+            deallocation_safety_period: row.get(4)?,
+            parent_id: None,
+            properties: row.get(5)?,
+            tenant: None,
+            tags: vec![],
+            archived_at: None,
+            default_user_input: json!({}),
+            created_at: to_time(row.get(6)?),
+            updated_at: to_time(row.get(7)?),
+            script_overridden: false,
+        })
+    }
+
+    fn row_to_resource(resource_pool_id: i64, row: &Row) -> rusqlite::Result<Resource> {
+        let state = row.get::<_, String>(2)?.parse()
+            .map_err(|err: anyhow::Error| rusqlite::Error::FromSqlConversionFailure(2, Type::Text, err.into()))?;
+        Ok(Resource {
+            id: row.get(0)?,
+            resource_pool_id,
+            value: row.get(1)?,
+            state,
+            lease_expires_at: row.get::<_, Option<f64>>(3)?.map(to_time),
+            quarantined_until: row.get::<_, Option<f64>>(4)?.map(to_time),
+            deleted_at: row.get::<_, Option<f64>>(5)?.map(to_time),
+            metadata: row.get(6)?,
+            owner: row.get(7)?,
+            description: row.get(8)?,
+            created_at: Some(to_time(row.get(9)?)),
+            updated_at: Some(to_time(row.get(10)?)),
+            canonical_value: None,
+        })
+    }
+
+    // As `DB::bump_version`, fails with `AllocationError::VersionConflict` if the pool was changed concurrently.
+    fn bump_version(connection: &Connection, pool: &mut ResourcePool) -> Result<()> {
+        let expected_current_version = pool.version;
+        let updated = connection.query_row(
+            &format!("UPDATE resource_pools SET version=?1, updated_at={} WHERE id=?2 AND version=?3 \
+                RETURNING updated_at", NOW),
+            params![expected_current_version + 1, pool.id, expected_current_version],
+            |row| row.get::<_, f64>(0)).optional()?
+            .ok_or_else(|| AllocationError::VersionConflict {
+                resource_pool: pool.name.clone(),
+                expected: expected_current_version,
+            })?;
+        pool.version += 1;
+        pool.updated_at = to_time(updated);
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn insert_allocation_strategy(&mut self, name: &str, script: &str) -> Result<i64> {
+        self.connection.execute("INSERT INTO allocation_strategies (name, script) VALUES (?1, ?2)",
+                                params![name, script])?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    fn get_allocation_script(&mut self, id: i64) -> Result<String> {
+        self.connection.query_row("SELECT script FROM allocation_strategies WHERE id=?1", [id], |row| row.get(0))
+            .optional()?
+            .ok_or_else(|| anyhow!("Allocation strategy {} not found", id))
+    }
+
+    fn insert_resource_pool_with_properties(&mut self, name: &str, allocation_strategy_id: i64,
+                                            properties: Option<Value>) -> Result<ResourcePool> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            &format!("INSERT INTO resource_pools (name, resource_pool_allocation_strategy, created_at, updated_at) \
+                VALUES (?1, ?2, {}, {})", NOW, NOW),
+            params![name, allocation_strategy_id])
+            .context(format!("Cannot insert resource pool '{}'", name))?;
+        let id = transaction.last_insert_rowid();
+        if let Some(properties) = properties {
+            transaction.execute("UPDATE resource_pools SET properties=?1 WHERE id=?2", params![properties, id])?;
+        }
+        let pool = Self::find_resource_pool(&transaction, "id=?1", &id)?
+            .ok_or_else(|| anyhow!("Inserted resource pool '{}' not found", name))?;
+        transaction.commit()?;
+        Ok(pool)
+    }
+
+    fn find_resource_pool_by_name(&mut self, name: &str) -> Result<Option<ResourcePool>> {
+        Self::find_resource_pool(&self.connection, "name=?1", &name)
+    }
+
+    fn delete_resource_pool(&mut self, mut pool: ResourcePool) -> Result<()> {
+        let transaction = self.connection.transaction()?;
+        Self::bump_version(&transaction, &mut pool)?;
+        let in_use: i64 = transaction.query_row(
+            "SELECT count(*) FROM resources WHERE resource_pool=?1 AND status <> 'retired'", [pool.id],
+            |row| row.get(0))?;
+        ensure!(in_use == 0, "Resource pool '{}' still has {} resources in use", pool.name, in_use);
+        transaction.execute("DELETE FROM resources WHERE resource_pool=?1", [pool.id])?;
+        transaction.execute("DELETE FROM resource_pools WHERE id=?1", [pool.id])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn get_resources(&mut self, resource_pool_id: i64) -> Result<Vec<Resource>> {
+        let mut statement = self.connection.prepare(&format!(
+            "SELECT {} FROM resources WHERE resource_pool=?1 AND status <> 'retired' ORDER BY id", RESOURCE_COLUMNS))?;
+        let resources = statement.query_map([resource_pool_id], |row| Self::row_to_resource(resource_pool_id, row))?
+            .collect::<rusqlite::Result<Vec<Resource>>>()?;
+        debug!("Found {} resources of pool {}", resources.len(), resource_pool_id);
+        Ok(resources)
+    }
+
+    fn insert_resources(&mut self, mut pool: ResourcePool, items: Vec<Resource>)
+                        -> Result<(ResourcePool, Vec<Resource>)> {
+        ensure!(!items.is_empty(), "Cannot insert zero resources");
+        let transaction = self.connection.transaction()?;
+        let mut inserted = Vec::with_capacity(items.len());
+        for resource in &items {
+            ensure!(resource.resource_pool_id == pool.id, "Wrong resource id");
+            ensure!(resource.state.is_initial(), "Cannot insert resource in state {}", resource.state);
+            let row = transaction.query_row(
+                &format!("INSERT INTO resources (resource_pool, value, status, lease_expires_at, owner, description, \
+                    metadata, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, {}, {}) RETURNING {}",
+                         NOW, NOW, RESOURCE_COLUMNS),
+                params![pool.id, resource.value, resource.state.as_str(), resource.lease_expires_at.map(from_time),
+                    resource.owner, resource.description, resource.metadata],
+                |row| Self::row_to_resource(pool.id, row))
+                .context(format!("Cannot insert resource {} into pool '{}'", resource.value, pool.name))?;
+            inserted.push(row);
+        }
+        Self::bump_version(&transaction, &mut pool)?;
+        transaction.commit()?;
+        trace!("Inserted {} resources", inserted.len());
+        Ok((pool, inserted))
+    }
+
+    fn deallocate_resource(&mut self, mut pool: ResourcePool, selector: &ResourceSelector)
+                           -> Result<(ResourcePool, Resource)> {
+        let to = DB::deallocated_state(&pool);
+        let transaction = self.connection.transaction()?;
+        let (condition, param): (&str, &dyn rusqlite::ToSql) = match selector {
+            ResourceSelector::Id(id) => ("id=?2", id),
+            ResourceSelector::Value(value) => ("json(value)=json(?2)", value),
+        };
+        // a value can also belong to retired resources, prefer the one in use
+        let found = transaction.query_row(
+            &format!("SELECT {} FROM resources WHERE resource_pool=?1 AND {} \
+                ORDER BY status = 'retired', id DESC LIMIT 1", RESOURCE_COLUMNS, condition),
+            params![pool.id, param], |row| Self::row_to_resource(pool.id, row)).optional()?
+            .ok_or_else(|| AllocationError::ResourceNotFound {
+                resource_pool: pool.name.clone(),
+                resource: selector.to_string(),
+            })?;
+        if found.state == ResourceState::Claimed {
+            return Err(AllocationError::ResourceClaimed {
+                resource_pool: pool.name.clone(),
+                resource: selector.to_string(),
+            }.into());
+        }
+        if !found.state.can_transition_to(to) {
+            return Err(AllocationError::IllegalTransition {
+                resource: selector.to_string(),
+                from: found.state,
+                to,
+            }.into());
+        }
+        let resource = transaction.query_row(
+            &format!("UPDATE resources SET status=?2, \
+                quarantined_until = CASE \
+                    WHEN ?2 = 'bench' THEN {} + ?3 \
+                    WHEN ?2 = 'retired' THEN quarantined_until END, \
+                deleted_at = CASE WHEN ?2 = 'retired' THEN {} END, updated_at={} WHERE id=?1 RETURNING {}",
+                     NOW, NOW, NOW, RESOURCE_COLUMNS),
+            params![found.id, to.as_str(), pool.deallocation_safety_period],
+            |row| Self::row_to_resource(pool.id, row))?;
+        Self::bump_version(&transaction, &mut pool)?;
+        transaction.commit()?;
+        debug!("Resource {:?} of pool {} moved from {} to {}", resource.id, pool.id, found.state, to);
+        Ok((pool, resource))
+    }
+}
+
+fn to_time(seconds: f64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs_f64(seconds)
+}
+
+fn from_time(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::tests::allocate_and_deallocate;
+    use crate::tests::{IPV4_ALLOCATION_STRATEGY_ID, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn sqlite_allocate_and_deallocate() {
+        initialize_logging();
+
+        let script = DB::new_from_env().unwrap().get_allocation_script(IPV4_ALLOCATION_STRATEGY_ID).unwrap();
+        let mut storage = SqliteStorage::open(":memory:").unwrap();
+        let strategy_id = storage.insert_allocation_strategy("ipv4", &script).unwrap();
+        allocate_and_deallocate(&mut storage, strategy_id);
+    }
+
+    #[test]
+    fn sqlite_duplicate_values() {
+        initialize_logging();
+
+        let mut storage = SqliteStorage::open(":memory:").unwrap();
+        let strategy_id = storage.insert_allocation_strategy("none", "function invoke() { return []; }").unwrap();
+        let pool = storage.insert_resource_pool_with_properties("pool", strategy_id, None).unwrap();
+        assert_eq!(json!({"address": "10.0.0.0", "prefix": 8}), pool.properties);
+        let resource = Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"}));
+        let (pool, _) = storage.insert_resources(pool, vec![resource.clone()]).unwrap();
+        assert!(storage.insert_resources(pool.clone(), vec![resource.clone()]).is_err());
+        let (pool, _) = storage.deallocate_resource(pool, &ResourceSelector::Value(resource.value.clone())).unwrap();
+        let (pool, inserted) = storage.insert_resources(pool, vec![resource]).unwrap();
+        assert_eq!(3, pool.version);
+        assert_eq!(vec![inserted[0].clone()], storage.get_resources(pool.id).unwrap());
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde_json::Value;
+use tracing::*;
+
+use crate::DB;
+use crate::shutdown::Shutdown;
+
+pub const CONTENT_TYPE: &str = "text/event-stream";
+// Comments are sent on idle connections, which keeps proxies from closing them and detects clients that went away.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+// How long the pushing thread waits for events before checking shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Milliseconds clients wait before reconnecting.
+const RETRY_MS: u64 = 1000;
+// Missed events are read from the audit log in pages of this size.
+const REPLAY_PAGE_SIZE: i64 = 1000;
+
+fn write_event(stream: &mut TcpStream, event: &Value) -> Result<()> {
+    write!(stream, "id: {}\nevent: {}\ndata: {}\n\n", event["id"], event["event"].as_str().unwrap_or("message"),
+           event)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Answers the request with an event stream of the pool. Events recorded after `last_event_id` are replayed
+// from the audit log first, then events are pushed as they are committed on a thread of its own, until the client
+// goes away or shutdown is requested. `events` must be subscribed before, so that no event is lost in between.
+pub fn serve_events(db: &mut DB, mut stream: TcpStream, resource_pool_id: i64, last_event_id: Option<i64>,
+                    events: Receiver<Value>, shutdown: Shutdown) -> Result<()> {
+    write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n\
+        retry: {}\n\n", CONTENT_TYPE, RETRY_MS)?;
+    stream.flush()?;
+    let mut last_sent = last_event_id;
+    while let Some(after_id) = last_sent {
+        let replayed = db.get_pool_events(resource_pool_id, after_id, REPLAY_PAGE_SIZE)?;
+        for event in &replayed {
+            write_event(&mut stream, event)?;
+            last_sent = event["id"].as_i64();
+        }
+        if (replayed.len() as i64) < REPLAY_PAGE_SIZE {
+            break;
+        }
+    }
+    thread::Builder::new().name("sse".to_owned()).spawn(move || {
+        if let Err(err) = push_events(&mut stream, last_sent, &events, shutdown) {
+            debug!("Event stream closed: {:#}", err);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    })?;
+    Ok(())
+}
+
+fn push_events(stream: &mut TcpStream, mut last_sent: Option<i64>, events: &Receiver<Value>, shutdown: Shutdown)
+               -> Result<()> {
+    let mut idle_since = Instant::now();
+    while !shutdown.is_requested() {
+        match events.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => {
+                // events committed while replaying were received as well
+                let id = event["id"].as_i64();
+                if last_sent.is_some() && id <= last_sent {
+                    continue;
+                }
+                write_event(stream, &event)?;
+                last_sent = id;
+                idle_since = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) if idle_since.elapsed() >= KEEP_ALIVE_INTERVAL => {
+                write!(stream, ": keep-alive\n\n")?;
+                stream.flush()?;
+                idle_since = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
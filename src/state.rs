@@ -0,0 +1,99 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+
+/// Lifecycle of a resource, stored in the `status` column.
+///
+/// `reserved → allocated → claimed → bench → retired`, where `bench` is the quarantine of
+/// pools with a deallocation safety period and `retired` resources are soft deleted.
+/// Retired resources are hidden from strategies and purged by `pool gc` after retention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ResourceState {
+    Reserved,
+    Allocated,
+    Claimed,
+    Bench,
+    Retired,
+}
+
+impl ResourceState {
+    pub const ALL: [ResourceState; 5] = [ResourceState::Reserved, ResourceState::Allocated,
+        ResourceState::Claimed, ResourceState::Bench, ResourceState::Retired];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResourceState::Reserved => "reserved",
+            ResourceState::Allocated => "allocated",
+            ResourceState::Claimed => "claimed",
+            ResourceState::Bench => "bench",
+            ResourceState::Retired => "retired",
+        }
+    }
+
+    /// States a resource can be inserted with.
+    pub fn is_initial(&self) -> bool {
+        matches!(self, ResourceState::Reserved | ResourceState::Allocated)
+    }
+
+    pub fn can_transition_to(&self, to: ResourceState) -> bool {
+        use ResourceState::*;
+        match (self, to) {
+            (Reserved, Allocated) | (Reserved, Retired) => true,
+            (Allocated, Claimed) | (Allocated, Bench) | (Allocated, Retired) => true,
+            (Claimed, Bench) | (Claimed, Retired) => true,
+            (Bench, Retired) => true,
+            // undo of a deallocation, until the resource is purged
+            (Bench, Allocated) | (Retired, Allocated) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ResourceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ResourceState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ResourceState> {
+        ResourceState::ALL.iter()
+            .find(|state| state.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown resource state '{}'", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::ResourceState::*;
+
+    #[test]
+    fn state_roundtrip() {
+        for state in ResourceState::ALL.iter() {
+            assert_eq!(*state, state.as_str().parse::<ResourceState>().unwrap());
+        }
+        assert!("free".parse::<ResourceState>().is_err());
+    }
+
+    #[test]
+    fn state_transitions() {
+        assert!(Reserved.can_transition_to(Allocated));
+        assert!(Allocated.can_transition_to(Claimed));
+        assert!(Claimed.can_transition_to(Bench));
+        assert!(Bench.can_transition_to(Retired));
+        assert!(Retired.can_transition_to(Allocated));
+
+        assert!(!Retired.can_transition_to(Retired));
+        assert!(!Bench.can_transition_to(Bench));
+        assert!(!Claimed.can_transition_to(Reserved));
+        assert!(!Retired.can_transition_to(Claimed));
+        for state in ResourceState::ALL.iter() {
+            assert!(!state.can_transition_to(Reserved));
+        }
+    }
+}
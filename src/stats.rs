@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Result, anyhow, ensure};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+use tracing::*;
+
+use crate::{DB, ResourcePool, WasmerEnv, metrics};
+
+// Samples taken within it give the recent allocation rate of a pool, see `DB::predict_exhaustion`.
+const PREDICTION_WINDOW: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Utilization of a pool at one point in time, recorded by `DB::record_pool_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStatsSample {
+    pub sampled_at: SystemTime,
+    // resources that are not retired
+    pub in_use: i64,
+    // number of resources by state, states without resources are omitted
+    pub by_state: Value,
+}
+
+impl PoolStatsSample {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "sampledAt": DateTime::<Utc>::from(self.sampled_at).to_rfc3339(),
+            "inUse": self.in_use,
+            "byState": &self.by_state,
+        })
+    }
+}
+
+// Change of a pool between its oldest and newest sample within `PREDICTION_WINDOW`.
+struct PoolGrowth {
+    resource_pool_id: i64,
+    // resources that are not retired in the newest sample
+    in_use: i64,
+    // resources per second, negative if the pool shrinks
+    rate: f64,
+}
+
+// Parses a range like `90s`, `30m`, `24h` or `7d`.
+pub fn parse_range(range: &str) -> Result<Duration> {
+    let invalid = || anyhow!("Invalid range '{}', expected a number followed by s, m, h or d, e.g. 7d", range);
+    let unit = range.chars().last().ok_or_else(invalid)?;
+    let seconds = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 24 * 3600,
+        _ => return Err(invalid()),
+    };
+    let amount: u64 = range[..range.len() - 1].parse().map_err(|_| invalid())?;
+    ensure!(amount > 0, "Range '{}' must be positive", range);
+    Ok(Duration::from_secs(amount * seconds))
+}
+
+impl DB {
+    // Samples every pool in one statement, then purges samples older than retention.
+    // Returns number of sampled pools.
+    pub fn record_pool_stats(&mut self, retention: Duration) -> Result<u64> {
+        let mut transaction = self.client.transaction()?;
+        let sampled = transaction.execute(
+            "INSERT INTO pool_stats_history (resource_pool, in_use, by_state) \
+            SELECT resource_pools.id, \
+            coalesce(sum(counts.count) FILTER (WHERE counts.status <> 'retired'), 0), \
+            coalesce(jsonb_object_agg(counts.status, counts.count) FILTER (WHERE counts.status IS NOT NULL), '{}') \
+            FROM resource_pools LEFT JOIN \
+            (SELECT resource_pool, status, count(*) FROM resources GROUP BY resource_pool, status) counts \
+            ON counts.resource_pool = resource_pools.id \
+            GROUP BY resource_pools.id", &[])?;
+        transaction.execute(
+            "DELETE FROM pool_stats_history WHERE sampled_at < now() - make_interval(secs => $1)",
+            &[&retention.as_secs_f64()])?;
+        transaction.commit()?;
+        Ok(sampled)
+    }
+
+    // Samples of the pool taken within the range, oldest first.
+    pub fn get_pool_stats(&mut self, resource_pool_id: i64, range: Duration) -> Result<Vec<PoolStatsSample>> {
+        let rows = self.reader().query(
+            "SELECT sampled_at, in_use, by_state FROM pool_stats_history \
+            WHERE resource_pool=$1 AND sampled_at >= now() - make_interval(secs => $2) ORDER BY sampled_at, id",
+            &[&resource_pool_id, &range.as_secs_f64()])?;
+        Ok(rows.into_iter()
+            .map(|row| PoolStatsSample { sampled_at: row.get(0), in_use: row.get(1), by_state: row.get(2) })
+            .collect())
+    }
+
+    // Growth of the pool, or of every pool if None, sampled at least twice within `PREDICTION_WINDOW`.
+    fn get_pool_growth(&mut self, resource_pool_id: Option<i64>) -> Result<Vec<PoolGrowth>> {
+        let rows = self.reader().query(
+            "SELECT resource_pool, (array_agg(in_use ORDER BY sampled_at DESC, id DESC))[1], \
+            (array_agg(in_use ORDER BY sampled_at DESC, id DESC))[1] - (array_agg(in_use ORDER BY sampled_at, id))[1], \
+            extract(epoch FROM max(sampled_at) - min(sampled_at))::float8 FROM pool_stats_history \
+            WHERE ($1::bigint IS NULL OR resource_pool=$1) AND sampled_at >= now() - make_interval(secs => $2) \
+            GROUP BY resource_pool HAVING max(sampled_at) > min(sampled_at)",
+            &[&resource_pool_id, &PREDICTION_WINDOW.as_secs_f64()])?;
+        Ok(rows.into_iter()
+            .map(|row| PoolGrowth {
+                resource_pool_id: row.get(0),
+                in_use: row.get(1),
+                rate: row.get::<_, i64>(2) as f64 / row.get::<_, f64>(3),
+            })
+            .collect())
+    }
+
+    // When the free capacity of the pool runs out if resources keep being allocated at the rate of the last
+    // 7 days of samples. None if the pool does not grow, was not sampled twice yet or its strategy does not
+    // define `capacity()`.
+    pub fn predict_exhaustion(&mut self, resource_pool_id: i64, wasmer_env: &mut WasmerEnv)
+                              -> Result<Option<SystemTime>> {
+        let growth = match self.get_pool_growth(Some(resource_pool_id))?.pop() {
+            Some(growth) => growth,
+            None => return Ok(None),
+        };
+        let pool = self.get_resource_pool_by_id(resource_pool_id)?;
+        self.exhaustion_of(&pool, &growth, wasmer_env)
+    }
+
+    fn exhaustion_of(&mut self, pool: &ResourcePool, growth: &PoolGrowth, wasmer_env: &mut WasmerEnv)
+                     -> Result<Option<SystemTime>> {
+        if growth.rate <= 0.0 {
+            return Ok(None);
+        }
+        let (utilized, free) = match self.pool_capacity(pool, wasmer_env)? {
+            Some(capacity) => capacity,
+            None => return Ok(None),
+        };
+        // a resource may take more than a unit of capacity, e.g. a subnet of an address pool
+        let per_resource = if growth.in_use > 0 && utilized > 0.0 { utilized / growth.in_use as f64 } else { 1.0 };
+        let remaining = Duration::try_from_secs_f64(free.max(0.0) / (growth.rate * per_resource)).ok();
+        Ok(remaining.and_then(|remaining| SystemTime::now().checked_add(remaining)))
+    }
+
+    // Predicts exhaustion of every growing pool and replaces the predictions exposed by
+    // `pool_exhaustion_timestamp_seconds`. Returns number of pools with a prediction.
+    pub fn record_exhaustion_predictions(&mut self, wasmer_env: &mut WasmerEnv) -> Result<u64> {
+        let mut predictions = BTreeMap::new();
+        for growth in self.get_pool_growth(None)? {
+            if growth.rate <= 0.0 {
+                continue;
+            }
+            let pool = match self.find_resource_pool_by_id(growth.resource_pool_id)? {
+                Some(pool) => pool,
+                None => continue,
+            };
+            match self.exhaustion_of(&pool, &growth, wasmer_env) {
+                Ok(Some(exhaustion)) => {
+                    predictions.insert(pool.id, exhaustion);
+                }
+                Ok(None) => {}
+                Err(err) => warn!("Cannot predict exhaustion of pool '{}': {:#}", pool.name, err),
+            }
+        }
+        let predicted = predictions.len() as u64;
+        metrics::set_exhaustion_predictions(predictions);
+        Ok(predicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Resource;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn stats_parse_range() {
+        assert_eq!(Duration::from_secs(7 * 24 * 3600), parse_range("7d").unwrap());
+        assert_eq!(Duration::from_secs(90), parse_range("90s").unwrap());
+        assert_eq!(Duration::from_secs(30 * 60), parse_range("30m").unwrap());
+        for invalid in ["", "d", "7", "7w", "-1h", "0d"] {
+            assert!(parse_range(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn db_record_pool_stats() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let range = Duration::from_secs(3600);
+        db.record_pool_stats(range).unwrap();
+        let resources = ["10.0.0.1", "10.0.0.2"].iter()
+            .map(|address| Resource::new_from_value(pool.id, json!({"address": address})))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        assert!(db.record_pool_stats(range).unwrap() >= 1);
+
+        // a worker running concurrently may sample the pool too
+        let samples = db.get_pool_stats(pool.id, range).unwrap()
+            .into_iter().map(|sample| (sample.in_use, sample.by_state)).collect::<Vec<_>>();
+        assert_eq!(Some(&(0, json!({}))), samples.first());
+        assert_eq!(Some(&(2, json!({"allocated": 2}))), samples.last());
+    }
+
+    #[test]
+    fn db_predict_exhaustion() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let pool = db.update_pool_properties(pool, &mut wasmer_env, json!({"address": "10.0.0.0", "prefix": 29}))
+            .unwrap();
+        assert_eq!(None, db.predict_exhaustion(pool.id, &mut wasmer_env).unwrap());
+        // other tests purge samples older than an hour
+        db.client.execute("INSERT INTO pool_stats_history (resource_pool, in_use, by_state, sampled_at) \
+                          VALUES ($1, 0, '{}', now() - interval '30 minutes')", &[&pool.id]).unwrap();
+        let resources = ["10.0.0.1", "10.0.0.2", "10.0.0.3"].iter()
+            .map(|address| Resource::new_from_value(pool.id, json!({"address": address})))
+            .collect();
+        let (pool, _) = db.insert_resources(pool, resources).unwrap();
+        db.record_pool_stats(PREDICTION_WINDOW).unwrap();
+
+        // half of the capacity was allocated within 30 minutes
+        let exhaustion = db.predict_exhaustion(pool.id, &mut wasmer_env).unwrap().unwrap();
+        let remaining = exhaustion.duration_since(SystemTime::now()).unwrap();
+        assert!(remaining > Duration::from_secs(29 * 60) && remaining < Duration::from_secs(31 * 60),
+                "{:?}", remaining);
+        assert!(db.record_exhaustion_predictions(&mut wasmer_env).unwrap() >= 1);
+        let line = format!("pool_exhaustion_timestamp_seconds{{pool=\"{}\"}} ", pool.id);
+        assert!(metrics::render().contains(&line), "{}", metrics::render());
+    }
+}
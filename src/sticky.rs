@@ -0,0 +1,71 @@
+use anyhow::{Result, anyhow, ensure};
+use serde_json::{Value, json};
+
+use crate::{DB, Resource};
+
+// Key of the requester given as `userInput.stickyKey`, e.g. the serial number of a device, None if missing.
+pub(crate) fn sticky_key(user_input: &Value) -> Result<Option<&str>> {
+    match &user_input["stickyKey"] {
+        Value::Null => Ok(None),
+        key => {
+            let key = key.as_str().ok_or_else(|| anyhow!("stickyKey must be a string, got {}", key))?;
+            ensure!(!key.is_empty(), "stickyKey must not be empty");
+            Ok(Some(key))
+        }
+    }
+}
+
+// Resources allocated for a sticky key keep it as `stickyKey` of their metadata.
+pub(crate) fn with_sticky_key(mut resources: Vec<Resource>, key: &str) -> Vec<Resource> {
+    for resource in &mut resources {
+        resource.metadata["stickyKey"] = json!(key);
+    }
+    resources
+}
+
+impl DB {
+    // Resources in use allocated for the sticky key, ordered by id. Read from the primary, as an allocation
+    // follows when there are none.
+    pub(crate) fn find_sticky_resources(&mut self, resource_pool_id: i64, key: &str) -> Result<Vec<Resource>> {
+        let rows = self.client.query(
+            format!("SELECT {} FROM resources WHERE resource_pool=$1 AND status <> 'retired' \
+                AND metadata @> $2 ORDER BY id", Self::RESOURCE_COLUMNS).as_str(),
+            &[&resource_pool_id, &json!({"stickyKey": key})])?;
+        rows.into_iter().map(|row| Self::row_to_resource(resource_pool_id, row)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AllocationOptions, ResourceSelector, WasmerEnv};
+    use crate::state::ResourceState;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_sticky_allocations() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let pool = create_random_pool(&mut db).unwrap();
+        let sticky = |key: &str| json!({"stickyKey": key});
+        let (pool, first) = db.allocate_resources(pool, &mut wasmer_env, sticky("device-1"), &options).unwrap();
+        assert_eq!(json!({"stickyKey": "device-1"}), first[0].metadata);
+        // the same resource without a new version
+        let (pool, again) = db.allocate_resources(pool, &mut wasmer_env, sticky("device-1"), &options).unwrap();
+        assert_eq!(first[0].value, again[0].value);
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+        let (pool, other) = db.allocate_resources(pool, &mut wasmer_env, sticky("device-2"), &options).unwrap();
+        assert_ne!(first[0].value, other[0].value);
+
+        // a new resource once the previous one is retired
+        let (pool, _) = db.transition_resource(pool, &ResourceSelector::Id(again[0].id.unwrap()),
+                                               ResourceState::Retired).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, sticky("device-1"), &options).unwrap();
+        assert_eq!(1, db.find_sticky_resources(pool.id, "device-1").unwrap().len());
+        assert_eq!(2, db.count_resources(pool.id).unwrap());
+        assert!(sticky_key(&json!({"stickyKey": 1})).is_err());
+    }
+}
@@ -0,0 +1,183 @@
+use anyhow::{Result, bail};
+use serde_json::Value;
+
+use crate::engine::StrategyEngine;
+use crate::strategy::StrategyFiles;
+use crate::{AllocationOptions, DB, Resource, ResourcePool, ResourceSelector, WasmerEnv};
+
+/// Strategies, pools and resources of the `lab` commands, stored by `DB` in Postgres or by `SqliteStorage`
+/// in a file. Only the core of the allocation is portable: hooks, sticky keys, free lists and the other features
+/// of `DB::allocate_resources` need Postgres.
+pub trait Storage {
+    // Plain scripts only, modules and their files need Postgres.
+    fn insert_allocation_strategy(&mut self, name: &str, script: &str) -> Result<i64>;
+
+    fn get_allocation_script(&mut self, id: i64) -> Result<String>;
+
+    fn insert_resource_pool_with_properties(&mut self, name: &str, allocation_strategy_id: i64,
+                                            properties: Option<Value>) -> Result<ResourcePool>;
+
+    fn find_resource_pool_by_name(&mut self, name: &str) -> Result<Option<ResourcePool>>;
+
+    // Fails if the pool still has resources in use.
+    fn delete_resource_pool(&mut self, pool: ResourcePool) -> Result<()>;
+
+    // Resources in use, ordered by id.
+    fn get_resources(&mut self, resource_pool_id: i64) -> Result<Vec<Resource>>;
+
+    // Inserts the resources and bumps the pool version in one transaction.
+    fn insert_resources(&mut self, pool: ResourcePool, items: Vec<Resource>) -> Result<(ResourcePool, Vec<Resource>)>;
+
+    // Fails with `AllocationError::ResourceClaimed` for claimed resources.
+    fn deallocate_resource(&mut self, pool: ResourcePool, selector: &ResourceSelector)
+                           -> Result<(ResourcePool, Resource)>;
+
+    // Runs the strategy against all resources in use and inserts what it returned.
+    fn allocate_resources(&mut self, pool: ResourcePool, wasmer_env: &mut WasmerEnv, user_input: Value,
+                          options: &AllocationOptions) -> Result<(ResourcePool, Vec<Resource>)> {
+        let script = self.get_allocation_script(pool.allocation_strategy_id)?;
+        let mut current_resources = self.get_resources(pool.id)?.iter().map(Resource::as_json).collect::<Vec<_>>();
+        let user_input = pool.user_input_with_defaults(user_input);
+        let values = wasmer_env.invoke_and_parse(&script, user_input, pool.get_pool_properties(), pool.as_json(),
+                                                 &mut current_resources, "invoke()")?;
+        let resources = DB::new_resources(&pool, values, options);
+        if options.dry_run {
+            return Ok((pool, resources));
+        }
+        self.insert_resources(pool, resources)
+    }
+
+    fn get_resource_pool_by_name(&mut self, name: &str) -> Result<ResourcePool> {
+        match self.find_resource_pool_by_name(name)? {
+            Some(pool) => Ok(pool),
+            None => bail!("Resource pool '{}' not found", name),
+        }
+    }
+}
+
+impl Storage for DB {
+    fn insert_allocation_strategy(&mut self, name: &str, script: &str) -> Result<i64> {
+        DB::insert_allocation_strategy(self, name, script, None, &StrategyFiles::new())
+    }
+
+    fn get_allocation_script(&mut self, id: i64) -> Result<String> {
+        DB::get_allocation_script(self, id)
+    }
+
+    fn insert_resource_pool_with_properties(&mut self, name: &str, allocation_strategy_id: i64,
+                                            properties: Option<Value>) -> Result<ResourcePool> {
+        DB::insert_resource_pool_with_properties(self, name, allocation_strategy_id, None, properties)
+    }
+
+    fn find_resource_pool_by_name(&mut self, name: &str) -> Result<Option<ResourcePool>> {
+        DB::find_resource_pool_by_name(self, name)
+    }
+
+    fn delete_resource_pool(&mut self, pool: ResourcePool) -> Result<()> {
+        DB::delete_resource_pool(self, pool)
+    }
+
+    fn get_resources(&mut self, resource_pool_id: i64) -> Result<Vec<Resource>> {
+        DB::get_resources(self, resource_pool_id)
+    }
+
+    fn insert_resources(&mut self, pool: ResourcePool, items: Vec<Resource>) -> Result<(ResourcePool, Vec<Resource>)> {
+        DB::insert_resources(self, pool, items)
+    }
+
+    fn deallocate_resource(&mut self, pool: ResourcePool, selector: &ResourceSelector)
+                           -> Result<(ResourcePool, Resource)> {
+        DB::deallocate_resource(self, pool, selector)
+    }
+
+    // Everything `DB::allocate_resources` supports, not just the portable core.
+    fn allocate_resources(&mut self, pool: ResourcePool, wasmer_env: &mut WasmerEnv, user_input: Value,
+                          options: &AllocationOptions) -> Result<(ResourcePool, Vec<Resource>)> {
+        DB::allocate_resources(self, pool, wasmer_env, user_input, options)
+    }
+}
+
+// SQLite file of `lab --sqlite`, created on first use. Builds without the `sqlite` feature refuse it.
+#[cfg(feature = "sqlite")]
+pub fn open(sqlite: Option<&str>) -> Result<Box<dyn Storage>> {
+    match sqlite {
+        Some(path) => Ok(Box::new(crate::sqlite::SqliteStorage::open(path)?)),
+        None => Ok(Box::new(DB::new_from_env()?)),
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+pub fn open(sqlite: Option<&str>) -> Result<Box<dyn Storage>> {
+    match sqlite {
+        Some(_) => bail!("SQLite is not available in this build, rebuild with `--features sqlite`"),
+        None => Ok(Box::new(DB::new_from_env()?)),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::error::AllocationError;
+    use crate::state::ResourceState;
+    use crate::tests::{IPV4_ALLOCATION_STRATEGY_ID, initialize_logging};
+    use super::*;
+
+    // Allocates from a new pool of the IPv4 strategy and deallocates through `Storage` only, so that
+    // every backend behaves the same.
+    pub(crate) fn allocate_and_deallocate(storage: &mut dyn Storage, ipv4_strategy_id: i64) {
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let properties = json!({"address": "10.0.0.0", "prefix": 29});
+        let pool = storage.insert_resource_pool_with_properties(&name, ipv4_strategy_id, Some(properties.clone()))
+            .unwrap();
+        assert_eq!(properties, pool.properties);
+        assert_eq!(Some(pool.clone()), storage.find_resource_pool_by_name(&name).unwrap());
+
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let (pool, dry_run) = storage.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 2}),
+                                                         &AllocationOptions { dry_run: true, ..Default::default() })
+            .unwrap();
+        assert_eq!(2, dry_run.len());
+        assert!(storage.get_resources(pool.id).unwrap().is_empty());
+        let (pool, _) = storage.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 2}),
+                                                   &AllocationOptions::default()).unwrap();
+        let values = storage.get_resources(pool.id).unwrap().into_iter().map(|it| it.value).collect::<Vec<_>>();
+        assert_eq!(vec![json!({"address": "10.0.0.0"}), json!({"address": "10.0.0.1"})], values);
+        assert_eq!(1, pool.version);
+
+        let err = storage.delete_resource_pool(pool.clone()).unwrap_err();
+        assert!(err.to_string().contains("still has 2 resources in use"), "{:#}", err);
+        let (pool, deallocated) = storage.deallocate_resource(pool, &ResourceSelector::Value(values[0].clone()))
+            .unwrap();
+        assert_eq!(ResourceState::Retired, deallocated.state);
+        assert!(deallocated.deleted_at.is_some());
+        let err = storage.deallocate_resource(pool.clone(), &ResourceSelector::Id(-1)).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(), Some(AllocationError::ResourceNotFound { .. })));
+        // the retired value is free again
+        let (pool, allocated) = storage.allocate_resources(pool, &mut wasmer_env, json!({}),
+                                                           &AllocationOptions::default()).unwrap();
+        assert_eq!(vec![values[0].clone()], allocated.into_iter().map(|it| it.value).collect::<Vec<_>>());
+
+        let mut pool = pool;
+        for resource in storage.get_resources(pool.id).unwrap() {
+            pool = storage.deallocate_resource(pool, &ResourceSelector::Id(resource.id.unwrap())).unwrap().0;
+        }
+        storage.delete_resource_pool(pool).unwrap();
+        assert_eq!(None, storage.find_resource_pool_by_name(&name).unwrap());
+    }
+
+    #[test]
+    fn db_storage() {
+        initialize_logging();
+
+        allocate_and_deallocate(open(None).unwrap().as_mut(), IPV4_ALLOCATION_STRATEGY_ID);
+    }
+
+    #[cfg(not(feature = "sqlite"))]
+    #[test]
+    fn sqlite_not_available() {
+        assert!(open(Some("lab.db")).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use chrono::{DateTime, Utc};
+use postgres::{Client, Row};
+use serde_json::{Value, json};
+
+use crate::DB;
+use crate::engine::Engine;
+
+/// Helper files of a strategy bundle by their path, e.g. `net.js`.
+pub type StrategyFiles = BTreeMap<String, String>;
+
+/// How the script of an allocation strategy is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptKind {
+    // functions such as `invoke()` declared globally
+    Script,
+    // functions exported with `export function invoke()`, may import other module strategies by name
+    Module,
+}
+
+impl ScriptKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScriptKind::Script => "script",
+            ScriptKind::Module => "module",
+        }
+    }
+
+    // Declared kind of the strategy, detected from the source if there is none.
+    pub fn of_strategy(declared: Option<&str>, source: &str) -> Result<ScriptKind> {
+        declared.map(str::parse).transpose().map(|declared| declared.unwrap_or_else(|| ScriptKind::detect(source)))
+    }
+
+    pub fn detect(source: &str) -> ScriptKind {
+        let is_module = source.lines()
+            .map(str::trim_start)
+            .any(|line| line.starts_with("export ") || line.starts_with("import "));
+        if is_module { ScriptKind::Module } else { ScriptKind::Script }
+    }
+}
+
+impl fmt::Display for ScriptKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ScriptKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<ScriptKind> {
+        [ScriptKind::Script, ScriptKind::Module].iter()
+            .find(|kind| kind.as_str() == s)
+            .copied()
+            .ok_or_else(|| anyhow!("Unknown script kind '{}'", s))
+    }
+}
+
+/// Documentation telling consumers how to call a strategy, None where undocumented.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StrategyDocs {
+    pub description: Option<String>,
+    // keys of pool properties the strategy reads and their meaning
+    pub expected_pool_properties: Option<String>,
+    // keys of the user input the strategy reads and their meaning
+    pub expected_user_input: Option<String>,
+}
+
+/// Deployed strategy as listed by `DB::list_strategies`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrategySummary {
+    pub id: i64,
+    pub name: String,
+    // `typescript` if the strategy was compiled from TypeScript, `javascript` otherwise
+    pub language: &'static str,
+    pub kind: ScriptKind,
+    pub engine: Engine,
+    pub version: i32,
+    // number of pools allocating with the strategy
+    pub pool_count: i64,
+    pub docs: StrategyDocs,
+    pub created_at: SystemTime,
+    pub updated_at: SystemTime,
+}
+
+impl StrategySummary {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "name": &self.name,
+            "language": self.language,
+            "kind": self.kind.as_str(),
+            "engine": self.engine.as_str(),
+            "version": self.version,
+            "poolCount": self.pool_count,
+            "description": &self.docs.description,
+            "expectedPoolPropertiesDoc": &self.docs.expected_pool_properties,
+            "expectedUserInputDoc": &self.docs.expected_user_input,
+            "createdAt": DateTime::<Utc>::from(self.created_at).to_rfc3339(),
+            "updatedAt": DateTime::<Utc>::from(self.updated_at).to_rfc3339(),
+        })
+    }
+
+    fn from_row(row: Row) -> Result<StrategySummary> {
+        Ok(StrategySummary {
+            id: row.get(0),
+            name: row.get(1),
+            language: if row.get(2) { "typescript" } else { "javascript" },
+            kind: ScriptKind::of_strategy(row.get(3), row.get(4))?,
+            engine: row.get::<_, &str>(5).parse()?,
+            version: row.get(6),
+            pool_count: row.get(7),
+            docs: StrategyDocs {
+                description: row.get(8),
+                expected_pool_properties: row.get(9),
+                expected_user_input: row.get(10),
+            },
+            created_at: row.get(11),
+            updated_at: row.get(12),
+        })
+    }
+}
+
+const STRATEGY_SUMMARY_QUERY: &str = "SELECT s.id, s.name, s.typescript IS NOT NULL, s.script_kind, s.script, \
+    s.engine, s.version, (SELECT count(*) FROM resource_pools p WHERE p.resource_pool_allocation_strategy = s.id), \
+    s.description, s.expected_pool_properties_doc, s.expected_user_input_doc, s.created_at, s.updated_at \
+    FROM allocation_strategies s";
+
+// `import { a, b as c } from 'strategy';` as the imported strategy name and `a, b: c` destructuring.
+fn parse_import(line: &str) -> Result<(String, String)> {
+    let invalid = || anyhow!("Unsupported import '{}', expected import {{ ... }} from 'strategy'", line);
+    let (names, from) = line.trim().strip_prefix("import").ok_or_else(invalid)?
+        .split_once(" from ").ok_or_else(invalid)?;
+    let names = names.trim().strip_prefix('{').and_then(|it| it.strip_suffix('}')).ok_or_else(invalid)?;
+    let bindings = names.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| match name.split_once(" as ") {
+            Some((imported, local)) => format!("{}: {}", imported.trim(), local.trim()),
+            None => name.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    let strategy = from.trim().trim_end_matches(';').trim().trim_matches(|c| c == '\'' || c == '"');
+    ensure!(!strategy.is_empty(), invalid());
+    Ok((strategy.to_owned(), bindings))
+}
+
+// Name declared by `export function name(`, `export const name =` and the like.
+fn exported_name(declaration: &str) -> Result<String> {
+    let mut words = declaration.split_whitespace();
+    let mut keyword = words.next().unwrap_or_default();
+    if keyword == "async" {
+        keyword = words.next().unwrap_or_default();
+    }
+    ensure!(["function", "function*", "const", "let", "var", "class"].contains(&keyword),
+            "Unsupported export '{}', only declarations can be exported", declaration.trim());
+    let name = words.next().unwrap_or_default()
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+        .next().unwrap_or_default();
+    ensure!(!name.is_empty(), "Cannot find name of export '{}'", declaration.trim());
+    Ok(name.to_owned())
+}
+
+impl DB {
+    // Stores the strategy together with its helper files in one transaction.
+    pub fn insert_allocation_strategy(&mut self, name: &str, script: &str, kind: Option<ScriptKind>,
+                                      files: &StrategyFiles) -> Result<i64> {
+        let mut transaction = self.client.transaction()?;
+        let id = Self::insert_strategy_rows(&mut transaction, name, script, kind, files)?;
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    pub(crate) fn insert_strategy_rows(client: &mut Client, name: &str, script: &str,
+                                       kind: Option<ScriptKind>, files: &StrategyFiles)
+                                       -> Result<i64> {
+        let kind = kind.map(|kind| kind.as_str());
+        let row = client.query_one(
+            "INSERT INTO allocation_strategies (name, script, script_kind) VALUES ($1, $2, $3) RETURNING id",
+            &[&name, &script, &kind])?;
+        let id: i64 = row.get(0);
+        Self::insert_strategy_files(client, id, files)?;
+        Ok(id)
+    }
+
+    pub(crate) fn insert_strategy_files(client: &mut Client, allocation_strategy_id: i64,
+                                        files: &StrategyFiles) -> Result<()> {
+        for (path, content) in files {
+            client.execute(
+                "INSERT INTO allocation_strategy_files (allocation_strategy_id, path, content) VALUES ($1, $2, $3)",
+                &[&allocation_strategy_id, path, content])?;
+        }
+        Ok(())
+    }
+
+    // Strategies ordered by id starting after `after_id`, all of them without a limit. From the replica if configured.
+    pub fn list_strategies(&mut self, after_id: Option<i64>, limit: Option<i64>) -> Result<Vec<StrategySummary>> {
+        let rows = self.reader().query(
+            format!("{} WHERE ($1::bigint IS NULL OR s.id > $1) ORDER BY s.id LIMIT $2",
+                    STRATEGY_SUMMARY_QUERY).as_str(),
+            &[&after_id, &limit])?;
+        rows.into_iter().map(StrategySummary::from_row).collect()
+    }
+
+    // From the replica if configured.
+    pub fn find_strategy(&mut self, allocation_strategy_id: i64) -> Result<Option<StrategySummary>> {
+        self.reader().query_opt(format!("{} WHERE s.id = $1", STRATEGY_SUMMARY_QUERY).as_str(),
+                                &[&allocation_strategy_id])?
+            .map(StrategySummary::from_row)
+            .transpose()
+    }
+
+    pub fn get_strategy(&mut self, allocation_strategy_id: i64) -> Result<StrategySummary> {
+        self.find_strategy(allocation_strategy_id)?
+            .ok_or_else(|| anyhow!("Allocation strategy {} not found", allocation_strategy_id))
+    }
+
+    // Fields of `docs` that are None are kept, empty ones are cleared.
+    pub fn set_strategy_docs(&mut self, allocation_strategy_id: i64, docs: &StrategyDocs) -> Result<()> {
+        let updated = self.client.execute(
+            "UPDATE allocation_strategies SET description = NULLIF(coalesce($2, description), ''), \
+            expected_pool_properties_doc = NULLIF(coalesce($3, expected_pool_properties_doc), ''), \
+            expected_user_input_doc = NULLIF(coalesce($4, expected_user_input_doc), '') WHERE id=$1",
+            &[&allocation_strategy_id, &docs.description, &docs.expected_pool_properties, &docs.expected_user_input])?;
+        if updated == 0 {
+            bail!("Allocation strategy {} not found", allocation_strategy_id);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn get_strategy_files(client: &mut Client, allocation_strategy_id: i64)
+                                     -> Result<StrategyFiles> {
+        let rows = client.query(
+            "SELECT path, content FROM allocation_strategy_files WHERE allocation_strategy_id=$1",
+            &[&allocation_strategy_id])?;
+        Ok(rows.into_iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    // Helper files of a plain script are evaluated before it, ordered by path.
+    pub(crate) fn bundle_script(script: &str, files: &StrategyFiles) -> String {
+        files.values()
+            .map(|content| content.as_str())
+            .chain(std::iter::once(script))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Modules are evaluated as plain scripts, so that the header and footer added by `WasmerEnv` stay
+    // in the same scope. Exports become global declarations, imported strategies and files of the bundle
+    // are inlined into closures and only their exports are bound.
+    pub(crate) fn module_to_script(&mut self, strategy: &str, source: &str, files: &StrategyFiles)
+                                   -> Result<String> {
+        Ok(self.link_module(strategy, source, files, &mut vec![strategy.to_owned()])?.0)
+    }
+
+    // Returns the script and names exported by the module, `files` are the bundle of `strategy`.
+    fn link_module(&mut self, strategy: &str, source: &str, files: &StrategyFiles, importing: &mut Vec<String>)
+                   -> Result<(String, Vec<String>)> {
+        let mut script = String::with_capacity(source.len());
+        let mut exports = Vec::new();
+        for line in source.lines() {
+            let trimmed = line.trim_start();
+            let indent = &line[..line.len() - trimmed.len()];
+            if trimmed.starts_with("import ") {
+                let (from, bindings) = parse_import(trimmed)?;
+                let imported = match from.strip_prefix("./") {
+                    Some(path) => {
+                        let source = files.get(path)
+                            .ok_or_else(|| anyhow!("File '{}' not found in strategy '{}'", path, strategy))?;
+                        self.get_module_exports(strategy, source, files, format!("{}/{}", strategy, path), importing)?
+                    }
+                    None => self.get_imported_module(&from, importing)?,
+                };
+                script += &format!("{}const {{ {} }} = (function () {{\n{}\n}})();\n", indent, bindings, imported);
+            } else if let Some(declaration) = trimmed.strip_prefix("export ") {
+                exports.push(exported_name(declaration)?);
+                script += indent;
+                script += declaration;
+                script += "\n";
+            } else {
+                script += line;
+                script += "\n";
+            }
+        }
+        Ok((script, exports))
+    }
+
+    // Body of a closure returning exports of the module strategy.
+    fn get_imported_module(&mut self, strategy: &str, importing: &mut Vec<String>) -> Result<String> {
+        let row = self.client.query_opt(
+            "SELECT id, script, script_kind FROM allocation_strategies WHERE name=$1", &[&strategy])?
+            .ok_or_else(|| anyhow!("Imported strategy '{}' not found", strategy))?;
+        let source: String = row.get(1);
+        let kind = ScriptKind::of_strategy(row.get(2), &source)?;
+        ensure!(kind == ScriptKind::Module, "Imported strategy '{}' is not a module", strategy);
+        let files = Self::get_strategy_files(&mut self.client, row.get(0))?;
+        self.get_module_exports(strategy, &source, &files, strategy.to_owned(), importing)
+    }
+
+    // `module` identifies the imported strategy or file in `importing`, to detect cycles.
+    fn get_module_exports(&mut self, strategy: &str, source: &str, files: &StrategyFiles, module: String,
+                          importing: &mut Vec<String>) -> Result<String> {
+        if importing.contains(&module) {
+            bail!("Circular import of '{}': {}", module, importing.join(" -> "));
+        }
+        importing.push(module.clone());
+        let (script, exports) = self.link_module(strategy, source, files, importing)
+            .context(format!("Cannot import '{}'", module))?;
+        importing.pop();
+        Ok(format!("{}return {{ {} }};", script, exports.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::WasmerEnv;
+    use crate::engine::StrategyEngine;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn script_kind_detect() {
+        assert_eq!(ScriptKind::Script, ScriptKind::detect("function invoke() { return [] }"));
+        assert_eq!(ScriptKind::Module, ScriptKind::detect("\"use strict\"\n  export function invoke() {}"));
+        assert_eq!(ScriptKind::Module, ScriptKind::detect("import { a } from 'shared'"));
+        assert_eq!("b: c", parse_import("import { b as c } from \"shared\";").unwrap().1);
+        assert!(parse_import("import * as shared from 'shared'").is_err());
+        assert_eq!("invoke", exported_name("async function invoke() {").unwrap());
+        assert!(exported_name("default function () {").is_err());
+    }
+
+    #[test]
+    fn db_list_strategies() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "export function invoke() { return [] }", None,
+                                               &StrategyFiles::new()).unwrap();
+        db.insert_resource_pool(&format!("{}-pool", name), id).unwrap();
+        let listed = db.list_strategies(None, None).unwrap().into_iter().find(|it| it.id == id).unwrap();
+        assert_eq!(vec![listed.clone()], db.list_strategies(Some(id - 1), Some(1)).unwrap());
+        assert_eq!(StrategySummary {
+            id,
+            name,
+            language: "javascript",
+            kind: ScriptKind::Module,
+            engine: Engine::QuickjsSubprocess,
+            version: 1,
+            pool_count: 1,
+            docs: StrategyDocs::default(),
+            created_at: listed.created_at,
+            updated_at: listed.created_at,
+        }, listed);
+        // documenting the strategy changes it
+        db.set_strategy_docs(id, &StrategyDocs { description: Some("None".to_owned()), ..Default::default() })
+            .unwrap();
+        let documented = db.get_strategy(id).unwrap();
+        assert_eq!(listed.created_at, documented.created_at);
+        assert!(documented.updated_at > listed.updated_at);
+    }
+
+    #[test]
+    fn db_strategy_docs() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() { return [] }", None,
+                                               &StrategyFiles::new()).unwrap();
+        db.set_strategy_docs(id, &StrategyDocs {
+            description: Some("Allocates nothing".to_owned()),
+            expected_pool_properties: Some("none".to_owned()),
+            expected_user_input: Some("`count`".to_owned()),
+        }).unwrap();
+        db.set_strategy_docs(id, &StrategyDocs {
+            expected_pool_properties: Some("".to_owned()),
+            ..StrategyDocs::default()
+        }).unwrap();
+        let strategy = db.get_strategy(id).unwrap();
+        assert_eq!(StrategyDocs {
+            description: Some("Allocates nothing".to_owned()),
+            expected_pool_properties: None,
+            expected_user_input: Some("`count`".to_owned()),
+        }, strategy.docs);
+        assert_eq!(json!("Allocates nothing"), strategy.as_json()["description"]);
+        assert_eq!(Value::Null, strategy.as_json()["expectedPoolPropertiesDoc"]);
+        assert!(db.get_strategy(i64::MAX).is_err());
+        assert!(db.set_strategy_docs(i64::MAX, &StrategyDocs::default()).is_err());
+    }
+
+    #[test]
+    fn db_module_strategies() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let prefix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let shared = format!("{}-shared", prefix);
+        db.insert_allocation_strategy(&shared, "const factor = 2;\n\
+            export function scale(n) { return n * factor }\n\
+            export const offset = 1;", Some(ScriptKind::Module), &StrategyFiles::new()).unwrap();
+        let id = db.insert_allocation_strategy(&format!("{}-main", prefix), &format!(
+            "import {{ scale as double, offset }} from '{}';\n\
+            export function invoke() {{\n    return [{{ n: double(userInput.n) + offset }}]\n}}", shared), None,
+            &StrategyFiles::new()).unwrap();
+        let script = db.get_allocation_script(id).unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let actual = wasmer_env.invoke_and_parse(&script, json!({"n": 20}), json!({}), json!({}), &mut vec![], "invoke()")
+            .unwrap();
+        assert_eq!(vec![json!({"n": 41})], actual);
+
+        let cyclic = format!("{}-cyclic", prefix);
+        let id = db.insert_allocation_strategy(&cyclic, &format!(
+            "import {{ a }} from '{0}';\nexport const b = 1;", cyclic), None, &StrategyFiles::new()).unwrap();
+        assert!(db.get_allocation_script(id).is_err());
+    }
+
+    #[test]
+    fn db_strategy_bundles() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let prefix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let mut invoke = |db: &mut DB, id: i64| {
+            let script = db.get_allocation_script(id).unwrap();
+            wasmer_env.invoke_and_parse(&script, json!({"n": 20}), json!({}), json!({}), &mut vec![], "invoke()")
+                .unwrap()
+        };
+
+        let files: StrategyFiles = vec![
+            ("net.js".to_owned(), "import { factor } from './consts.js';\n\
+                export function scale(n) { return n * factor }".to_owned()),
+            ("consts.js".to_owned(), "export const factor = 3;".to_owned()),
+        ].into_iter().collect();
+        let id = db.insert_allocation_strategy(&format!("{}-module", prefix),
+            "import { scale } from './net.js';\nexport function invoke() { return [{ n: scale(userInput.n) }] }",
+            None, &files).unwrap();
+        assert_eq!(vec![json!({"n": 60})], invoke(&mut db, id));
+        // files of an imported strategy are resolved within its own bundle
+        let id = db.insert_allocation_strategy(&format!("{}-importing", prefix), &format!(
+            "import {{ invoke as base }} from '{}-module';\nexport function invoke() {{ return base() }}", prefix),
+            None, &StrategyFiles::new()).unwrap();
+        assert_eq!(vec![json!({"n": 60})], invoke(&mut db, id));
+
+        let files: StrategyFiles = vec![("helpers.js".to_owned(), "const offset = 1;".to_owned())]
+            .into_iter().collect();
+        let id = db.insert_allocation_strategy(&format!("{}-script", prefix),
+            "function invoke() { return [{ n: userInput.n + offset }] }", None, &files).unwrap();
+        assert_eq!(vec![json!({"n": 21})], invoke(&mut db, id));
+
+        let id = db.insert_allocation_strategy(&format!("{}-missing", prefix),
+            "import { a } from './missing.js';", None, &StrategyFiles::new()).unwrap();
+        assert!(db.get_allocation_script(id).is_err());
+    }
+}
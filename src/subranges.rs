@@ -0,0 +1,237 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{Result, bail};
+use serde_json::Value;
+
+use crate::ResourcePool;
+use crate::error::{AllocationError, FieldError};
+
+/// Part of an IPv4 pool declared in `subRanges` of its properties, e.g.
+/// `[{"cidr": "10.0.0.0/28", "reserved": true}, {"cidr": "10.0.1.0/24", "priority": 10}]`.
+/// Reserved ranges are only allocated from when selected by `userInput.subRange`, other allocations
+/// take ranges of positive priority first, highest first, and those of negative priority after the rest of the pool.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SubRange {
+    pub cidr: String,
+    pub first: i64,
+    pub last: i64,
+    pub priority: i64,
+    pub reserved: bool,
+}
+
+// Inclusive range of addresses as numbers, e.g. `10.0.0.0/28`.
+fn parse_cidr(cidr: &str) -> Option<(i64, i64)> {
+    let (address, prefix) = cidr.split_once('/')?;
+    let address = i64::from(u32::from(address.parse::<Ipv4Addr>().ok()?));
+    let prefix = prefix.parse::<u32>().ok().filter(|prefix| *prefix <= 32)?;
+    let size = 1i64 << (32 - prefix);
+    if address % size != 0 {
+        return None;
+    }
+    Some((address, address + size - 1))
+}
+
+fn address_of(value: &Value) -> Option<i64> {
+    value["address"].as_str()?.parse::<Ipv4Addr>().ok().map(|address| i64::from(u32::from(address)))
+}
+
+impl SubRange {
+    // Sub-ranges of the properties ordered by priority, the reason if they are invalid. Ranges must lie
+    // within `address` and `prefix` of the pool if it has them and must not overlap.
+    pub(crate) fn from_properties(properties: &Value) -> std::result::Result<Vec<SubRange>, String> {
+        let declared = match &properties["subRanges"] {
+            Value::Null => return Ok(Vec::new()),
+            Value::Array(declared) => declared,
+            other => return Err(format!("'subRanges' must be an array, got {}", other)),
+        };
+        let pool = match (properties["address"].as_str(), properties["prefix"].as_i64()) {
+            (Some(address), Some(prefix)) => parse_cidr(&format!("{}/{}", address, prefix)),
+            _ => None,
+        };
+        let mut sub_ranges = Vec::with_capacity(declared.len());
+        for sub_range in declared {
+            let cidr = sub_range["cidr"].as_str()
+                .ok_or_else(|| format!("sub-range {} must have a 'cidr'", sub_range))?;
+            let (first, last) = parse_cidr(cidr).ok_or_else(|| format!("'{}' is not a valid IPv4 network", cidr))?;
+            if let Some((pool_first, pool_last)) = pool {
+                if first < pool_first || last > pool_last {
+                    return Err(format!("sub-range '{}' is outside of the pool", cidr));
+                }
+            }
+            let priority = match &sub_range["priority"] {
+                Value::Null => 0,
+                priority => priority.as_i64().ok_or_else(|| format!("priority of '{}' must be an integer", cidr))?,
+            };
+            let reserved = match &sub_range["reserved"] {
+                Value::Null => false,
+                reserved => reserved.as_bool()
+                    .ok_or_else(|| format!("reserved of '{}' must be a boolean", cidr))?,
+            };
+            let overlapping = sub_ranges.iter().find(|other: &&SubRange| other.first <= last && first <= other.last);
+            if let Some(other) = overlapping {
+                return Err(format!("sub-ranges '{}' and '{}' overlap", other.cidr, cidr));
+            }
+            sub_ranges.push(SubRange { cidr: cidr.to_owned(), first, last, priority, reserved });
+        }
+        sub_ranges.sort_by_key(|sub_range| -sub_range.priority);
+        Ok(sub_ranges)
+    }
+
+    // Sub-ranges of the pool, fails with `AllocationError::InvalidPoolProperties` if they are invalid.
+    pub(crate) fn of_pool(pool: &ResourcePool) -> Result<Vec<SubRange>> {
+        SubRange::from_properties(&pool.properties).map_err(|reason| AllocationError::InvalidPoolProperties {
+            resource_pool: pool.name.clone(),
+            reason,
+        }.into())
+    }
+
+    // The sub-range selected by `userInput.subRange`, fails with `AllocationError::InvalidUserInput`
+    // if the pool does not declare it.
+    pub(crate) fn selected<'a>(pool: &ResourcePool, sub_ranges: &'a [SubRange], user_input: &Value)
+                               -> Result<Option<&'a SubRange>> {
+        let cidr = match &user_input["subRange"] {
+            Value::Null => return Ok(None),
+            cidr => cidr,
+        };
+        match sub_ranges.iter().find(|sub_range| Some(sub_range.cidr.as_str()) == cidr.as_str()) {
+            Some(sub_range) => Ok(Some(sub_range)),
+            None => Err(AllocationError::InvalidUserInput {
+                resource_pool: pool.name.clone(),
+                errors: vec![FieldError {
+                    field: "subRange".to_owned(),
+                    message: format!("{} is not a sub-range of the pool", cidr),
+                }],
+            }.into()),
+        }
+    }
+
+    // Disjoint ranges within `first..=last` to allocate from, in order: the selected sub-range only, otherwise
+    // sub-ranges of positive priority, the rest of the pool and sub-ranges of negative priority, never reserved ones.
+    pub(crate) fn allocation_order(sub_ranges: &[SubRange], selected: Option<&SubRange>, first: i64, last: i64)
+                                   -> Vec<(i64, i64)> {
+        let clamp = |sub_range: &SubRange| (sub_range.first.max(first), sub_range.last.min(last));
+        let ranked = |positive: bool| sub_ranges.iter()
+            .filter(move |it| !it.reserved && if positive { it.priority > 0 } else { it.priority < 0 })
+            .map(clamp);
+        let mut excluded = sub_ranges.iter()
+            .filter(|it| it.reserved || it.priority != 0)
+            .map(|it| (it.first, it.last))
+            .collect::<Vec<_>>();
+        excluded.sort_unstable();
+        let mut rest = Vec::new();
+        let mut start = first;
+        for (excluded_first, excluded_last) in excluded {
+            if excluded_first > start {
+                rest.push((start, (excluded_first - 1).min(last)));
+            }
+            start = start.max(excluded_last + 1);
+        }
+        rest.push((start, last));
+        let order = match selected {
+            Some(selected) => vec![clamp(selected)],
+            None => ranked(true).chain(rest).chain(ranked(false)).collect(),
+        };
+        order.into_iter().filter(|(start, end)| start <= end).collect()
+    }
+
+    // Addresses returned by a script must avoid reserved sub-ranges unless one was selected,
+    // in which case they must lie within it. Values without an `address` are not checked.
+    pub(crate) fn check_values(sub_ranges: &[SubRange], selected: Option<&SubRange>, values: &[Value])
+                               -> Result<()> {
+        for value in values {
+            let address = match address_of(value) {
+                Some(address) => address,
+                None => continue,
+            };
+            match selected {
+                Some(selected) if address < selected.first || address > selected.last =>
+                    bail!("Strategy returned {} outside of the selected sub-range '{}'", value, selected.cidr),
+                Some(_) => {}
+                None => if let Some(reserved) = sub_ranges.iter()
+                    .find(|it| it.reserved && it.first <= address && address <= it.last) {
+                    bail!("Strategy returned {} from the reserved sub-range '{}'", value, reserved.cidr);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::{AllocationOptions, DB, WasmerEnv};
+    use crate::tests::{initialize_logging, IPV4_ALLOCATION_STRATEGY_ID};
+    use super::*;
+
+    fn address(address: &str) -> i64 {
+        i64::from(u32::from(address.parse::<Ipv4Addr>().unwrap()))
+    }
+
+    #[test]
+    fn sub_ranges() {
+        let properties = json!({"address": "10.0.0.0", "prefix": 24, "subRanges": [
+            {"cidr": "10.0.0.0/28", "reserved": true},
+            {"cidr": "10.0.0.128/26", "priority": 10},
+        ]});
+        let sub_ranges = SubRange::from_properties(&properties).unwrap();
+        assert_eq!(vec!["10.0.0.128/26", "10.0.0.0/28"],
+                   sub_ranges.iter().map(|it| it.cidr.as_str()).collect::<Vec<_>>());
+        let (first, last) = (address("10.0.0.0"), address("10.0.0.255"));
+        assert_eq!(vec![(address("10.0.0.128"), address("10.0.0.191")), (address("10.0.0.16"), address("10.0.0.127")),
+                        (address("10.0.0.192"), last)],
+                   SubRange::allocation_order(&sub_ranges, None, first, last));
+        assert_eq!(vec![(first, address("10.0.0.15"))],
+                   SubRange::allocation_order(&sub_ranges, Some(&sub_ranges[1]), first, last));
+        assert!(SubRange::check_values(&sub_ranges, None, &[json!({"address": "10.0.0.16"})]).is_ok());
+        assert!(SubRange::check_values(&sub_ranges, None, &[json!({"address": "10.0.0.1"})]).is_err());
+        assert!(SubRange::check_values(&sub_ranges, Some(&sub_ranges[1]), &[json!({"address": "10.0.0.16"})])
+            .is_err());
+
+        let invalid = |sub_ranges: Value| SubRange::from_properties(
+            &json!({"address": "10.0.0.0", "prefix": 24, "subRanges": sub_ranges})).unwrap_err();
+        assert!(invalid(json!([{"cidr": "10.0.1.0/28"}])).contains("outside"));
+        assert!(invalid(json!([{"cidr": "10.0.0.1/28"}])).contains("not a valid"));
+        assert!(invalid(json!([{"cidr": "10.0.0.0/28"}, {"cidr": "10.0.0.0/29"}])).contains("overlap"));
+        assert!(invalid(json!([{"cidr": "10.0.0.0/28", "priority": "high"}])).contains("integer"));
+    }
+
+    #[test]
+    fn db_sub_ranges() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let properties = |sub_ranges: Value| {
+            Some(json!({"address": "10.0.0.0", "prefix": 24, "subRanges": sub_ranges}))
+        };
+        let err = db.insert_resource_pool_with_properties(&name, IPV4_ALLOCATION_STRATEGY_ID, None,
+                                                          properties(json!([{"cidr": "10.0.1.0/28"}]))).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(), Some(AllocationError::InvalidPoolProperties { .. })));
+        let pool = db.insert_resource_pool_with_properties(&name, IPV4_ALLOCATION_STRATEGY_ID, None, properties(json!([
+            {"cidr": "10.0.0.0/28", "reserved": true},
+            {"cidr": "10.0.0.64/26", "priority": -1},
+            {"cidr": "10.0.0.128/26", "priority": 10},
+        ]))).unwrap();
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"resourceCount": 65}), &options)
+            .unwrap();
+        assert_eq!(json!({"address": "10.0.0.128"}), resources[0].value);
+        // the rest of the pool follows the preferred sub-range, skipping reserved addresses
+        assert_eq!(json!({"address": "10.0.0.16"}), resources[64].value);
+
+        let (pool, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({"subRange": "10.0.0.0/28"}),
+                                                      &options).unwrap();
+        assert_eq!(json!({"address": "10.0.0.0"}), resources[0].value);
+        let err = db.allocate_resources(pool.clone(), &mut wasmer_env,
+                                        json!({"desiredValue": {"address": "10.0.0.5"}}), &options).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(), Some(AllocationError::ValueUnavailable { .. })));
+        let err = db.allocate_resources(pool, &mut wasmer_env, json!({"subRange": "10.0.0.16/28"}), &options)
+            .unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(), Some(AllocationError::InvalidUserInput { .. })));
+    }
+}
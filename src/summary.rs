@@ -0,0 +1,132 @@
+use anyhow::{Result, anyhow, bail, ensure};
+use postgres::Client;
+use postgres::types::ToSql;
+use serde_json::{Map, Value};
+
+use crate::DB;
+
+/// Queries a strategy declares to get aggregates of the pool instead of all its resources, as JSON object of
+/// SQL select lists by name, e.g. `{"lastAddress": "SELECT max(ip)"}`. Every query is completed with
+/// `FROM resources WHERE` resources of the pool in use and must return a single value, results are embedded
+/// into the script as `resourceSummary`. Queries are trusted like scripts of the strategy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextQueries(Map<String, Value>);
+
+impl ContextQueries {
+    pub fn from_json(queries: Value) -> Result<ContextQueries> {
+        let queries = match queries {
+            Value::Object(queries) => queries,
+            other => bail!("Context queries must be a JSON object, got '{}'", other),
+        };
+        for (name, query) in &queries {
+            let query = query.as_str().ok_or_else(|| anyhow!("Context query '{}' must be a string", name))?;
+            ensure!(query.trim_start().to_lowercase().starts_with("select"),
+                    "Context query '{}' must be a select list starting with SELECT, got '{}'", name, query);
+        }
+        Ok(ContextQueries(queries))
+    }
+
+    pub fn as_json(&self) -> Value {
+        Value::Object(self.0.clone())
+    }
+
+    // One statement evaluating all queries into a JSON object, names are bound as parameters.
+    fn to_sql(&self) -> String {
+        let fields = self.0.values().enumerate()
+            .map(|(idx, query)| format!(
+                "${}::text, to_jsonb(({} FROM resources WHERE resource_pool=$1 AND status <> 'retired'))",
+                idx + 2, query.as_str().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        format!("SELECT jsonb_build_object({})", fields.join(", "))
+    }
+
+    pub(crate) fn evaluate(&self, client: &mut Client, resource_pool_id: i64) -> Result<Value> {
+        let mut params: Vec<&(dyn ToSql + Sync)> = vec![&resource_pool_id];
+        params.extend(self.0.keys().map(|name| name as &(dyn ToSql + Sync)));
+        Ok(client.query_one(self.to_sql().as_str(), &params)?.get(0))
+    }
+}
+
+impl DB {
+    pub(crate) fn find_context_queries(client: &mut Client, allocation_strategy_id: i64)
+                                       -> Result<Option<ContextQueries>> {
+        let row = client.query_opt(
+            "SELECT context_queries FROM allocation_strategies WHERE id=$1", &[&allocation_strategy_id])?
+            .ok_or_else(|| anyhow!("Allocation strategy {} not found", allocation_strategy_id))?;
+        row.get::<_, Option<Value>>(0).map(ContextQueries::from_json).transpose()
+    }
+
+    // Declare queries of the strategy, None computes no summary. Queries are tried on an empty pool first,
+    // so that a typo does not fail every allocation.
+    pub fn set_context_queries(&mut self, allocation_strategy_id: i64, queries: Option<&ContextQueries>)
+                               -> Result<()> {
+        let mut transaction = self.client.transaction()?;
+        if let Some(queries) = queries {
+            queries.evaluate(&mut transaction, 0)
+                .map_err(|err| anyhow!("Invalid context queries: {}", err))?;
+        }
+        let queries = queries.map(ContextQueries::as_json);
+        let updated = transaction.execute(
+            "UPDATE allocation_strategies SET context_queries=$2 WHERE id=$1", &[&allocation_strategy_id, &queries])?;
+        if updated == 0 {
+            bail!("Allocation strategy {} not found", allocation_strategy_id);
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // Results of the context queries of the pool's strategy by name, empty without queries.
+    pub(crate) fn resource_summary(client: &mut Client, resource_pool_id: i64) -> Result<Value> {
+        let row = client.query_opt(
+            "SELECT resource_pool_allocation_strategy FROM resource_pools WHERE id=$1", &[&resource_pool_id])?
+            .ok_or_else(|| anyhow!("Resource pool {} not found", resource_pool_id))?;
+        match Self::find_context_queries(client, row.get(0))? {
+            Some(queries) => queries.evaluate(client, resource_pool_id),
+            None => Ok(Value::Object(Map::new())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::{AllocationOptions, Resource, WasmerEnv};
+    use crate::strategy::StrategyFiles;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_context_queries() {
+        initialize_logging();
+
+        assert!(ContextQueries::from_json(json!(["SELECT 1"])).is_err());
+        assert!(ContextQueries::from_json(json!({"count": 1})).is_err());
+        assert!(ContextQueries::from_json(json!({"drop": "DELETE FROM resources"})).is_err());
+
+        let mut db = DB::new_from_env().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let script = "function invoke() { return [{ n: resourceSummary.count + 1, last: resourceSummary.last }] }";
+        let strategy_id = db.insert_allocation_strategy(&name, script, None, &StrategyFiles::new()).unwrap();
+        let invalid = ContextQueries::from_json(json!({"count": "SELECT count(*) FROM"})).unwrap();
+        let err = db.set_context_queries(strategy_id, Some(&invalid)).unwrap_err();
+        assert!(err.to_string().contains("Invalid context queries"), "{}", err);
+        let queries = ContextQueries::from_json(json!({
+            "count": "SELECT count(*)",
+            "last": "select max((value->>'n')::int)",
+        })).unwrap();
+        db.set_context_queries(strategy_id, Some(&queries)).unwrap();
+        assert_eq!(Some(queries), DB::find_context_queries(&mut db.client, strategy_id).unwrap());
+
+        let pool = db.insert_resource_pool(&name, strategy_id).unwrap();
+        assert_eq!(json!({"count": 0, "last": null}), DB::resource_summary(&mut db.client, pool.id).unwrap());
+        let resource = Resource::new_from_value(pool.id, json!({"n": 4}));
+        let (pool, _) = db.insert_resources(pool, vec![resource]).unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let (_, resources) = db.allocate_resources(pool, &mut wasmer_env, json!({}),
+                                                   &AllocationOptions::default()).unwrap();
+        assert_eq!(json!({"n": 2, "last": 4}), resources[0].value);
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+use serde_json::{Map, Value, json};
+use tracing::*;
+
+use crate::shutdown::Shutdown;
+
+// Pause before the first restart of a crashed subsystem, doubled by every further crash up to `MAX_RESTART_DELAY`.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+// A subsystem running this long is considered recovered, its next crash is restarted after `RESTART_DELAY`.
+const STABLE_AFTER: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubsystemState {
+    Running,
+    // crashed, waiting to be started again
+    Restarting,
+    Stopped,
+}
+
+impl SubsystemState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubsystemState::Running => "running",
+            SubsystemState::Restarting => "restarting",
+            SubsystemState::Stopped => "stopped",
+        }
+    }
+}
+
+impl fmt::Display for SubsystemState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SubsystemHealth {
+    pub state: SubsystemState,
+    pub restarts: u64,
+    // error or panic message of the last crash
+    pub last_error: Option<String>,
+}
+
+impl SubsystemHealth {
+    pub fn as_json(&self) -> Value {
+        json!({"state": self.state.as_str(), "restarts": self.restarts, "lastError": self.last_error})
+    }
+}
+
+/// Health of supervised subsystems by name, reported by `GET /readyz`. Without subsystems the process is ready.
+#[derive(Debug, Clone, Default)]
+pub struct Health(Arc<Mutex<BTreeMap<&'static str, SubsystemHealth>>>);
+
+impl Health {
+    fn update(&self, name: &'static str, update: impl FnOnce(&mut SubsystemHealth)) {
+        let mut subsystems = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let health = subsystems.entry(name).or_insert(SubsystemHealth {
+            state: SubsystemState::Running,
+            restarts: 0,
+            last_error: None,
+        });
+        update(health);
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, name: &str) -> Option<SubsystemHealth> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(name).cloned()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).values()
+            .all(|health| health.state == SubsystemState::Running)
+    }
+
+    pub fn as_json(&self) -> Value {
+        let subsystems = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter()
+            .map(|(name, health)| (name.to_string(), health.as_json()))
+            .collect::<Map<_, _>>();
+        json!({"ready": self.is_ready(), "subsystems": subsystems})
+    }
+}
+
+/// Runs subsystems on their own threads until shutdown, restarting them when they fail or panic.
+pub struct Supervisor {
+    health: Health,
+    shutdown: Shutdown,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    pub fn new(shutdown: Shutdown) -> Supervisor {
+        Supervisor { health: Health::default(), shutdown, threads: vec![] }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health.clone()
+    }
+
+    // Starts the subsystem, which is expected to run until shutdown is requested. Returning earlier, with or
+    // without an error, or panicking is a crash.
+    pub fn spawn<F>(&mut self, name: &'static str, mut run: F) -> Result<()>
+        where F: FnMut() -> Result<()> + Send + 'static {
+        let health = self.health.clone();
+        let shutdown = self.shutdown;
+        health.update(name, |_| {});
+        let thread = thread::Builder::new().name(name.to_owned()).spawn(move || {
+            let mut delay = RESTART_DELAY;
+            while !shutdown.is_requested() {
+                let started = Instant::now();
+                let result = panic::catch_unwind(AssertUnwindSafe(&mut run))
+                    .unwrap_or_else(|panic| Err(anyhow!("Panicked: {}", panic_message(&*panic))));
+                if shutdown.is_requested() {
+                    if let Err(err) = result {
+                        warn!("{} failed while shutting down: {:#}", name, err);
+                    }
+                    break;
+                }
+                let err = result.err().unwrap_or_else(|| anyhow!("Stopped unexpectedly"));
+                if started.elapsed() >= STABLE_AFTER {
+                    delay = RESTART_DELAY;
+                }
+                error!("{} crashed, restarting in {:?}: {:#}", name, delay, err);
+                health.update(name, |health| {
+                    health.state = SubsystemState::Restarting;
+                    health.restarts += 1;
+                    health.last_error = Some(format!("{:#}", err));
+                });
+                shutdown.sleep(delay);
+                delay = (delay * 2).min(MAX_RESTART_DELAY);
+                health.update(name, |health| health.state = SubsystemState::Running);
+            }
+            health.update(name, |health| health.state = SubsystemState::Stopped);
+        })?;
+        self.threads.push(thread);
+        Ok(())
+    }
+
+    // Waits until every subsystem has stopped.
+    pub fn join(self) -> Result<()> {
+        for thread in self.threads {
+            thread.join().map_err(|_| anyhow!("Supervisor thread panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic.downcast_ref::<&str>().map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn supervisor_restarts_crashed_subsystem() {
+        initialize_logging();
+
+        let shutdown = Shutdown::new();
+        let mut supervisor = Supervisor::new(shutdown);
+        let health = supervisor.health();
+        let mut runs = 0;
+        supervisor.spawn("flaky", move || {
+            runs += 1;
+            if runs == 1 {
+                panic!("first run");
+            }
+            while !shutdown.is_requested() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            Ok(())
+        }).unwrap();
+
+        let mut attempts = 0;
+        while health.get("flaky").unwrap().restarts == 0 {
+            attempts += 1;
+            assert!(attempts < 100, "Subsystem was not restarted");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let flaky = health.get("flaky").unwrap();
+        assert_eq!(Some("Panicked: first run".to_owned()), flaky.last_error);
+        assert!(!health.is_ready());
+        thread::sleep(RESTART_DELAY + Duration::from_millis(200));
+        assert_eq!(json!({"ready": true, "subsystems": {"flaky": {
+            "state": "running", "restarts": 1, "lastError": "Panicked: first run",
+        }}}), health.as_json());
+
+        shutdown.request();
+        supervisor.join().unwrap();
+        assert_eq!(SubsystemState::Stopped, health.get("flaky").unwrap().state);
+    }
+}
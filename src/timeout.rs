@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use postgres::error::SqlState;
+
+use crate::DB;
+use crate::error::AllocationError;
+use crate::transaction::Transaction;
+
+/// Limits of allocation transactions, so that a stuck allocation cannot hold the pool version row indefinitely.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TransactionTimeouts {
+    // None keeps the server setting
+    pub statement_timeout: Option<Duration>,
+    pub lock_timeout: Option<Duration>,
+}
+
+impl DB {
+    // Transaction with the configured timeouts, they are reset when it ends.
+    pub(crate) fn allocation_transaction(&mut self) -> Result<Transaction<'_>> {
+        let mut transaction = self.client.transaction()?;
+        let settings = [
+            ("statement_timeout", self.timeouts.statement_timeout),
+            ("lock_timeout", self.timeouts.lock_timeout),
+        ];
+        for (setting, timeout) in settings.iter() {
+            if let Some(timeout) = timeout {
+                transaction.execute("SELECT set_config($1, $2, true)",
+                                    &[setting, &format!("{}ms", timeout.as_millis())])?;
+            }
+        }
+        Ok(transaction)
+    }
+
+    // Converts a timeout of an allocation transaction into `AllocationError::Timeout`.
+    pub(crate) fn timeout_error(resource_pool: &str, err: anyhow::Error) -> anyhow::Error {
+        let reason = match err.downcast_ref::<postgres::Error>().and_then(|err| err.code()) {
+            Some(code) if *code == SqlState::QUERY_CANCELED => "statement_timeout",
+            Some(code) if *code == SqlState::LOCK_NOT_AVAILABLE => "lock_timeout",
+            _ => return err,
+        };
+        AllocationError::Timeout { resource_pool: resource_pool.to_owned(), reason: reason.to_owned() }.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::Resource;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_allocation_timeouts() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        db.timeouts = TransactionTimeouts {
+            statement_timeout: Some(Duration::from_millis(300)),
+            lock_timeout: Some(Duration::from_millis(50)),
+        };
+        let pool = create_random_pool(&mut db).unwrap();
+        let mut other = DB::new_from_env().unwrap();
+        let mut locking = other.client.transaction().unwrap();
+        locking.execute("SELECT id FROM resource_pools WHERE id=$1 FOR UPDATE", &[&pool.id]).unwrap();
+        let err = db.insert_resources(pool.clone(), vec![
+            Resource::new_from_value(pool.id, json!({"address": "10.0.0.1"}))]).unwrap_err();
+        let timeout = |reason: &str| AllocationError::Timeout { resource_pool: pool.name.clone(), reason: reason.into() };
+        assert_eq!(Some(&timeout("lock_timeout")), err.downcast_ref::<AllocationError>());
+        locking.rollback().unwrap();
+        assert_eq!(0, db.count_resources(pool.id).unwrap());
+
+        let mut transaction = db.allocation_transaction().unwrap();
+        let err = transaction.execute("SELECT pg_sleep(1)", &[]).map_err(anyhow::Error::from).unwrap_err();
+        assert_eq!(Some(&timeout("statement_timeout")),
+                   DB::timeout_error(&pool.name, err).downcast_ref::<AllocationError>());
+        drop(transaction);
+        // timeouts are local to the transaction
+        let statement_timeout: String = db.client.query_one("SHOW statement_timeout", &[]).unwrap().get(0);
+        assert_eq!("0", statement_timeout);
+    }
+}
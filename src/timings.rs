@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use serde_json::{Value, json};
+use tracing::*;
+use tracing::span::EnteredSpan;
+
+use crate::metrics;
+
+/// Times an operation of the engine or the database within an `operation` span. The finished operation is logged
+/// with `duration_ms` and observed by `operation_duration_seconds`, see `metrics::render`.
+pub struct Timer {
+    operation: &'static str,
+    started: Instant,
+    _span: EnteredSpan,
+}
+
+impl Timer {
+    pub fn start(operation: &'static str) -> Timer {
+        Timer { operation, started: Instant::now(), _span: debug_span!("operation", operation).entered() }
+    }
+
+    pub fn finish(self) -> Duration {
+        let duration = self.started.elapsed();
+        metrics::record_operation(self.operation, duration);
+        debug!(duration_ms = duration.as_millis() as u64, "{} finished in {}ms", self.operation, duration.as_millis());
+        duration
+    }
+}
+
+/// Where an allocation spent its time: running the strategy, reading the pool and writing allocated resources.
+/// Reads of resources by host functions of a running strategy count as script time. Every part is timed
+/// by a `Timer` named after the operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings {
+    pub script: Duration,
+    pub db_read: Duration,
+    pub db_write: Duration,
+    pub total: Duration,
+}
+
+fn measure<T>(slot: &mut Duration, operation: &'static str, f: impl FnOnce() -> T) -> T {
+    let timer = Timer::start(operation);
+    let result = f();
+    *slot += timer.finish();
+    result
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+impl Timings {
+    pub(crate) fn script<T>(&mut self, operation: &'static str, f: impl FnOnce() -> T) -> T {
+        measure(&mut self.script, operation, f)
+    }
+
+    pub(crate) fn db_read<T>(&mut self, operation: &'static str, f: impl FnOnce() -> T) -> T {
+        measure(&mut self.db_read, operation, f)
+    }
+
+    pub(crate) fn db_write<T>(&mut self, operation: &'static str, f: impl FnOnce() -> T) -> T {
+        measure(&mut self.db_write, operation, f)
+    }
+
+    // Fractional milliseconds, e.g. `{"script_ms": 41.2, "db_read_ms": 0.8, "db_write_ms": 2.5, "total_ms": 45.1}`.
+    pub fn as_json(&self) -> Value {
+        json!({
+            "script_ms": millis(self.script),
+            "db_read_ms": millis(self.db_read),
+            "db_write_ms": millis(self.db_write),
+            "total_ms": millis(self.total),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::{AllocationOptions, DB, WasmerEnv};
+    use crate::strategy::StrategyFiles;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_allocation_timings() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        // the free list of the IPv4 strategy is read without running the script
+        let pool = create_random_pool(&mut db).unwrap();
+        let (_, _, timings) = db.allocate_resources_with_timings(pool, &mut wasmer_env, json!({}),
+                                                                 &AllocationOptions::default()).unwrap();
+        assert_eq!(Duration::from_secs(0), timings.script);
+        assert!(timings.db_write > Duration::from_secs(0));
+        assert!(timings.total >= timings.db_read + timings.db_write);
+
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_allocation_strategy(&name, "function invoke() { return [{n: currentResources.length}] }",
+                                               None, &StrategyFiles::new()).unwrap();
+        let pool = db.insert_resource_pool(&name, id).unwrap();
+        let dry_run = AllocationOptions { dry_run: true, ..AllocationOptions::default() };
+        let (_, _, timings) = db.allocate_resources_with_timings(pool, &mut wasmer_env, json!({}), &dry_run)
+            .unwrap();
+        assert!(timings.script > Duration::from_secs(0));
+        assert_eq!(Duration::from_secs(0), timings.db_write);
+        assert_eq!(json!(0.0), timings.as_json()["db_write_ms"]);
+    }
+}
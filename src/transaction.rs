@@ -0,0 +1,182 @@
+use std::ops::{Deref, DerefMut};
+
+use anyhow::{Result, ensure};
+use postgres::Client;
+
+use crate::DB;
+
+/// Connection whose transactions are savepoints of the transaction of `DB::dry_run` while one runs,
+/// so that operations of the dry run see the changes of each other and all of them are rolled back.
+pub(crate) struct Connection {
+    client: Client,
+    dry_run: bool,
+}
+
+impl Connection {
+    pub(crate) fn new(client: Client) -> Connection {
+        Connection { client, dry_run: false }
+    }
+
+    pub(crate) fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn transaction(&mut self) -> Result<Transaction<'_>, postgres::Error> {
+        let savepoint = if self.dry_run { Some(1) } else { None };
+        Transaction::begin(&mut self.client, savepoint)
+    }
+}
+
+impl Deref for Connection {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        &self.client
+    }
+}
+
+impl DerefMut for Connection {
+    fn deref_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+}
+
+/// As `postgres::Transaction`, rolled back when dropped without a commit. Nested transactions are savepoints.
+pub struct Transaction<'a> {
+    client: &'a mut Client,
+    // depth of the savepoint, None for a transaction of its own
+    savepoint: Option<u32>,
+    done: bool,
+}
+
+impl<'a> Transaction<'a> {
+    fn begin(client: &'a mut Client, savepoint: Option<u32>) -> Result<Transaction<'a>, postgres::Error> {
+        match savepoint {
+            Some(depth) => client.batch_execute(&format!("SAVEPOINT sp_{}", depth))?,
+            None => client.batch_execute("BEGIN")?,
+        }
+        Ok(Transaction { client, savepoint, done: false })
+    }
+
+    pub fn transaction(&mut self) -> Result<Transaction<'_>, postgres::Error> {
+        let depth = self.savepoint.map_or(1, |depth| depth + 1);
+        Transaction::begin(self.client, Some(depth))
+    }
+
+    pub fn commit(mut self) -> Result<(), postgres::Error> {
+        self.done = true;
+        match self.savepoint {
+            Some(depth) => self.client.batch_execute(&format!("RELEASE sp_{}", depth)),
+            None => self.client.batch_execute("COMMIT"),
+        }
+    }
+
+    pub fn rollback(mut self) -> Result<(), postgres::Error> {
+        self.done = true;
+        self.roll_back()
+    }
+
+    fn roll_back(&mut self) -> Result<(), postgres::Error> {
+        match self.savepoint {
+            Some(depth) => self.client.batch_execute(&format!("ROLLBACK TO sp_{0}; RELEASE sp_{0}", depth)),
+            None => self.client.batch_execute("ROLLBACK"),
+        }
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = self.roll_back();
+        }
+    }
+}
+
+impl<'a> Deref for Transaction<'a> {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        self.client
+    }
+}
+
+impl<'a> DerefMut for Transaction<'a> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client
+    }
+}
+
+impl DB {
+    // Runs the operations within a transaction that is always rolled back, returning what they would return.
+    // Allocations, transitions and any other operations of `f` see the changes of each other as if they were
+    // committed, while other connections see none of them. Pool events are not delivered, scripts of changed
+    // strategies are not cached and allocations are not counted in metrics. Dry runs within a dry run
+    // are a part of it.
+    pub fn dry_run<T>(&mut self, f: impl FnOnce(&mut DB) -> Result<T>) -> Result<T> {
+        if self.client.dry_run {
+            return f(self);
+        }
+        self.client.batch_execute("BEGIN")?;
+        self.client.dry_run = true;
+        let result = f(self);
+        self.client.dry_run = false;
+        self.client.batch_execute("ROLLBACK")?;
+        result
+    }
+
+    // Backups and the like read a snapshot of committed data, they cannot be a part of a dry run.
+    pub(crate) fn check_not_dry_run(&self, operation: &str) -> Result<()> {
+        ensure!(!self.client.dry_run, "{} cannot be a part of a dry run", operation);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{AllocationOptions, ResourceSelector, WasmerEnv};
+    use crate::state::ResourceState;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_dry_run() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let options = AllocationOptions::default();
+        let pool = create_random_pool(&mut db).unwrap();
+        let (pool, _) = db.allocate_resources(pool, &mut wasmer_env, json!({}), &options).unwrap();
+        let (version, values) = db.dry_run(|db| {
+            let (stale, first) = db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &options)?;
+            // a failed operation leaves the changes before it in place
+            assert!(db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &options).is_err());
+            let (pool, second) = db.allocate_resources(stale, &mut wasmer_env, json!({}), &options)?;
+            let (pool, _) = db.transition_resource(pool, &ResourceSelector::Value(first[0].value.clone()),
+                                                   ResourceState::Claimed)?;
+            let nested = db.dry_run(|db| db.count_resources(pool.id))?;
+            let mut streamed = 0;
+            for _ in 0..2 {
+                streamed += db.stream_resources(pool.id, 1, |_| Ok(()))?;
+            }
+            assert_eq!((3, 6), (nested, streamed));
+            Ok((pool.version, vec![first[0].value.clone(), second[0].value.clone()]))
+        }).unwrap();
+        assert_eq!(pool.version + 3, version);
+        assert_eq!(vec![json!({"address": "10.0.0.1"}), json!({"address": "10.0.0.2"})], values);
+
+        // nothing is kept, also when the dry run fails
+        assert_eq!(pool, db.get_resource_pool_by_id(pool.id).unwrap());
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+        let err = db.dry_run(|db| {
+            db.allocate_resources(pool.clone(), &mut wasmer_env, json!({}), &options)?;
+            db.get_resource_pool_by_name("")
+        }).unwrap_err();
+        assert!(format!("{:#}", err).contains("not found"), "{:#}", err);
+        assert_eq!(1, db.count_resources(pool.id).unwrap());
+        assert!(db.check_not_dry_run("Backup").is_ok());
+        assert!(db.dry_run(|db| db.backup(vec![])).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+use std::process::Command;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::DB;
+use crate::config::Config;
+use crate::error::AllocationError;
+use crate::host::ScriptDir;
+use crate::strategy::{ScriptKind, StrategyFiles};
+
+// Globals defined by the header of `WasmerEnv` and the host functions, so that strategies type check.
+const GLOBALS: &str = "
+declare const userInput: any;
+declare const resourcePoolProperties: any;
+declare const resourcePool: any;
+declare const currentResources: { Properties: any }[];
+declare function log(...args: any[]): void;
+declare function getCurrentResources(offset: number, limit: number): { Properties: any }[];
+declare function isAllocated(value: any): boolean;
+declare function now(): string;
+declare function uuid(): string;
+declare function logStructured(fields: object): void;
+";
+
+/// Compiles TypeScript strategies to JavaScript with `tsc`, when they are saved.
+pub struct TypeScriptCompiler {
+    tsc_bin: String,
+}
+
+impl TypeScriptCompiler {
+    pub fn new() -> Result<TypeScriptCompiler> {
+        let tsc_bin = Config::from_env()?.tsc_bin.ok_or_else(|| anyhow!("RM_TSC_BIN is not set"))?;
+        Ok(TypeScriptCompiler { tsc_bin })
+    }
+
+    // Type errors fail with `AllocationError::InvalidStrategy`. Helper files of the bundle are JavaScript,
+    // they are only type checked where a module imports them.
+    pub fn compile(&self, strategy: &str, source: &str, files: &StrategyFiles) -> Result<String> {
+        let dir = ScriptDir::create()?;
+        fs::write(dir.0.join("main.ts"), source)?;
+        fs::write(dir.0.join("globals.d.ts"), GLOBALS)?;
+        for (path, content) in files {
+            fs::write(dir.0.join(path), content)?;
+        }
+        let output = Command::new(&self.tsc_bin)
+            .args(["--strict", "--noEmitOnError", "--allowJs", "--target", "es2020", "--module", "es2020",
+                "--moduleResolution", "node", "--outDir", "out", "main.ts", "globals.d.ts"])
+            .current_dir(&dir.0)
+            .output()
+            .context("Cannot execute tsc")?;
+        if !output.status.success() {
+            // tsc reports type errors on stdout
+            let reason = String::from_utf8_lossy(if output.stdout.is_empty() { &output.stderr } else { &output.stdout });
+            return Err(AllocationError::InvalidStrategy {
+                strategy: strategy.to_owned(),
+                reason: reason.trim().to_owned(),
+            }.into());
+        }
+        fs::read_to_string(dir.0.join("out").join("main.js")).context("Cannot read output of tsc")
+    }
+}
+
+impl DB {
+    // Stores the TypeScript source together with the JavaScript compiled from it, which is what gets executed.
+    pub fn insert_typescript_strategy(&mut self, name: &str, typescript: &str, kind: Option<ScriptKind>,
+                                      files: &StrategyFiles, compiler: &TypeScriptCompiler) -> Result<i64> {
+        let script = compiler.compile(name, typescript, files)?;
+        if kind.unwrap_or_else(|| ScriptKind::detect(&script)) == ScriptKind::Module {
+            // imports of strategies are linked when the strategy is executed, fail early if they are invalid
+            self.module_to_script(name, &script, files).map_err(|err| AllocationError::InvalidStrategy {
+                strategy: name.to_owned(),
+                reason: format!("{:#}", err),
+            })?;
+        }
+        let mut transaction = self.client.transaction()?;
+        let id = Self::insert_strategy_rows(&mut transaction, name, &script, kind, files)?;
+        transaction.execute("UPDATE allocation_strategies SET typescript=$2 WHERE id=$1", &[&id, &typescript])?;
+        transaction.commit()?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+    use serde_json::json;
+
+    use crate::WasmerEnv;
+    use crate::engine::StrategyEngine;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    #[test]
+    fn db_typescript_strategies() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let compiler = TypeScriptCompiler::new().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let id = db.insert_typescript_strategy(&name,
+            "export function invoke(): object[] {\n    const step: number = 2;\n    \
+            return [{ n: userInput.n * step }];\n}", None, &StrategyFiles::new(), &compiler).unwrap();
+        let typescript: Option<String> = db.client.query_one(
+            "SELECT typescript FROM allocation_strategies WHERE id=$1", &[&id]).unwrap().get(0);
+        assert!(typescript.unwrap().contains("step: number"));
+        let script = db.get_allocation_script(id).unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let actual = wasmer_env.invoke_and_parse(&script, json!({"n": 21}), json!({}), json!({}), &mut vec![],
+                                                 "invoke()").unwrap();
+        assert_eq!(vec![json!({"n": 42})], actual);
+
+        let err = db.insert_typescript_strategy(&format!("{}-invalid", name),
+            "function invoke() {\n    const step: number = 'two';\n}", None, &StrategyFiles::new(), &compiler)
+            .unwrap_err();
+        match err.downcast_ref::<AllocationError>() {
+            Some(AllocationError::InvalidStrategy { reason, .. }) => assert!(reason.contains("main.ts(2,15)"), "{}", reason),
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::error::Error;
+
+use anyhow::{Result, anyhow, bail};
+use postgres::Row;
+use postgres::error::DbError;
+use serde_json::{Value, json};
+
+use crate::{DB, ResourcePool};
+use crate::error::AllocationError;
+
+// Violated when a value enters use while another pool of a uniqueness group uses it,
+// see the `resources_uniqueness_groups` trigger.
+const CONFLICT_CONSTRAINT: &str = "uniqueness_group_values_pkey";
+
+/// Pools that never have the same value in use, e.g. IPv4 pools sharing one address space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UniquenessGroup {
+    pub id: i32,
+    pub name: String,
+    pub resource_pool_ids: Vec<i64>,
+}
+
+impl UniquenessGroup {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "name": &self.name,
+            "resourcePools": &self.resource_pool_ids,
+        })
+    }
+
+    fn from_row(row: Row) -> UniquenessGroup {
+        UniquenessGroup { id: row.get(0), name: row.get(1), resource_pool_ids: row.get(2) }
+    }
+}
+
+const UNIQUENESS_GROUP_QUERY: &str = "SELECT g.id, g.name, \
+    coalesce(array_agg(gp.resource_pool ORDER BY gp.resource_pool) FILTER (WHERE gp.resource_pool IS NOT NULL), '{}') \
+    FROM uniqueness_groups g LEFT JOIN uniqueness_group_pools gp ON gp.uniqueness_group = g.id";
+
+impl DB {
+    pub fn create_uniqueness_group(&mut self, name: &str) -> Result<UniquenessGroup> {
+        let id: i32 = self.client.query_one("INSERT INTO uniqueness_groups (name) VALUES ($1) RETURNING id",
+                                            &[&name])?.get(0);
+        Ok(UniquenessGroup { id, name: name.to_owned(), resource_pool_ids: vec![] })
+    }
+
+    pub fn delete_uniqueness_group(&mut self, name: &str) -> Result<()> {
+        if self.client.execute("DELETE FROM uniqueness_groups WHERE name=$1", &[&name])? == 0 {
+            bail!("Uniqueness group '{}' not found", name);
+        }
+        Ok(())
+    }
+
+    pub fn get_uniqueness_group(&mut self, name: &str) -> Result<UniquenessGroup> {
+        let row = self.reader().query_opt(format!("{} WHERE g.name=$1 GROUP BY g.id", UNIQUENESS_GROUP_QUERY).as_str(),
+                                          &[&name])?
+            .ok_or_else(|| anyhow!("Uniqueness group '{}' not found", name))?;
+        Ok(UniquenessGroup::from_row(row))
+    }
+
+    pub fn get_uniqueness_groups(&mut self) -> Result<Vec<UniquenessGroup>> {
+        let rows = self.reader().query(format!("{} GROUP BY g.id ORDER BY g.name", UNIQUENESS_GROUP_QUERY).as_str(),
+                                       &[])?;
+        Ok(rows.into_iter().map(UniquenessGroup::from_row).collect())
+    }
+
+    // Values in use by the pool are claimed in the group. Fails with `AllocationError::UniquenessConflict`
+    // if another pool of the group uses any of them.
+    pub fn add_pool_to_uniqueness_group(&mut self, group: &UniquenessGroup, pool: &ResourcePool) -> Result<()> {
+        let mut transaction = self.client.transaction()?;
+        // concurrent allocations of the pool wait, so that no value enters use unclaimed
+        Self::check_not_archived(&mut transaction, pool.id)?;
+        transaction.execute("SELECT id FROM resource_pools WHERE id=$1 FOR UPDATE", &[&pool.id])?;
+        transaction.execute("INSERT INTO uniqueness_group_pools (uniqueness_group, resource_pool) VALUES ($1, $2)",
+                            &[&group.id, &pool.id])?;
+        let conflict = transaction.query_opt(
+            "SELECT p.name, r.value FROM resources r \
+            JOIN uniqueness_group_values v ON v.uniqueness_group = $1 AND v.value = r.value \
+            JOIN resource_pools p ON p.id = v.resource_pool \
+            WHERE r.resource_pool = $2 AND r.status <> 'retired' LIMIT 1",
+            &[&group.id, &pool.id])?;
+        if let Some(conflict) = conflict {
+            return Err(AllocationError::UniquenessConflict {
+                uniqueness_group: group.name.clone(),
+                resource_pool: conflict.get(0),
+                value: conflict.get(1),
+            }.into());
+        }
+        transaction.execute(
+            "INSERT INTO uniqueness_group_values (uniqueness_group, value, resource_pool) \
+            SELECT $1, value, resource_pool FROM resources WHERE resource_pool = $2 AND status <> 'retired'",
+            &[&group.id, &pool.id])?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // Values of the pool are released in the group.
+    pub fn remove_pool_from_uniqueness_group(&mut self, group: &UniquenessGroup, pool: &ResourcePool) -> Result<()> {
+        if self.client.execute("DELETE FROM uniqueness_group_pools WHERE uniqueness_group=$1 AND resource_pool=$2",
+                               &[&group.id, &pool.id])? == 0 {
+            bail!("Pool '{}' is not in uniqueness group '{}'", pool.name, group.name);
+        }
+        Ok(())
+    }
+
+    // Converts the violation raised by the `resources_uniqueness_groups` trigger into
+    // `AllocationError::UniquenessConflict`.
+    pub(crate) fn uniqueness_error(err: anyhow::Error) -> anyhow::Error {
+        let detail = err.downcast_ref::<postgres::Error>()
+            .and_then(|err| err.source())
+            .and_then(|err| err.downcast_ref::<DbError>())
+            .filter(|err| err.constraint() == Some(CONFLICT_CONSTRAINT))
+            .and_then(|err| err.detail())
+            .and_then(|detail| serde_json::from_str::<Value>(detail).ok());
+        match detail {
+            Some(detail) => AllocationError::UniquenessConflict {
+                uniqueness_group: detail["uniqueness_group"].as_str().unwrap_or_default().to_owned(),
+                resource_pool: detail["resource_pool"].as_str().unwrap_or_default().to_owned(),
+                value: detail["value"].clone(),
+            }.into(),
+            None => err,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::{AllocationOptions, BulkSelector, Resource, ResourceState, WasmerEnv};
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    #[test]
+    fn db_uniqueness_groups() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let mut wasmer_env = WasmerEnv::new().unwrap();
+        let name: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let group = db.create_uniqueness_group(&name).unwrap();
+        let first = create_random_pool(&mut db).unwrap();
+        let second = create_random_pool(&mut db).unwrap();
+        let (first, allocated) = db.allocate_resources(first, &mut wasmer_env, json!({}),
+                                                       &AllocationOptions::default()).unwrap();
+        db.add_pool_to_uniqueness_group(&group, &first).unwrap();
+        db.add_pool_to_uniqueness_group(&group, &second).unwrap();
+        let group = db.get_uniqueness_group(&name).unwrap();
+        assert_eq!(vec![first.id, second.id], group.resource_pool_ids);
+
+        // both pools allocate the lowest free address
+        let err = db.allocate_resources(second.clone(), &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .unwrap_err();
+        let expected = AllocationError::UniquenessConflict {
+            uniqueness_group: name.clone(),
+            resource_pool: first.name.clone(),
+            value: allocated[0].value.clone(),
+        };
+        assert_eq!(Some(&expected), err.downcast_ref::<AllocationError>(), "{:#}", err);
+        let resource = Resource::new_from_value(second.id, json!({"address": "10.0.0.2"}));
+        let (second, _) = db.insert_resources(second, vec![resource]).unwrap();
+
+        // retired values are released
+        db.deallocate_resources(first.clone(), &BulkSelector::State(ResourceState::Allocated)).unwrap();
+        let (second, _) = db.allocate_resources(second, &mut wasmer_env, json!({}), &AllocationOptions::default())
+            .unwrap();
+        let resource = Resource::new_from_value(first.id, json!({"address": "10.0.0.2"}));
+        let first = db.get_resource_pool_by_id(first.id).unwrap();
+        let err = db.insert_resources(first, vec![resource]).unwrap_err();
+        assert!(matches!(err.downcast_ref::<AllocationError>(), Some(AllocationError::UniquenessConflict { .. })));
+
+        db.remove_pool_from_uniqueness_group(&group, &second).unwrap();
+        assert!(db.remove_pool_from_uniqueness_group(&group, &second).is_err());
+        db.delete_uniqueness_group(&name).unwrap();
+        assert!(db.get_uniqueness_group(&name).is_err());
+    }
+}
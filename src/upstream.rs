@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use postgres::{Client, Row};
+use serde_json::{Map, Value, json};
+use tracing::*;
+
+use crate::{DB, Resource};
+use crate::strategy::{StrategyDocs, StrategyFiles};
+
+// Resources are inserted in batches of this size.
+const RESOURCE_BATCH_SIZE: usize = 1000;
+
+/// What `DB::import_from_resource_manager` recreated, and what it could not with the reason.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    pub strategies: usize,
+    pub pools: usize,
+    pub resources: usize,
+    pub skipped: Vec<String>,
+}
+
+impl ImportReport {
+    pub fn as_json(&self) -> Value {
+        json!({
+            "strategies": self.strategies,
+            "pools": self.pools,
+            "resources": self.resources,
+            "skipped": &self.skipped,
+        })
+    }
+}
+
+// Selected after the columns identifying the owner of a property.
+const PROPERTY_COLUMNS: &str = "t.name, t.type, pr.int_val::bigint, pr.bool_val, pr.float_val::float8, \
+    pr.string_val, pr.range_from_val::float8, pr.range_to_val::float8, pr.latitude_val::float8, \
+    pr.longitude_val::float8";
+
+// Typed value of an upstream property starting at column `idx`, see `PROPERTY_COLUMNS`.
+// Types without a column of their own, e.g. `date` or `enum`, are stored as strings.
+fn property_value(row: &Row, idx: usize) -> (String, Value) {
+    let value = match row.get::<_, &str>(idx + 1) {
+        "int" => json!(row.get::<_, Option<i64>>(idx + 2)),
+        "bool" => json!(row.get::<_, Option<bool>>(idx + 3)),
+        "float" => json!(row.get::<_, Option<f64>>(idx + 4)),
+        "range" => json!({"from": row.get::<_, Option<f64>>(idx + 6), "to": row.get::<_, Option<f64>>(idx + 7)}),
+        "gps_location" => json!({
+            "latitude": row.get::<_, Option<f64>>(idx + 8),
+            "longitude": row.get::<_, Option<f64>>(idx + 9),
+        }),
+        _ => json!(row.get::<_, Option<String>>(idx + 5)),
+    };
+    (row.get(idx), value)
+}
+
+// Upstream pool to recreate.
+struct UpstreamPool {
+    id: i64,
+    name: String,
+    pool_type: String,
+    deallocation_safety_period: i32,
+    allocation_strategy_id: Option<i64>,
+    parent_id: Option<i64>,
+    tags: Vec<String>,
+}
+
+impl DB {
+    // Recreates strategies, allocating pools with their properties and tags, and claimed resources of the
+    // FRINX resource-manager database `upstream`. Properties become JSON objects keyed by property type name.
+    // Strategies are matched by name, so built-in ones such as `ipv4` are reused, only JavaScript ones are created.
+    // Pools that exist here by name are skipped, so that an interrupted import can be run again.
+    pub fn import_from_resource_manager(&mut self, upstream: &mut Client) -> Result<ImportReport> {
+        let mut report = ImportReport::default();
+        let strategy_ids = self.import_upstream_strategies(upstream, &mut report)?;
+        let pool_properties = Self::upstream_pool_properties(upstream)?;
+        let mut pending = upstream.query(
+            "SELECT p.id::bigint, p.name, p.pool_type::text, p.dealocation_safety_period::int, \
+            p.allocation_strategy_pools::bigint, r.resource_pool_claims::bigint, \
+            coalesce(array_agg(t.tag ORDER BY t.tag) FILTER (WHERE t.tag IS NOT NULL), '{}') \
+            FROM resource_pools p LEFT JOIN resources r ON r.id = p.resource_nested_pool \
+            LEFT JOIN tag_pools tp ON tp.resource_pool_id = p.id LEFT JOIN tags t ON t.id = tp.tag_id \
+            GROUP BY p.id, r.resource_pool_claims ORDER BY p.id", &[])
+            .context("Cannot read pools of resource-manager")?
+            .into_iter()
+            .map(|row| UpstreamPool {
+                id: row.get(0),
+                name: row.get(1),
+                pool_type: row.get(2),
+                deallocation_safety_period: row.get(3),
+                allocation_strategy_id: row.get(4),
+                parent_id: row.get(5),
+                tags: row.get(6),
+            })
+            .collect::<Vec<_>>();
+        // nested pools are created after their parents, pools whose parent was skipped are skipped as well
+        let mut pool_ids = HashMap::new();
+        let mut skipped_ids = Vec::new();
+        while !pending.is_empty() {
+            let (ready, waiting): (Vec<_>, Vec<_>) = pending.into_iter()
+                .partition(|pool| pool.parent_id.is_none_or(|parent| pool_ids.contains_key(&parent)
+                    || skipped_ids.contains(&parent)));
+            if ready.is_empty() {
+                for pool in waiting {
+                    report.skipped.push(format!("pool '{}': parent pool not found", pool.name));
+                }
+                break;
+            }
+            for pool in ready {
+                // None if the parent was skipped
+                let parent_id = pool.parent_id.map(|parent| pool_ids.get(&parent).copied());
+                let strategy_id = pool.allocation_strategy_id.and_then(|id| strategy_ids.get(&id)).copied();
+                let skip_reason = match (strategy_id, parent_id) {
+                    _ if pool.pool_type != "allocating" => Some(format!("{} pools are not supported", pool.pool_type)),
+                    (None, _) => Some("strategy was not imported".to_owned()),
+                    (_, Some(None)) => Some("parent pool was skipped".to_owned()),
+                    _ if self.find_resource_pool_by_name(&pool.name)?.is_some() => Some("already exists".to_owned()),
+                    (Some(strategy_id), parent_id) => {
+                        let properties = pool_properties.get(&pool.id).cloned().map(Value::Object);
+                        let created = self.insert_resource_pool_with_properties(
+                            &pool.name, strategy_id, parent_id.flatten(), properties)
+                            .context(format!("Cannot create pool '{}'", pool.name))?;
+                        let created = self.set_deallocation_safety_period(created, pool.deallocation_safety_period)?;
+                        let created = self.set_pool_labels(created, None, pool.tags)?;
+                        report.resources += self.import_upstream_resources(upstream, pool.id, created.id)?;
+                        report.pools += 1;
+                        pool_ids.insert(pool.id, created.id);
+                        None
+                    }
+                };
+                if let Some(reason) = skip_reason {
+                    report.skipped.push(format!("pool '{}': {}", pool.name, reason));
+                    skipped_ids.push(pool.id);
+                }
+            }
+            pending = waiting;
+        }
+        info!("Imported {} strategies, {} pools and {} resources from resource-manager, skipped {}",
+              report.strategies, report.pools, report.resources, report.skipped.len());
+        Ok(report)
+    }
+
+    // Local strategy ids by upstream id.
+    fn import_upstream_strategies(&mut self, upstream: &mut Client, report: &mut ImportReport)
+                                  -> Result<HashMap<i64, i64>> {
+        let rows = upstream.query(
+            "SELECT id::bigint, name, description, lang::text, script FROM allocation_strategies ORDER BY id", &[])
+            .context("Cannot read strategies of resource-manager")?;
+        let mut strategy_ids = HashMap::new();
+        for row in rows {
+            let name: String = row.get(1);
+            let existing = self.client.query_opt("SELECT id FROM allocation_strategies WHERE name=$1", &[&name])?;
+            let id = match existing {
+                Some(existing) => existing.get(0),
+                None if row.get::<_, &str>(3) == "js" => {
+                    let id = self.insert_allocation_strategy(&name, row.get(4), None, &StrategyFiles::new())
+                        .context(format!("Cannot create strategy '{}'", name))?;
+                    let description: Option<String> = row.get(2);
+                    self.set_strategy_docs(id, &StrategyDocs { description, ..StrategyDocs::default() })?;
+                    report.strategies += 1;
+                    id
+                }
+                None => {
+                    report.skipped.push(format!("strategy '{}': {} strategies are not supported", name,
+                                                row.get::<_, &str>(3)));
+                    continue;
+                }
+            };
+            strategy_ids.insert(row.get(0), id);
+        }
+        Ok(strategy_ids)
+    }
+
+    // Properties as JSON objects by upstream pool id.
+    fn upstream_pool_properties(upstream: &mut Client) -> Result<HashMap<i64, Map<String, Value>>> {
+        let rows = upstream.query(format!(
+            "SELECT pp.resource_pool_pool_properties::bigint, {} FROM properties pr \
+            JOIN property_types t ON t.id = pr.property_type \
+            JOIN pool_properties pp ON pp.id = pr.pool_properties_properties ORDER BY pr.id",
+            PROPERTY_COLUMNS).as_str(), &[])
+            .context("Cannot read properties of resource-manager")?;
+        let mut properties: HashMap<i64, Map<String, Value>> = HashMap::new();
+        for row in rows {
+            let (name, value) = property_value(&row, 1);
+            properties.entry(row.get(0)).or_default().insert(name, value);
+        }
+        Ok(properties)
+    }
+
+    // Claimed resources of the upstream pool, the others are not in use.
+    fn import_upstream_resources(&mut self, upstream: &mut Client, upstream_pool_id: i64, resource_pool_id: i64)
+                                 -> Result<usize> {
+        let rows = upstream.query(
+            format!("SELECT r.id::bigint, r.description, {} FROM resources r \
+            JOIN properties pr ON pr.resource_properties = r.id JOIN property_types t ON t.id = pr.property_type \
+            WHERE r.resource_pool_claims = $1 AND r.status::text = 'claimed' ORDER BY r.id, pr.id",
+                    PROPERTY_COLUMNS).as_str(),
+            &[&upstream_pool_id])?;
+        let mut resources: Vec<(i64, Resource)> = Vec::new();
+        for row in rows {
+            let id: i64 = row.get(0);
+            if resources.last().is_none_or(|(last, _)| *last != id) {
+                let resource = Resource {
+                    description: row.get(1),
+                    ..Resource::new_from_value(resource_pool_id, json!({}))
+                };
+                resources.push((id, resource));
+            }
+            let (name, value) = property_value(&row, 2);
+            if let Some((_, resource)) = resources.last_mut() {
+                resource.value[name] = value;
+            }
+        }
+        let count = resources.len();
+        let mut pool = self.get_resource_pool_by_id(resource_pool_id)?;
+        let mut resources = resources.into_iter().map(|(_, resource)| resource).peekable();
+        while resources.peek().is_some() {
+            pool = self.insert_resources(pool, resources.by_ref().take(RESOURCE_BATCH_SIZE).collect())?.0;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::Rng;
+    use rand::distributions::Alphanumeric;
+
+    use crate::config::Config;
+    use crate::connect::ConnectRetry;
+    use crate::tests::initialize_logging;
+    use super::*;
+
+    // Tables of resource-manager read by the import, as created by its ent migrations.
+    const UPSTREAM_SCHEMA: &str = "\
+        CREATE TABLE allocation_strategies (id BIGSERIAL PRIMARY KEY, name VARCHAR NOT NULL UNIQUE, \
+            description VARCHAR, lang VARCHAR NOT NULL DEFAULT 'js', script VARCHAR NOT NULL);
+        CREATE TABLE resource_types (id BIGSERIAL PRIMARY KEY, name VARCHAR NOT NULL UNIQUE);
+        CREATE TABLE property_types (id BIGSERIAL PRIMARY KEY, type VARCHAR NOT NULL, name VARCHAR NOT NULL, \
+            resource_type_property_types BIGINT REFERENCES resource_types);
+        CREATE TABLE resource_pools (id BIGSERIAL PRIMARY KEY, name VARCHAR NOT NULL UNIQUE, description VARCHAR, \
+            pool_type VARCHAR NOT NULL, dealocation_safety_period BIGINT NOT NULL DEFAULT 0, \
+            allocation_strategy_pools BIGINT REFERENCES allocation_strategies, \
+            resource_type_pools BIGINT REFERENCES resource_types, resource_nested_pool BIGINT UNIQUE);
+        CREATE TABLE resources (id BIGSERIAL PRIMARY KEY, status VARCHAR NOT NULL, description VARCHAR, \
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT now(), resource_pool_claims BIGINT REFERENCES resource_pools);
+        CREATE TABLE pool_properties (id BIGSERIAL PRIMARY KEY, \
+            resource_pool_pool_properties BIGINT UNIQUE REFERENCES resource_pools);
+        CREATE TABLE properties (id BIGSERIAL PRIMARY KEY, int_val BIGINT, bool_val BOOLEAN, \
+            float_val DOUBLE PRECISION, latitude_val DOUBLE PRECISION, longitude_val DOUBLE PRECISION, \
+            range_from_val DOUBLE PRECISION, range_to_val DOUBLE PRECISION, string_val VARCHAR, \
+            pool_properties_properties BIGINT REFERENCES pool_properties, \
+            property_type BIGINT NOT NULL REFERENCES property_types, resource_properties BIGINT REFERENCES resources);
+        CREATE TABLE tags (id BIGSERIAL PRIMARY KEY, tag VARCHAR NOT NULL UNIQUE);
+        CREATE TABLE tag_pools (tag_id BIGINT REFERENCES tags, resource_pool_id BIGINT REFERENCES resource_pools, \
+            PRIMARY KEY (tag_id, resource_pool_id));";
+
+    #[test]
+    fn db_import_from_resource_manager() {
+        initialize_logging();
+
+        let mut db = DB::new_from_env().unwrap();
+        let prefix: String = rand::thread_rng().sample_iter(&Alphanumeric).take(10).collect();
+        let schema = format!("rm_{}", prefix.to_lowercase());
+        db.client.batch_execute(&format!("CREATE SCHEMA {}", schema)).unwrap();
+        let mut upstream = DB::connect(&Config::from_env().unwrap().db_params, Some(&schema),
+                                       &ConnectRetry::default()).unwrap();
+        upstream.batch_execute(UPSTREAM_SCHEMA).unwrap();
+        upstream.batch_execute(&format!("\
+            INSERT INTO allocation_strategies (id, name, lang, script) VALUES \
+                (1, 'ipv4', 'js', 'not imported'), (2, '{0}-go', 'go', '');
+            INSERT INTO allocation_strategies (id, name, description, script) VALUES \
+                (3, '{0}-vlan', 'VLAN ids', 'function invoke() {{ return [] }}');
+            INSERT INTO resource_types (id, name) VALUES (1, 'ipv4'), (2, 'vlan');
+            INSERT INTO property_types (id, type, name, resource_type_property_types) VALUES \
+                (1, 'string', 'address', 1), (2, 'int', 'prefix', 1), (3, 'int', 'vlan', 2), (4, 'range', 'span', 2);
+            INSERT INTO resource_pools (id, name, pool_type, dealocation_safety_period, allocation_strategy_pools, \
+                resource_type_pools) VALUES (1, '{0}-root', 'allocating', 60, 1, 1), \
+                (2, '{0}-set', 'set', 0, NULL, 2), (3, '{0}-go', 'allocating', 0, 2, 2);
+            INSERT INTO resources (id, status, description, resource_pool_claims) VALUES \
+                (1, 'claimed', 'loopback', 1), (2, 'bench', NULL, 1), (3, 'claimed', NULL, 1);
+            INSERT INTO resource_pools (id, name, pool_type, allocation_strategy_pools, resource_nested_pool) \
+                VALUES (4, '{0}-nested', 'allocating', 3, 3);
+            INSERT INTO pool_properties (id, resource_pool_pool_properties) VALUES (1, 1), (2, 4);
+            INSERT INTO properties (property_type, string_val, int_val, range_from_val, range_to_val, \
+                pool_properties_properties, resource_properties) VALUES \
+                (1, '10.0.0.0', NULL, NULL, NULL, 1, NULL), (2, NULL, 24, NULL, NULL, 1, NULL), \
+                (4, NULL, NULL, 1, 4094, 2, NULL), \
+                (1, '10.0.0.1', NULL, NULL, NULL, NULL, 1), (1, '10.0.0.2', NULL, NULL, NULL, NULL, 2), \
+                (1, '10.0.0.3', NULL, NULL, NULL, NULL, 3);
+            INSERT INTO tags (id, tag) VALUES (1, 'edge');
+            INSERT INTO tag_pools (tag_id, resource_pool_id) VALUES (1, 1);", prefix)).unwrap();
+
+        let report = db.import_from_resource_manager(&mut upstream).unwrap();
+        assert_eq!(ImportReport {
+            strategies: 1,
+            pools: 2,
+            resources: 2,
+            skipped: vec![
+                format!("strategy '{}-go': go strategies are not supported", prefix),
+                format!("pool '{}-set': set pools are not supported", prefix),
+                format!("pool '{}-go': strategy was not imported", prefix),
+            ],
+        }, report);
+        let root = db.get_resource_pool_by_name(&format!("{}-root", prefix)).unwrap();
+        assert_eq!((1, 60, vec!["edge".to_owned()]),
+                   (root.allocation_strategy_id, root.deallocation_safety_period, root.tags.clone()));
+        assert_eq!(json!({"address": "10.0.0.0", "prefix": 24}), root.get_pool_properties());
+        let resources = db.get_resources(root.id).unwrap();
+        let values = resources.iter().map(|resource| resource.value.clone()).collect::<Vec<_>>();
+        assert_eq!(vec![json!({"address": "10.0.0.1"}), json!({"address": "10.0.0.3"})], values);
+        assert_eq!(Some("loopback"), resources[0].description.as_deref());
+        let nested = db.get_resource_pool_by_name(&format!("{}-nested", prefix)).unwrap();
+        assert_eq!(Some(root.id), nested.parent_id);
+        assert_eq!(json!({"span": {"from": 1.0, "to": 4094.0}}), nested.get_pool_properties());
+        let strategy = db.get_strategy(nested.allocation_strategy_id).unwrap();
+        assert_eq!(Some("VLAN ids"), strategy.docs.description.as_deref());
+
+        // existing pools are skipped
+        let report = db.import_from_resource_manager(&mut upstream).unwrap();
+        assert_eq!((0, 0), (report.strategies, report.pools));
+        db.client.batch_execute(&format!("DROP SCHEMA {} CASCADE", schema)).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, bail};
+use serde_json::Value;
+use sha1::{Digest, Sha1};
+use tracing::*;
+
+use crate::shutdown::Shutdown;
+
+// Appended to `Sec-WebSocket-Key` of the handshake, see RFC 6455.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Idle connections are pinged, which also detects clients that went away.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+// How long frames of the client wait for an answer while no events arrive.
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Control frames of clients cannot be larger, see RFC 6455.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+// `Sec-WebSocket-Accept` answering the `Sec-WebSocket-Key` of a client.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(Sha1::digest(format!("{}{}", key.trim(), ACCEPT_GUID).as_bytes()))
+}
+
+fn write_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) -> Result<()> {
+    // final frame, servers do not mask
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+// Opcode and unmasked payload of a client frame. Data frames are skipped without being buffered.
+fn read_frame(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header)?;
+    let opcode = header[0] & 0x0f;
+    let len = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if header[1] & 0x80 == 0 {
+        bail!("Client frame is not masked");
+    }
+    let mut mask = [0; 4];
+    stream.read_exact(&mut mask)?;
+    if opcode & 0x8 == 0 {
+        skip(stream, len)?;
+        return Ok((opcode, vec![]));
+    }
+    if len as usize > MAX_CONTROL_PAYLOAD {
+        bail!("Control frame of {} bytes is too large", len);
+    }
+    let mut payload = vec![0; len as usize];
+    stream.read_exact(&mut payload)?;
+    for (idx, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[idx % 4];
+    }
+    Ok((opcode, payload))
+}
+
+fn skip(stream: &mut TcpStream, len: u64) -> Result<()> {
+    std::io::copy(&mut stream.take(len), &mut std::io::sink())?;
+    Ok(())
+}
+
+// Frames received from the client that the pushing thread has to answer.
+enum Control {
+    Ping(Vec<u8>),
+    Closed,
+}
+
+// Answers the upgrade request and pushes events to the client as text frames on a thread of its own,
+// until the client closes the connection or goes away, or shutdown is requested.
+pub fn serve_events(mut stream: TcpStream, key: &str, events: Receiver<Value>, shutdown: Shutdown) -> Result<()> {
+    write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+        Sec-WebSocket-Accept: {}\r\n\r\n", accept_key(key))?;
+    stream.flush()?;
+    let (sender, controls) = mpsc::channel();
+    let mut reader = stream.try_clone()?;
+    thread::Builder::new().name("websocket-reader".to_owned()).spawn(move || {
+        loop {
+            let control = match read_frame(&mut reader) {
+                Ok((OPCODE_PING, payload)) => Control::Ping(payload),
+                Ok((OPCODE_CLOSE, _)) | Err(_) => Control::Closed,
+                Ok(_) => continue,
+            };
+            let closed = matches!(control, Control::Closed);
+            if sender.send(control).is_err() || closed {
+                break;
+            }
+        }
+    })?;
+    thread::Builder::new().name("websocket".to_owned()).spawn(move || {
+        if let Err(err) = push_events(&mut stream, &events, &controls, shutdown) {
+            debug!("WebSocket closed: {:#}", err);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    })?;
+    Ok(())
+}
+
+fn push_events(stream: &mut TcpStream, events: &Receiver<Value>, controls: &Receiver<Control>, shutdown: Shutdown)
+               -> Result<()> {
+    let mut idle_since = Instant::now();
+    while !shutdown.is_requested() {
+        match controls.try_recv() {
+            Ok(Control::Ping(payload)) => write_frame(stream, OPCODE_PONG, &payload)?,
+            Ok(Control::Closed) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {}
+        }
+        match events.recv_timeout(CONTROL_POLL_INTERVAL) {
+            Ok(event) => {
+                write_frame(stream, OPCODE_TEXT, event.to_string().as_bytes())?;
+                idle_since = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) if idle_since.elapsed() >= PING_INTERVAL => {
+                write_frame(stream, OPCODE_PING, &[])?;
+                idle_since = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    // 1000 is a normal closure, 1001 a server going away
+    let code: u16 = if shutdown.is_requested() { 1001 } else { 1000 };
+    write_frame(stream, OPCODE_CLOSE, &code.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn websocket_accept_key() {
+        // example of RFC 6455
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", accept_key("dGhlIHNhbXBsZSBub25jZQ=="));
+    }
+}
@@ -0,0 +1,380 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tracing::*;
+
+use crate::{DB, WasmerEnv};
+use crate::outbox::Webhook;
+use crate::shutdown::Shutdown;
+
+/// Advisory lock held by the worker that runs pool maintenance.
+pub const DEFAULT_LEADER_LOCK_KEY: i64 = 0x726d_776f_726b;
+
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    // pause between checks for pending allocation jobs
+    pub poll_interval: Duration,
+    // pause between gc runs of the leader
+    pub gc_interval: Duration,
+    pub gc_batch_size: i64,
+    // pending jobs of pools sharing a strategy allocated by one script invocation
+    pub job_batch_size: i64,
+    // running jobs are failed by the leader after this duration, see `DB::fail_stale_allocation_jobs`
+    pub job_timeout: Duration,
+    pub retention: Duration,
+    // pause between utilization samples, alert rule evaluations and exhaustion predictions of the leader,
+    // see `DB::record_pool_stats`
+    pub stats_interval: Duration,
+    pub stats_retention: Duration,
+    // None to leave the outbox to other workers, see `DB::drain_outbox`
+    pub outbox_webhook: Option<Webhook>,
+    pub outbox_batch_size: i64,
+    pub leader_lock_key: i64,
+    // how long the tick running when shutdown is requested and the last delivery of the outbox may take
+    pub drain_timeout: Duration,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> WorkerConfig {
+        WorkerConfig {
+            poll_interval: Duration::from_secs(5),
+            gc_interval: Duration::from_secs(60),
+            gc_batch_size: 1000,
+            job_batch_size: 10,
+            job_timeout: Duration::from_secs(600),
+            retention: Duration::from_secs(7 * 24 * 3600),
+            stats_interval: Duration::from_secs(300),
+            stats_retention: Duration::from_secs(90 * 24 * 3600),
+            outbox_webhook: None,
+            outbox_batch_size: 100,
+            leader_lock_key: DEFAULT_LEADER_LOCK_KEY,
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// What a single `Worker::tick` did.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TickReport {
+    pub scheduled_jobs: u64,
+    pub jobs: u64,
+    // running jobs past `WorkerConfig::job_timeout`, None if this worker is not the leader
+    pub stale_jobs: Option<u64>,
+    // None if this worker is not the leader or gc was not due yet
+    pub collected_pools: Option<u64>,
+    // None if this worker is not the leader or sampling was not due yet
+    pub sampled_pools: Option<u64>,
+    // alert rules that crossed their threshold, evaluated together with sampling
+    pub fired_alerts: Option<u64>,
+    // growing pools with a predicted exhaustion, predicted together with sampling
+    pub predicted_pools: Option<u64>,
+    // None if this worker is not the leader or has no outbox webhook
+    pub delivered_events: Option<u64>,
+    // periodic steps of the leader that failed, their counts above are None
+    pub failed_steps: u64,
+}
+
+/// Enqueues scheduled allocations, processes allocation jobs and, if it is the leader, fails stale jobs, expires
+/// leases, promotes resources out of quarantine, purges retired resources, samples utilization of pools, evaluates
+/// their alert rules, predicts their exhaustion and delivers events of the outbox.
+///
+/// Every replica runs schedules and allocation jobs, they are claimed with SKIP LOCKED. Maintenance is done
+/// only by the replica holding the session level advisory lock, the lock is released by
+/// Postgres when the leader's connection closes.
+pub struct Worker {
+    db: DB,
+    wasmer_env: WasmerEnv,
+    config: WorkerConfig,
+    leader: bool,
+    last_gc: Option<Instant>,
+    last_stats: Option<Instant>,
+}
+
+impl Worker {
+    pub fn new(db: DB, wasmer_env: WasmerEnv, config: WorkerConfig) -> Worker {
+        Worker { db, wasmer_env, config, leader: false, last_gc: None, last_stats: None }
+    }
+
+    // Ticks until shutdown is requested, the current tick is finished first.
+    pub fn run(mut self, shutdown: Shutdown) -> Result<()> {
+        info!("Worker started with {:?}", self.config);
+        if let Err(err) = self.wasmer_env.warm_up(&mut self.db) {
+            warn!("Warm-up failed: {:#}", err);
+        }
+        let mut tick_started = Instant::now();
+        while !shutdown.is_requested() {
+            tick_started = Instant::now();
+            match self.tick() {
+                Ok(report) if report != TickReport::default() => debug!("Worker tick: {:?}", report),
+                Ok(_) => {}
+                Err(err) => {
+                    error!("Worker tick failed: {:#}", err);
+                    if self.db.client.is_closed() {
+                        // leadership is lost together with the connection
+                        self.leader = false;
+                        // keep running while the database restarts, the next tick tries again
+                        match DB::new_from_env() {
+                            Ok(db) => self.db = db,
+                            Err(err) => error!("Cannot reconnect: {:#}", err),
+                        }
+                    }
+                }
+            }
+            shutdown.sleep(self.config.poll_interval);
+        }
+        // shutdown was requested during the last tick or the pause after it
+        let delivered = self.finish(tick_started + self.config.drain_timeout);
+        info!("Worker stopped, delivered {} outbox events", delivered);
+        Ok(())
+    }
+
+    // The leader delivers events committed before shutdown, instead of leaving them to the next leader,
+    // until the outbox is empty or the deadline passes. Returns number of delivered events.
+    fn finish(&mut self, deadline: Instant) -> u64 {
+        if !self.leader || self.config.outbox_webhook.is_none() {
+            return 0;
+        }
+        self.drain_outbox(Some(deadline))
+    }
+
+    // Does nothing during maintenance, due schedules and queued jobs wait for the next tick after it.
+    pub fn tick(&mut self) -> Result<TickReport> {
+        if self.db.sync_maintenance()? {
+            return Ok(TickReport::default());
+        }
+        let mut report = TickReport {
+            scheduled_jobs: self.db.enqueue_due_schedules()?.len() as u64,
+            ..TickReport::default()
+        };
+        loop {
+            let batch = self.db.run_allocation_batch(&mut self.wasmer_env, self.config.job_batch_size)?;
+            if batch.is_empty() {
+                break;
+            }
+            report.jobs += batch.len() as u64;
+        }
+
+        if !self.leader {
+            self.leader = self.db.try_advisory_lock(self.config.leader_lock_key)?;
+            if self.leader {
+                info!("Worker became the leader");
+            }
+        }
+        if self.leader {
+            let job_timeout = self.config.job_timeout;
+            report.stale_jobs = self.run_step(&mut report.failed_steps, "stale job recovery",
+                                              |worker| worker.db.fail_stale_allocation_jobs(job_timeout));
+        }
+        let gc_due = self.last_gc.is_none_or(|last_gc| last_gc.elapsed() >= self.config.gc_interval);
+        if self.leader && gc_due {
+            report.collected_pools = self.run_step(&mut report.failed_steps, "gc", Worker::gc);
+            self.last_gc = Some(Instant::now());
+        }
+        let stats_due = self.last_stats.is_none_or(|last_stats| last_stats.elapsed() >= self.config.stats_interval);
+        if self.leader && stats_due {
+            let retention = self.config.stats_retention;
+            report.sampled_pools = self.run_step(&mut report.failed_steps, "sampling",
+                                                 |worker| worker.db.record_pool_stats(retention));
+            report.fired_alerts = self.run_step(&mut report.failed_steps, "alert evaluation", |worker| {
+                Ok(worker.db.evaluate_alert_rules(&mut worker.wasmer_env)?.fired.len() as u64)
+            });
+            report.predicted_pools = self.run_step(&mut report.failed_steps, "exhaustion prediction", |worker| {
+                worker.db.record_exhaustion_predictions(&mut worker.wasmer_env)
+            });
+            self.last_stats = Some(Instant::now());
+        }
+        if self.leader && self.config.outbox_webhook.is_some() {
+            report.delivered_events = Some(self.drain_outbox(None));
+        }
+        Ok(report)
+    }
+
+    // A failed step of the leader is logged and counted instead of failing the tick, so that the following steps
+    // still run and the step is retried after its interval rather than every tick.
+    fn run_step(&mut self, failed_steps: &mut u64, step: &str, f: impl FnOnce(&mut Worker) -> Result<u64>)
+                -> Option<u64> {
+        match f(self) {
+            Ok(count) => Some(count),
+            Err(err) => {
+                error!("Worker step {} failed: {:#}", step, err);
+                *failed_steps += 1;
+                None
+            }
+        }
+    }
+
+    // Drains every tick until the outbox is empty, a delivery fails or the deadline passes, failed events wait
+    // for the next tick.
+    fn drain_outbox(&mut self, deadline: Option<Instant>) -> u64 {
+        let (webhook, batch_size) = match &self.config.outbox_webhook {
+            Some(webhook) => (webhook, self.config.outbox_batch_size),
+            None => return 0,
+        };
+        let mut delivered = 0;
+        while deadline.is_none_or(|deadline| Instant::now() < deadline) {
+            match self.db.drain_outbox(batch_size, |events| webhook.deliver(events)) {
+                Ok(count) => {
+                    delivered += count;
+                    if count < batch_size as u64 {
+                        return delivered;
+                    }
+                }
+                Err(err) => {
+                    warn!("{:#}", err);
+                    return delivered;
+                }
+            }
+        }
+        delivered
+    }
+
+    fn gc(&mut self) -> Result<u64> {
+        let pool_ids = self.db.find_pools_to_gc(self.config.retention)?;
+        for pool_id in &pool_ids {
+            let pool = self.db.get_resource_pool_by_id(*pool_id)?;
+            let (pool, report) = self.db.gc_pool(pool, self.config.gc_batch_size, self.config.retention)?;
+            info!("Collected pool '{}': {:?}", pool.name, report);
+        }
+        Ok(pool_ids.len() as u64)
+    }
+}
+
+impl DB {
+    // Session level lock, held until it is unlocked or the connection is closed.
+    // Advisory locks are database wide, the key is combined with the schema so that instances do not share it.
+    pub fn try_advisory_lock(&mut self, key: i64) -> Result<bool> {
+        let row = self.client.query_one(
+            "SELECT pg_try_advisory_lock($1 # coalesce(hashtext(current_schema()), 0)::bigint)", &[&key])?;
+        Ok(row.get(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use rand::Rng;
+    use serde_json::json;
+
+    use crate::AllocationOptions;
+    use crate::tests::{create_random_pool, initialize_logging};
+    use super::*;
+
+    fn new_worker(config: &WorkerConfig) -> Worker {
+        Worker::new(DB::new_from_env().unwrap(), WasmerEnv::new().unwrap(), config.clone())
+    }
+
+    #[test]
+    fn worker_leader_election() {
+        initialize_logging();
+
+        let config = WorkerConfig {
+            gc_interval: Duration::from_secs(3600),
+            leader_lock_key: rand::thread_rng().gen(),
+            ..WorkerConfig::default()
+        };
+        let mut leader = new_worker(&config);
+        let mut follower = new_worker(&config);
+        let report = leader.tick().unwrap();
+        assert!(report.stale_jobs.is_some());
+        assert!(report.collected_pools.is_some());
+        assert!(report.sampled_pools.is_some());
+        assert!(report.fired_alerts.is_some());
+        assert!(report.predicted_pools.is_some());
+        let report = follower.tick().unwrap();
+        assert_eq!((None, None), (report.stale_jobs, report.collected_pools));
+        // gc and sampling are not due yet
+        let report = leader.tick().unwrap();
+        assert_eq!((None, None), (report.collected_pools, report.sampled_pools));
+
+        let mut db = DB::new_from_env().unwrap();
+        let pool = create_random_pool(&mut db).unwrap();
+        let job_id = db.enqueue_allocation(pool.id, json!({}), &AllocationOptions::default()).unwrap();
+        // jobs are processed by any worker
+        follower.tick().unwrap();
+        assert_ne!(crate::jobs::JobStatus::Pending, db.get_job_status(job_id).unwrap().status);
+
+        // the lock is released once the backend of the closed connection exits
+        drop(leader);
+        let mut attempts = 0;
+        while follower.tick().unwrap().collected_pools.is_none() {
+            attempts += 1;
+            assert!(attempts < 50, "Follower did not become the leader");
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn worker_failed_step() {
+        initialize_logging();
+
+        // purging samples older than the retention fails, it is out of range
+        let config = WorkerConfig {
+            stats_retention: Duration::from_secs(u64::MAX),
+            leader_lock_key: rand::thread_rng().gen(),
+            ..WorkerConfig::default()
+        };
+        let mut leader = new_worker(&config);
+        let report = leader.tick().unwrap();
+        assert_eq!((1, None), (report.failed_steps, report.sampled_pools));
+        assert!(report.collected_pools.is_some() && report.fired_alerts.is_some() && report.predicted_pools.is_some());
+        // the step waits for its interval
+        let report = leader.tick().unwrap();
+        assert_eq!((0, None, None), (report.failed_steps, report.sampled_pools, report.collected_pools));
+    }
+
+    #[test]
+    fn worker_drains_outbox_when_stopping() {
+        initialize_logging();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = WorkerConfig {
+            outbox_webhook: Some(format!("http://{}/events", listener.local_addr().unwrap()).parse().unwrap()),
+            leader_lock_key: rand::thread_rng().gen(),
+            ..WorkerConfig::default()
+        };
+        let (sender, received) = mpsc::channel();
+        // acknowledges deliveries of this and other tests
+        thread::spawn(move || {
+            for mut stream in listener.incoming().map(Result::unwrap) {
+                let mut request = vec![0; 1024 * 1024];
+                let mut len = 0;
+                while len == 0 || request[len - 1] != b'}' {
+                    len += stream.read(&mut request[len..]).unwrap();
+                }
+                stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+                let _ = sender.send(String::from_utf8(request[..len].to_vec()).unwrap());
+            }
+        });
+        let mut leader = new_worker(&config);
+        leader.tick().unwrap();
+        let mut db = DB::new_from_env().unwrap();
+        db.outbox = true;
+        let pool = create_random_pool(&mut db).unwrap();
+        db.allocate_resources(pool.clone(), &mut WasmerEnv::new().unwrap(), json!({}),
+                              &AllocationOptions::default()).unwrap();
+
+        assert_eq!(0, leader.finish(Instant::now()));
+        assert!(leader.finish(Instant::now() + Duration::from_secs(10)) >= 1);
+        let event = format!("\"pool\":{}", pool.id);
+        // sent by the listener after it responded
+        let mut requests = std::iter::from_fn(|| received.recv_timeout(Duration::from_secs(10)).ok());
+        assert!(requests.any(|request| request.contains(&event)));
+    }
+
+    #[test]
+    fn worker_shutdown() {
+        initialize_logging();
+
+        let config = WorkerConfig { poll_interval: Duration::from_secs(3600), ..WorkerConfig::default() };
+        let shutdown = Shutdown::new();
+        let handle = thread::spawn(move || new_worker(&config).run(shutdown));
+        // the pause between ticks is interrupted
+        thread::sleep(Duration::from_millis(200));
+        shutdown.request();
+        handle.join().unwrap().unwrap();
+    }
+}